@@ -0,0 +1,64 @@
+use crate::{
+    core::{progress, writeback},
+    error::AppError,
+    AppState,
+};
+use std::path::PathBuf;
+use tauri::State;
+
+fn library_path(state: &State<'_, AppState>) -> Result<PathBuf, AppError> {
+    Ok(PathBuf::from(&state.server.default_library()?.path))
+}
+
+/// Lists the Calibre custom columns that can receive reading progress.
+#[tauri::command]
+pub async fn list_calibre_columns(
+    state: State<'_, AppState>,
+) -> Result<Vec<writeback::CustomColumn>, AppError> {
+    let library_path = library_path(&state)?;
+    tauri::async_runtime::spawn_blocking(move || writeback::list_columns(&library_path))
+        .await
+        .map_err(|e| AppError::Unknown(e.to_string()))?
+}
+
+#[tauri::command]
+pub fn get_calibre_writeback(
+    state: State<'_, AppState>,
+) -> Result<writeback::WritebackConfig, AppError> {
+    writeback::load_config(&state.server.app_data_dir)
+}
+
+#[tauri::command]
+pub fn set_calibre_writeback(
+    config: writeback::WritebackConfig,
+    state: State<'_, AppState>,
+) -> Result<(), AppError> {
+    writeback::save_config(&state.server.app_data_dir, &config)
+}
+
+/// Writes the current reading progress into the configured Calibre columns.
+///
+/// Fails without changing anything if write-back is disabled or Calibre has
+/// the library locked.
+#[tauri::command]
+pub async fn run_calibre_writeback(
+    state: State<'_, AppState>,
+) -> Result<writeback::WritebackReport, AppError> {
+    let library = state.server.default_library()?;
+    let library_path = PathBuf::from(&library.path);
+    let app_data_dir = state.server.app_data_dir.clone();
+    let records = library.progress_db.run(progress::get_all_progress).await?;
+
+    // Retries sleep while Calibre holds the lock, so keep this off the async runtime
+    tauri::async_runtime::spawn_blocking(move || {
+        let config = writeback::load_config(&app_data_dir)?;
+        if !config.enabled {
+            return Err(AppError::Other(
+                "Calibre write-back is disabled".to_string(),
+            ));
+        }
+        writeback::write_back(&library_path, &app_data_dir, &config, &records)
+    })
+    .await
+    .map_err(|e| AppError::Unknown(e.to_string()))?
+}
@@ -0,0 +1,30 @@
+use crate::{core::inbox, error::AppError, AppState};
+use tauri::State;
+
+/// Lists books uploaded by clients that are waiting to be imported.
+#[tauri::command]
+pub fn list_inbox(state: State<'_, AppState>) -> Result<Vec<inbox::InboxItem>, AppError> {
+    inbox::list_items(&state.server.app_data_dir)
+}
+
+/// Moves an uploaded book into `destination`, typically Calibre's auto-add folder,
+/// and returns the new file path.
+#[tauri::command]
+pub fn import_inbox_item(
+    id: String,
+    destination: String,
+    state: State<'_, AppState>,
+) -> Result<String, AppError> {
+    let path = inbox::move_item_to(
+        &state.server.app_data_dir,
+        &id,
+        std::path::Path::new(&destination),
+    )?;
+    Ok(path.to_string_lossy().to_string())
+}
+
+/// Deletes an uploaded book without importing it.
+#[tauri::command]
+pub fn discard_inbox_item(id: String, state: State<'_, AppState>) -> Result<(), AppError> {
+    inbox::remove_item(&state.server.app_data_dir, &id)
+}
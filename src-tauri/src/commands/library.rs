@@ -1,85 +1,193 @@
-use crate::{core::db, error::AppError, models::Book, AppState};
+use crate::{
+    core::{
+        devices::{Device, Scope},
+        discovery,
+        libraries::LibraryInfo,
+        rules::{self, SyncPlan, SyncRule},
+        sync::{self, SyncManager, SyncTask},
+    },
+    error::AppError,
+    models::Book,
+    AppState,
+};
+use std::path::Path;
 use tauri::State;
 
 #[tauri::command]
 pub fn get_books(library_path: String, state: State<'_, AppState>) -> Result<Vec<Book>, AppError> {
-    // 1. Fetch from DB
-    let books = db::get_calibre_metadata(&library_path)?;
-
-    // 2. Update Server State Cache
-    {
-        let mut path_lock = state
-            .server
-            .library_path
-            .lock()
-            .map_err(|_| AppError::Unknown("Failed to lock library path".to_string()))?;
-        *path_lock = Some(library_path.clone());
-
-        let mut books_lock = state
-            .server
-            .books
-            .lock()
-            .map_err(|_| AppError::Unknown("Failed to lock books cache".to_string()))?;
-        *books_lock = books.clone();
-    }
-
-    Ok(books)
+    // Reads through the server's kept connection and refreshes its cache
+    state.server.load_library(&library_path)
 }
 
 #[tauri::command]
 pub fn set_library_path(path: String, state: State<'_, AppState>) -> Result<(), AppError> {
     // Also fetch and cache books when explicitly setting path
-    let books = db::get_calibre_metadata(&path)?;
-
-    let mut lib_path = state
-        .server
-        .library_path
-        .lock()
-        .map_err(|_| AppError::Unknown("Failed to lock library path".to_string()))?;
-    *lib_path = Some(path);
+    state.server.load_library(&path)?;
+    Ok(())
+}
 
-    let mut books_lock = state
-        .server
-        .books
-        .lock()
-        .map_err(|_| AppError::Unknown("Failed to lock books cache".to_string()))?;
-    *books_lock = books;
+/// Where books are synced from and downloaded to.
+#[derive(serde::Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SyncTarget {
+    pub host_ip: String,
+    pub host_port: u16,
+    pub token: String,
+    /// Stable identity of the host, its ID or else its address.
+    pub host_key: String,
+    /// Library on the host; `None` for its default one.
+    pub library_id: Option<String>,
+    pub destination_root: String,
+}
 
-    Ok(())
+impl SyncTarget {
+    fn task(&self, book: Book) -> SyncTask {
+        SyncTask {
+            book,
+            host_ip: self.host_ip.clone(),
+            host_port: self.host_port,
+            token: self.token.clone(),
+            host_key: self.host_key.clone(),
+            library_id: self.library_id.clone(),
+            destination_root: std::path::PathBuf::from(&self.destination_root),
+        }
+    }
 }
 
 #[tauri::command]
 pub async fn start_bulk_sync(
     books: Vec<Book>,
-    host_ip: String,
-    host_port: u16,
-    token: String,
-    destination_root: String,
+    target: SyncTarget,
     state: State<'_, AppState>,
 ) -> Result<(), AppError> {
-    // Clone the sync manager reference before locking
-    let sync_manager = {
-        let sync_manager_lock = state.sync_manager.lock().unwrap();
-        sync_manager_lock
-            .as_ref()
-            .ok_or_else(|| AppError::Other("Sync manager not initialized".to_string()))?
-            .clone()
-    }; // Lock is dropped here
-
-    let tasks = books
-        .into_iter()
-        .map(|book| crate::core::sync::SyncTask {
-            book,
-            host_ip: host_ip.clone(),
-            host_port,
-            token: token.clone(),
-            destination_root: std::path::PathBuf::from(&destination_root),
-        })
-        .collect();
+    let tasks = books.into_iter().map(|book| target.task(book)).collect();
 
-    sync_manager
+    sync_manager(&state)?
         .add_tasks(tasks)
         .await
         .map_err(AppError::Other)?;
     Ok(())
 }
+
+/// Evaluates the sync rules for one of a host's libraries against its
+/// current manifest and progress, without downloading anything.
+#[tauri::command]
+pub async fn preview_sync_rules(
+    rules: Vec<SyncRule>,
+    target: SyncTarget,
+    state: State<'_, AppState>,
+) -> Result<SyncPlan, AppError> {
+    plan_sync_rules(&rules, &target, &sync_manager(&state)?).await
+}
+
+/// Downloads the books the sync rules select that are not on this device
+/// yet, and reports what was queued.
+#[tauri::command]
+pub async fn run_sync_rules(
+    rules: Vec<SyncRule>,
+    target: SyncTarget,
+    state: State<'_, AppState>,
+) -> Result<SyncPlan, AppError> {
+    let sync_manager = sync_manager(&state)?;
+    let plan = plan_sync_rules(&rules, &target, &sync_manager).await?;
+
+    let tasks = plan
+        .books
+        .iter()
+        .map(|book| target.task(book.clone()))
+        .collect();
+    sync_manager
+        .add_tasks(tasks)
+        .await
+        .map_err(AppError::Other)?;
+    Ok(plan)
+}
+
+fn sync_manager(state: &AppState) -> Result<SyncManager, AppError> {
+    state
+        .sync_manager
+        .lock()
+        .unwrap()
+        .as_ref()
+        .cloned()
+        .ok_or_else(|| AppError::Other("Sync manager not initialized".to_string()))
+}
+
+/// Plans the rules of the target's library; books already downloaded from
+/// it or waiting in the sync queue are not downloaded again.
+async fn plan_sync_rules(
+    rules: &[SyncRule],
+    target: &SyncTarget,
+    sync_manager: &SyncManager,
+) -> Result<SyncPlan, AppError> {
+    let library_id = target.library_id.as_deref();
+    let selected: Vec<SyncRule> = rules
+        .iter()
+        .filter(|rule| rule.library_id.as_deref() == library_id)
+        .cloned()
+        .collect();
+    let api_base = discovery::api_base(&target.host_ip, target.host_port, library_id);
+    let (books, progress) =
+        rules::fetch_library(&reqwest::Client::new(), &api_base, &target.token).await?;
+    let matched =
+        rules::matching_books(&selected, &books, &progress, chrono::Utc::now().timestamp());
+
+    let queued = sync_manager.active_queue.lock().unwrap().clone();
+    let dir = sync::library_dir(
+        Path::new(&target.destination_root),
+        &target.host_key,
+        library_id,
+    );
+    Ok(rules::plan(matched, |book| {
+        queued.contains(&sync::queue_key(&target.host_key, library_id, book.id))
+            || dir.join(&book.path).exists()
+    }))
+}
+
+/// Lists the libraries this host serves, the default one first.
+#[tauri::command]
+pub fn list_libraries(state: State<'_, AppState>) -> Vec<LibraryInfo> {
+    state.server.library_infos()
+}
+
+/// Serves another Calibre library besides the default one.
+#[tauri::command]
+pub fn add_library(
+    path: String,
+    name: Option<String>,
+    state: State<'_, AppState>,
+) -> Result<LibraryInfo, AppError> {
+    state.server.add_library(&path, name)
+}
+
+#[tauri::command]
+pub fn remove_library(id: String, state: State<'_, AppState>) -> Result<bool, AppError> {
+    state.server.remove_library(&id)
+}
+
+/// Lists the devices paired with this host.
+#[tauri::command]
+pub fn list_devices(state: State<'_, AppState>) -> Vec<Device> {
+    state.server.devices()
+}
+
+/// Replaces what a paired device may see and do.
+#[tauri::command]
+pub fn set_device_scope(
+    device_id: String,
+    scope: Scope,
+    state: State<'_, AppState>,
+) -> Result<(), AppError> {
+    state.server.set_device_scope(&device_id, scope)
+}
+
+/// What newly paired devices may see and do.
+#[tauri::command]
+pub fn get_default_scope(state: State<'_, AppState>) -> Result<Scope, AppError> {
+    state.server.default_scope()
+}
+
+#[tauri::command]
+pub fn set_default_scope(scope: Scope, state: State<'_, AppState>) -> Result<(), AppError> {
+    state.server.set_default_scope(scope)
+}
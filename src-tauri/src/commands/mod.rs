@@ -1,2 +1,5 @@
+pub mod calibre;
+pub mod inbox;
 pub mod library;
 pub mod network;
+pub mod reading;
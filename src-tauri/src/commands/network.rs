@@ -1,21 +1,131 @@
-use crate::{models::ConnectionInfo, AppState};
-use tauri::State;
+use crate::{
+    core::{discovery, pairing},
+    error::AppError,
+    models::ConnectionInfo,
+    AppState,
+};
+use tauri::{AppHandle, State};
 
 #[tauri::command]
 pub fn get_connection_info(state: State<'_, AppState>) -> ConnectionInfo {
+    let addresses: Vec<String> = discovery::local_addresses()
+        .iter()
+        .map(|ip| ip.to_string())
+        .collect();
     ConnectionInfo {
-        ip: local_ip_address::local_ip()
-            .unwrap_or("127.0.0.1".parse().unwrap())
-            .to_string(),
-        port: 8080,
+        ip: addresses[0].clone(),
+        addresses,
+        port: state.server.port(),
         hostname: hostname::get()
             .map(|h| h.to_string_lossy().to_string())
             .unwrap_or("Unknown".to_string()),
         pin: Some(state.server.pin.clone()),
+        advertisement: state.server.advertisement(),
+        compatible: true,
     }
 }
 
+/// Lists hosts found over mDNS and added by address. Hosts running an API this build cannot talk
+/// to are left out unless `include_incompatible` is set.
 #[tauri::command]
-pub fn discover_hosts(state: State<'_, AppState>) -> Vec<ConnectionInfo> {
-    state.discovery.hosts.lock().unwrap().clone()
+pub fn discover_hosts(
+    include_incompatible: Option<bool>,
+    state: State<'_, AppState>,
+) -> Vec<ConnectionInfo> {
+    let include_incompatible = include_incompatible.unwrap_or(false);
+    state
+        .discovery
+        .hosts()
+        .into_iter()
+        .filter(|h| include_incompatible || h.compatible)
+        .collect()
+}
+
+/// Adds a host by address for networks where mDNS does not reach, and
+/// probes it right away.
+#[tauri::command]
+pub async fn add_manual_host(
+    address: String,
+    port: Option<u16>,
+    app: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<ConnectionInfo, AppError> {
+    let added = state
+        .discovery
+        .add_manual(&address, port.unwrap_or(discovery::DEFAULT_PORT))?;
+    state.discovery.probe_all(&reqwest::Client::new()).await;
+    crate::emit_discovery_update(&app, &state.discovery);
+
+    // Look the host up again to return what the probe learned
+    Ok(state
+        .discovery
+        .hosts()
+        .into_iter()
+        .find(|h| h.manual && h.addresses.contains(&added.ip) && h.port == added.port)
+        .unwrap_or(added))
+}
+
+/// Forgets a host added by address.
+#[tauri::command]
+pub fn remove_manual_host(
+    key: String,
+    app: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<(), AppError> {
+    if state.discovery.remove_manual(&key)? {
+        crate::emit_discovery_update(&app, &state.discovery);
+    }
+    Ok(())
+}
+
+/// Opens or closes PIN pairing for new devices.
+#[tauri::command]
+pub fn set_pairing_open(open: bool, state: State<'_, AppState>) {
+    state.server.set_pairing_open(open);
+}
+
+/// Reports whether mDNS and the UDP beacon fallback are working.
+#[tauri::command]
+pub fn get_discovery_status(state: State<'_, AppState>) -> discovery::DiscoveryStatus {
+    state.discovery.status()
+}
+
+/// Issues a one-time pairing code for this host, with its QR code, so a
+/// device can pair by scanning instead of typing the address and PIN.
+#[tauri::command]
+pub fn create_pairing_code(state: State<'_, AppState>) -> Result<pairing::PairingCode, AppError> {
+    let addresses = discovery::local_addresses()
+        .iter()
+        .map(|ip| ip.to_string())
+        .collect();
+    state.server.pairing_code(addresses, state.server.port())
+}
+
+/// Pairs with the host a scanned or pasted pairing URI points at. Hosts not
+/// discovered otherwise are remembered like ones added by address.
+#[tauri::command]
+pub async fn pair_with_uri(
+    uri: String,
+    app: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<pairing::Paired, AppError> {
+    let device = hostname::get()
+        .map(|h| h.to_string_lossy().to_string())
+        .ok();
+    let paired = pairing::pair_with_uri(
+        &reqwest::Client::new(),
+        &uri,
+        device,
+        chrono::Utc::now().timestamp(),
+    )
+    .await?;
+    let key = paired.host.key();
+    if !state.discovery.hosts().iter().any(|h| h.key() == key) {
+        state
+            .discovery
+            .add_manual(&paired.host.ip, paired.host.port)?;
+        state.discovery.probe_all(&reqwest::Client::new()).await;
+        crate::emit_discovery_update(&app, &state.discovery);
+    }
+    Ok(paired)
 }
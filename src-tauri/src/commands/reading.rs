@@ -0,0 +1,116 @@
+use crate::{
+    core::{annotations, importers, progress},
+    error::AppError,
+    AppState,
+};
+use std::collections::HashSet;
+use std::path::PathBuf;
+use tauri::State;
+
+/// Returns reading statistics for the host's library, optionally limited to
+/// sessions starting between `from` and `to` (Unix timestamps).
+#[tauri::command]
+pub async fn get_reading_stats(
+    from: Option<i64>,
+    to: Option<i64>,
+    utc_offset_minutes: Option<i32>,
+    state: State<'_, AppState>,
+) -> Result<progress::ReadingStats, AppError> {
+    let library = state.server.default_library()?;
+    let books = library.books()?.clone();
+    let query = progress::StatsQuery {
+        from,
+        to,
+        utc_offset_minutes: utc_offset_minutes.unwrap_or(0),
+    };
+    library
+        .progress_db
+        .run(move |conn| progress::get_reading_stats(conn, &books, &HashSet::new(), &query))
+        .await
+}
+
+/// Reads reading history from `path` and proposes a library match for each
+/// book, without changing anything.
+///
+/// `path` is a KOReader `statistics.sqlite3`, a folder containing KOReader
+/// `.sdr` sidecars, or a Calibre library folder (the current library if
+/// omitted).
+#[tauri::command]
+pub async fn preview_history_import(
+    source: importers::ImportSource,
+    path: Option<String>,
+    state: State<'_, AppState>,
+) -> Result<importers::ImportPlan, AppError> {
+    let library = state.server.default_library()?;
+    let library_path = PathBuf::from(&library.path);
+    let books = library.books()?.clone();
+    let progress_db = library.progress_db.clone();
+
+    // Matching by MD5 may hash the whole library the first time
+    tauri::async_runtime::spawn_blocking(move || {
+        let conn = progress_db.get()?;
+        let source_path = path.map(PathBuf::from);
+        let required = |p: Option<PathBuf>| {
+            p.ok_or_else(|| AppError::Other("No import path given".to_string()))
+        };
+        match source {
+            importers::ImportSource::KoreaderStatistics => importers::preview_koreader_statistics(
+                &required(source_path)?,
+                &conn,
+                &library_path,
+                &books,
+            ),
+            importers::ImportSource::KoreaderSidecar => importers::preview_koreader_sidecars(
+                &required(source_path)?,
+                &conn,
+                &library_path,
+                &books,
+            ),
+            importers::ImportSource::Calibre => importers::preview_calibre(
+                source_path.as_deref().unwrap_or(&library_path),
+                &conn,
+                &library_path,
+                &books,
+            ),
+        }
+    })
+    .await
+    .map_err(|e| AppError::Unknown(e.to_string()))?
+}
+
+/// Imports the included, matched items of a reviewed plan.
+#[tauri::command]
+pub async fn apply_history_import(
+    plan: importers::ImportPlan,
+    state: State<'_, AppState>,
+) -> Result<importers::ImportReport, AppError> {
+    let library = state.server.default_library()?;
+    let book_ids = library.books()?.iter().map(|b| b.id).collect();
+    library
+        .progress_db
+        .run(move |conn| importers::apply_plan(conn, &plan, &book_ids))
+        .await
+}
+
+/// Exports a book's highlights and notes as Markdown or JSON text.
+#[tauri::command]
+pub async fn export_annotations(
+    book_id: i64,
+    format: Option<annotations::ExportFormat>,
+    state: State<'_, AppState>,
+) -> Result<String, AppError> {
+    let library = state.server.default_library()?;
+    let book = library
+        .books()?
+        .iter()
+        .find(|b| b.id == book_id)
+        .cloned()
+        .ok_or_else(|| AppError::Other(format!("Book {} is not in the library", book_id)))?;
+    library
+        .annotations_db
+        .run(move |conn| {
+            let (document, _) = annotations::export_book(conn, &book, format.unwrap_or_default())?;
+            Ok(document)
+        })
+        .await
+}
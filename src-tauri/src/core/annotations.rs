@@ -0,0 +1,494 @@
+use crate::core::migrations::{self, Migration};
+use crate::core::pool;
+use crate::core::progress::UNKNOWN_DEVICE;
+use crate::error::AppError;
+use crate::models::Book;
+use rusqlite::{Connection, OptionalExtension};
+use std::path::Path;
+
+/// Client timestamps further in the future than this are clamped, so a device
+/// with a wrong clock cannot make its edits win forever.
+const MAX_CLOCK_SKEW_SECS: i64 = 5 * 60;
+
+/// A highlight or note on a passage of a book.
+///
+/// Deleted annotations are kept as tombstones (`deleted = true`) so the
+/// deletion reaches every device on its next sync.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct Annotation {
+    pub id: String,
+    pub book_id: i64,
+    /// The highlighted text.
+    pub text: Option<String>,
+    /// EPUB CFI range of the highlighted passage.
+    pub cfi_range: Option<String>,
+    pub note: Option<String>,
+    pub color: Option<String>,
+    /// Device that made the latest change.
+    pub device: String,
+    pub created_at: i64,
+    /// Client time of the latest change, used to merge edits.
+    pub updated_at: i64,
+    pub deleted: bool,
+    /// Server time the latest change was received; the cursor for `since`.
+    pub synced_at: i64,
+}
+
+/// A create, edit or deletion sent by a device.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
+pub struct AnnotationChange {
+    /// Client-generated ID; a new one is assigned when missing.
+    pub id: Option<String>,
+    pub book_id: i64,
+    pub text: Option<String>,
+    pub cfi_range: Option<String>,
+    pub note: Option<String>,
+    pub color: Option<String>,
+    pub device: Option<String>,
+    pub created_at: Option<i64>,
+    pub updated_at: Option<i64>,
+    #[serde(default)]
+    pub deleted: bool,
+}
+
+/// The stored annotation after a change, and whether the change was applied.
+///
+/// `applied` is false when the store already held a newer edit, in which case
+/// `annotation` is that newer edit.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct SaveResult {
+    pub annotation: Annotation,
+    pub applied: bool,
+    pub created: bool,
+}
+
+#[derive(serde::Deserialize, Debug, Clone, Default)]
+pub struct AnnotationQuery {
+    pub book_id: Option<i64>,
+    /// Only annotations received at or after this server time (`synced_at`).
+    /// Changes received in the same second may be returned again; applying
+    /// them twice is harmless.
+    pub since: Option<i64>,
+    /// Include tombstones. Always on when `since` is given, so deletions sync.
+    #[serde(default)]
+    pub include_deleted: bool,
+}
+
+/// Schema history of `annotations.db`.
+const MIGRATIONS: &[Migration] = &[Migration {
+    version: 1,
+    description: "annotations table",
+    // Also run on databases created before versioning, hence IF NOT EXISTS
+    apply: |conn| {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS annotations (
+                id TEXT PRIMARY KEY,
+                book_id INTEGER NOT NULL,
+                text TEXT,
+                cfi_range TEXT,
+                note TEXT,
+                color TEXT,
+                device TEXT NOT NULL,
+                created_at INTEGER NOT NULL,
+                updated_at INTEGER NOT NULL,
+                deleted INTEGER NOT NULL DEFAULT 0,
+                synced_at INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS annotations_book ON annotations (book_id);
+            CREATE INDEX IF NOT EXISTS annotations_synced ON annotations (synced_at);",
+        )?;
+        Ok(())
+    },
+}];
+
+/// Opens `annotations.db` in the app data directory, creating or migrating the
+/// schema as needed.
+pub fn open_annotations_db(app_data_dir: &Path) -> Result<Connection, AppError> {
+    let conn = pool::open_wal(&app_data_dir.join("annotations.db"))?;
+    migrations::migrate(&conn, "annotations.db", MIGRATIONS)?;
+    Ok(conn)
+}
+
+/// Applies a change from a device, keeping the most recent edit of each
+/// annotation (last writer wins on `updated_at`).
+///
+/// Ties go to the deletion, then to the device name that sorts last, so every
+/// replica settles on the same version whatever order changes arrive in.
+pub fn save_annotation(
+    conn: &Connection,
+    change: &AnnotationChange,
+) -> Result<SaveResult, AppError> {
+    let now = now();
+    let updated_at = change
+        .updated_at
+        .unwrap_or(now)
+        .min(now + MAX_CLOCK_SKEW_SECS);
+    let id = change
+        .id
+        .clone()
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+
+    let incoming = Annotation {
+        id: id.clone(),
+        book_id: change.book_id,
+        text: change.text.clone(),
+        cfi_range: change.cfi_range.clone(),
+        note: change.note.clone(),
+        color: change.color.clone(),
+        device: change
+            .device
+            .clone()
+            .unwrap_or_else(|| UNKNOWN_DEVICE.to_string()),
+        created_at: change.created_at.unwrap_or(updated_at),
+        updated_at,
+        deleted: change.deleted,
+        synced_at: now,
+    };
+
+    let existing = get_annotation(conn, &id)?;
+    let (applied, created) = match &existing {
+        None => (true, true),
+        Some(current) => (wins(&incoming, current), false),
+    };
+    if !applied {
+        return Ok(SaveResult {
+            annotation: existing.expect("existing annotation was compared"),
+            applied,
+            created,
+        });
+    }
+
+    // The first creation time survives later edits
+    let created_at = existing.as_ref().map_or(incoming.created_at, |e| {
+        e.created_at.min(incoming.created_at)
+    });
+    let annotation = Annotation {
+        created_at,
+        ..incoming
+    };
+
+    conn.execute(
+        "INSERT OR REPLACE INTO annotations (id, book_id, text, cfi_range, note, color, device, created_at, updated_at, deleted, synced_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+        rusqlite::params![
+            annotation.id,
+            annotation.book_id,
+            annotation.text,
+            annotation.cfi_range,
+            annotation.note,
+            annotation.color,
+            annotation.device,
+            annotation.created_at,
+            annotation.updated_at,
+            annotation.deleted,
+            annotation.synced_at,
+        ],
+    )?;
+
+    Ok(SaveResult {
+        annotation,
+        applied,
+        created,
+    })
+}
+
+fn wins(incoming: &Annotation, current: &Annotation) -> bool {
+    (incoming.updated_at, incoming.deleted, &incoming.device)
+        > (current.updated_at, current.deleted, &current.device)
+}
+
+/// Marks an annotation as deleted, returning `None` if it does not exist.
+pub fn delete_annotation(
+    conn: &Connection,
+    id: &str,
+    device: Option<&str>,
+    deleted_at: Option<i64>,
+) -> Result<Option<SaveResult>, AppError> {
+    let Some(current) = get_annotation(conn, id)? else {
+        return Ok(None);
+    };
+    let change = AnnotationChange {
+        id: Some(current.id),
+        book_id: current.book_id,
+        text: current.text,
+        cfi_range: current.cfi_range,
+        note: current.note,
+        color: current.color,
+        device: device.map(|d| d.to_string()),
+        created_at: Some(current.created_at),
+        updated_at: deleted_at,
+        deleted: true,
+    };
+    save_annotation(conn, &change).map(Some)
+}
+
+pub fn get_annotation(conn: &Connection, id: &str) -> Result<Option<Annotation>, AppError> {
+    let mut stmt = conn.prepare(
+        "SELECT id, book_id, text, cfi_range, note, color, device, created_at, updated_at, deleted, synced_at
+         FROM annotations WHERE id = ?1",
+    )?;
+    Ok(stmt.query_row([id], map_annotation).optional()?)
+}
+
+/// Lists annotations matching `query`, oldest change first.
+pub fn list_annotations(
+    conn: &Connection,
+    query: &AnnotationQuery,
+) -> Result<Vec<Annotation>, AppError> {
+    let include_deleted = query.include_deleted || query.since.is_some();
+    let mut stmt = conn.prepare(
+        "SELECT id, book_id, text, cfi_range, note, color, device, created_at, updated_at, deleted, synced_at
+         FROM annotations
+         WHERE (?1 IS NULL OR book_id = ?1)
+           AND (?2 IS NULL OR synced_at >= ?2)
+           AND (?3 OR deleted = 0)
+         ORDER BY synced_at, id",
+    )?;
+    let rows = stmt.query_map(
+        rusqlite::params![query.book_id, query.since, include_deleted],
+        map_annotation,
+    )?;
+    Ok(rows.collect::<Result<Vec<_>, _>>()?)
+}
+
+fn map_annotation(row: &rusqlite::Row) -> rusqlite::Result<Annotation> {
+    Ok(Annotation {
+        id: row.get(0)?,
+        book_id: row.get(1)?,
+        text: row.get(2)?,
+        cfi_range: row.get(3)?,
+        note: row.get(4)?,
+        color: row.get(5)?,
+        device: row.get(6)?,
+        created_at: row.get(7)?,
+        updated_at: row.get(8)?,
+        deleted: row.get(9)?,
+        synced_at: row.get(10)?,
+    })
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Markdown,
+    Json,
+}
+
+/// Exports a book's live annotations in reading order.
+///
+/// # Returns
+///
+/// Returns the document text and its MIME type.
+pub fn export_book(
+    conn: &Connection,
+    book: &Book,
+    format: ExportFormat,
+) -> Result<(String, &'static str), AppError> {
+    let mut annotations = list_annotations(
+        conn,
+        &AnnotationQuery {
+            book_id: Some(book.id),
+            ..Default::default()
+        },
+    )?;
+    annotations.sort_by(|a, b| {
+        cfi_key(a.cfi_range.as_deref())
+            .cmp(&cfi_key(b.cfi_range.as_deref()))
+            .then(a.created_at.cmp(&b.created_at))
+    });
+
+    match format {
+        ExportFormat::Json => {
+            let document = serde_json::json!({
+                "book_id": book.id,
+                "title": book.title,
+                "authors": book.authors,
+                "annotations": annotations,
+            });
+            let json = serde_json::to_string_pretty(&document)
+                .map_err(|e| AppError::Other(e.to_string()))?;
+            Ok((json, "application/json"))
+        }
+        ExportFormat::Markdown => Ok((to_markdown(book, &annotations), "text/markdown")),
+    }
+}
+
+fn to_markdown(book: &Book, annotations: &[Annotation]) -> String {
+    let mut out = format!("# {}\n\n*{}*\n", book.title, book.authors);
+
+    for annotation in annotations {
+        out.push('\n');
+        if let Some(text) = annotation.text.as_deref().filter(|t| !t.is_empty()) {
+            for line in text.lines() {
+                out.push_str(&format!("> {}\n", line));
+            }
+            out.push('\n');
+        }
+        if let Some(note) = annotation.note.as_deref().filter(|n| !n.is_empty()) {
+            out.push_str(note);
+            out.push_str("\n\n");
+        }
+
+        let mut details = Vec::new();
+        if let Some(color) = &annotation.color {
+            details.push(color.clone());
+        }
+        details.push(annotation.device.clone());
+        if let Some(date) = chrono::DateTime::from_timestamp(annotation.updated_at, 0) {
+            details.push(date.format("%Y-%m-%d").to_string());
+        }
+        out.push_str(&format!("*{}*\n", details.join(" · ")));
+    }
+
+    out
+}
+
+/// Sort key placing CFIs in document order: the numeric steps of the common
+/// path followed by those of the range start.
+fn cfi_key(cfi: Option<&str>) -> Vec<u64> {
+    let Some(cfi) = cfi else {
+        return vec![u64::MAX];
+    };
+    let inner = cfi
+        .trim()
+        .trim_start_matches("epubcfi(")
+        .trim_end_matches(')');
+    let mut parts = inner.split(',');
+    let start = format!(
+        "{}{}",
+        parts.next().unwrap_or(""),
+        parts.next().unwrap_or("")
+    );
+    start
+        .split(|c: char| !c.is_ascii_digit())
+        .filter_map(|n| n.parse().ok())
+        .collect()
+}
+
+fn now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn highlight(id: &str, device: &str, updated_at: i64) -> AnnotationChange {
+        AnnotationChange {
+            id: Some(id.to_string()),
+            book_id: 1,
+            text: Some("It was a bright cold day in April".to_string()),
+            cfi_range: Some("epubcfi(/6/4!/4/2,/1:0,/1:33)".to_string()),
+            color: Some("yellow".to_string()),
+            device: Some(device.to_string()),
+            updated_at: Some(updated_at),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_last_writer_wins() {
+        let dir = tempdir().unwrap();
+        let conn = open_annotations_db(dir.path()).unwrap();
+
+        let created = save_annotation(&conn, &highlight("a", "Phone", 100)).unwrap();
+        assert!(created.created && created.applied);
+
+        let edit = AnnotationChange {
+            note: Some("Opening line".to_string()),
+            ..highlight("a", "Tablet", 200)
+        };
+        assert!(save_annotation(&conn, &edit).unwrap().applied);
+
+        // A stale edit from a device that was offline loses
+        let stale = save_annotation(&conn, &highlight("a", "Phone", 150)).unwrap();
+        assert!(!stale.applied);
+        assert_eq!(stale.annotation.note.as_deref(), Some("Opening line"));
+        assert_eq!(stale.annotation.created_at, 100);
+
+        // A deletion made at the same moment as an edit wins the tie
+        let deleted = delete_annotation(&conn, "a", Some("Phone"), Some(200))
+            .unwrap()
+            .unwrap();
+        assert!(deleted.applied && deleted.annotation.deleted);
+
+        assert!(list_annotations(&conn, &AnnotationQuery::default())
+            .unwrap()
+            .is_empty());
+        let changes = list_annotations(
+            &conn,
+            &AnnotationQuery {
+                since: Some(0),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(changes.len(), 1);
+        assert!(changes[0].deleted);
+        assert!(delete_annotation(&conn, "missing", None, None)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_migrates_unversioned_db() {
+        let dir = tempdir().unwrap();
+        // annotations.db as created before versioning
+        Connection::open(dir.path().join("annotations.db"))
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE annotations (id TEXT PRIMARY KEY, book_id INTEGER NOT NULL, text TEXT,
+                    cfi_range TEXT, note TEXT, color TEXT, device TEXT NOT NULL, created_at INTEGER NOT NULL,
+                    updated_at INTEGER NOT NULL, deleted INTEGER NOT NULL DEFAULT 0, synced_at INTEGER NOT NULL);
+                 INSERT INTO annotations VALUES ('a', 1, 'text', NULL, NULL, NULL, 'Phone', 10, 10, 0, 10);",
+            )
+            .unwrap();
+
+        let conn = open_annotations_db(dir.path()).unwrap();
+        assert_eq!(migrations::user_version(&conn).unwrap(), 1);
+        assert_eq!(
+            get_annotation(&conn, "a").unwrap().unwrap().text.as_deref(),
+            Some("text")
+        );
+    }
+
+    #[test]
+    fn test_export_in_reading_order() {
+        let dir = tempdir().unwrap();
+        let conn = open_annotations_db(dir.path()).unwrap();
+        save_annotation(
+            &conn,
+            &AnnotationChange {
+                cfi_range: Some("epubcfi(/6/12!/4/2,/1:0,/1:5)".to_string()),
+                text: Some("Later".to_string()),
+                note: Some("A note".to_string()),
+                color: None,
+                ..highlight("b", "Phone", 1_700_000_000)
+            },
+        )
+        .unwrap();
+        save_annotation(&conn, &highlight("a", "Phone", 1_700_000_100)).unwrap();
+
+        let book = Book {
+            authors: "George Orwell".to_string(),
+            ..Book::test(1, "Nineteen Eighty-Four")
+        };
+        let (markdown, mime) = export_book(&conn, &book, ExportFormat::Markdown).unwrap();
+        assert_eq!(mime, "text/markdown");
+        assert_eq!(
+            markdown,
+            "# Nineteen Eighty-Four\n\n*George Orwell*\n\
+             \n> It was a bright cold day in April\n\n*yellow · Phone · 2023-11-14*\n\
+             \n> Later\n\nA note\n\n*Phone · 2023-11-14*\n"
+        );
+
+        let (json, _) = export_book(&conn, &book, ExportFormat::Json).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(json["annotations"][1]["id"], "b");
+    }
+}
@@ -0,0 +1,219 @@
+use crate::core::discovery::{
+    self, Advertise, DiscoveryState, DiscoveryUpdate, HostAdvertisement, Notify,
+};
+use crate::models::ConnectionInfo;
+use log::{error, info};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// UDP port beacons are broadcast to.
+pub const BEACON_PORT: u16 = 45480;
+
+/// How often a host broadcasts a beacon.
+const BEACON_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Beacon hosts not heard from for this long are dropped.
+const BEACON_EXPIRY: Duration = Duration::from_secs(35);
+
+/// Marks datagrams as ours, since anything on the LAN may send to the port.
+const SERVICE: &str = "shelfsync";
+
+/// A UDP broadcast announcing a host, for clients that cannot use mDNS (no
+/// multicast on their machine or network).
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct Beacon {
+    pub service: String,
+    /// Same service name the host registers over mDNS.
+    pub hostname: String,
+    pub port: u16,
+    pub addresses: Vec<String>,
+    #[serde(flatten)]
+    pub advertisement: HostAdvertisement,
+}
+
+impl Beacon {
+    /// Decodes a datagram received from `from`, ignoring anything that is
+    /// not a ShelfSync beacon.
+    pub fn parse(datagram: &[u8], from: IpAddr) -> Option<ConnectionInfo> {
+        let beacon: Beacon = serde_json::from_slice(datagram).ok()?;
+        if beacon.service != SERVICE {
+            return None;
+        }
+        // The sender's address is known to reach us, so it is tried first
+        let addresses: Vec<String> = discovery::order_addresses(
+            beacon
+                .addresses
+                .iter()
+                .filter_map(|a| a.parse().ok())
+                .chain(std::iter::once(from)),
+            Some(from),
+        )
+        .iter()
+        .map(|ip| ip.to_string())
+        .collect();
+
+        Some(ConnectionInfo {
+            ip: addresses
+                .first()
+                .cloned()
+                .unwrap_or_else(|| from.to_string()),
+            addresses,
+            port: beacon.port,
+            hostname: beacon.hostname,
+            compatible: beacon.advertisement.is_compatible(),
+            advertisement: beacon.advertisement,
+            ..Default::default()
+        })
+    }
+}
+
+/// Broadcasts a beacon every `BEACON_INTERVAL`.
+///
+/// Beacons are sent even while mDNS is running here, since mDNS working on
+/// this host says nothing about it working for the clients. Clients that see
+/// the host both ways list it once, by `ConnectionInfo::key`.
+///
+/// Runs forever.
+pub async fn announce(
+    discovery: Arc<DiscoveryState>,
+    advertise: Advertise,
+    port: u16,
+    notify: Notify,
+) {
+    let machine_name = hostname::get()
+        .map(|h| h.to_string_lossy().to_string())
+        .unwrap_or_else(|_| "ShelfSync-Host".to_string());
+    let hostname = format!("ShelfSync on {}.{}", machine_name, discovery::SERVICE_TYPE);
+    let mut ticks = tokio::time::interval(BEACON_INTERVAL);
+
+    loop {
+        ticks.tick().await;
+        let beacon = Beacon {
+            service: SERVICE.to_string(),
+            hostname: hostname.clone(),
+            port,
+            addresses: discovery::local_addresses()
+                .iter()
+                .map(|ip| ip.to_string())
+                .collect(),
+            advertisement: advertise(),
+        };
+        let sending = match send(&beacon).await {
+            Ok(()) => true,
+            Err(e) => {
+                error!("Failed to broadcast discovery beacon: {}", e);
+                false
+            }
+        };
+        if discovery.update_status(|status| status.beacon_sending = sending) {
+            notify(DiscoveryUpdate::Status);
+        }
+    }
+}
+
+async fn send(beacon: &Beacon) -> std::io::Result<()> {
+    let socket = tokio::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.set_broadcast(true)?;
+    let datagram = serde_json::to_vec(beacon)?;
+    socket
+        .send_to(&datagram, (Ipv4Addr::BROADCAST, BEACON_PORT))
+        .await?;
+    Ok(())
+}
+
+/// Listens for beacons from other hosts, expiring ones that go quiet.
+///
+/// Runs forever; if the port cannot be bound, beacons are simply not heard.
+pub async fn listen(discovery: Arc<DiscoveryState>, notify: Notify) {
+    let socket = match bind_listener() {
+        Ok(socket) => socket,
+        Err(e) => {
+            error!(
+                "Failed to listen for discovery beacons on port {}: {}",
+                BEACON_PORT, e
+            );
+            return;
+        }
+    };
+    info!("Listening for discovery beacons on port {}", BEACON_PORT);
+    if discovery.update_status(|status| status.beacon_listening = true) {
+        notify(DiscoveryUpdate::Status);
+    }
+
+    let mut buf = vec![0u8; 8192];
+    let mut sweep = tokio::time::interval(BEACON_INTERVAL);
+    loop {
+        tokio::select! {
+            received = socket.recv_from(&mut buf) => {
+                let Ok((len, from)) = received else {
+                    continue;
+                };
+                if let Some(host) = Beacon::parse(&buf[..len], from.ip()) {
+                    if discovery.beacon_received(host) {
+                        notify(DiscoveryUpdate::Hosts);
+                    }
+                }
+            }
+            _ = sweep.tick() => {
+                if discovery.expire_beacons(BEACON_EXPIRY) {
+                    notify(DiscoveryUpdate::Hosts);
+                }
+            }
+        }
+    }
+}
+
+fn bind_listener() -> std::io::Result<tokio::net::UdpSocket> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    // Lets several ShelfSync instances on one machine share the port
+    socket.set_reuse_address(true)?;
+    socket.set_broadcast(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, BEACON_PORT)).into())?;
+    tokio::net::UdpSocket::from_std(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_beacon_hosts_are_listed_until_quiet() {
+        let dir = tempdir().unwrap();
+        let discovery = DiscoveryState::new(dir.path());
+        let beacon = Beacon {
+            service: SERVICE.to_string(),
+            hostname: "ShelfSync on desk._shelfsync._tcp.local.".to_string(),
+            port: 8080,
+            addresses: vec!["10.8.0.2".to_string(), "192.168.1.20".to_string()],
+            advertisement: HostAdvertisement {
+                host_id: Some("desk".to_string()),
+                ..Default::default()
+            },
+        };
+        let datagram = serde_json::to_vec(&beacon).unwrap();
+        let from: IpAddr = "192.168.1.20".parse().unwrap();
+
+        let host = Beacon::parse(&datagram, from).unwrap();
+        assert_eq!(host.ip, "192.168.1.20");
+        assert_eq!(host.addresses, ["192.168.1.20", "10.8.0.2"]);
+        assert!(Beacon::parse(b"{\"service\":\"other\"}", from).is_none());
+        assert!(Beacon::parse(b"not json", from).is_none());
+
+        assert!(discovery.beacon_received(host.clone()));
+        assert!(!discovery.beacon_received(host.clone()));
+        assert_eq!(discovery.hosts()[0].key(), "desk");
+
+        // Seen over mDNS too: listed once
+        assert!(discovery.resolved(host));
+        assert_eq!(discovery.hosts().len(), 1);
+
+        assert!(!discovery.expire_beacons(Duration::from_secs(60)));
+        assert!(discovery.expire_beacons(Duration::ZERO));
+        assert_eq!(discovery.hosts().len(), 1);
+    }
+}
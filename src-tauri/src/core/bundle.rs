@@ -0,0 +1,218 @@
+use crate::core::zipstream::ZipStream;
+use crate::error::AppError;
+use crate::models::Book;
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::PathBuf;
+
+/// Name of the manifest file placed at the root of every bundle.
+pub const MANIFEST_NAME: &str = "manifest.json";
+
+/// A book file selected for inclusion in a bundle.
+pub struct BundleEntry {
+    pub book: Book,
+    pub file_path: PathBuf,
+    pub format: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct BundleManifest {
+    /// Unix timestamp of when the bundle was created.
+    pub created_at: i64,
+    pub books: Vec<BundleManifestEntry>,
+    /// IDs of requested books that were not found, or had no file in any
+    /// acceptable format.
+    pub missing: Vec<i64>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct BundleManifestEntry {
+    pub book: Book,
+    pub format: String,
+    /// Path of the book file inside the zip.
+    pub path: String,
+}
+
+/// Writes a zip containing every entry under its own folder, plus a `manifest.json`,
+/// to `out`, which is returned when done.
+///
+/// Book files are stored uncompressed: e-books and comics are already compressed,
+/// so deflating them again only costs CPU. The zip is written front to back without
+/// seeking, so `out` can be a response body, and files are copied straight from disk,
+/// so memory use does not grow with the size of the bundle.
+pub fn write_bundle<W: Write>(
+    out: W,
+    entries: &[BundleEntry],
+    missing: &[i64],
+) -> Result<W, AppError> {
+    let mut writer = ZipStream::new(out);
+
+    let mut used_folders = HashSet::new();
+    let mut manifest = BundleManifest {
+        created_at: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64,
+        books: Vec::with_capacity(entries.len()),
+        missing: missing.to_vec(),
+    };
+
+    let mut paths = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let mut folder = folder_name(&entry.book);
+        if !used_folders.insert(folder.clone()) {
+            folder = format!("{} ({})", folder, entry.book.id);
+            used_folders.insert(folder.clone());
+        }
+
+        let file_name = entry
+            .file_path
+            .file_name()
+            .map(|f| sanitize_file_name(&f.to_string_lossy()))
+            .unwrap_or_else(|| format!("book.{}", entry.format));
+        let path = format!("{}/{}", folder, file_name);
+
+        manifest.books.push(BundleManifestEntry {
+            book: entry.book.clone(),
+            format: entry.format.clone(),
+            path: path.clone(),
+        });
+        paths.push(path);
+    }
+
+    // Manifest first so tools reading the zip sequentially see it before the books
+    let manifest_json =
+        serde_json::to_vec_pretty(&manifest).map_err(|e| AppError::Other(e.to_string()))?;
+    writer.add_bytes(MANIFEST_NAME, &manifest_json)?;
+
+    for (entry, path) in entries.iter().zip(paths) {
+        writer.add_file(&path, &entry.file_path)?;
+    }
+
+    writer.finish()
+}
+
+/// Builds the folder a book is placed in inside a bundle.
+///
+/// Series books are named `Series 03 - Title` so they sort in reading order;
+/// others are named `Author - Title`.
+pub fn folder_name(book: &Book) -> String {
+    let name = match &book.series {
+        Some(series) => format!(
+            "{} {} - {}",
+            series,
+            format_index(book.series_index),
+            book.title
+        ),
+        None if !book.authors.is_empty() => format!("{} - {}", book.authors, book.title),
+        None => book.title.clone(),
+    };
+    sanitize_file_name(&name)
+}
+
+/// Zero-pads whole series indices (`3` -> `03`) and keeps fractional ones (`3.5`).
+fn format_index(index: f64) -> String {
+    if index.fract() == 0.0 {
+        format!("{:02}", index as i64)
+    } else {
+        format!("{:04.1}", index)
+    }
+}
+
+/// Replaces characters that are invalid in file names on common platforms.
+pub fn sanitize_file_name(name: &str) -> String {
+    let cleaned: String = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    let trimmed = cleaned.trim().trim_end_matches('.');
+    if trimmed.is_empty() {
+        "Untitled".to_string()
+    } else {
+        trimmed.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Read};
+    use tempfile::tempdir;
+    use zip::ZipArchive;
+
+    fn book(id: i64, title: &str, series: Option<&str>, index: f64) -> Book {
+        Book {
+            authors: "Kentaro Miura".to_string(),
+            formats: vec!["CBZ".to_string()],
+            series: series.map(|s| s.to_string()),
+            series_index: index,
+            ..Book::test(id, title)
+        }
+    }
+
+    #[test]
+    fn test_folder_name() {
+        assert_eq!(
+            folder_name(&book(1, "The Black Swordsman", Some("Berserk"), 1.0)),
+            "Berserk 01 - The Black Swordsman"
+        );
+        assert_eq!(
+            folder_name(&book(2, "Side Story", Some("Berserk"), 1.5)),
+            "Berserk 01.5 - Side Story"
+        );
+        assert_eq!(
+            folder_name(&book(3, "What? Why: No", None, 1.0)),
+            "Kentaro Miura - What_ Why_ No"
+        );
+    }
+
+    #[test]
+    fn test_write_bundle() {
+        let dir = tempdir().unwrap();
+        let file_a = dir.path().join("a.cbz");
+        let file_b = dir.path().join("b.cbz");
+        std::fs::write(&file_a, "volume one").unwrap();
+        std::fs::write(&file_b, "volume two").unwrap();
+
+        // Two books with the same folder name must not collide
+        let entries = vec![
+            BundleEntry {
+                book: book(1, "Vol", Some("Berserk"), 1.0),
+                file_path: file_a,
+                format: "cbz".to_string(),
+            },
+            BundleEntry {
+                book: book(2, "Vol", Some("Berserk"), 1.0),
+                file_path: file_b,
+                format: "cbz".to_string(),
+            },
+        ];
+
+        let zip = write_bundle(Vec::new(), &entries, &[3]).unwrap();
+
+        let mut archive = ZipArchive::new(Cursor::new(zip)).unwrap();
+        let mut manifest = String::new();
+        archive
+            .by_name(MANIFEST_NAME)
+            .unwrap()
+            .read_to_string(&mut manifest)
+            .unwrap();
+        let manifest: BundleManifest = serde_json::from_str(&manifest).unwrap();
+        assert_eq!(manifest.books.len(), 2);
+        assert_eq!(manifest.missing, vec![3]);
+        assert_eq!(manifest.books[0].path, "Berserk 01 - Vol/a.cbz");
+        assert_eq!(manifest.books[1].path, "Berserk 01 - Vol (2)/b.cbz");
+
+        let mut content = String::new();
+        archive
+            .by_name("Berserk 01 - Vol (2)/b.cbz")
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, "volume two");
+    }
+}
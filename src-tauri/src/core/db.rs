@@ -1,9 +1,18 @@
+use crate::core::search::{CustomValues, SavedSearch, SearchIndex, SearchKind};
 use crate::error::AppError;
 use crate::models::Book;
-use rusqlite::{Connection, OpenFlags};
+use rusqlite::types::Value;
+use rusqlite::{Connection, OpenFlags, OptionalExtension};
+use std::collections::HashMap;
 use std::path::Path;
 
 pub fn get_calibre_metadata(library_path: &str) -> Result<Vec<Book>, AppError> {
+    let conn = open_metadata_db(library_path)?;
+    read_books(&conn)
+}
+
+/// Opens a Calibre library's `metadata.db` read-only.
+pub fn open_metadata_db(library_path: &str) -> Result<Connection, AppError> {
     let lib_path = Path::new(library_path);
     let db_path = lib_path.join("metadata.db");
 
@@ -12,8 +21,14 @@ pub fn get_calibre_metadata(library_path: &str) -> Result<Vec<Book>, AppError> {
     }
 
     // Open the DB in Read-Only mode directly
-    let conn = Connection::open_with_flags(&db_path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    Ok(Connection::open_with_flags(
+        &db_path,
+        OpenFlags::SQLITE_OPEN_READ_ONLY,
+    )?)
+}
 
+/// Reads the book list from an open `metadata.db`.
+pub fn read_books(conn: &Connection) -> Result<Vec<Book>, AppError> {
     // Query: Books joined with Authors
     // Calibre schema:
     // books (id, title, path, ...)
@@ -30,7 +45,9 @@ pub fn get_calibre_metadata(library_path: &str) -> Result<Vec<Book>, AppError> {
             s.name as series,
             b.series_index,
             (SELECT GROUP_CONCAT(t.name, ',') FROM books_tags_link btl JOIN tags t ON btl.tag = t.id WHERE btl.book = b.id) as tags,
-            p.name as publisher
+            p.name as publisher,
+            CAST(strftime('%s', b.timestamp) AS INTEGER) as added_at,
+            (SELECT MAX(d.uncompressed_size) FROM data d WHERE d.book = b.id) as size
          FROM books b
          LEFT JOIN series s ON b.series = s.id
          LEFT JOIN books_publishers_link bpl ON b.id = bpl.book
@@ -59,6 +76,8 @@ pub fn get_calibre_metadata(library_path: &str) -> Result<Vec<Book>, AppError> {
             series_index: row.get(6).unwrap_or(1.0),
             tags,
             publisher: row.get(8)?,
+            added_at: row.get(9)?,
+            size: row.get(10)?,
         })
     })?;
 
@@ -70,6 +89,117 @@ pub fn get_calibre_metadata(library_path: &str) -> Result<Vec<Book>, AppError> {
     Ok(books)
 }
 
+/// Reads what searches need besides the books: the virtual libraries and
+/// saved searches, and every custom column's values.
+pub fn read_search_index(conn: &Connection) -> Result<SearchIndex, AppError> {
+    Ok(SearchIndex {
+        searches: read_saved_searches(conn)?,
+        columns: read_custom_values(conn)?,
+    })
+}
+
+/// Reads the virtual libraries and saved searches Calibre keeps in its
+/// `preferences` table, as JSON objects of name to search.
+pub fn read_saved_searches(conn: &Connection) -> Result<Vec<SavedSearch>, AppError> {
+    if !has_table(conn, "preferences")? {
+        return Ok(Vec::new());
+    }
+    let mut searches = Vec::new();
+    for (key, kind) in [
+        ("virtual_libraries", SearchKind::VirtualLibrary),
+        ("saved_searches", SearchKind::SavedSearch),
+    ] {
+        let val: Option<String> = conn
+            .query_row("SELECT val FROM preferences WHERE key = ?1", [key], |row| {
+                row.get(0)
+            })
+            .optional()?;
+        let Some(val) = val else { continue };
+        let map: HashMap<String, String> = serde_json::from_str(&val)
+            .map_err(|e| AppError::Other(format!("Malformed Calibre {}: {}", key, e)))?;
+        let mut entries: Vec<_> = map
+            .into_iter()
+            .map(|(name, query)| SavedSearch { name, kind, query })
+            .collect();
+        entries.sort_by_key(|search| search.name.to_lowercase());
+        searches.extend(entries);
+    }
+    Ok(searches)
+}
+
+/// Reads the values of every custom column, by lowercased lookup name.
+/// Composite columns are computed by Calibre and have no stored values.
+pub fn read_custom_values(conn: &Connection) -> Result<HashMap<String, CustomValues>, AppError> {
+    if !has_table(conn, "custom_columns")? {
+        return Ok(HashMap::new());
+    }
+    let mut stmt = conn.prepare(
+        "SELECT id, label, datatype, normalized FROM custom_columns
+         WHERE mark_for_delete = 0 AND datatype != 'composite'",
+    )?;
+    let columns = stmt
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, bool>(3)?,
+            ))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut values = HashMap::new();
+    for (id, label, datatype, normalized) in columns {
+        // Normalized columns (tags-like, series, enumerations, ratings) link
+        // books to shared values; the others store one value per book
+        let sql = if normalized {
+            format!(
+                "SELECT l.book, v.value FROM books_custom_column_{id}_link l
+                 JOIN custom_column_{id} v ON l.value = v.id"
+            )
+        } else {
+            format!("SELECT book, value FROM custom_column_{id}")
+        };
+        let mut column = CustomValues {
+            datatype,
+            values: HashMap::new(),
+        };
+        let mut stmt = conn.prepare(&sql)?;
+        let rows = stmt.query_map([], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, Value>(1)?))
+        })?;
+        for row in rows {
+            let (book, value) = row?;
+            let value = match value {
+                Value::Integer(i) if column.datatype == "bool" => {
+                    if i == 0 {
+                        continue;
+                    }
+                    "yes".to_string()
+                }
+                Value::Integer(i) => i.to_string(),
+                Value::Real(f) => f.to_string(),
+                Value::Text(text) => text,
+                Value::Null | Value::Blob(_) => continue,
+            };
+            column.values.entry(book).or_default().push(value);
+        }
+        values.insert(label.to_lowercase(), column);
+    }
+    Ok(values)
+}
+
+fn has_table(conn: &Connection, name: &str) -> Result<bool, AppError> {
+    Ok(conn
+        .query_row(
+            "SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1",
+            [name],
+            |_| Ok(()),
+        )
+        .optional()?
+        .is_some())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -80,7 +210,7 @@ mod tests {
         let conn = Connection::open(path.join("metadata.db")).unwrap();
 
         conn.execute(
-            "CREATE TABLE books (id INTEGER PRIMARY KEY, title TEXT, path TEXT, series INTEGER, series_index REAL)",
+            "CREATE TABLE books (id INTEGER PRIMARY KEY, title TEXT, path TEXT, series INTEGER, series_index REAL, timestamp TIMESTAMP)",
             [],
         ).unwrap();
 
@@ -103,7 +233,7 @@ mod tests {
         .unwrap();
         conn.execute("CREATE TABLE books_publishers_link (id INTEGER PRIMARY KEY, book INTEGER, publisher INTEGER)", []).unwrap();
         conn.execute(
-            "CREATE TABLE data (id INTEGER PRIMARY KEY, book INTEGER, format TEXT)",
+            "CREATE TABLE data (id INTEGER PRIMARY KEY, book INTEGER, format TEXT, uncompressed_size INTEGER)",
             [],
         )
         .unwrap();
@@ -120,7 +250,12 @@ mod tests {
         ).unwrap();
 
         // Insert mock data
-        conn.execute("INSERT INTO books (id, title, path) VALUES (1, 'The Great Gatsby', 'fitzgerald/gatsby')", []).unwrap();
+        conn.execute("INSERT INTO books (id, title, path, timestamp) VALUES (1, 'The Great Gatsby', 'fitzgerald/gatsby', '2024-03-01 12:00:00.123456+00:00')", []).unwrap();
+        conn.execute(
+            "INSERT INTO data (book, format, uncompressed_size) VALUES (1, 'EPUB', 2048), (1, 'PDF', 4096)",
+            [],
+        )
+        .unwrap();
         conn.execute(
             "INSERT INTO authors (id, name) VALUES (1, 'F. Scott Fitzgerald')",
             [],
@@ -161,9 +296,64 @@ mod tests {
         assert_eq!(books[0].authors, "F. Scott Fitzgerald");
         assert_eq!(books[1].title, "1984");
         assert_eq!(books[1].authors, "George Orwell");
+        assert_eq!(books[0].added_at, Some(1709294400));
+        assert_eq!(books[0].size, Some(4096));
+        assert_eq!(books[1].added_at, None);
+    }
+
+    #[test]
+    fn test_read_search_index() {
+        let dir = tempdir().unwrap();
+        create_mock_calibre_db(dir.path());
+        let conn = Connection::open(dir.path().join("metadata.db")).unwrap();
+
+        // Mock libraries without these tables have no searches
+        let index = read_search_index(&conn).unwrap();
+        assert!(index.searches.is_empty() && index.columns.is_empty());
+
+        conn.execute_batch(
+            r##"CREATE TABLE preferences (id INTEGER PRIMARY KEY, key TEXT NOT NULL, val TEXT NOT NULL);
+               INSERT INTO preferences (key, val) VALUES
+                 ('virtual_libraries', '{"Orwell": "authors:orwell"}'),
+                 ('saved_searches', '{"Read": "#read:true", "Genres": "#genre:dystopia"}');
+               CREATE TABLE custom_columns (id INTEGER PRIMARY KEY, label TEXT, name TEXT, datatype TEXT,
+                 mark_for_delete BOOL DEFAULT 0, normalized BOOL);
+               INSERT INTO custom_columns (id, label, name, datatype, normalized) VALUES
+                 (1, 'read', 'Read', 'bool', 0), (2, 'genre', 'Genre', 'text', 1);
+               CREATE TABLE custom_column_1 (id INTEGER PRIMARY KEY, book INTEGER, value BOOL);
+               INSERT INTO custom_column_1 (book, value) VALUES (1, 1), (2, 0);
+               CREATE TABLE custom_column_2 (id INTEGER PRIMARY KEY, value TEXT);
+               CREATE TABLE books_custom_column_2_link (id INTEGER PRIMARY KEY, book INTEGER, value INTEGER);
+               INSERT INTO custom_column_2 (id, value) VALUES (1, 'Dystopia'), (2, 'Classic');
+               INSERT INTO books_custom_column_2_link (book, value) VALUES (2, 1), (2, 2), (1, 2);"##,
+        )
+        .unwrap();
+
+        let index = read_search_index(&conn).unwrap();
+        let names: Vec<_> = index.searches.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, vec!["Orwell", "Genres", "Read"]);
+        assert_eq!(index.searches[0].kind, SearchKind::VirtualLibrary);
+        assert_eq!(
+            index.columns["read"].values.get(&1),
+            Some(&vec!["yes".to_string()])
+        );
+        assert_eq!(index.columns["read"].values.get(&2), None);
+        assert_eq!(index.columns["genre"].values[&2].len(), 2);
+
+        let books = read_books(&conn).unwrap();
+        for (query, expected) in [("vl:orwell", 2), ("search:read", 1), ("search:genres", 2)] {
+            let ids = index.matching_ids(query, &books).unwrap();
+            assert_eq!(
+                ids.into_iter().collect::<Vec<_>>(),
+                vec![expected],
+                "{}",
+                query
+            );
+        }
     }
 
     #[test]
+    #[allow(clippy::assertions_on_constants)]
     fn test_get_calibre_metadata_missing_db() {
         let dir = tempdir().unwrap();
         let result = get_calibre_metadata(dir.path().to_str().unwrap());
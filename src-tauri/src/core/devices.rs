@@ -0,0 +1,163 @@
+use crate::core::libraries::DEFAULT_LIBRARY_ID;
+use crate::error::AppError;
+use crate::models::Book;
+use log::error;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// File in the app data dir keeping the paired devices.
+const DEVICES_FILE: &str = "devices.json";
+
+/// A device paired with this host, as shown to the host's user.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct Device {
+    /// Identifies the device when managing it; its token is never shown.
+    pub id: String,
+    /// Name the device gave when pairing, if any.
+    pub name: Option<String>,
+    /// Unix timestamp of pairing.
+    pub paired_at: i64,
+    /// What the device may see and do.
+    #[serde(flatten)]
+    pub scope: Scope,
+}
+
+impl Device {
+    /// A newly paired device, limited to `scope`.
+    pub fn new(name: Option<String>, paired_at: i64, scope: Scope) -> Self {
+        Device {
+            id: uuid::Uuid::new_v4().to_string(),
+            name: name
+                .map(|n| n.trim().chars().take(64).collect::<String>())
+                .filter(|n| !n.is_empty()),
+            paired_at,
+            scope,
+        }
+    }
+}
+
+/// The paired devices by bearer token, as saved in `devices.json`, so they
+/// stay paired and restricted across restarts.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct DeviceRegistry {
+    pub devices: HashMap<String, Device>,
+    /// What newly paired devices may see and do.
+    #[serde(default)]
+    pub default_scope: Scope,
+}
+
+/// Reads the paired devices; none if the file is missing or unreadable.
+pub fn load_registry(app_data_dir: &Path) -> DeviceRegistry {
+    let path = app_data_dir.join(DEVICES_FILE);
+    let Ok(content) = std::fs::read_to_string(&path) else {
+        return DeviceRegistry::default();
+    };
+    serde_json::from_str(&content).unwrap_or_else(|e| {
+        error!("Ignoring malformed {:?}: {}", path, e);
+        DeviceRegistry::default()
+    })
+}
+
+pub fn save_registry(app_data_dir: &Path, registry: &DeviceRegistry) -> Result<(), AppError> {
+    let json =
+        serde_json::to_string_pretty(registry).map_err(|e| AppError::Other(e.to_string()))?;
+    std::fs::write(app_data_dir.join(DEVICES_FILE), json)?;
+    Ok(())
+}
+
+/// Limits on what a paired device may do, e.g. for a child's or a guest's
+/// reader. The default grants everything.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct Scope {
+    /// Libraries the device may use, by [`Library::scope_id`]. `None` grants
+    /// every library, including ones registered later. `default` stands for
+    /// the library selected when the device pairs, and is replaced by it then.
+    ///
+    /// [`Library::scope_id`]: crate::core::libraries::Library::scope_id
+    pub libraries: Option<Vec<String>>,
+    /// Books with any of these tags, or a tag nested under one (Calibre's
+    /// `Parent.Child`), are hidden from the device as if they did not exist.
+    #[serde(default)]
+    pub hidden_tags: Vec<String>,
+    /// Name of a Calibre virtual library the device is limited to. In
+    /// libraries without a virtual library of that name it sees no books.
+    #[serde(default)]
+    pub virtual_library: Option<String>,
+    /// Whether book files may be downloaded, rather than only browsed.
+    pub can_download: bool,
+    /// Whether the device may record reading progress, sessions and
+    /// annotations, and upload books to the inbox.
+    pub can_write: bool,
+}
+
+impl Default for Scope {
+    fn default() -> Self {
+        Scope {
+            libraries: None,
+            hidden_tags: Vec::new(),
+            virtual_library: None,
+            can_download: true,
+            can_write: true,
+        }
+    }
+}
+
+impl Scope {
+    pub fn can_access(&self, library_id: &str) -> bool {
+        self.libraries
+            .as_ref()
+            .is_none_or(|ids| ids.iter().any(|id| id == library_id))
+    }
+
+    /// Replaces `default` in the granted libraries with `scope_id`, the
+    /// library selected now. Returns whether anything changed.
+    pub fn resolve_default_library(&mut self, scope_id: &str) -> bool {
+        let Some(libraries) = self.libraries.as_mut() else {
+            return false;
+        };
+        let mut changed = false;
+        for id in libraries.iter_mut() {
+            if id == DEFAULT_LIBRARY_ID {
+                *id = scope_id.to_string();
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// Whether `book` is visible under the hidden tags.
+    pub fn can_see(&self, book: &Book) -> bool {
+        !self.hidden_tags.iter().any(|hidden| book.has_tag(hidden))
+    }
+
+    /// Whether nothing is restricted.
+    pub fn is_unrestricted(&self) -> bool {
+        *self == Scope::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn book(tags: &[&str]) -> Book {
+        Book {
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+            ..Book::test(1, "Title")
+        }
+    }
+
+    #[test]
+    fn test_hidden_tags_hide_nested_tags() {
+        let scope = Scope {
+            hidden_tags: vec!["Adult".to_string()],
+            ..Default::default()
+        };
+        assert!(!scope.can_see(&book(&["Fiction", "adult"])));
+        assert!(!scope.can_see(&book(&["Adult.Romance"])));
+        assert!(scope.can_see(&book(&["Adulthood"])));
+        assert!(scope.can_see(&book(&[])));
+        assert!(Scope::default().can_see(&book(&["Adult"])));
+        assert!(!scope.is_unrestricted());
+    }
+}
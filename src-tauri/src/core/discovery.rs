@@ -0,0 +1,788 @@
+use crate::error::AppError;
+use crate::models::{Book, ConnectionInfo};
+use md5::{Digest, Md5};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+pub const SERVICE_TYPE: &str = "_shelfsync._tcp.local.";
+
+/// Version of the HTTP API a host serves. Bump on breaking changes.
+pub const API_VERSION: u32 = 1;
+
+/// Oldest host API this build can talk to; older hosts are hidden by default.
+pub const MIN_API_VERSION: u32 = 1;
+
+/// TXT values longer than this are cut, keeping the record well under the
+/// 255-byte limit per entry.
+const MAX_TXT_VALUE_LEN: usize = 63;
+
+/// How often known hosts are probed with `GET /api/health`.
+pub const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long one address may take to answer a health probe.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Port the server listens on, also used for manual hosts added without one.
+pub const DEFAULT_PORT: u16 = 8080;
+
+/// What a host publishes about itself in its mDNS TXT record.
+///
+/// Every field is optional when parsing, since hosts from before a field was
+/// introduced do not publish it.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct HostAdvertisement {
+    /// Persistent ID of the host install; unlike the IP it survives DHCP changes.
+    pub host_id: Option<String>,
+    /// ShelfSync version of the host.
+    pub version: Option<String>,
+    pub api_version: Option<u32>,
+    /// Name of the Calibre library folder being served.
+    pub library_name: Option<String>,
+    pub book_count: Option<usize>,
+    /// Changes whenever the served book list changes, so clients can skip
+    /// refetching an unchanged manifest.
+    pub library_revision: Option<String>,
+    /// SHA-256 fingerprint of the host's TLS certificate, when it serves TLS.
+    pub tls_fingerprint: Option<String>,
+    /// Whether the host currently accepts PIN pairing.
+    pub pairing_open: Option<bool>,
+    /// How many libraries the host serves. Their names would not fit the
+    /// TXT record, so clients list them with `GET /api/libraries`.
+    pub library_count: Option<usize>,
+    /// IDs of the served libraries, as far as they fit the TXT record.
+    pub library_ids: Option<Vec<String>>,
+}
+
+impl HostAdvertisement {
+    /// Encodes the advertisement as TXT record entries.
+    pub fn to_txt(&self) -> Vec<(&'static str, String)> {
+        let mut txt = Vec::new();
+        let mut push = |key, value: Option<String>| {
+            if let Some(value) = value {
+                txt.push((key, truncate(&value, MAX_TXT_VALUE_LEN)));
+            }
+        };
+        push("version", self.version.clone());
+        push("id", self.host_id.clone());
+        push("api", self.api_version.map(|v| v.to_string()));
+        push("library", self.library_name.clone());
+        push("books", self.book_count.map(|c| c.to_string()));
+        push("rev", self.library_revision.clone());
+        push("tls", self.tls_fingerprint.clone());
+        push(
+            "pairing",
+            self.pairing_open
+                .map(|open| if open { "open" } else { "closed" }.to_string()),
+        );
+        push("nlibs", self.library_count.map(|c| c.to_string()));
+        push(
+            "libs",
+            self.library_ids
+                .as_ref()
+                .map(|ids| join_within(ids, MAX_TXT_VALUE_LEN)),
+        );
+        txt
+    }
+
+    /// Decodes a TXT record, ignoring unknown keys and malformed values.
+    pub fn from_txt(txt: &HashMap<String, String>) -> Self {
+        let text = |key: &str| txt.get(key).filter(|v| !v.is_empty()).cloned();
+        HostAdvertisement {
+            host_id: text("id"),
+            version: text("version"),
+            api_version: text("api").and_then(|v| v.parse().ok()),
+            library_name: text("library"),
+            book_count: text("books").and_then(|v| v.parse().ok()),
+            library_revision: text("rev"),
+            tls_fingerprint: text("tls"),
+            pairing_open: text("pairing").map(|v| v == "open"),
+            library_count: text("nlibs").and_then(|v| v.parse().ok()),
+            library_ids: text("libs").map(|v| v.split(',').map(str::to_string).collect()),
+        }
+    }
+
+    /// Whether this build can talk to the host. Hosts from before the API
+    /// version was published serve the first API.
+    pub fn is_compatible(&self) -> bool {
+        self.api_version.unwrap_or(1) >= MIN_API_VERSION
+    }
+}
+
+/// Joins `ids` with commas, leaving out the ones past `max_len` so no ID is cut.
+fn join_within(ids: &[String], max_len: usize) -> String {
+    let mut joined = String::new();
+    for id in ids {
+        let separator = if joined.is_empty() { 0 } else { 1 };
+        if joined.len() + separator + id.len() > max_len {
+            break;
+        }
+        if separator == 1 {
+            joined.push(',');
+        }
+        joined.push_str(id);
+    }
+    joined
+}
+
+fn truncate(value: &str, max_len: usize) -> String {
+    let mut end = value.len().min(max_len);
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+    value[..end].to_string()
+}
+
+/// Whether a client on another machine could reach this address.
+///
+/// IPv6 link-local addresses are left out: they only work together with a
+/// zone index, which is specific to the machine that resolved them.
+fn is_reachable(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => !v4.is_loopback() && !v4.is_unspecified() && !v4.is_link_local(),
+        IpAddr::V6(v6) => {
+            !v6.is_loopback() && !v6.is_unspecified() && (v6.segments()[0] & 0xffc0) != 0xfe80
+        }
+    }
+}
+
+/// Filters `addresses` down to reachable ones, ordered the way clients should
+/// try them: `preferred` (the address of the default route) first, then IPv4,
+/// then IPv6.
+pub fn order_addresses(
+    addresses: impl IntoIterator<Item = IpAddr>,
+    preferred: Option<IpAddr>,
+) -> Vec<IpAddr> {
+    let mut ordered: Vec<IpAddr> = addresses.into_iter().filter(is_reachable).collect();
+    ordered.sort_by_key(|ip| (Some(*ip) != preferred, ip.is_ipv6(), *ip));
+    ordered.dedup();
+    ordered
+}
+
+/// Addresses of every interface of this machine (Ethernet, Wi-Fi, VPN, ...).
+pub fn local_addresses() -> Vec<IpAddr> {
+    let interfaces = local_ip_address::list_afinet_netifas().unwrap_or_else(|e| {
+        log::error!("Failed to list network interfaces: {}", e);
+        Vec::new()
+    });
+    let addresses = order_addresses(
+        interfaces.into_iter().map(|(_, ip)| ip),
+        local_ip_address::local_ip().ok(),
+    );
+    if addresses.is_empty() {
+        vec![IpAddr::V4(Ipv4Addr::LOCALHOST)]
+    } else {
+        addresses
+    }
+}
+
+/// Formats `ip` and `port` for use in a URL, bracketing IPv6 addresses.
+pub fn url_authority(ip: &str, port: u16) -> String {
+    match ip.parse::<IpAddr>() {
+        Ok(ip) => SocketAddr::new(ip, port).to_string(),
+        Err(_) => format!("{}:{}", ip, port),
+    }
+}
+
+/// Root of a host's API for one of its libraries; its default library for
+/// `None`.
+pub fn api_base(ip: &str, port: u16, library_id: Option<&str>) -> String {
+    match library_id {
+        Some(id) => format!("http://{}/api/libraries/{}", url_authority(ip, port), id),
+        None => format!("http://{}/api", url_authority(ip, port)),
+    }
+}
+
+/// Builds the mDNS registration for this host, announcing all of `addresses`.
+pub fn service_info(
+    machine_name: &str,
+    addresses: &[IpAddr],
+    port: u16,
+    advertisement: &HostAdvertisement,
+) -> Result<mdns_sd::ServiceInfo, mdns_sd::Error> {
+    let instance_name = format!("ShelfSync on {}", machine_name);
+    let host_name = format!("{}.local.", machine_name);
+    let properties: Vec<(&str, String)> = advertisement.to_txt();
+    mdns_sd::ServiceInfo::new(
+        SERVICE_TYPE,
+        &instance_name,
+        &host_name,
+        addresses,
+        port,
+        &properties[..],
+    )
+}
+
+/// Turns a resolved mDNS service into a host entry for the client UI.
+pub fn connection_info(service: &mdns_sd::ResolvedService) -> ConnectionInfo {
+    let txt = service
+        .get_properties()
+        .iter()
+        .map(|p| (p.key().to_string(), p.val_str().to_string()))
+        .collect();
+    let advertisement = HostAdvertisement::from_txt(&txt);
+    let addresses: Vec<String> =
+        order_addresses(service.get_addresses().iter().map(|a| a.to_ip_addr()), None)
+            .iter()
+            .map(|ip| ip.to_string())
+            .collect();
+
+    ConnectionInfo {
+        ip: addresses.first().cloned().unwrap_or_default(),
+        addresses,
+        port: service.get_port(),
+        hostname: service.get_fullname().to_string(),
+        pin: None,
+        compatible: advertisement.is_compatible(),
+        advertisement,
+        ..Default::default()
+    }
+}
+
+/// Adds a resolved host, or updates the known entry for the same host in
+/// place when it comes back with a new address or TXT record.
+///
+/// # Returns
+///
+/// Returns whether the list changed.
+pub fn upsert_host(hosts: &mut Vec<ConnectionInfo>, mut host: ConnectionInfo) -> bool {
+    match hosts.iter_mut().find(|h| h.key() == host.key()) {
+        Some(existing) => {
+            // Reachability comes from health probes, not from mDNS
+            host.online = existing.online;
+            host.latency_ms = existing.latency_ms;
+            if *existing == host {
+                return false;
+            }
+            *existing = host;
+            true
+        }
+        None => {
+            hosts.push(host);
+            true
+        }
+    }
+}
+
+/// Drops the host registered under the mDNS service name `fullname`.
+///
+/// # Returns
+///
+/// Returns whether the list changed.
+pub fn remove_host(hosts: &mut Vec<ConnectionInfo>, fullname: &str) -> bool {
+    let len_before = hosts.len();
+    hosts.retain(|h| h.hostname != fullname);
+    hosts.len() != len_before
+}
+
+/// Body of `GET /api/health`.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Health {
+    pub status: String,
+    #[serde(flatten)]
+    pub advertisement: HostAdvertisement,
+}
+
+/// A host the user added by address, as saved in `manual_hosts.json`.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+struct ManualHost {
+    address: String,
+    port: u16,
+}
+
+impl ManualHost {
+    fn to_connection_info(&self) -> ConnectionInfo {
+        ConnectionInfo {
+            ip: self.address.clone(),
+            addresses: vec![self.address.clone()],
+            port: self.port,
+            // Unique per manual host until a probe learns the host ID
+            hostname: url_authority(&self.address, self.port),
+            compatible: true,
+            manual: true,
+            ..Default::default()
+        }
+    }
+}
+
+/// Hosts known to a client: those found over mDNS and those added by address
+/// for networks where mDNS does not reach (guest VLANs, VPNs).
+pub struct DiscoveryState {
+    /// Hosts seen over mDNS, one entry per `ConnectionInfo::key`.
+    mdns: Mutex<Vec<ConnectionInfo>>,
+    /// Hosts heard from over UDP broadcast beacons, for when mDNS is down
+    /// here or on the host.
+    beacon: Mutex<Vec<ConnectionInfo>>,
+    /// When each beacon host (by service name) was last heard from.
+    beacon_seen: Mutex<HashMap<String, Instant>>,
+    /// Hosts added by address, saved to `manual_path`.
+    manual: Mutex<Vec<ConnectionInfo>>,
+    manual_path: PathBuf,
+    status: Mutex<DiscoveryStatus>,
+}
+
+/// State of this machine's mDNS responder.
+#[derive(serde::Serialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum MdnsState {
+    #[default]
+    Starting,
+    Running,
+    /// Failed and waiting to retry; hosts are only found via beacons and
+    /// manual addresses meanwhile.
+    Failed,
+}
+
+/// Health of the discovery machinery, shown to the user so a network that
+/// blocks multicast does not look like an empty one.
+#[derive(serde::Serialize, Debug, Clone, PartialEq, Default)]
+pub struct DiscoveryStatus {
+    pub mdns: MdnsState,
+    /// Why mDNS last failed.
+    pub error: Option<String>,
+    /// Seconds until the next mDNS attempt, while failed.
+    pub retry_in_secs: Option<u64>,
+    /// Whether this host is announcing itself with UDP broadcast beacons.
+    pub beacon_sending: bool,
+    /// Whether beacons from other hosts are being listened for.
+    pub beacon_listening: bool,
+}
+
+/// What changed in a [`DiscoveryState`], for forwarding to the UI.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DiscoveryUpdate {
+    Hosts,
+    Status,
+}
+
+/// Called by the discovery tasks whenever hosts or status change.
+pub type Notify = std::sync::Arc<dyn Fn(DiscoveryUpdate) + Send + Sync>;
+
+/// Produces this host's current advertisement.
+pub type Advertise = std::sync::Arc<dyn Fn() -> HostAdvertisement + Send + Sync>;
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl DiscoveryState {
+    /// Creates the state, loading the manual hosts saved in `app_data_dir`.
+    pub fn new(app_data_dir: &Path) -> Self {
+        let manual_path = app_data_dir.join("manual_hosts.json");
+        let manual: Vec<ManualHost> = match std::fs::read_to_string(&manual_path) {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+                log::error!("Ignoring malformed {:?}: {}", manual_path, e);
+                Vec::new()
+            }),
+            Err(_) => Vec::new(),
+        };
+        DiscoveryState {
+            mdns: Mutex::new(Vec::new()),
+            beacon: Mutex::new(Vec::new()),
+            beacon_seen: Mutex::new(HashMap::new()),
+            manual: Mutex::new(manual.iter().map(ManualHost::to_connection_info).collect()),
+            manual_path,
+            status: Mutex::new(DiscoveryStatus::default()),
+        }
+    }
+
+    /// All known hosts: mDNS results first, then beacon and manual hosts not
+    /// also found over mDNS.
+    pub fn hosts(&self) -> Vec<ConnectionInfo> {
+        let mut hosts = lock(&self.mdns).clone();
+        for beacon in lock(&self.beacon).iter() {
+            if !hosts.iter().any(|h| h.key() == beacon.key()) {
+                hosts.push(beacon.clone());
+            }
+        }
+        for manual in lock(&self.manual).iter() {
+            match hosts.iter_mut().find(|h| h.key() == manual.key()) {
+                Some(found) => found.manual = true,
+                None => hosts.push(manual.clone()),
+            }
+        }
+        hosts
+    }
+
+    pub fn status(&self) -> DiscoveryStatus {
+        lock(&self.status).clone()
+    }
+
+    /// Applies `update` to the status. Returns whether it changed.
+    pub fn update_status(&self, update: impl FnOnce(&mut DiscoveryStatus)) -> bool {
+        let mut status = lock(&self.status);
+        let before = status.clone();
+        update(&mut status);
+        *status != before
+    }
+
+    /// Records a host heard from over a UDP beacon. Returns whether the host
+    /// list changed.
+    pub fn beacon_received(&self, host: ConnectionInfo) -> bool {
+        lock(&self.beacon_seen).insert(host.hostname.clone(), Instant::now());
+        upsert_host(&mut lock(&self.beacon), host)
+    }
+
+    /// Drops beacon hosts not heard from within `max_age`. Returns whether
+    /// the host list changed.
+    pub fn expire_beacons(&self, max_age: Duration) -> bool {
+        let mut seen = lock(&self.beacon_seen);
+        seen.retain(|_, at| at.elapsed() < max_age);
+        let mut beacon = lock(&self.beacon);
+        let len_before = beacon.len();
+        beacon.retain(|h| seen.contains_key(&h.hostname));
+        beacon.len() != len_before
+    }
+
+    /// Records a host resolved over mDNS. Returns whether anything changed.
+    pub fn resolved(&self, host: ConnectionInfo) -> bool {
+        upsert_host(&mut lock(&self.mdns), host)
+    }
+
+    /// Forgets the mDNS service `fullname`. Returns whether anything changed.
+    pub fn removed(&self, fullname: &str) -> bool {
+        remove_host(&mut lock(&self.mdns), fullname)
+    }
+
+    /// Adds and saves a host by address (an IP or a DNS name).
+    ///
+    /// # Returns
+    ///
+    /// Returns the new host, or the existing one if it was already added.
+    pub fn add_manual(&self, address: &str, port: u16) -> Result<ConnectionInfo, AppError> {
+        let address = address.trim().trim_start_matches('[').trim_end_matches(']');
+        if address.is_empty() || address.contains(|c: char| c.is_whitespace() || "/@".contains(c)) {
+            return Err(AppError::Other(format!(
+                "Invalid host address: {}",
+                address
+            )));
+        }
+        let added = ManualHost {
+            address: address.to_string(),
+            port,
+        }
+        .to_connection_info();
+
+        let mut manual = lock(&self.manual);
+        if let Some(existing) = manual.iter().find(|h| h.hostname == added.hostname) {
+            return Ok(existing.clone());
+        }
+        manual.push(added.clone());
+        self.save_manual(&manual)?;
+        Ok(added)
+    }
+
+    /// Removes a manual host by its `ConnectionInfo::key`. Returns whether it
+    /// was known.
+    pub fn remove_manual(&self, key: &str) -> Result<bool, AppError> {
+        let mut manual = lock(&self.manual);
+        let len_before = manual.len();
+        manual.retain(|h| h.key() != key);
+        if manual.len() == len_before {
+            return Ok(false);
+        }
+        self.save_manual(&manual)?;
+        Ok(true)
+    }
+
+    fn save_manual(&self, manual: &[ConnectionInfo]) -> Result<(), AppError> {
+        let saved: Vec<ManualHost> = manual
+            .iter()
+            .map(|h| ManualHost {
+                address: h.addresses.first().unwrap_or(&h.ip).clone(),
+                port: h.port,
+            })
+            .collect();
+        let json =
+            serde_json::to_string_pretty(&saved).map_err(|e| AppError::Other(e.to_string()))?;
+        std::fs::write(&self.manual_path, json)?;
+        Ok(())
+    }
+
+    /// Probes every known host and records whether it answered.
+    ///
+    /// # Returns
+    ///
+    /// Returns whether any host changed, so callers know to emit an update.
+    pub async fn probe_all(&self, client: &reqwest::Client) -> bool {
+        let lists = [&self.mdns, &self.beacon, &self.manual];
+        let snapshot: Vec<(usize, ConnectionInfo)> = lists
+            .iter()
+            .enumerate()
+            .flat_map(|(list, hosts)| lock(hosts).clone().into_iter().map(move |h| (list, h)))
+            .collect();
+        let probes = futures_util::future::join_all(
+            snapshot.iter().map(|(_, host)| probe_host(client, host)),
+        )
+        .await;
+
+        let mut changed = false;
+        for ((list, before), probe) in snapshot.iter().zip(probes) {
+            let mut hosts = lock(lists[*list]);
+            // Matched by service name, since a probe may have just learned the host ID
+            if let Some(host) = hosts
+                .iter_mut()
+                .find(|h| h.hostname == before.hostname && h.port == before.port)
+            {
+                let updated = apply_probe(host.clone(), probe);
+                if *host != updated {
+                    *host = updated;
+                    changed = true;
+                }
+            }
+        }
+        changed
+    }
+}
+
+/// Outcome of probing one host.
+#[derive(Debug)]
+pub struct Probe {
+    /// The first address that answered.
+    pub ip: String,
+    pub latency: Duration,
+    /// What the host reported, if it serves `/api/health`.
+    pub health: Option<Health>,
+}
+
+/// Sends `GET /api/health` to each of the host's addresses in order, stopping
+/// at the first that answers. Returns `None` if none did.
+///
+/// Any HTTP response counts as online, so hosts from before the health
+/// endpoint existed are not reported offline.
+pub async fn probe_host(client: &reqwest::Client, host: &ConnectionInfo) -> Option<Probe> {
+    let candidates = if host.addresses.is_empty() {
+        std::slice::from_ref(&host.ip)
+    } else {
+        &host.addresses[..]
+    };
+    for ip in candidates {
+        let url = format!("http://{}/api/health", url_authority(ip, host.port));
+        let started = Instant::now();
+        let Ok(response) = client.get(&url).timeout(PROBE_TIMEOUT).send().await else {
+            continue;
+        };
+        let latency = started.elapsed();
+        let health = if response.status().is_success() {
+            response.json::<Health>().await.ok()
+        } else {
+            None
+        };
+        return Some(Probe {
+            ip: ip.clone(),
+            latency,
+            health,
+        });
+    }
+    None
+}
+
+/// Records the outcome of a probe on `host`.
+pub fn apply_probe(mut host: ConnectionInfo, probe: Option<Probe>) -> ConnectionInfo {
+    let Some(probe) = probe else {
+        host.online = Some(false);
+        host.latency_ms = None;
+        return host;
+    };
+    host.online = Some(true);
+    host.latency_ms = Some(probe.latency.as_millis() as u64);
+    host.ip = probe.ip;
+    if let Some(health) = probe.health {
+        // Fresher than the TXT record, and the only source for manual hosts
+        host.compatible = health.advertisement.is_compatible();
+        host.advertisement = health.advertisement;
+    }
+    host
+}
+
+/// Returns this install's host ID, creating and saving one on first use.
+pub fn load_host_id(app_data_dir: &Path) -> String {
+    let path = app_data_dir.join("host_id");
+    if let Ok(id) = std::fs::read_to_string(&path) {
+        let id = id.trim();
+        if !id.is_empty() {
+            return id.to_string();
+        }
+    }
+
+    let id = uuid::Uuid::new_v4().to_string();
+    if let Err(e) = std::fs::create_dir_all(app_data_dir).and_then(|_| std::fs::write(&path, &id)) {
+        log::error!("Failed to save host ID to {:?}: {}", path, e);
+    }
+    id
+}
+
+/// Short fingerprint of the served book list, used as the library revision.
+pub fn library_revision(books: &[Book]) -> String {
+    let mut hasher = Md5::new();
+    for book in books {
+        hasher.update(serde_json::to_vec(book).unwrap_or_default());
+    }
+    format!("{:x}", hasher.finalize())[..12].to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_txt_round_trip() {
+        let advertisement = HostAdvertisement {
+            host_id: Some("0b6c1f4e".to_string()),
+            version: Some("0.1.0".to_string()),
+            api_version: Some(API_VERSION),
+            library_name: Some("Calibre Library".to_string()),
+            book_count: Some(1234),
+            library_revision: Some("abcdef012345".to_string()),
+            tls_fingerprint: None,
+            pairing_open: Some(false),
+            library_count: Some(2),
+            library_ids: Some(vec!["default".to_string(), "3f9c2a1b".to_string()]),
+        };
+        let txt = advertisement
+            .to_txt()
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v))
+            .collect();
+        assert_eq!(HostAdvertisement::from_txt(&txt), advertisement);
+
+        // Library IDs past the length limit are left out whole
+        let many = HostAdvertisement {
+            library_ids: Some((0..10).map(|i| format!("{:08}", i)).collect()),
+            ..Default::default()
+        };
+        let txt = many
+            .to_txt()
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v))
+            .collect();
+        let ids = HostAdvertisement::from_txt(&txt).library_ids.unwrap();
+        assert_eq!(ids.len(), 7);
+        assert_eq!(ids[6], "00000006");
+
+        // Hosts from before this record only published their version
+        let legacy = HostAdvertisement::from_txt(&HashMap::from([(
+            "version".to_string(),
+            "0.1.0".to_string(),
+        )]));
+        assert_eq!(legacy.host_id, None);
+        assert!(legacy.is_compatible());
+        let future =
+            HostAdvertisement::from_txt(&HashMap::from([("api".to_string(), "0".to_string())]));
+        assert!(!future.is_compatible());
+    }
+
+    fn host(host_id: Option<&str>, hostname: &str, ip: &str) -> ConnectionInfo {
+        ConnectionInfo {
+            ip: ip.to_string(),
+            addresses: vec![ip.to_string()],
+            port: 8080,
+            hostname: hostname.to_string(),
+            pin: None,
+            advertisement: HostAdvertisement {
+                host_id: host_id.map(str::to_string),
+                ..Default::default()
+            },
+            compatible: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_hosts_keyed_by_id_across_address_changes() {
+        let mut hosts = Vec::new();
+        assert!(upsert_host(
+            &mut hosts,
+            host(
+                Some("a"),
+                "ShelfSync on desk._shelfsync._tcp.local.",
+                "10.0.0.5"
+            )
+        ));
+        assert!(!upsert_host(
+            &mut hosts,
+            host(
+                Some("a"),
+                "ShelfSync on desk._shelfsync._tcp.local.",
+                "10.0.0.5"
+            )
+        ));
+
+        // A DHCP change moves the same host rather than adding a second one
+        assert!(upsert_host(
+            &mut hosts,
+            host(
+                Some("a"),
+                "ShelfSync on desk._shelfsync._tcp.local.",
+                "10.0.0.9"
+            )
+        ));
+        assert_eq!(hosts.len(), 1);
+        assert_eq!(hosts[0].ip, "10.0.0.9");
+
+        // Another host that took over the old address is a separate entry
+        assert!(upsert_host(
+            &mut hosts,
+            host(
+                Some("b"),
+                "ShelfSync on laptop._shelfsync._tcp.local.",
+                "10.0.0.5"
+            )
+        ));
+        // Hosts without an ID fall back to their service name
+        assert!(upsert_host(
+            &mut hosts,
+            host(None, "ShelfSync on old._shelfsync._tcp.local.", "10.0.0.7")
+        ));
+        assert!(upsert_host(
+            &mut hosts,
+            host(None, "ShelfSync on old._shelfsync._tcp.local.", "10.0.0.8")
+        ));
+        assert_eq!(hosts.len(), 3);
+
+        assert!(remove_host(
+            &mut hosts,
+            "ShelfSync on desk._shelfsync._tcp.local."
+        ));
+        assert!(!remove_host(
+            &mut hosts,
+            "ShelfSync on desk._shelfsync._tcp.local."
+        ));
+        assert_eq!(hosts.len(), 2);
+        assert_eq!(hosts[0].key(), "b");
+    }
+
+    #[test]
+    fn test_orders_reachable_addresses() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let addresses = order_addresses(
+            [
+                ip("fe80::1"),
+                ip("2001:db8::5"),
+                ip("127.0.0.1"),
+                ip("10.8.0.2"),
+                ip("192.168.1.20"),
+                ip("::1"),
+                ip("10.8.0.2"),
+            ],
+            Some(ip("192.168.1.20")),
+        );
+        assert_eq!(
+            addresses,
+            [ip("192.168.1.20"), ip("10.8.0.2"), ip("2001:db8::5")]
+        );
+        assert_eq!(url_authority("2001:db8::5", 8080), "[2001:db8::5]:8080");
+        assert_eq!(url_authority("10.8.0.2", 8080), "10.8.0.2:8080");
+    }
+
+    #[test]
+    fn test_host_id_is_stable() {
+        let dir = tempdir().unwrap();
+        let id = load_host_id(dir.path());
+        assert_eq!(load_host_id(dir.path()), id);
+        assert_eq!(truncate("ééé", 3), "é");
+    }
+}
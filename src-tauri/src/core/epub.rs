@@ -0,0 +1,660 @@
+use crate::core::zipstream::ZipStream;
+use crate::error::AppError;
+use crate::models::Book;
+use log::warn;
+use quick_xml::events::{BytesEnd, BytesStart, BytesText, Event};
+use quick_xml::{Reader, Writer};
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufReader, Cursor, Read, Write};
+use std::path::Path;
+use zip::ZipArchive;
+
+/// Namespace of the Dublin Core elements (`dc:title`, ...) in OPF metadata.
+const DC_NAMESPACE: &str = "http://purl.org/dc/elements/1.1/";
+
+/// An EPUB with its OPF metadata replaced by the current values from a [`Book`]
+/// and, when a cover was given, its cover image swapped, ready to be written out.
+///
+/// Everything that can fail on a malformed EPUB is done by [`embed_metadata`],
+/// before anything is written, so callers can fall back to the original file.
+pub struct MetadataRewrite {
+    archive: ZipArchive<BufReader<File>>,
+    opf_path: String,
+    new_opf: String,
+    /// Entry name of the cover image and what replaces it.
+    cover: Option<(String, Vec<u8>)>,
+}
+
+/// Prepares a copy of the EPUB at `source` with the metadata from `book` and,
+/// when `cover` is given, that image as the embedded cover.
+///
+/// The cover keeps the entry name and format of the one it replaces, so the
+/// manifest and any pages showing it stay valid: a JPEG cover is used as is,
+/// others are converted, and a cover in a format we cannot write is left alone.
+pub fn embed_metadata(
+    source: &Path,
+    book: &Book,
+    cover: Option<&Path>,
+) -> Result<MetadataRewrite, AppError> {
+    let mut archive = ZipArchive::new(BufReader::new(File::open(source)?))?;
+
+    let opf_path = find_opf_path(&mut archive)?;
+    let opf = read_entry_to_string(&mut archive, &opf_path)?;
+    let new_opf = rewrite_opf(&opf, book)?;
+
+    let cover_entry = find_cover_href(&opf).map(|href| resolve_href(&opf_path, &href));
+    let cover = match (cover_entry, cover) {
+        (Some(name), Some(path)) if path.exists() => {
+            convert_cover(&name, std::fs::read(path)?).map(|bytes| (name, bytes))
+        }
+        _ => None,
+    };
+
+    Ok(MetadataRewrite {
+        archive,
+        opf_path,
+        new_opf,
+        cover,
+    })
+}
+
+impl MetadataRewrite {
+    /// Writes the EPUB to `out`, which is returned when done.
+    ///
+    /// The zip is written front to back, so `out` can be a response body. Entries
+    /// other than the OPF and the cover are copied without recompression, so the
+    /// rewrite costs little more than a file copy.
+    pub fn write_to<W: Write>(mut self, out: W) -> Result<W, AppError> {
+        let mut writer = ZipStream::new(out);
+        for i in 0..self.archive.len() {
+            let name = self.archive.by_index_raw(i)?.name().to_string();
+
+            if name == self.opf_path {
+                writer.add_bytes(&name, self.new_opf.as_bytes())?;
+            } else if let Some((_, bytes)) = self.cover.as_ref().filter(|(cover, _)| *cover == name)
+            {
+                writer.add_bytes(&name, bytes)?;
+            } else {
+                writer.add_raw(&mut self.archive, i)?;
+            }
+        }
+        writer.finish()
+    }
+}
+
+/// Converts a JPEG `cover` to the format of the cover entry `name` it replaces,
+/// or returns `None` if that format cannot be written.
+fn convert_cover(name: &str, cover: Vec<u8>) -> Option<Vec<u8>> {
+    let format = image::ImageFormat::from_path(name).ok()?;
+    if format == image::ImageFormat::Jpeg {
+        return Some(cover);
+    }
+
+    let converted = image::load_from_memory(&cover).and_then(|img| {
+        let mut bytes = Vec::new();
+        img.write_to(&mut Cursor::new(&mut bytes), format)?;
+        Ok(bytes)
+    });
+    match converted {
+        Ok(bytes) => Some(bytes),
+        Err(e) => {
+            warn!("Keeping the original cover {}: {}", name, e);
+            None
+        }
+    }
+}
+
+/// Finds the path of the package document (OPF) from `META-INF/container.xml`.
+pub fn find_opf_path<R: Read + std::io::Seek>(
+    archive: &mut ZipArchive<R>,
+) -> Result<String, AppError> {
+    let container = read_entry_to_string(archive, "META-INF/container.xml")?;
+    let mut reader = Reader::from_str(&container);
+
+    loop {
+        match reader.read_event().map_err(xml_error)? {
+            Event::Start(e) | Event::Empty(e) if e.local_name().as_ref() == b"rootfile" => {
+                if let Some(path) = attribute(&e, "full-path") {
+                    return Ok(path);
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    Err(AppError::InvalidEpub(
+        "container.xml has no rootfile".to_string(),
+    ))
+}
+
+/// Reads a single archive entry as UTF-8 text.
+pub fn read_entry_to_string<R: Read + std::io::Seek>(
+    archive: &mut ZipArchive<R>,
+    name: &str,
+) -> Result<String, AppError> {
+    let mut entry = archive.by_name(name)?;
+    let mut content = String::new();
+    entry.read_to_string(&mut content)?;
+    Ok(content)
+}
+
+/// Resolves an `href` from the OPF (relative to the OPF's directory) to an archive entry name.
+pub fn resolve_href(opf_path: &str, href: &str) -> String {
+    let href = href.split('#').next().unwrap_or_default();
+    let mut parts: Vec<String> = match opf_path.rsplit_once('/') {
+        Some((dir, _)) => dir.split('/').map(|s| s.to_string()).collect(),
+        None => Vec::new(),
+    };
+
+    for segment in href.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            s => parts.push(percent_decode(s)),
+        }
+    }
+    parts.join("/")
+}
+
+/// Decodes the `%XX` escapes of a URL path segment; malformed ones are kept as they are.
+fn percent_decode(segment: &str) -> String {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = match bytes.get(i + 1..i + 3) {
+            Some([hi, lo]) if bytes[i] == b'%' => {
+                (*hi as char).to_digit(16).zip((*lo as char).to_digit(16))
+            }
+            _ => None,
+        };
+        match escaped {
+            Some((hi, lo)) => {
+                decoded.push((hi * 16 + lo) as u8);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Returns the manifest `href` of the cover image, handling both the EPUB 2
+/// `<meta name="cover">` convention and the EPUB 3 `cover-image` property.
+fn find_cover_href(opf: &str) -> Option<String> {
+    let mut reader = Reader::from_str(opf);
+    let mut cover_id = None;
+    let mut items: Vec<(String, String, String)> = Vec::new(); // (id, href, properties)
+
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(e)) | Ok(Event::Empty(e)) => match e.local_name().as_ref() {
+                b"meta" if attribute(&e, "name").as_deref() == Some("cover") => {
+                    cover_id = attribute(&e, "content");
+                }
+                b"item" => items.push((
+                    attribute(&e, "id").unwrap_or_default(),
+                    attribute(&e, "href").unwrap_or_default(),
+                    attribute(&e, "properties").unwrap_or_default(),
+                )),
+                _ => {}
+            },
+            Ok(Event::Eof) | Err(_) => break,
+            _ => {}
+        }
+    }
+
+    items
+        .iter()
+        .find(|(_, _, props)| props.split_whitespace().any(|p| p == "cover-image"))
+        .or_else(|| {
+            cover_id
+                .as_ref()
+                .and_then(|id| items.iter().find(|(item_id, _, _)| item_id == id))
+        })
+        .map(|(_, href, _)| href.clone())
+}
+
+/// Rewrites the `<metadata>` block of an OPF document with the fields from `book`.
+///
+/// Title, creators, subjects, publisher and series entries (Calibre's `calibre:series`
+/// metas and EPUB 3 collections) are dropped along with any `<meta refines>` pointing
+/// at them, wherever it appears; everything else (identifiers, language, cover meta,
+/// ...) is kept as-is. New elements use the prefix the document binds to Dublin Core.
+pub fn rewrite_opf(opf: &str, book: &Book) -> Result<String, AppError> {
+    let MetadataScan {
+        removed_ids,
+        dc_prefix,
+    } = scan_metadata(opf)?;
+    let mut reader = Reader::from_str(opf);
+    let mut writer = Writer::new(Vec::new());
+    let mut in_metadata = false;
+    let mut skip_depth = 0usize;
+
+    loop {
+        let event = reader.read_event().map_err(xml_error)?;
+
+        if skip_depth > 0 {
+            match event {
+                Event::Start(_) => skip_depth += 1,
+                Event::End(_) => skip_depth -= 1,
+                Event::Eof => break,
+                _ => {}
+            }
+            continue;
+        }
+
+        match event {
+            Event::Start(ref e) if e.local_name().as_ref() == b"metadata" => {
+                in_metadata = true;
+                writer.write_event(event).map_err(write_error)?;
+            }
+            Event::End(ref e) if in_metadata && e.local_name().as_ref() == b"metadata" => {
+                write_book_metadata(&mut writer, book, dc_prefix.as_deref())?;
+                in_metadata = false;
+                writer.write_event(event).map_err(write_error)?;
+            }
+            Event::Start(ref e) if in_metadata && is_replaced(e, &removed_ids) => {
+                skip_depth = 1;
+            }
+            Event::Empty(ref e) if in_metadata && is_replaced(e, &removed_ids) => {}
+            Event::Eof => break,
+            _ => writer.write_event(event).map_err(write_error)?,
+        }
+    }
+
+    String::from_utf8(writer.into_inner()).map_err(|e| AppError::InvalidEpub(e.to_string()))
+}
+
+/// What [`rewrite_opf`] needs to know about the metadata before rewriting it.
+struct MetadataScan {
+    /// IDs of the elements that are dropped, including metas refining them.
+    removed_ids: HashSet<String>,
+    /// Prefix bound to Dublin Core (empty when it is the default namespace), or
+    /// `None` when the document never declares it.
+    dc_prefix: Option<String>,
+}
+
+/// Reads the metadata ahead of the rewrite, since a `<meta refines>` may come
+/// before the element it refines, and metas may refine one another.
+fn scan_metadata(opf: &str) -> Result<MetadataScan, AppError> {
+    let mut reader = Reader::from_str(opf);
+    let mut in_metadata = false;
+    let mut dc_prefix = None;
+    // (id, refined id) of every metadata element not dropped outright
+    let mut kept = Vec::new();
+    let mut removed_ids = HashSet::new();
+
+    loop {
+        match reader.read_event().map_err(xml_error)? {
+            Event::Start(ref e) | Event::Empty(ref e)
+                if matches!(e.local_name().as_ref(), b"package" | b"metadata") =>
+            {
+                // A binding on <metadata> overrides one on <package>
+                if let Some(prefix) = dc_binding(e) {
+                    dc_prefix = Some(prefix);
+                }
+                in_metadata = e.local_name().as_ref() == b"metadata";
+            }
+            Event::End(ref e) if e.local_name().as_ref() == b"metadata" => break,
+            Event::Start(ref e) | Event::Empty(ref e) if in_metadata => {
+                let id = attribute(e, "id");
+                if is_replaced(e, &HashSet::new()) {
+                    removed_ids.extend(id);
+                } else if let Some(id) = id {
+                    let refines = attribute(e, "refines")
+                        .and_then(|r| r.strip_prefix('#').map(str::to_string));
+                    kept.push((id, refines));
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    // Follow chains of refines until nothing more is dropped
+    loop {
+        let before = removed_ids.len();
+        for (id, refines) in &kept {
+            if refines.as_ref().is_some_and(|r| removed_ids.contains(r)) {
+                removed_ids.insert(id.clone());
+            }
+        }
+        if removed_ids.len() == before {
+            break;
+        }
+    }
+
+    Ok(MetadataScan {
+        removed_ids,
+        dc_prefix,
+    })
+}
+
+/// The prefix an element binds to the Dublin Core namespace, if it does.
+fn dc_binding(e: &BytesStart) -> Option<String> {
+    e.attributes().flatten().find_map(|a| {
+        if a.value.as_ref() != DC_NAMESPACE.as_bytes() {
+            return None;
+        }
+        match a.key.as_ref() {
+            b"xmlns" => Some(String::new()),
+            key => key
+                .strip_prefix(b"xmlns:")
+                .map(|prefix| String::from_utf8_lossy(prefix).into_owned()),
+        }
+    })
+}
+
+/// Whether a metadata element is one of the fields regenerated from the `Book` record.
+fn is_replaced(e: &BytesStart, removed_ids: &HashSet<String>) -> bool {
+    match e.local_name().as_ref() {
+        b"title" | b"creator" | b"subject" | b"publisher" => true,
+        b"meta" => {
+            let name = attribute(e, "name").unwrap_or_default();
+            let property = attribute(e, "property").unwrap_or_default();
+            let refines = attribute(e, "refines").unwrap_or_default();
+            name == "calibre:series"
+                || name == "calibre:series_index"
+                || property == "belongs-to-collection"
+                || refines
+                    .strip_prefix('#')
+                    .is_some_and(|id| removed_ids.contains(id))
+        }
+        _ => false,
+    }
+}
+
+/// Writes the fields from `book` as Dublin Core elements under `dc_prefix`, or
+/// under `dc:` declared on each element when the document has no binding.
+fn write_book_metadata(
+    writer: &mut Writer<Vec<u8>>,
+    book: &Book,
+    dc_prefix: Option<&str>,
+) -> Result<(), AppError> {
+    let dc = DublinCore(dc_prefix);
+    dc.write(writer, "title", &book.title)?;
+
+    for author in book
+        .authors
+        .split(", ")
+        .map(str::trim)
+        .filter(|a| !a.is_empty())
+    {
+        dc.write(writer, "creator", author)?;
+    }
+
+    if let Some(publisher) = &book.publisher {
+        dc.write(writer, "publisher", publisher)?;
+    }
+
+    for tag in book.tags.iter().filter(|t| !t.is_empty()) {
+        dc.write(writer, "subject", tag)?;
+    }
+
+    if let Some(series) = &book.series {
+        write_meta(writer, "calibre:series", series)?;
+        write_meta(
+            writer,
+            "calibre:series_index",
+            &format_series_index(book.series_index),
+        )?;
+    }
+
+    writer
+        .write_event(Event::Text(BytesText::new("\n  ")))
+        .map_err(write_error)
+}
+
+/// Writes Dublin Core elements under the prefix the document binds to it.
+struct DublinCore<'a>(Option<&'a str>);
+
+impl DublinCore<'_> {
+    fn write(&self, writer: &mut Writer<Vec<u8>>, name: &str, text: &str) -> Result<(), AppError> {
+        let (name, declare) = match self.0 {
+            Some("") => (name.to_string(), false),
+            Some(prefix) => (format!("{}:{}", prefix, name), false),
+            None => (format!("dc:{}", name), true),
+        };
+        let mut start = BytesStart::new(name.as_str());
+        if declare {
+            start.push_attribute(("xmlns:dc", DC_NAMESPACE));
+        }
+
+        writer
+            .write_event(Event::Text(BytesText::new("\n    ")))
+            .map_err(write_error)?;
+        writer
+            .write_event(Event::Start(start))
+            .map_err(write_error)?;
+        writer
+            .write_event(Event::Text(BytesText::new(text)))
+            .map_err(write_error)?;
+        writer
+            .write_event(Event::End(BytesEnd::new(name)))
+            .map_err(write_error)
+    }
+}
+
+fn write_meta(writer: &mut Writer<Vec<u8>>, name: &str, content: &str) -> Result<(), AppError> {
+    let mut meta = BytesStart::new("meta");
+    meta.push_attribute(("name", name));
+    meta.push_attribute(("content", content));
+
+    writer
+        .write_event(Event::Text(BytesText::new("\n    ")))
+        .map_err(write_error)?;
+    writer.write_event(Event::Empty(meta)).map_err(write_error)
+}
+
+/// Formats a series index the way Calibre does: `3` rather than `3.0`, but `3.5` as is.
+fn format_series_index(index: f64) -> String {
+    if index.fract() == 0.0 {
+        format!("{}", index as i64)
+    } else {
+        index.to_string()
+    }
+}
+
+fn attribute(e: &BytesStart, name: &str) -> Option<String> {
+    e.try_get_attribute(name)
+        .ok()
+        .flatten()
+        .and_then(|a| a.unescape_value().ok().map(|v| v.into_owned()))
+}
+
+pub(crate) fn xml_error(e: quick_xml::Error) -> AppError {
+    AppError::InvalidEpub(e.to_string())
+}
+
+pub(crate) fn write_error(e: std::io::Error) -> AppError {
+    AppError::InvalidEpub(e.to_string())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use tempfile::tempdir;
+    use zip::write::SimpleFileOptions;
+    use zip::{CompressionMethod, ZipWriter};
+
+    const CONTAINER: &str = r#"<?xml version="1.0"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+  <rootfiles>
+    <rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/>
+  </rootfiles>
+</container>"#;
+
+    const OPF: &str = r##"<?xml version="1.0" encoding="UTF-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="3.0" unique-identifier="uid">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+    <dc:identifier id="uid">urn:uuid:1234</dc:identifier>
+    <dc:title id="t1">Old Title</dc:title>
+    <meta refines="#t1" property="title-type">main</meta>
+    <dc:creator>Old Author</dc:creator>
+    <dc:language>en</dc:language>
+    <meta name="cover" content="cover-img"/>
+  </metadata>
+  <manifest>
+    <item id="cover-img" href="images/cover.jpg" media-type="image/jpeg"/>
+  </manifest>
+</package>"##;
+
+    const CHAPTER: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<html xmlns="http://www.w3.org/1999/xhtml"><head><title>Chapter 1</title></head>
+<body><p>It was a dark night. The end.</p></body></html>"#;
+
+    pub(crate) fn write_test_epub(path: &Path) {
+        write_epub_with_cover(path, "cover.jpg", b"old cover");
+    }
+
+    fn write_epub_with_cover(path: &Path, cover_name: &str, cover: &[u8]) {
+        let mut zip = ZipWriter::new(File::create(path).unwrap());
+        let stored = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+        zip.start_file("mimetype", stored).unwrap();
+        zip.write_all(b"application/epub+zip").unwrap();
+        zip.start_file("META-INF/container.xml", SimpleFileOptions::default())
+            .unwrap();
+        zip.write_all(CONTAINER.as_bytes()).unwrap();
+        zip.start_file("OEBPS/content.opf", SimpleFileOptions::default())
+            .unwrap();
+        zip.write_all(OPF.replace("cover.jpg", cover_name).as_bytes())
+            .unwrap();
+        zip.start_file("OEBPS/chapter1.xhtml", SimpleFileOptions::default())
+            .unwrap();
+        zip.write_all(CHAPTER.as_bytes()).unwrap();
+        zip.start_file(format!("OEBPS/images/{}", cover_name), stored)
+            .unwrap();
+        zip.write_all(cover).unwrap();
+        zip.finish().unwrap();
+    }
+
+    fn test_book() -> Book {
+        Book {
+            authors: "Jane Doe, John Roe".to_string(),
+            series: Some("Saga".to_string()),
+            series_index: 2.0,
+            tags: vec!["Fantasy".to_string()],
+            ..Book::test(1, "New Title & More")
+        }
+    }
+
+    #[test]
+    fn test_rewrite_opf_replaces_metadata() {
+        let opf = rewrite_opf(OPF, &test_book()).unwrap();
+
+        assert!(!opf.contains("Old Title"));
+        assert!(!opf.contains("Old Author"));
+        assert!(!opf.contains("title-type"));
+        assert!(opf.contains("<dc:title>New Title &amp; More</dc:title>"));
+        assert!(opf.contains("<dc:creator>John Roe</dc:creator>"));
+        assert!(opf.contains("<dc:subject>Fantasy</dc:subject>"));
+        assert!(opf.contains(r#"<meta name="calibre:series_index" content="2"/>"#));
+        // Untouched fields survive
+        assert!(opf.contains("urn:uuid:1234"));
+        assert!(opf.contains(r#"<meta name="cover" content="cover-img"/>"#));
+    }
+
+    #[test]
+    fn test_rewrite_opf_drops_refines_before_their_element() {
+        let source = OPF
+            .replace(
+                r#"<metadata xmlns:dc="http://purl.org/dc/elements/1.1/">"#,
+                r##"<metadata xmlns:dce="http://purl.org/dc/elements/1.1/">
+    <meta refines="#c1" property="file-as">Author, Old</meta>
+    <meta refines="#coll" property="collection-type" id="type">series</meta>
+    <meta refines="#type" property="alternate-script">シリーズ</meta>"##,
+            )
+            .replace("dc:", "dce:")
+            .replace("<dce:creator>", r#"<dce:creator id="c1">"#)
+            .replace(
+                "</metadata>",
+                r#"<meta property="belongs-to-collection" id="coll">Old Saga</meta>
+  </metadata>"#,
+            );
+        let opf = rewrite_opf(&source, &test_book()).unwrap();
+
+        assert!(!opf.contains("refines"));
+        assert!(!opf.contains("Old Saga"));
+        assert!(opf.contains("<dce:title>New Title &amp; More</dce:title>"));
+        assert!(!opf.contains("<dc:"));
+
+        // Without a binding, new elements declare the namespace themselves
+        let unbound = rewrite_opf(
+            &OPF.replace(r#" xmlns:dc="http://purl.org/dc/elements/1.1/""#, ""),
+            &test_book(),
+        )
+        .unwrap();
+        assert!(unbound.contains(
+            r#"<dc:title xmlns:dc="http://purl.org/dc/elements/1.1/">New Title &amp; More</dc:title>"#
+        ));
+    }
+
+    #[test]
+    fn test_embed_metadata_replaces_opf_and_cover() {
+        let dir = tempdir().unwrap();
+        let source = dir.path().join("source.epub");
+        let cover = dir.path().join("cover.jpg");
+        write_test_epub(&source);
+        std::fs::write(&cover, "new cover").unwrap();
+
+        let epub = embed_metadata(&source, &test_book(), Some(&cover))
+            .unwrap()
+            .write_to(Vec::new())
+            .unwrap();
+
+        let mut archive = ZipArchive::new(Cursor::new(epub)).unwrap();
+        assert_eq!(archive.by_index(0).unwrap().name(), "mimetype");
+        let opf = read_entry_to_string(&mut archive, "OEBPS/content.opf").unwrap();
+        assert!(opf.contains("New Title"));
+        let cover = read_entry_to_string(&mut archive, "OEBPS/images/cover.jpg").unwrap();
+        assert_eq!(cover, "new cover");
+    }
+
+    #[test]
+    fn test_embed_metadata_converts_cover_to_existing_format() {
+        let dir = tempdir().unwrap();
+        let source = dir.path().join("source.epub");
+        let cover = dir.path().join("cover.jpg");
+        write_epub_with_cover(&source, "cover.png", b"old cover");
+        image::RgbImage::new(4, 4).save(&cover).unwrap();
+
+        let epub = embed_metadata(&source, &test_book(), Some(&cover))
+            .unwrap()
+            .write_to(Vec::new())
+            .unwrap();
+
+        let mut archive = ZipArchive::new(Cursor::new(epub)).unwrap();
+        let mut png = Vec::new();
+        archive
+            .by_name("OEBPS/images/cover.png")
+            .unwrap()
+            .read_to_end(&mut png)
+            .unwrap();
+        assert_eq!(image::guess_format(&png).unwrap(), image::ImageFormat::Png);
+    }
+
+    #[test]
+    fn test_resolve_href() {
+        assert_eq!(
+            resolve_href("OEBPS/content.opf", "images/cover.jpg"),
+            "OEBPS/images/cover.jpg"
+        );
+        assert_eq!(
+            resolve_href("OEBPS/content.opf", "../cover%20art.jpg"),
+            "cover art.jpg"
+        );
+        assert_eq!(resolve_href("content.opf", "cover.jpg"), "cover.jpg");
+        assert_eq!(
+            resolve_href("OEBPS/content.opf", "Text/Caf%C3%A9%20bar%ZZ.xhtml#top"),
+            "OEBPS/Text/Café bar%ZZ.xhtml"
+        );
+    }
+}
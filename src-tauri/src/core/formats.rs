@@ -0,0 +1,171 @@
+/// Broad category of a book format, used by clients to pick a viewer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FormatKind {
+    Ebook,
+    Comic,
+    Document,
+    Audiobook,
+    Archive,
+}
+
+/// A book format that Calibre can store and ShelfSync can serve.
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+pub struct BookFormat {
+    /// Lowercase Calibre format name, which is also the file extension on disk.
+    pub name: &'static str,
+    /// Value sent in the `Content-Type` header when serving the file.
+    pub mime_type: &'static str,
+    pub kind: FormatKind,
+    /// Whether common reading apps on client devices can open the file directly.
+    /// Formats that are not readable are only served when explicitly requested.
+    pub readable: bool,
+}
+
+const fn format(
+    name: &'static str,
+    mime_type: &'static str,
+    kind: FormatKind,
+    readable: bool,
+) -> BookFormat {
+    BookFormat {
+        name,
+        mime_type,
+        kind,
+        readable,
+    }
+}
+
+/// Every format known to ShelfSync, in order of preference when a client asks
+/// for the "best" available copy of a book.
+#[rustfmt::skip]
+pub const FORMATS: &[BookFormat] = &[
+    format("epub",  "application/epub+zip",               FormatKind::Ebook,     true),
+    format("kepub", "application/kepub+zip",              FormatKind::Ebook,     true),
+    format("azw3",  "application/vnd.amazon.mobi8-ebook", FormatKind::Ebook,     true),
+    format("mobi",  "application/x-mobipocket-ebook",     FormatKind::Ebook,     true),
+    format("azw",   "application/vnd.amazon.ebook",       FormatKind::Ebook,     true),
+    format("pdf",   "application/pdf",                    FormatKind::Document,  true),
+    format("cbz",   "application/vnd.comicbook+zip",      FormatKind::Comic,     true),
+    format("cbr",   "application/vnd.comicbook-rar",      FormatKind::Comic,     true),
+    format("cb7",   "application/x-cb7",                  FormatKind::Comic,     true),
+    format("fb2",   "application/x-fictionbook+xml",      FormatKind::Ebook,     true),
+    format("djvu",  "image/vnd.djvu",                     FormatKind::Document,  true),
+    format("docx",  "application/vnd.openxmlformats-officedocument.wordprocessingml.document", FormatKind::Document, true),
+    format("rtf",   "application/rtf",                    FormatKind::Document,  true),
+    format("txt",   "text/plain; charset=utf-8",          FormatKind::Document,  true),
+    format("m4b",   "audio/mp4",                          FormatKind::Audiobook, true),
+    format("mp3",   "audio/mpeg",                         FormatKind::Audiobook, true),
+    format("kfx",   "application/vnd.amazon.ebook",       FormatKind::Ebook,     false),
+    format("lit",   "application/x-ms-reader",            FormatKind::Ebook,     false),
+    format("htmlz", "application/zip",                    FormatKind::Archive,   false),
+    format("zip",   "application/zip",                    FormatKind::Archive,   false),
+];
+
+/// Looks up a format by Calibre name or file extension (case-insensitive).
+pub fn lookup(name: &str) -> Option<&'static BookFormat> {
+    FORMATS.iter().find(|f| f.name.eq_ignore_ascii_case(name))
+}
+
+/// Returns the MIME type for a format, falling back to `application/octet-stream`.
+pub fn mime_type(name: &str) -> &'static str {
+    lookup(name)
+        .map(|f| f.mime_type)
+        .unwrap_or("application/octet-stream")
+}
+
+/// Builds the ordered list of formats to look for when serving a download.
+///
+/// The requested format always comes first. It is followed by the formats the
+/// client says it can open (`accept`), or by every readable format in registry
+/// order when the client does not say.
+pub fn search_order(requested: &str, accept: Option<&[String]>) -> Vec<String> {
+    let mut order = vec![requested.to_lowercase()];
+    let fallbacks: Vec<String> = match accept {
+        Some(list) => list.iter().map(|f| f.trim().to_lowercase()).collect(),
+        None => FORMATS
+            .iter()
+            .filter(|f| f.readable)
+            .map(|f| f.name.to_string())
+            .collect(),
+    };
+
+    for f in fallbacks {
+        if !f.is_empty() && !order.contains(&f) {
+            order.push(f);
+        }
+    }
+    order
+}
+
+/// Checks that the first bytes of a file look like the given format.
+///
+/// This is a cheap guard against mislabelled or truncated uploads, not a full
+/// validation; `header` should hold at least the first 68 bytes of the file.
+pub fn sniff(name: &str, header: &[u8]) -> bool {
+    const ZIP: &[u8] = b"PK\x03\x04";
+
+    match name.to_lowercase().as_str() {
+        "epub" | "kepub" | "cbz" | "docx" | "htmlz" | "zip" => header.starts_with(ZIP),
+        "pdf" => header.starts_with(b"%PDF"),
+        "mobi" | "azw" | "azw3" => header.get(60..68) == Some(b"BOOKMOBI".as_slice()),
+        "kfx" => header.starts_with(b"CONT") || header.starts_with(ZIP),
+        "cbr" => header.starts_with(b"Rar!"),
+        "cb7" => header.starts_with(&[0x37, 0x7A, 0xBC, 0xAF, 0x27, 0x1C]),
+        "djvu" => header.starts_with(b"AT&TFORM"),
+        "rtf" => header.starts_with(b"{\\rtf"),
+        "lit" => header.starts_with(b"ITOLITLS"),
+        "m4b" => header.get(4..8) == Some(b"ftyp".as_slice()),
+        "mp3" => {
+            header.starts_with(b"ID3")
+                || (header.len() > 1 && header[0] == 0xFF && header[1] & 0xE0 == 0xE0)
+        }
+        "fb2" => header.contains(&b'<'),
+        "txt" => !header.contains(&0),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup_is_case_insensitive() {
+        assert_eq!(
+            lookup("AZW3").unwrap().mime_type,
+            "application/vnd.amazon.mobi8-ebook"
+        );
+        assert_eq!(mime_type("CBR"), "application/vnd.comicbook-rar");
+        assert_eq!(mime_type("unknown"), "application/octet-stream");
+    }
+
+    #[test]
+    fn test_search_order_defaults_to_readable_formats() {
+        let order = search_order("best", None);
+        assert_eq!(order[0], "best");
+        assert_eq!(order[1], "epub");
+        assert!(order.contains(&"m4b".to_string()));
+        assert!(!order.contains(&"kfx".to_string()));
+    }
+
+    #[test]
+    fn test_sniff() {
+        assert!(sniff("EPUB", b"PK\x03\x04rest-of-zip"));
+        assert!(sniff("pdf", b"%PDF-1.7"));
+        assert!(!sniff("pdf", b"PK\x03\x04"));
+
+        let mut mobi = vec![0u8; 68];
+        mobi[60..68].copy_from_slice(b"BOOKMOBI");
+        assert!(sniff("azw3", &mobi));
+        assert!(!sniff("mobi", b"short"));
+        assert!(!sniff("unknown", b"anything"));
+    }
+
+    #[test]
+    fn test_search_order_respects_accept_list() {
+        let accept = vec!["PDF".to_string(), "cbz".to_string()];
+        let order = search_order("epub", Some(&accept));
+        assert_eq!(order, vec!["epub", "pdf", "cbz"]);
+    }
+}
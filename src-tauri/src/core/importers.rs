@@ -0,0 +1,1223 @@
+use crate::core::progress::{self, ProgressUpdate, ReadingSession, ReadingStatus};
+use crate::core::{db, kosync};
+use crate::error::AppError;
+use crate::models::Book;
+use rusqlite::{Connection, OpenFlags, Transaction, TransactionBehavior};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+/// Page views further apart than this start a new KOReader reading session.
+const SESSION_GAP_SECS: i64 = 10 * 60;
+
+/// Where an imported record came from.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ImportSource {
+    KoreaderStatistics,
+    KoreaderSidecar,
+    Calibre,
+}
+
+impl ImportSource {
+    /// Device name recorded with imported progress and sessions.
+    fn device(&self) -> &'static str {
+        match self {
+            ImportSource::KoreaderStatistics | ImportSource::KoreaderSidecar => "KOReader (import)",
+            ImportSource::Calibre => "Calibre (import)",
+        }
+    }
+}
+
+/// How an imported record was matched to a library book, strongest first.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum MatchMethod {
+    /// KOReader's partial MD5 of the book file.
+    Md5,
+    /// A shared ISBN or other identifier, e.g. `isbn:9780441013593`.
+    Identifier,
+    /// Same Calibre book ID with the same title.
+    CalibreId,
+    TitleAuthor,
+    /// Title only; worth a second look before importing.
+    Title,
+}
+
+/// One book's worth of history found in an import source.
+///
+/// Previews return these with a suggested match; the user can change
+/// `book_id` or clear `include` before passing the plan to [`apply_plan`].
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct ImportItem {
+    pub source: ImportSource,
+    pub title: String,
+    pub authors: Option<String>,
+    /// KOReader partial MD5 of the book file, when known.
+    pub md5: Option<String>,
+    /// Identifiers such as ISBNs, normalized by [`identifier_key`].
+    #[serde(default)]
+    pub identifiers: Vec<String>,
+    pub book_id: Option<i64>,
+    pub matched_title: Option<String>,
+    pub method: Option<MatchMethod>,
+    pub include: bool,
+    pub status: Option<ReadingStatus>,
+    pub percentage: Option<f64>,
+    pub locator: Option<String>,
+    /// Unix timestamp of the last recorded activity.
+    pub last_read: Option<i64>,
+    pub sessions: Vec<ReadingSession>,
+    /// Number of highlights and notes the source holds for the book.
+    pub annotations: usize,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ImportPlan {
+    pub items: Vec<ImportItem>,
+}
+
+impl ImportPlan {
+    pub fn matched(&self) -> usize {
+        self.items.iter().filter(|i| i.book_id.is_some()).count()
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ImportReport {
+    pub progress_updated: usize,
+    pub sessions_imported: usize,
+    /// Sessions already present from an earlier import.
+    pub sessions_skipped: usize,
+    /// Items left out because they were unmatched, excluded, matched to a book
+    /// that is not in the library or held an out-of-range position.
+    pub items_skipped: usize,
+}
+
+/// Reads a KOReader `statistics.sqlite3` and proposes matches for each book.
+pub fn preview_koreader_statistics(
+    stats_path: &Path,
+    conn: &Connection,
+    library_path: &Path,
+    books: &[Book],
+) -> Result<ImportPlan, AppError> {
+    let stats = Connection::open_with_flags(stats_path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let mut items = Vec::new();
+
+    let mut book_stmt = stats.prepare("SELECT id, title, authors, md5, last_open FROM book")?;
+    let rows = book_stmt
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, Option<String>>(1)?,
+                row.get::<_, Option<String>>(2)?,
+                row.get::<_, Option<String>>(3)?,
+                row.get::<_, Option<i64>>(4)?,
+            ))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut page_stmt = stats.prepare(
+        "SELECT start_time, duration, page, total_pages FROM page_stat_data
+         WHERE id_book = ?1 ORDER BY start_time",
+    )?;
+
+    for (id, title, authors, md5, last_open) in rows {
+        let pages = page_stmt
+            .query_map([id], |row| {
+                Ok(PageView {
+                    start_time: row.get(0)?,
+                    duration: row.get(1)?,
+                    page: row.get(2)?,
+                    total_pages: row.get(3)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let sessions = group_sessions(&pages, ImportSource::KoreaderStatistics);
+        let last = pages.last();
+        let percentage = last
+            .filter(|p| p.total_pages > 0)
+            .map(|p| (p.page as f64 / p.total_pages as f64 * 100.0).min(100.0));
+        let status = match percentage {
+            Some(p) if p >= 100.0 => Some(ReadingStatus::Finished),
+            Some(_) => Some(ReadingStatus::Reading),
+            None => None,
+        };
+
+        items.push(ImportItem {
+            source: ImportSource::KoreaderStatistics,
+            title: title.unwrap_or_default(),
+            authors: authors.map(|a| a.replace('\n', ", ")),
+            md5,
+            identifiers: Vec::new(),
+            book_id: None,
+            matched_title: None,
+            method: None,
+            include: true,
+            status,
+            percentage,
+            locator: last.map(|p| p.page.to_string()),
+            last_read: last_open.or_else(|| last.map(|p| p.start_time + p.duration)),
+            sessions,
+            annotations: 0,
+        });
+    }
+
+    Ok(match_items(items, conn, library_path, books))
+}
+
+struct PageView {
+    start_time: i64,
+    duration: i64,
+    page: i64,
+    total_pages: i64,
+}
+
+/// Groups KOReader page views into sessions separated by [`SESSION_GAP_SECS`].
+fn group_sessions(pages: &[PageView], source: ImportSource) -> Vec<ReadingSession> {
+    let percent = |p: &PageView| {
+        (p.total_pages > 0).then(|| (p.page as f64 / p.total_pages as f64 * 100.0).min(100.0))
+    };
+
+    let mut sessions: Vec<ReadingSession> = Vec::new();
+    let mut pages_seen = std::collections::HashSet::new();
+    for view in pages {
+        let continues = sessions
+            .last()
+            .is_some_and(|s| view.start_time - s.ended_at <= SESSION_GAP_SECS);
+        if !continues {
+            pages_seen.clear();
+            sessions.push(ReadingSession {
+                device: Some(source.device().to_string()),
+                started_at: view.start_time,
+                ended_at: view.start_time,
+                start_percentage: percent(view),
+                pages_read: Some(0),
+                ..Default::default()
+            });
+        }
+
+        let session = sessions.last_mut().expect("session was just pushed");
+        session.ended_at = session.ended_at.max(view.start_time + view.duration);
+        session.end_percentage = percent(view);
+        if pages_seen.insert(view.page) {
+            session.pages_read = Some(pages_seen.len() as i64);
+        }
+    }
+    sessions
+}
+
+/// Finds KOReader sidecar files (`*.sdr/metadata.<ext>.lua`) under `root` and
+/// proposes matches for each book.
+pub fn preview_koreader_sidecars(
+    root: &Path,
+    conn: &Connection,
+    library_path: &Path,
+    books: &[Book],
+) -> Result<ImportPlan, AppError> {
+    let mut items = Vec::new();
+    for path in find_sidecars(root)? {
+        let source = std::fs::read_to_string(&path)?;
+        let table = match parse_lua_table(&source) {
+            Ok(table) => table,
+            Err(e) => {
+                log::warn!("Skipping unreadable KOReader sidecar {:?}: {}", path, e);
+                continue;
+            }
+        };
+        items.push(sidecar_item(&path, &table));
+    }
+    Ok(match_items(items, conn, library_path, books))
+}
+
+fn find_sidecars(root: &Path) -> Result<Vec<PathBuf>, AppError> {
+    let mut found = Vec::new();
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        for entry in std::fs::read_dir(&dir)?.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                pending.push(path);
+                continue;
+            }
+            let in_sdr = dir.extension().is_some_and(|ext| ext == "sdr");
+            let name = entry.file_name().to_string_lossy().to_string();
+            if in_sdr && name.starts_with("metadata.") && name.ends_with(".lua") {
+                found.push(path);
+            }
+        }
+    }
+    found.sort();
+    Ok(found)
+}
+
+fn sidecar_item(path: &Path, table: &serde_json::Value) -> ImportItem {
+    let text = |value: &serde_json::Value| value.as_str().map(|s| s.to_string());
+    let doc_props = &table["doc_props"];
+    let stats = &table["stats"];
+
+    // The book file name without `.sdr` is the best fallback title
+    let file_title = path
+        .parent()
+        .and_then(|p| p.file_stem())
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let title = text(&doc_props["title"])
+        .or_else(|| text(&stats["title"]))
+        .filter(|t| !t.is_empty())
+        .unwrap_or(file_title);
+    let authors = text(&doc_props["authors"])
+        .or_else(|| text(&stats["authors"]))
+        .map(|a| a.replace('\n', ", "));
+
+    let status = match table["summary"]["status"].as_str() {
+        Some("complete") => Some(ReadingStatus::Finished),
+        Some("abandoned") => Some(ReadingStatus::Abandoned),
+        Some(_) => Some(ReadingStatus::Reading),
+        None if table["percent_finished"].is_number() => Some(ReadingStatus::Reading),
+        None => None,
+    };
+
+    let modified = table["summary"]["modified"]
+        .as_str()
+        .and_then(|d| chrono::NaiveDate::parse_from_str(d, "%Y-%m-%d").ok())
+        .and_then(|d| d.and_hms_opt(0, 0, 0))
+        .map(|d| d.and_utc().timestamp());
+    let file_modified = std::fs::metadata(path)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|m| m.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|d| d.as_secs() as i64);
+
+    let annotations = table["annotations"]
+        .as_array()
+        .map(|a| a.len())
+        .or_else(|| table["highlight"].as_object().map(|h| h.len()))
+        .unwrap_or(0);
+
+    ImportItem {
+        source: ImportSource::KoreaderSidecar,
+        title,
+        authors,
+        md5: text(&table["partial_md5_checksum"]).or_else(|| text(&stats["md5"])),
+        // KOReader lists them one per line, e.g. `urn:isbn:9780441013593`
+        identifiers: text(&doc_props["identifiers"])
+            .map(|ids| ids.lines().filter_map(identifier_key).collect())
+            .unwrap_or_default(),
+        book_id: None,
+        matched_title: None,
+        method: None,
+        include: true,
+        status,
+        percentage: table["percent_finished"]
+            .as_f64()
+            .map(|p| (p * 100.0).clamp(0.0, 100.0)),
+        locator: text(&table["last_xpointer"])
+            .or_else(|| table["last_page"].as_i64().map(|p| p.to_string())),
+        last_read: modified.or(file_modified),
+        sessions: Vec::new(),
+        annotations,
+    }
+}
+
+/// Reads Calibre's viewer positions (`last_read_positions`) and annotations
+/// from a library's `metadata.db` and proposes matches for each book.
+pub fn preview_calibre(
+    source_library: &Path,
+    conn: &Connection,
+    library_path: &Path,
+    books: &[Book],
+) -> Result<ImportPlan, AppError> {
+    let calibre = Connection::open_with_flags(
+        source_library.join("metadata.db"),
+        OpenFlags::SQLITE_OPEN_READ_ONLY,
+    )?;
+
+    let mut titles = HashMap::new();
+    {
+        let mut stmt = calibre.prepare(
+            "SELECT b.id, b.title,
+                (SELECT GROUP_CONCAT(a.name, ', ') FROM books_authors_link bal JOIN authors a ON bal.author = a.id WHERE bal.book = b.id)
+             FROM books b",
+        )?;
+        for row in stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, Option<String>>(2)?,
+            ))
+        })? {
+            let (id, title, authors) = row?;
+            titles.insert(id, (title, authors));
+        }
+    }
+
+    let identifiers = read_identifiers(&calibre)?;
+
+    let mut items: HashMap<i64, ImportItem> = HashMap::new();
+    let new_item = |book: i64| {
+        let (title, authors) = titles.get(&book).cloned().unwrap_or_default();
+        ImportItem {
+            source: ImportSource::Calibre,
+            title,
+            authors,
+            md5: None,
+            identifiers: identifiers.get(&book).cloned().unwrap_or_default(),
+            // Provisional: kept only if the title matches, see match_items
+            book_id: Some(book),
+            matched_title: None,
+            method: None,
+            include: true,
+            status: None,
+            percentage: None,
+            locator: None,
+            last_read: None,
+            sessions: Vec::new(),
+            annotations: 0,
+        }
+    };
+
+    if has_table(&calibre, "last_read_positions")? {
+        let mut stmt =
+            calibre.prepare("SELECT book, cfi, epoch, pos_frac FROM last_read_positions")?;
+        for row in stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, Option<String>>(1)?,
+                row.get::<_, f64>(2)?,
+                row.get::<_, Option<f64>>(3)?,
+            ))
+        })? {
+            let (book, cfi, epoch, pos_frac) = row?;
+            let item = items.entry(book).or_insert_with(|| new_item(book));
+            let epoch = epoch as i64;
+            // Several formats/devices can have positions; keep the latest
+            if item.last_read.is_some_and(|last| last >= epoch) && item.percentage.is_some() {
+                continue;
+            }
+            let percentage = pos_frac.map(|p| (p * 100.0).clamp(0.0, 100.0));
+            item.percentage = percentage;
+            item.locator = cfi.map(|c| {
+                format!(
+                    "epubcfi({})",
+                    c.trim_start_matches("epubcfi(").trim_end_matches(')')
+                )
+            });
+            item.last_read = Some(epoch);
+            item.status = Some(if percentage.is_some_and(|p| p >= 99.0) {
+                ReadingStatus::Finished
+            } else {
+                ReadingStatus::Reading
+            });
+        }
+    }
+
+    if has_table(&calibre, "annotations")? {
+        let mut stmt = calibre.prepare("SELECT book, timestamp, annot_data FROM annotations")?;
+        for row in stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, f64>(1)?,
+                row.get::<_, String>(2)?,
+            ))
+        })? {
+            let (book, timestamp, data) = row?;
+            let removed = serde_json::from_str::<serde_json::Value>(&data)
+                .map(|v| v["removed"].as_bool() == Some(true))
+                .unwrap_or(false);
+            if removed {
+                continue;
+            }
+
+            let item = items.entry(book).or_insert_with(|| new_item(book));
+            item.annotations += 1;
+            let timestamp = timestamp as i64;
+            if item.last_read.is_none_or(|last| timestamp > last) {
+                item.last_read = Some(timestamp);
+            }
+            item.status.get_or_insert(ReadingStatus::Reading);
+        }
+    }
+
+    let mut items: Vec<ImportItem> = items.into_values().collect();
+    items.sort_by(|a, b| a.title.cmp(&b.title));
+    Ok(match_items(items, conn, library_path, books))
+}
+
+fn has_table(conn: &Connection, name: &str) -> Result<bool, AppError> {
+    Ok(conn.query_row(
+        "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1)",
+        [name],
+        |row| row.get(0),
+    )?)
+}
+
+/// Reads each book's identifiers from a Calibre `metadata.db`, normalized by
+/// [`identifier_key`].
+fn read_identifiers(calibre: &Connection) -> Result<HashMap<i64, Vec<String>>, AppError> {
+    let mut identifiers: HashMap<i64, Vec<String>> = HashMap::new();
+    if !has_table(calibre, "identifiers")? {
+        return Ok(identifiers);
+    }
+    let mut stmt = calibre.prepare("SELECT book, type, val FROM identifiers")?;
+    for row in stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, i64>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, String>(2)?,
+        ))
+    })? {
+        let (book, kind, value) = row?;
+        if let Some(key) = identifier_key(&format!("{}:{}", kind, value)) {
+            identifiers.entry(book).or_default().push(key);
+        }
+    }
+    Ok(identifiers)
+}
+
+/// Normalizes an identifier to `type:value`, e.g. `urn:ISBN:0-441-01359-7`
+/// and `isbn:9780441013593` both become `isbn:9780441013593`. A bare ISBN is
+/// recognized too.
+fn identifier_key(text: &str) -> Option<String> {
+    let text = text.trim();
+    let text = text
+        .get(..4)
+        .filter(|prefix| prefix.eq_ignore_ascii_case("urn:"))
+        .map_or(text, |_| &text[4..]);
+    let (kind, value) = match text.split_once(':') {
+        Some((kind, value)) => (kind.trim().to_lowercase(), value.trim()),
+        None => ("isbn".to_string(), text),
+    };
+    if kind == "isbn" {
+        return normalize_isbn(value).map(|isbn| format!("isbn:{}", isbn));
+    }
+    (!kind.is_empty() && !value.is_empty()).then(|| format!("{}:{}", kind, value.to_lowercase()))
+}
+
+/// The ISBN-13 form of an ISBN-10 or ISBN-13, ignoring hyphens and spaces.
+fn normalize_isbn(value: &str) -> Option<String> {
+    let isbn: String = value
+        .chars()
+        .filter(|c| !matches!(c, '-' | ' '))
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    let all_digits = |s: &str| s.chars().all(|c| c.is_ascii_digit());
+    match isbn.len() {
+        13 if all_digits(&isbn) => Some(isbn),
+        10 if all_digits(&isbn[..9]) && (all_digits(&isbn[9..]) || &isbn[9..] == "X") => {
+            let body = format!("978{}", &isbn[..9]);
+            let sum: u32 = body
+                .chars()
+                .enumerate()
+                .map(|(i, c)| c.to_digit(10).unwrap() * if i % 2 == 0 { 1 } else { 3 })
+                .sum();
+            Some(format!("{}{}", body, (10 - sum % 10) % 10))
+        }
+        _ => None,
+    }
+}
+
+/// Matches each item to a library book, trying the KOReader MD5, then
+/// identifiers, then the Calibre ID, then title and author, then title alone.
+fn match_items(
+    items: Vec<ImportItem>,
+    conn: &Connection,
+    library_path: &Path,
+    books: &[Book],
+) -> ImportPlan {
+    // Libraries without a readable metadata.db (e.g. in tests) just have no identifiers
+    let library_identifiers = db::open_metadata_db(&library_path.to_string_lossy())
+        .and_then(|calibre| read_identifiers(&calibre))
+        .unwrap_or_default();
+    let mut by_identifier: HashMap<&str, Vec<i64>> = HashMap::new();
+    for (book, keys) in &library_identifiers {
+        for key in keys {
+            by_identifier.entry(key).or_default().push(*book);
+        }
+    }
+
+    let items = items
+        .into_iter()
+        .map(|mut item| {
+            let found = match_book(&item, conn, library_path, books, &by_identifier);
+            item.book_id = found.map(|(book, _)| book.id);
+            item.matched_title = found.map(|(book, _)| book.title.clone());
+            item.method = found.map(|(_, method)| method);
+            // Weak matches are proposed but need to be opted into
+            item.include = matches!(
+                item.method,
+                Some(
+                    MatchMethod::Md5
+                        | MatchMethod::Identifier
+                        | MatchMethod::CalibreId
+                        | MatchMethod::TitleAuthor
+                )
+            );
+            item
+        })
+        .collect();
+    ImportPlan { items }
+}
+
+fn match_book<'a>(
+    item: &ImportItem,
+    conn: &Connection,
+    library_path: &Path,
+    books: &'a [Book],
+    by_identifier: &HashMap<&str, Vec<i64>>,
+) -> Option<(&'a Book, MatchMethod)> {
+    if let Some(md5) = &item.md5 {
+        match kosync::find_book(conn, library_path, books, md5) {
+            Ok(Some(id)) => {
+                if let Some(book) = books.iter().find(|b| b.id == id) {
+                    return Some((book, MatchMethod::Md5));
+                }
+            }
+            Ok(None) => {}
+            Err(e) => log::warn!("Could not match by MD5: {}", e),
+        }
+    }
+
+    // An identifier shared by several books (e.g. editions) is not trusted
+    for key in &item.identifiers {
+        if let Some([id]) = by_identifier.get(key.as_str()).map(Vec::as_slice) {
+            if let Some(book) = books.iter().find(|b| b.id == *id) {
+                return Some((book, MatchMethod::Identifier));
+            }
+        }
+    }
+
+    let title = normalize(&item.title);
+    if title.is_empty() {
+        return None;
+    }
+
+    if let Some(id) = item.book_id {
+        if let Some(book) = books
+            .iter()
+            .find(|b| b.id == id && normalize(&b.title) == title)
+        {
+            return Some((book, MatchMethod::CalibreId));
+        }
+    }
+
+    let same_title: Vec<&Book> = books
+        .iter()
+        .filter(|b| normalize(&b.title) == title)
+        .collect();
+    let source_authors = author_keys(item.authors.as_deref().unwrap_or(""));
+    if let Some(book) = same_title.iter().find(|b| {
+        author_keys(&b.authors)
+            .iter()
+            .any(|a| source_authors.contains(a))
+    }) {
+        return Some((book, MatchMethod::TitleAuthor));
+    }
+
+    // Only trust a bare title when it is unambiguous
+    match same_title.as_slice() {
+        [book] => Some((book, MatchMethod::Title)),
+        _ => None,
+    }
+}
+
+/// Lowercases and strips punctuation so "The Hobbit: Or There and Back Again"
+/// and "the hobbit or there and back again" compare equal.
+fn normalize(text: &str) -> String {
+    text.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Surnames of each author, tolerant of "Last, First" and "First Last" forms
+/// and of `,`, `&`, `;` and newline separators.
+fn author_keys(authors: &str) -> Vec<String> {
+    authors
+        .split(['&', ';', '\n'])
+        .flat_map(|part| {
+            // "Herbert, Frank" is one author; "Frank Herbert, Brian Herbert" is two
+            let pieces: Vec<&str> = part.split(',').map(str::trim).collect();
+            if pieces.len() == 2 && !pieces[0].contains(' ') && !pieces[1].contains(' ') {
+                vec![pieces[0].to_string()]
+            } else {
+                pieces
+                    .iter()
+                    .filter_map(|p| p.split_whitespace().last().map(|s| s.to_string()))
+                    .collect()
+            }
+        })
+        .map(|a| normalize(&a))
+        .filter(|a| !a.is_empty())
+        .collect()
+}
+
+/// Writes the included, matched items of a (possibly edited) plan into progress.
+///
+/// The plan comes back from the user, so items matched to a book that is not
+/// in `book_ids` or holding an invalid position are skipped. Everything is written
+/// in one `BEGIN IMMEDIATE` transaction, so a failed import leaves no trace.
+/// Imported positions carry their original timestamps, so they never override
+/// newer progress from a connected device.
+pub fn apply_plan(
+    conn: &Connection,
+    plan: &ImportPlan,
+    book_ids: &HashSet<i64>,
+) -> Result<ImportReport, AppError> {
+    let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+    let mut report = ImportReport::default();
+
+    for item in &plan.items {
+        let Some(book_id) = item
+            .book_id
+            .filter(|id| item.include && book_ids.contains(id))
+        else {
+            report.items_skipped += 1;
+            continue;
+        };
+        let device = item.source.device();
+        let update = item.status.map(|status| ProgressUpdate {
+            book_id,
+            status,
+            percentage: item.percentage,
+            locator: item.locator.clone(),
+            device: Some(device.to_string()),
+            updated_at: item.last_read,
+            ..Default::default()
+        });
+        if let Some(Err((field, message))) = update.as_ref().map(ProgressUpdate::validate) {
+            log::warn!(
+                "Skipping import of {:?}: invalid {}: {}",
+                item.title,
+                field,
+                message
+            );
+            report.items_skipped += 1;
+            continue;
+        }
+
+        if let Some(update) = &update {
+            progress::update_progress(&tx, update)?;
+            report.progress_updated += 1;
+        }
+
+        for session in &item.sessions {
+            let exists: bool = tx.query_row(
+                "SELECT EXISTS(SELECT 1 FROM reading_sessions WHERE book_id = ?1 AND device = ?2 AND started_at = ?3)",
+                rusqlite::params![book_id, device, session.started_at],
+                |row| row.get(0),
+            )?;
+            if exists {
+                report.sessions_skipped += 1;
+                continue;
+            }
+            progress::record_session(
+                &tx,
+                &ReadingSession {
+                    book_id,
+                    device: Some(device.to_string()),
+                    ..session.clone()
+                },
+            )?;
+            report.sessions_imported += 1;
+        }
+    }
+
+    tx.commit()?;
+    Ok(report)
+}
+
+/// Parses the Lua table returned by a KOReader sidecar (`return { ... }`) into
+/// JSON. Tables whose keys are all `1..n` become arrays; others become objects.
+pub fn parse_lua_table(source: &str) -> Result<serde_json::Value, AppError> {
+    let mut parser = LuaParser {
+        chars: source.chars().collect(),
+        pos: 0,
+    };
+    parser.skip_trivia();
+    if parser.source_starts_with("return") {
+        parser.pos += "return".len();
+    }
+    parser.value()
+}
+
+struct LuaParser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl LuaParser {
+    fn error(&self, message: &str) -> AppError {
+        AppError::Other(format!("Invalid Lua at {}: {}", self.pos, message))
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn source_starts_with(&self, text: &str) -> bool {
+        text.chars()
+            .enumerate()
+            .all(|(i, c)| self.chars.get(self.pos + i) == Some(&c))
+    }
+
+    fn skip_trivia(&mut self) {
+        loop {
+            while self.peek().is_some_and(char::is_whitespace) {
+                self.pos += 1;
+            }
+            if !self.source_starts_with("--") {
+                return;
+            }
+            self.pos += 2;
+            if self.source_starts_with("[[") {
+                while self.pos < self.chars.len() && !self.source_starts_with("]]") {
+                    self.pos += 1;
+                }
+                self.pos += 2;
+            } else {
+                while self.peek().is_some_and(|c| c != '\n') {
+                    self.pos += 1;
+                }
+            }
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), AppError> {
+        self.skip_trivia();
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected '{}'", c)))
+        }
+    }
+
+    fn value(&mut self) -> Result<serde_json::Value, AppError> {
+        self.skip_trivia();
+        match self.peek() {
+            Some('{') => self.table(),
+            Some('"') | Some('\'') => Ok(serde_json::Value::String(self.string()?)),
+            Some('[') if self.source_starts_with("[[") => {
+                Ok(serde_json::Value::String(self.long_string()?))
+            }
+            Some(c) if c == '-' || c == '.' || c.is_ascii_digit() => self.number(),
+            Some(c) if c.is_alphabetic() || c == '_' => match self.name().as_str() {
+                "true" => Ok(serde_json::Value::Bool(true)),
+                "false" => Ok(serde_json::Value::Bool(false)),
+                "nil" => Ok(serde_json::Value::Null),
+                other => Err(self.error(&format!("unexpected identifier {}", other))),
+            },
+            _ => Err(self.error("expected a value")),
+        }
+    }
+
+    fn name(&mut self) -> String {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_alphanumeric() || c == '_') {
+            self.pos += 1;
+        }
+        self.chars[start..self.pos].iter().collect()
+    }
+
+    fn number(&mut self) -> Result<serde_json::Value, AppError> {
+        let start = self.pos;
+        while self
+            .peek()
+            .is_some_and(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '+' | '.'))
+        {
+            self.pos += 1;
+        }
+        let text: String = self.chars[start..self.pos].iter().collect();
+        if let Ok(n) = text.parse::<i64>() {
+            return Ok(n.into());
+        }
+        text.parse::<f64>()
+            .ok()
+            .and_then(serde_json::Number::from_f64)
+            .map(serde_json::Value::Number)
+            .ok_or_else(|| self.error(&format!("invalid number {}", text)))
+    }
+
+    fn string(&mut self) -> Result<String, AppError> {
+        let quote = self.peek().ok_or_else(|| self.error("expected string"))?;
+        self.pos += 1;
+        let mut out = String::new();
+        loop {
+            let c = self
+                .peek()
+                .ok_or_else(|| self.error("unterminated string"))?;
+            self.pos += 1;
+            match c {
+                c if c == quote => return Ok(out),
+                '\\' => {
+                    let escaped = self.peek().ok_or_else(|| self.error("bad escape"))?;
+                    self.pos += 1;
+                    match escaped {
+                        'n' => out.push('\n'),
+                        't' => out.push('\t'),
+                        'r' => out.push('\r'),
+                        '\n' => out.push('\n'),
+                        d if d.is_ascii_digit() => {
+                            // \ddd decimal byte escape
+                            let mut code = d.to_digit(10).unwrap_or(0);
+                            for _ in 0..2 {
+                                match self.peek().and_then(|c| c.to_digit(10)) {
+                                    Some(digit) => {
+                                        code = code * 10 + digit;
+                                        self.pos += 1;
+                                    }
+                                    None => break,
+                                }
+                            }
+                            out.push(char::from_u32(code).unwrap_or('\u{FFFD}'));
+                        }
+                        other => out.push(other),
+                    }
+                }
+                c => out.push(c),
+            }
+        }
+    }
+
+    fn long_string(&mut self) -> Result<String, AppError> {
+        self.pos += 2;
+        let start = self.pos;
+        while self.pos < self.chars.len() && !self.source_starts_with("]]") {
+            self.pos += 1;
+        }
+        if self.pos >= self.chars.len() {
+            return Err(self.error("unterminated long string"));
+        }
+        let text = self.chars[start..self.pos].iter().collect();
+        self.pos += 2;
+        Ok(text)
+    }
+
+    fn table(&mut self) -> Result<serde_json::Value, AppError> {
+        self.expect('{')?;
+        let mut entries: Vec<(serde_json::Value, serde_json::Value)> = Vec::new();
+        let mut next_index = 1i64;
+
+        loop {
+            self.skip_trivia();
+            match self.peek() {
+                Some('}') => {
+                    self.pos += 1;
+                    break;
+                }
+                Some('[') if !self.source_starts_with("[[") => {
+                    self.pos += 1;
+                    let key = self.value()?;
+                    self.expect(']')?;
+                    self.expect('=')?;
+                    entries.push((key, self.value()?));
+                }
+                Some(c) if c.is_alphabetic() || c == '_' => {
+                    let start = self.pos;
+                    let name = self.name();
+                    self.skip_trivia();
+                    if self.peek() == Some('=') {
+                        self.pos += 1;
+                        entries.push((name.into(), self.value()?));
+                    } else {
+                        // A bare value such as `true` in a list
+                        self.pos = start;
+                        entries.push((next_index.into(), self.value()?));
+                        next_index += 1;
+                    }
+                }
+                Some(_) => {
+                    entries.push((next_index.into(), self.value()?));
+                    next_index += 1;
+                }
+                None => return Err(self.error("unterminated table")),
+            }
+
+            self.skip_trivia();
+            if matches!(self.peek(), Some(',') | Some(';')) {
+                self.pos += 1;
+            }
+        }
+
+        let is_array = entries
+            .iter()
+            .enumerate()
+            .all(|(i, (key, _))| key.as_i64() == Some(i as i64 + 1));
+        if is_array && !entries.is_empty() {
+            return Ok(serde_json::Value::Array(
+                entries.into_iter().map(|(_, v)| v).collect(),
+            ));
+        }
+
+        let map = entries
+            .into_iter()
+            .map(|(key, value)| {
+                let key = match key {
+                    serde_json::Value::String(s) => s,
+                    other => other.to_string(),
+                };
+                (key, value)
+            })
+            .collect();
+        Ok(serde_json::Value::Object(map))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    const SIDECAR: &str = r#"-- we can read Lua syntax here!
+return {
+    ["annotations"] = {
+        [1] = {
+            ["chapter"] = "Chapter 1",
+            ["text"] = "A \"quoted\" line",
+        },
+    },
+    ["doc_props"] = {
+        ["authors"] = "Frank Herbert",
+        ["title"] = "Dune",
+    },
+    ["last_xpointer"] = "/body/DocFragment[7]/body/p[12]/text().0",
+    ["partial_md5_checksum"] = "0123456789abcdef0123456789abcdef",
+    ["percent_finished"] = 0.4215,
+    ["summary"] = {
+        ["modified"] = "2024-03-02",
+        ["status"] = "reading",
+    },
+}
+"#;
+
+    fn library_book(id: i64, title: &str, authors: &str) -> Book {
+        Book {
+            authors: authors.to_string(),
+            ..Book::test(id, title)
+        }
+    }
+
+    fn progress_db(dir: &Path) -> Connection {
+        progress::open_progress_db(dir).unwrap()
+    }
+
+    #[test]
+    fn test_parse_lua_table() {
+        let table = parse_lua_table(SIDECAR).unwrap();
+        assert_eq!(table["doc_props"]["title"], "Dune");
+        assert_eq!(table["percent_finished"], 0.4215);
+        assert_eq!(table["annotations"][0]["text"], "A \"quoted\" line");
+        assert_eq!(
+            parse_lua_table("{ 1, 2, three = true, [5] = nil }").unwrap(),
+            serde_json::json!({ "1": 1, "2": 2, "three": true, "5": null })
+        );
+    }
+
+    #[test]
+    fn test_author_keys() {
+        assert_eq!(author_keys("Herbert, Frank"), vec!["herbert"]);
+        assert_eq!(
+            author_keys("Terry Pratchett & Neil Gaiman"),
+            vec!["pratchett", "gaiman"]
+        );
+        assert_eq!(
+            author_keys("Frank Herbert, Brian Herbert"),
+            vec!["herbert", "herbert"]
+        );
+    }
+
+    fn ids(books: &[Book]) -> HashSet<i64> {
+        books.iter().map(|b| b.id).collect()
+    }
+
+    #[test]
+    fn test_sidecar_import() {
+        let dir = tempdir().unwrap();
+        let sdr = dir.path().join("device/books/Dune.sdr");
+        std::fs::create_dir_all(&sdr).unwrap();
+        std::fs::write(sdr.join("metadata.epub.lua"), SIDECAR).unwrap();
+        std::fs::write(sdr.join("other.lua"), "ignored").unwrap();
+
+        let conn = progress_db(dir.path());
+        let books = vec![
+            library_book(1, "Dune", "Frank Herbert"),
+            library_book(2, "Dune Messiah", "Frank Herbert"),
+        ];
+        let plan = preview_koreader_sidecars(dir.path(), &conn, dir.path(), &books).unwrap();
+
+        assert_eq!(plan.items.len(), 1);
+        let item = &plan.items[0];
+        assert_eq!(item.book_id, Some(1));
+        assert_eq!(item.method, Some(MatchMethod::TitleAuthor));
+        assert!(item.include);
+        assert_eq!(item.percentage, Some(42.15));
+        assert_eq!(item.annotations, 1);
+        assert_eq!(item.last_read, Some(1_709_337_600));
+
+        let report = apply_plan(&conn, &plan, &ids(&books)).unwrap();
+        assert_eq!(report.progress_updated, 1);
+        let record = progress::get_progress(&conn, 1).unwrap().unwrap();
+        assert_eq!(record.status, ReadingStatus::Reading);
+        assert_eq!(
+            record.locator.as_deref(),
+            Some("/body/DocFragment[7]/body/p[12]/text().0")
+        );
+
+        // An edited plan cannot point at books outside the library nor carry
+        // out-of-range positions
+        let mut edited = plan.clone();
+        edited.items[0].book_id = Some(99);
+        edited.items.push(ImportItem {
+            book_id: Some(2),
+            percentage: Some(250.0),
+            ..plan.items[0].clone()
+        });
+        let report = apply_plan(&conn, &edited, &ids(&books)).unwrap();
+        assert_eq!(report.items_skipped, 2);
+        assert_eq!(report.progress_updated, 0);
+        assert!(progress::get_progress(&conn, 2).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_identifier_match_ranks_above_title_and_author() {
+        assert_eq!(
+            identifier_key("urn:ISBN:0-441-01359-7").as_deref(),
+            Some("isbn:9780441013593")
+        );
+        assert_eq!(
+            identifier_key("978-0-441-01359-3").as_deref(),
+            Some("isbn:9780441013593")
+        );
+        assert_eq!(identifier_key("isbn:123"), None);
+        assert_eq!(
+            identifier_key("Goodreads:234225").as_deref(),
+            Some("goodreads:234225")
+        );
+
+        let dir = tempdir().unwrap();
+        let sdr = dir.path().join("device/books/Dune.sdr");
+        std::fs::create_dir_all(&sdr).unwrap();
+        let sidecar = SIDECAR.replace(
+            r#"["title"] = "Dune","#,
+            r#"["title"] = "Dune",
+        ["identifiers"] = "calibre:1234\nurn:isbn:0-441-01359-7","#,
+        );
+        std::fs::write(sdr.join("metadata.epub.lua"), sidecar).unwrap();
+
+        let library = dir.path().join("library");
+        std::fs::create_dir_all(&library).unwrap();
+        Connection::open(library.join("metadata.db"))
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE identifiers (id INTEGER PRIMARY KEY, book INTEGER, type TEXT, val TEXT);
+                 INSERT INTO identifiers (book, type, val) VALUES (5, 'isbn', '9780441013593'), (1, 'amazon', 'B00B7NPRY8');",
+            )
+            .unwrap();
+
+        let conn = progress_db(dir.path());
+        let books = vec![
+            library_book(1, "Dune", "Frank Herbert"),
+            library_book(5, "Dune (40th Anniversary Edition)", "Herbert, Frank"),
+        ];
+        let plan =
+            preview_koreader_sidecars(&dir.path().join("device"), &conn, &library, &books).unwrap();
+
+        let item = &plan.items[0];
+        assert!(item.identifiers.contains(&"isbn:9780441013593".to_string()));
+        assert_eq!(item.book_id, Some(5));
+        assert_eq!(item.method, Some(MatchMethod::Identifier));
+        assert!(item.include);
+    }
+
+    #[test]
+    fn test_koreader_statistics_import() {
+        let dir = tempdir().unwrap();
+        let stats_path = dir.path().join("statistics.sqlite3");
+        {
+            let stats = Connection::open(&stats_path).unwrap();
+            stats
+                .execute_batch(
+                    "CREATE TABLE book (id INTEGER PRIMARY KEY, title TEXT, authors TEXT, md5 TEXT, last_open INTEGER);
+                     CREATE TABLE page_stat_data (id_book INTEGER, page INTEGER, start_time INTEGER, duration INTEGER, total_pages INTEGER);
+                     INSERT INTO book VALUES (1, 'Emma', 'Jane Austen', 'nomatch', 1700010000);
+                     INSERT INTO book VALUES (2, 'Unknown Book', 'Nobody', NULL, 1700000000);
+                     INSERT INTO page_stat_data VALUES
+                        (1, 1, 1700000000, 60, 100),
+                        (1, 2, 1700000060, 60, 100),
+                        (1, 3, 1700009000, 60, 100);",
+                )
+                .unwrap();
+        }
+
+        let conn = progress_db(dir.path());
+        let books = vec![library_book(7, "Emma", "Austen, Jane")];
+        let plan = preview_koreader_statistics(&stats_path, &conn, dir.path(), &books).unwrap();
+
+        assert_eq!(plan.items.len(), 2);
+        assert_eq!(plan.matched(), 1);
+        let emma = &plan.items[0];
+        assert_eq!(emma.book_id, Some(7));
+        assert_eq!(emma.sessions.len(), 2);
+        assert_eq!(emma.sessions[0].pages_read, Some(2));
+        assert_eq!(emma.sessions[0].ended_at, 1_700_000_120);
+        assert_eq!(emma.percentage, Some(3.0));
+
+        let report = apply_plan(&conn, &plan, &ids(&books)).unwrap();
+        assert_eq!(report.sessions_imported, 2);
+        assert_eq!(report.items_skipped, 1);
+
+        // Importing again does not duplicate sessions
+        let report = apply_plan(&conn, &plan, &ids(&books)).unwrap();
+        assert_eq!(report.sessions_imported, 0);
+        assert_eq!(report.sessions_skipped, 2);
+    }
+
+    #[test]
+    fn test_calibre_import() {
+        let dir = tempdir().unwrap();
+        let source = dir.path().join("old-library");
+        std::fs::create_dir_all(&source).unwrap();
+        {
+            let calibre = Connection::open(source.join("metadata.db")).unwrap();
+            calibre
+                .execute_batch(
+                    r#"CREATE TABLE books (id INTEGER PRIMARY KEY, title TEXT);
+                     CREATE TABLE authors (id INTEGER PRIMARY KEY, name TEXT);
+                     CREATE TABLE books_authors_link (id INTEGER PRIMARY KEY, book INTEGER, author INTEGER);
+                     CREATE TABLE last_read_positions (id INTEGER PRIMARY KEY, book INTEGER, format TEXT, user TEXT, device TEXT, cfi TEXT, epoch REAL, pos_frac REAL);
+                     CREATE TABLE annotations (id INTEGER PRIMARY KEY, book INTEGER, format TEXT, user_type TEXT, user TEXT, timestamp REAL, annot_id TEXT, annot_type TEXT, annot_data TEXT, searchable_text TEXT);
+                     INSERT INTO books VALUES (3, 'Persuasion'), (4, 'Middlemarch');
+                     INSERT INTO authors VALUES (1, 'Jane Austen'), (2, 'George Eliot');
+                     INSERT INTO books_authors_link (book, author) VALUES (3, 1), (4, 2);
+                     INSERT INTO last_read_positions (book, format, user, device, cfi, epoch, pos_frac)
+                        VALUES (3, 'EPUB', 'local', 'viewer', '/6/14!/4/2', 1700000000.5, 0.5);
+                     INSERT INTO annotations (book, format, user_type, user, timestamp, annot_id, annot_type, annot_data)
+                        VALUES (4, 'EPUB', 'local', 'viewer', 1700000100.0, 'a1', 'highlight', '{"highlighted_text": "x"}'),
+                               (4, 'EPUB', 'local', 'viewer', 1700000200.0, 'a2', 'highlight', '{"removed": true}');"#,
+                )
+                .unwrap();
+        }
+
+        let conn = progress_db(dir.path());
+        // Same IDs as the source library for Persuasion; Middlemarch matches by title
+        let books = vec![
+            library_book(3, "Persuasion", "Jane Austen"),
+            library_book(10, "Middlemarch", "George Eliot"),
+        ];
+        let plan = preview_calibre(&source, &conn, dir.path(), &books).unwrap();
+
+        assert_eq!(plan.items.len(), 2);
+        let middlemarch = &plan.items[0];
+        assert_eq!(middlemarch.book_id, Some(10));
+        assert_eq!(middlemarch.method, Some(MatchMethod::TitleAuthor));
+        assert_eq!(middlemarch.annotations, 1);
+        assert_eq!(middlemarch.last_read, Some(1_700_000_100));
+
+        let persuasion = &plan.items[1];
+        assert_eq!(persuasion.method, Some(MatchMethod::CalibreId));
+        assert_eq!(persuasion.percentage, Some(50.0));
+        assert_eq!(persuasion.locator.as_deref(), Some("epubcfi(/6/14!/4/2)"));
+    }
+}
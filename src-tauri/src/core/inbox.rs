@@ -0,0 +1,169 @@
+use crate::error::AppError;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// Largest upload accepted from a client (500 MiB, enough for long audiobooks).
+pub const MAX_UPLOAD_BYTES: u64 = 500 * 1024 * 1024;
+
+/// Name of the metadata file stored next to each staged upload.
+const ITEM_FILE: &str = "item.json";
+
+/// A book uploaded by a client and waiting for the host to import it.
+///
+/// Each item lives in `app_data_dir/inbox/{id}/` alongside an `item.json`
+/// holding this record. Directories without `item.json` are uploads that
+/// are still in progress (or were interrupted) and are not listed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InboxItem {
+    pub id: String,
+    pub file_name: String,
+    pub format: String,
+    pub size: u64,
+    pub title: Option<String>,
+    pub authors: Option<String>,
+    pub series: Option<String>,
+    pub series_index: Option<f64>,
+    pub tags: Vec<String>,
+    /// Unix timestamp of when the upload completed.
+    pub uploaded_at: i64,
+}
+
+pub fn inbox_dir(app_data_dir: &Path) -> PathBuf {
+    app_data_dir.join("inbox")
+}
+
+/// Returns the staging directory for an item, rejecting IDs that are not UUIDs
+/// so that a caller cannot escape the inbox with `..` or absolute paths.
+pub fn item_dir(app_data_dir: &Path, id: &str) -> Result<PathBuf, AppError> {
+    uuid::Uuid::parse_str(id)
+        .map_err(|_| AppError::Other(format!("Invalid inbox item: {}", id)))?;
+    Ok(inbox_dir(app_data_dir).join(id))
+}
+
+/// Marks an upload as complete by writing its `item.json`.
+pub fn save_item(app_data_dir: &Path, item: &InboxItem) -> Result<(), AppError> {
+    let json = serde_json::to_vec_pretty(item).map_err(|e| AppError::Other(e.to_string()))?;
+    std::fs::write(item_dir(app_data_dir, &item.id)?.join(ITEM_FILE), json)?;
+    Ok(())
+}
+
+/// Lists completed uploads, newest first.
+pub fn list_items(app_data_dir: &Path) -> Result<Vec<InboxItem>, AppError> {
+    let dir = inbox_dir(app_data_dir);
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut items = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let item_path = entry?.path().join(ITEM_FILE);
+        let Ok(content) = std::fs::read_to_string(&item_path) else {
+            continue;
+        };
+        match serde_json::from_str::<InboxItem>(&content) {
+            Ok(item) => items.push(item),
+            Err(e) => log::warn!("Skipping unreadable inbox item {:?}: {}", item_path, e),
+        }
+    }
+
+    items.sort_by_key(|item| std::cmp::Reverse(item.uploaded_at));
+    Ok(items)
+}
+
+/// Deletes a staged item and its file.
+pub fn remove_item(app_data_dir: &Path, id: &str) -> Result<(), AppError> {
+    let dir = item_dir(app_data_dir, id)?;
+    if dir.exists() {
+        std::fs::remove_dir_all(dir)?;
+    }
+    Ok(())
+}
+
+/// Moves a staged book into `destination` (typically Calibre's auto-add folder)
+/// and removes it from the inbox.
+///
+/// # Returns
+///
+/// Returns the path the book was moved to.
+pub fn move_item_to(
+    app_data_dir: &Path,
+    id: &str,
+    destination: &Path,
+) -> Result<PathBuf, AppError> {
+    let dir = item_dir(app_data_dir, id)?;
+    let content = std::fs::read_to_string(dir.join(ITEM_FILE))
+        .map_err(|_| AppError::Other(format!("Inbox item not found: {}", id)))?;
+    let item: InboxItem =
+        serde_json::from_str(&content).map_err(|e| AppError::Other(e.to_string()))?;
+
+    std::fs::create_dir_all(destination)?;
+    let source = dir.join(&item.file_name);
+    let mut target = destination.join(&item.file_name);
+    if target.exists() {
+        target = destination.join(format!("{}-{}", &item.id[..8], item.file_name));
+    }
+
+    // rename fails across filesystems, so fall back to copying
+    if std::fs::rename(&source, &target).is_err() {
+        std::fs::copy(&source, &target)?;
+    }
+    std::fs::remove_dir_all(dir)?;
+
+    Ok(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn stage(app_data_dir: &Path, uploaded_at: i64) -> InboxItem {
+        let item = InboxItem {
+            id: uuid::Uuid::new_v4().to_string(),
+            file_name: "book.epub".to_string(),
+            format: "epub".to_string(),
+            size: 4,
+            title: Some("Phone Find".to_string()),
+            authors: None,
+            series: None,
+            series_index: None,
+            tags: Vec::new(),
+            uploaded_at,
+        };
+        let dir = item_dir(app_data_dir, &item.id).unwrap();
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join(&item.file_name), "data").unwrap();
+        save_item(app_data_dir, &item).unwrap();
+        item
+    }
+
+    #[test]
+    fn test_list_and_move_items() {
+        let dir = tempdir().unwrap();
+        let older = stage(dir.path(), 100);
+        let newer = stage(dir.path(), 200);
+
+        // An interrupted upload has no item.json and is not listed
+        std::fs::create_dir_all(inbox_dir(dir.path()).join(uuid::Uuid::new_v4().to_string()))
+            .unwrap();
+
+        let items = list_items(dir.path()).unwrap();
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].id, newer.id);
+
+        let auto_add = dir.path().join("calibre-auto-add");
+        let first = move_item_to(dir.path(), &older.id, &auto_add).unwrap();
+        let second = move_item_to(dir.path(), &newer.id, &auto_add).unwrap();
+        assert_eq!(first, auto_add.join("book.epub"));
+        assert_ne!(first, second);
+        assert!(second.exists());
+        assert!(list_items(dir.path()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_item_dir_rejects_traversal() {
+        let dir = tempdir().unwrap();
+        assert!(item_dir(dir.path(), "../../etc").is_err());
+        assert!(remove_item(dir.path(), "..").is_err());
+    }
+}
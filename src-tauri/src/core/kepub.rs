@@ -0,0 +1,258 @@
+use crate::core::epub::{write_error, xml_error};
+use crate::error::AppError;
+use quick_xml::events::{BytesEnd, BytesStart, BytesText, Event};
+use quick_xml::{Reader, Writer};
+use std::fs::File;
+use std::io::{BufReader, Read, Write};
+use std::path::Path;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+/// Elements that start a new Kobo "paragraph" (the first number in `kobo.N.M`).
+const BLOCK_ELEMENTS: &[&[u8]] = &[
+    b"p",
+    b"h1",
+    b"h2",
+    b"h3",
+    b"h4",
+    b"h5",
+    b"h6",
+    b"li",
+    b"div",
+    b"blockquote",
+    b"dt",
+    b"dd",
+    b"td",
+    b"th",
+    b"figcaption",
+    b"pre",
+];
+
+/// Elements whose text must not be wrapped in spans.
+const SKIPPED_ELEMENTS: &[&[u8]] = &[b"script", b"style", b"svg", b"math", b"head"];
+
+/// Converts an EPUB at `source` into a Kobo EPUB (KEPUB) at `dest`.
+///
+/// Every XHTML content document gets its text wrapped in `koboSpan` elements,
+/// which is what Kobo firmware uses for reading statistics and page turns.
+/// Other entries are copied without recompression.
+pub fn convert_to_kepub(source: &Path, dest: &Path) -> Result<(), AppError> {
+    let mut archive = ZipArchive::new(BufReader::new(File::open(source)?))?;
+    let mut writer = ZipWriter::new(File::create(dest)?);
+
+    for i in 0..archive.len() {
+        let name = archive.by_index_raw(i)?.name().to_string();
+
+        if is_content_document(&name) {
+            let mut xhtml = String::new();
+            archive.by_index(i)?.read_to_string(&mut xhtml)?;
+
+            let options =
+                SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+            writer.start_file(name, options)?;
+            writer.write_all(add_kobo_spans(&xhtml)?.as_bytes())?;
+        } else {
+            writer.raw_copy_file(archive.by_index_raw(i)?)?;
+        }
+    }
+    writer.finish()?;
+
+    Ok(())
+}
+
+fn is_content_document(name: &str) -> bool {
+    let lower = name.to_lowercase();
+    lower.ends_with(".xhtml") || lower.ends_with(".html") || lower.ends_with(".htm")
+}
+
+/// Wraps each sentence of body text in `<span class="koboSpan" id="kobo.N.M">`,
+/// where `N` counts block elements and `M` counts sentences within the block,
+/// and wraps the body content in the `book-columns`/`book-inner` divs Kobo expects.
+///
+/// Documents that already contain `koboSpan` markup are returned unchanged.
+pub fn add_kobo_spans(xhtml: &str) -> Result<String, AppError> {
+    if xhtml.contains("koboSpan") {
+        return Ok(xhtml.to_string());
+    }
+
+    let mut reader = Reader::from_str(xhtml);
+    let mut writer = Writer::new(Vec::new());
+    let mut in_body = false;
+    let mut skip_depth = 0usize;
+    let mut paragraph = 0u32;
+    let mut sentence = 0u32;
+
+    loop {
+        let event = reader.read_event().map_err(xml_error)?;
+        match event {
+            Event::Start(ref e) if e.local_name().as_ref() == b"body" => {
+                in_body = true;
+                writer.write_event(event).map_err(write_error)?;
+                write_div_start(&mut writer, "book-columns")?;
+                write_div_start(&mut writer, "book-inner")?;
+            }
+            Event::End(ref e) if e.local_name().as_ref() == b"body" => {
+                in_body = false;
+                for _ in 0..2 {
+                    writer
+                        .write_event(Event::End(BytesEnd::new("div")))
+                        .map_err(write_error)?;
+                }
+                writer.write_event(event).map_err(write_error)?;
+            }
+            Event::Start(ref e) => {
+                let name = e.local_name();
+                if skip_depth > 0 || SKIPPED_ELEMENTS.contains(&name.as_ref()) {
+                    skip_depth += 1;
+                } else if BLOCK_ELEMENTS.contains(&name.as_ref()) {
+                    paragraph += 1;
+                    sentence = 0;
+                }
+                writer.write_event(event).map_err(write_error)?;
+            }
+            Event::End(_) => {
+                skip_depth = skip_depth.saturating_sub(1);
+                writer.write_event(event).map_err(write_error)?;
+            }
+            Event::Text(ref text) if in_body && skip_depth == 0 => {
+                let raw =
+                    std::str::from_utf8(text).map_err(|e| AppError::InvalidEpub(e.to_string()))?;
+                if raw.trim().is_empty() {
+                    writer.write_event(event).map_err(write_error)?;
+                    continue;
+                }
+
+                // Text directly inside <body> before any block element
+                if paragraph == 0 {
+                    paragraph = 1;
+                }
+
+                for segment in split_sentences(raw) {
+                    if segment.trim().is_empty() {
+                        writer
+                            .write_event(Event::Text(BytesText::from_escaped(segment)))
+                            .map_err(write_error)?;
+                        continue;
+                    }
+
+                    sentence += 1;
+                    let mut span = BytesStart::new("span");
+                    span.push_attribute(("class", "koboSpan"));
+                    span.push_attribute((
+                        "id",
+                        format!("kobo.{}.{}", paragraph, sentence).as_str(),
+                    ));
+                    writer
+                        .write_event(Event::Start(span))
+                        .map_err(write_error)?;
+                    writer
+                        .write_event(Event::Text(BytesText::from_escaped(segment)))
+                        .map_err(write_error)?;
+                    writer
+                        .write_event(Event::End(BytesEnd::new("span")))
+                        .map_err(write_error)?;
+                }
+            }
+            Event::Eof => break,
+            _ => writer.write_event(event).map_err(write_error)?,
+        }
+    }
+
+    String::from_utf8(writer.into_inner()).map_err(|e| AppError::InvalidEpub(e.to_string()))
+}
+
+/// Splits text after sentence-ending punctuation, keeping the trailing whitespace
+/// with the sentence it follows so the output re-joins to the original text.
+fn split_sentences(text: &str) -> Vec<&str> {
+    let mut segments = Vec::new();
+    let mut start = 0;
+    let mut chars = text.char_indices().peekable();
+
+    while let Some((_, c)) = chars.next() {
+        if matches!(c, '.' | '!' | '?' | '…') {
+            // Swallow closing quotes/brackets and the whitespace that follows
+            while let Some(&(_, next)) = chars.peek() {
+                if matches!(next, '"' | '\'' | '”' | '’' | ')' | ']') {
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+
+            let mut saw_space = false;
+            while let Some(&(_, next)) = chars.peek() {
+                if next.is_whitespace() {
+                    saw_space = true;
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+
+            if saw_space {
+                let end = chars.peek().map(|&(i, _)| i).unwrap_or(text.len());
+                segments.push(&text[start..end]);
+                start = end;
+            }
+        }
+    }
+
+    if start < text.len() {
+        segments.push(&text[start..]);
+    }
+    segments
+}
+
+fn write_div_start(writer: &mut Writer<Vec<u8>>, id: &str) -> Result<(), AppError> {
+    let mut div = BytesStart::new("div");
+    div.push_attribute(("id", id));
+    writer.write_event(Event::Start(div)).map_err(write_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::epub;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_split_sentences() {
+        assert_eq!(
+            split_sentences("One. Two! \"Three?\" Four"),
+            vec!["One. ", "Two! ", "\"Three?\" ", "Four"]
+        );
+        assert_eq!(split_sentences("v1.2 is out"), vec!["v1.2 is out"]);
+    }
+
+    #[test]
+    fn test_add_kobo_spans() {
+        let xhtml = r#"<html><head><title>Skip me.</title></head><body><p>Hello there. General &amp; Co.</p><p><em>Bold</em> move</p></body></html>"#;
+        let out = add_kobo_spans(xhtml).unwrap();
+
+        assert!(out.contains("<title>Skip me.</title>"));
+        assert!(out.contains(r#"<span class="koboSpan" id="kobo.1.1">Hello there. </span>"#));
+        assert!(out.contains(r#"<span class="koboSpan" id="kobo.1.2">General &amp; Co.</span>"#));
+        assert!(out.contains(r#"<em><span class="koboSpan" id="kobo.2.1">Bold</span></em>"#));
+        assert!(out.contains(r#"<span class="koboSpan" id="kobo.2.2"> move</span>"#));
+        assert!(out.contains(r#"<body><div id="book-columns"><div id="book-inner"><p>"#));
+        assert!(out.contains("</p></div></div></body>"));
+
+        // Running the conversion twice must not double-wrap
+        assert_eq!(add_kobo_spans(&out).unwrap(), out);
+    }
+
+    #[test]
+    fn test_convert_to_kepub() {
+        let dir = tempdir().unwrap();
+        let source = dir.path().join("book.epub");
+        let dest = dir.path().join("book.kepub.epub");
+        epub::tests::write_test_epub(&source);
+
+        convert_to_kepub(&source, &dest).unwrap();
+
+        let mut archive = ZipArchive::new(File::open(&dest).unwrap()).unwrap();
+        assert_eq!(archive.by_index(0).unwrap().name(), "mimetype");
+        let chapter = epub::read_entry_to_string(&mut archive, "OEBPS/chapter1.xhtml").unwrap();
+        assert!(chapter.contains(r#"id="kobo.1.1""#));
+    }
+}
@@ -0,0 +1,534 @@
+use crate::core::formats;
+use crate::core::migrations;
+use crate::core::progress::{self, ProgressUpdate, ReadingStatus};
+use crate::error::AppError;
+use crate::models::Book;
+use log::warn;
+use md5::{Digest, Md5};
+use rusqlite::{Connection, OptionalExtension};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// Position stored for a KOReader document, in the shape the kosync protocol uses.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct KosyncProgress {
+    pub document: String,
+    /// XPointer for reflowable documents (`/body/DocFragment[3]/body/p[5]/text().0`)
+    /// or the page number for fixed-layout ones.
+    pub progress: String,
+    /// Fraction read, from 0.0 to 1.0.
+    pub percentage: f64,
+    pub device: String,
+    pub device_id: String,
+    pub timestamp: i64,
+}
+
+/// Creates the kosync tables in `progress.db` if they are missing.
+///
+/// Applied as a `progress.db` migration by [`progress::open_progress_db`].
+pub fn ensure_schema(conn: &Connection) -> Result<(), AppError> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS kosync_users (
+            username TEXT PRIMARY KEY,
+            userkey TEXT NOT NULL,
+            created_at INTEGER NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS kosync_progress (
+            username TEXT NOT NULL,
+            document TEXT NOT NULL,
+            progress TEXT NOT NULL,
+            percentage REAL NOT NULL,
+            device TEXT NOT NULL,
+            device_id TEXT NOT NULL,
+            timestamp INTEGER NOT NULL,
+            PRIMARY KEY (username, document)
+        );
+        CREATE TABLE IF NOT EXISTS kosync_documents (
+            path TEXT PRIMARY KEY,
+            book_id INTEGER NOT NULL,
+            size INTEGER NOT NULL,
+            modified INTEGER NOT NULL,
+            partial_md5 TEXT NOT NULL,
+            filename_md5 TEXT NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS kosync_documents_partial ON kosync_documents (partial_md5);
+        CREATE INDEX IF NOT EXISTS kosync_documents_filename ON kosync_documents (filename_md5);",
+    )?;
+    Ok(())
+}
+
+/// How long a document that matched no book is remembered. Files the library
+/// cannot match (e.g. downloads with embedded metadata or converted to KEPUB)
+/// would otherwise re-index every book file on each sync from KOReader.
+pub const MISS_TTL_SECS: i64 = 10 * 60;
+
+/// Creates the table of recently unmatched documents if it is missing.
+pub fn ensure_miss_schema(conn: &Connection) -> Result<(), AppError> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS kosync_misses (
+            document TEXT PRIMARY KEY,
+            checked_at INTEGER NOT NULL
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+pub fn md5_hex(data: &[u8]) -> String {
+    format!("{:x}", Md5::digest(data))
+}
+
+/// Computes KOReader's "binary" document fingerprint.
+///
+/// KOReader hashes 1 KiB samples taken at offsets 0 and `1024 << 2i` for
+/// `i` in `0..=10`, stopping at the end of the file, rather than the whole file.
+pub fn partial_md5(path: &Path) -> Result<String, AppError> {
+    let mut file = File::open(path)?;
+    let mut hasher = Md5::new();
+    let mut buffer = [0u8; 1024];
+
+    for i in -1i32..=10 {
+        let offset = if i < 0 { 0 } else { 1024u64 << (2 * i) };
+        file.seek(SeekFrom::Start(offset))?;
+        let read = read_sample(&mut file, &mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn read_sample(file: &mut File, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut total = 0;
+    while total < buffer.len() {
+        match file.read(&mut buffer[total..])? {
+            0 => break,
+            n => total += n,
+        }
+    }
+    Ok(total)
+}
+
+/// Adds the paired device to KOReader accounts, so syncing is limited to
+/// what the device may see and do.
+///
+/// Applied as a `progress.db` migration by [`progress::open_progress_db`].
+/// Accounts registered before have no device and are refused until they
+/// register again.
+pub fn ensure_device_schema(conn: &Connection) -> Result<(), AppError> {
+    migrations::ensure_column(conn, "kosync_users", "device_token", "TEXT")
+}
+
+/// Registers a KOReader user, paired as the device with bearer token
+/// `device_token`. Returns `false` if the username is taken by another
+/// paired account.
+pub fn create_user(
+    conn: &Connection,
+    username: &str,
+    userkey: &str,
+    device_token: &str,
+) -> Result<bool, AppError> {
+    let inserted = conn.execute(
+        "INSERT INTO kosync_users (username, userkey, created_at, device_token) VALUES (?1, ?2, ?3, ?4)
+         ON CONFLICT (username) DO UPDATE SET userkey = ?2, created_at = ?3, device_token = ?4
+         WHERE device_token IS NULL",
+        rusqlite::params![username, userkey, now(), device_token],
+    )?;
+    Ok(inserted == 1)
+}
+
+/// Removes a KOReader account, if it is still the one paired under `device_token`.
+pub fn remove_user(conn: &Connection, username: &str, device_token: &str) -> Result<(), AppError> {
+    conn.execute(
+        "DELETE FROM kosync_users WHERE username = ?1 AND device_token = ?2",
+        [username, device_token],
+    )?;
+    Ok(())
+}
+
+/// Checks a KOReader user's key.
+///
+/// # Returns
+///
+/// Returns the bearer token of the device the user is paired as, or `None`
+/// if the key is wrong or the account has no device.
+pub fn authenticate(
+    conn: &Connection,
+    username: &str,
+    userkey: &str,
+) -> Result<Option<String>, AppError> {
+    let stored: Option<(String, Option<String>)> = conn
+        .query_row(
+            "SELECT userkey, device_token FROM kosync_users WHERE username = ?1",
+            [username],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()?;
+    Ok(stored
+        .filter(|(key, _)| key == userkey)
+        .and_then(|(_, device_token)| device_token))
+}
+
+/// Finds the Calibre book a KOReader document hash refers to, refreshing the
+/// hash index for the library when the hash is not known yet.
+///
+/// Both KOReader matching methods are supported: the partial MD5 of the file
+/// contents and the MD5 of the file name. Documents that match nothing are not
+/// looked for again for [`MISS_TTL_SECS`].
+pub fn find_book(
+    conn: &Connection,
+    library_path: &Path,
+    books: &[Book],
+    document: &str,
+) -> Result<Option<i64>, AppError> {
+    if let Some(book_id) = lookup_document(conn, document)? {
+        return Ok(Some(book_id));
+    }
+    let now = now();
+    let recently_missed: bool = conn.query_row(
+        "SELECT EXISTS(SELECT 1 FROM kosync_misses WHERE document = ?1 AND checked_at > ?2)",
+        rusqlite::params![document, now - MISS_TTL_SECS],
+        |row| row.get(0),
+    )?;
+    if recently_missed {
+        return Ok(None);
+    }
+
+    index_library(conn, library_path, books)?;
+    let found = lookup_document(conn, document)?;
+    if found.is_none() {
+        conn.execute(
+            "INSERT OR REPLACE INTO kosync_misses (document, checked_at) VALUES (?1, ?2)",
+            rusqlite::params![document, now],
+        )?;
+    }
+    Ok(found)
+}
+
+fn lookup_document(conn: &Connection, document: &str) -> Result<Option<i64>, AppError> {
+    Ok(conn
+        .query_row(
+            "SELECT book_id FROM kosync_documents WHERE partial_md5 = ?1 OR filename_md5 = ?1 LIMIT 1",
+            [document],
+            |row| row.get(0),
+        )
+        .optional()?)
+}
+
+/// Hashes every book file in the library, skipping files whose size and
+/// modification time match the cached entry.
+pub fn index_library(
+    conn: &Connection,
+    library_path: &Path,
+    books: &[Book],
+) -> Result<(), AppError> {
+    for book in books {
+        let Ok(entries) = std::fs::read_dir(library_path.join(&book.path)) else {
+            continue;
+        };
+
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let is_book_file = path
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .is_some_and(|ext| formats::lookup(ext).is_some());
+            if !is_book_file {
+                continue;
+            }
+
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+            let size = metadata.len() as i64;
+            let modified = metadata
+                .modified()
+                .ok()
+                .and_then(|m| m.duration_since(std::time::UNIX_EPOCH).ok())
+                .map(|d| d.as_secs() as i64)
+                .unwrap_or(0);
+            let key = path.to_string_lossy().to_string();
+
+            let unchanged: bool = conn.query_row(
+                "SELECT EXISTS(SELECT 1 FROM kosync_documents WHERE path = ?1 AND size = ?2 AND modified = ?3 AND book_id = ?4)",
+                rusqlite::params![key, size, modified, book.id],
+                |row| row.get(0),
+            )?;
+            if unchanged {
+                continue;
+            }
+
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            conn.execute(
+                "INSERT OR REPLACE INTO kosync_documents (path, book_id, size, modified, partial_md5, filename_md5)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                rusqlite::params![
+                    key,
+                    book.id,
+                    size,
+                    modified,
+                    partial_md5(&path)?,
+                    md5_hex(file_name.as_bytes()),
+                ],
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Stores a KOReader position and, when the document is a known book, mirrors it
+/// into the shared progress so other clients see it.
+///
+/// # Returns
+///
+/// Returns the timestamp recorded for the update.
+pub fn save_progress(
+    conn: &Connection,
+    username: &str,
+    update: &KosyncProgress,
+    book_id: Option<i64>,
+) -> Result<i64, AppError> {
+    let timestamp = now();
+    conn.execute(
+        "INSERT OR REPLACE INTO kosync_progress (username, document, progress, percentage, device, device_id, timestamp)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        rusqlite::params![
+            username,
+            update.document,
+            update.progress,
+            update.percentage,
+            update.device,
+            update.device_id,
+            timestamp,
+        ],
+    )?;
+
+    if let Some(book_id) = book_id {
+        let status = if update.percentage >= 1.0 {
+            ReadingStatus::Finished
+        } else {
+            ReadingStatus::Reading
+        };
+        let mirrored = ProgressUpdate {
+            book_id,
+            status,
+            percentage: Some(update.percentage * 100.0),
+            locator: Some(update.progress.clone()),
+            // KOReader reports pages of paginated documents as the position
+            page: update.progress.parse().ok(),
+            device: Some(update.device.clone()),
+            updated_at: Some(timestamp),
+            ..Default::default()
+        };
+        // Checked like any other client's report; a position the shared progress
+        // would refuse is kept for KOReader only
+        match mirrored.validate() {
+            Ok(()) => {
+                progress::update_progress(conn, &mirrored)?;
+            }
+            Err((field, message)) => {
+                warn!(
+                    "Not sharing KOReader progress for book {} ({}): {}",
+                    book_id, field, message
+                )
+            }
+        }
+    }
+
+    Ok(timestamp)
+}
+
+/// Returns the position KOReader should jump to for a document.
+///
+/// The user's own KOReader record is compared with the merged progress for the
+/// matching book; the newer one wins as long as KOReader can navigate to it.
+pub fn get_progress(
+    conn: &Connection,
+    username: &str,
+    document: &str,
+    book_id: Option<i64>,
+) -> Result<Option<KosyncProgress>, AppError> {
+    let own: Option<KosyncProgress> = conn
+        .query_row(
+            "SELECT document, progress, percentage, device, device_id, timestamp
+             FROM kosync_progress WHERE username = ?1 AND document = ?2",
+            [username, document],
+            |row| {
+                Ok(KosyncProgress {
+                    document: row.get(0)?,
+                    progress: row.get(1)?,
+                    percentage: row.get(2)?,
+                    device: row.get(3)?,
+                    device_id: row.get(4)?,
+                    timestamp: row.get(5)?,
+                })
+            },
+        )
+        .optional()?;
+
+    let Some(book_id) = book_id else {
+        return Ok(own);
+    };
+    let Some(record) = progress::get_progress(conn, book_id)? else {
+        return Ok(own);
+    };
+    if own
+        .as_ref()
+        .is_some_and(|own| own.timestamp >= record.last_updated)
+    {
+        return Ok(own);
+    }
+
+    // Only positions KOReader understands: XPointers and page numbers
+    let position = match (&record.locator, record.page) {
+        (Some(locator), _) if locator.starts_with('/') => Some(locator.clone()),
+        (_, Some(page)) => Some(page.to_string()),
+        _ => None,
+    };
+    let Some(position) = position else {
+        return Ok(own);
+    };
+
+    let device = record
+        .device
+        .unwrap_or_else(|| progress::UNKNOWN_DEVICE.to_string());
+    Ok(Some(KosyncProgress {
+        document: document.to_string(),
+        progress: position,
+        percentage: record.percentage.unwrap_or(0.0) / 100.0,
+        device_id: device.clone(),
+        device,
+        timestamp: record.last_updated,
+    }))
+}
+
+fn now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn book(id: i64, path: &str) -> Book {
+        Book {
+            path: path.to_string(),
+            ..Book::test(id, "Dune")
+        }
+    }
+
+    #[test]
+    fn test_partial_md5_matches_koreader() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("small.epub");
+
+        // Files smaller than 1 KiB are hashed in full by the first sample
+        std::fs::write(&path, b"hello kosync").unwrap();
+        assert_eq!(partial_md5(&path).unwrap(), md5_hex(b"hello kosync"));
+
+        // Larger files are sampled at 0, 1024, 4096, ...
+        let data: Vec<u8> = (0..5000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+        let mut expected = Vec::new();
+        expected.extend_from_slice(&data[0..1024]);
+        expected.extend_from_slice(&data[1024..2048]);
+        expected.extend_from_slice(&data[4096..5000]);
+        assert_eq!(partial_md5(&path).unwrap(), md5_hex(&expected));
+    }
+
+    #[test]
+    fn test_sync_round_trip_through_book_progress() {
+        let dir = tempdir().unwrap();
+        let library = dir.path().join("library");
+        std::fs::create_dir_all(library.join("herbert/dune")).unwrap();
+        std::fs::write(library.join("herbert/dune/Dune.epub"), "dune contents").unwrap();
+        let books = vec![book(1, "herbert/dune")];
+
+        let conn = progress::open_progress_db(dir.path()).unwrap();
+
+        assert!(create_user(&conn, "reader", "key", "token").unwrap());
+        assert!(!create_user(&conn, "reader", "other", "other-token").unwrap());
+        assert_eq!(
+            authenticate(&conn, "reader", "key").unwrap().as_deref(),
+            Some("token")
+        );
+        assert_eq!(authenticate(&conn, "reader", "wrong").unwrap(), None);
+
+        // Accounts from before they were paired as devices can be claimed again
+        conn.execute(
+            "INSERT INTO kosync_users (username, userkey, created_at) VALUES ('legacy', 'key', 0)",
+            [],
+        )
+        .unwrap();
+        assert_eq!(authenticate(&conn, "legacy", "key").unwrap(), None);
+        assert!(create_user(&conn, "legacy", "new", "legacy-token").unwrap());
+        assert_eq!(
+            authenticate(&conn, "legacy", "new").unwrap().as_deref(),
+            Some("legacy-token")
+        );
+
+        // Removing an account frees its username, but only for its own device
+        remove_user(&conn, "legacy", "other-token").unwrap();
+        assert!(!create_user(&conn, "legacy", "again", "again-token").unwrap());
+        remove_user(&conn, "legacy", "legacy-token").unwrap();
+        assert!(create_user(&conn, "legacy", "again", "again-token").unwrap());
+
+        let document = md5_hex(b"dune contents");
+        assert_eq!(
+            find_book(&conn, &library, &books, &document).unwrap(),
+            Some(1)
+        );
+        let by_name = md5_hex(b"Dune.epub");
+        assert_eq!(
+            find_book(&conn, &library, &books, &by_name).unwrap(),
+            Some(1)
+        );
+        assert_eq!(find_book(&conn, &library, &books, "unknown").unwrap(), None);
+
+        // A miss is remembered, so a file added since is only found once it expires
+        let pdf = md5_hex(b"pdf contents");
+        assert_eq!(find_book(&conn, &library, &books, &pdf).unwrap(), None);
+        std::fs::write(library.join("herbert/dune/Dune.pdf"), "pdf contents").unwrap();
+        assert_eq!(find_book(&conn, &library, &books, &pdf).unwrap(), None);
+        conn.execute("UPDATE kosync_misses SET checked_at = 0", [])
+            .unwrap();
+        assert_eq!(find_book(&conn, &library, &books, &pdf).unwrap(), Some(1));
+
+        let update = KosyncProgress {
+            document: document.clone(),
+            progress: "/body/DocFragment[12]/body/p[3]/text().0".to_string(),
+            percentage: 0.25,
+            device: "Kindle".to_string(),
+            device_id: "K1".to_string(),
+            timestamp: 0,
+        };
+        save_progress(&conn, "reader", &update, Some(1)).unwrap();
+
+        let record = progress::get_progress(&conn, 1).unwrap().unwrap();
+        assert_eq!(record.percentage, Some(25.0));
+        assert_eq!(record.device.as_deref(), Some("Kindle"));
+
+        let fetched = get_progress(&conn, "reader", &document, Some(1))
+            .unwrap()
+            .unwrap();
+        assert_eq!(fetched.progress, update.progress);
+
+        // A position the shared progress would refuse is only kept for KOReader
+        let invalid = KosyncProgress {
+            progress: "0".to_string(),
+            percentage: 1.5,
+            ..update
+        };
+        save_progress(&conn, "reader", &invalid, Some(1)).unwrap();
+        let record = progress::get_progress(&conn, 1).unwrap().unwrap();
+        assert_eq!(record.percentage, Some(25.0));
+        assert_eq!(record.page, None);
+    }
+}
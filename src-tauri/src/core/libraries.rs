@@ -0,0 +1,251 @@
+use crate::core::pool::DbPool;
+use crate::core::search::SearchIndex;
+use crate::core::{annotations, db, discovery, progress};
+use crate::error::AppError;
+use crate::models::Book;
+use log::error;
+use rusqlite::Connection;
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// ID of the library picked with "Select Library". It is also served on the
+/// original un-namespaced `/api/...` routes, for clients from before hosts
+/// served several libraries.
+pub const DEFAULT_LIBRARY_ID: &str = "default";
+
+/// File in the app data dir listing the libraries registered besides the
+/// default one.
+const LIBRARIES_FILE: &str = "libraries.json";
+
+/// A registered library, as saved in `libraries.json`.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct LibraryConfig {
+    pub id: String,
+    pub name: String,
+    pub path: String,
+}
+
+/// Summary of a library, as listed by `GET /api/libraries`.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct LibraryInfo {
+    pub id: String,
+    pub name: String,
+    pub book_count: usize,
+    /// Same as the advertised `library_revision`, but for this library.
+    pub revision: String,
+    /// Whether this is the library served on the un-namespaced routes.
+    pub default: bool,
+    /// How device scopes refer to this library (see [`Library::scope_id`]).
+    #[serde(default)]
+    pub scope_id: String,
+}
+
+/// A Calibre library served by this host.
+///
+/// Book IDs are only unique within one library, so each library keeps its
+/// own progress and annotation databases and caches, in
+/// `app_data_dir/libraries/{id}` for registered libraries and in a folder
+/// named after the path for the default one (see [`Library::open_default`]).
+pub struct Library {
+    pub id: String,
+    /// How device scopes refer to this library: its ID, except for the
+    /// default library, whose ID stays the same when another library is
+    /// selected. That one is referred to by its path instead, so a device
+    /// granted it keeps that library rather than whichever is selected.
+    pub scope_id: String,
+    pub name: String,
+    pub path: String,
+    pub data_dir: PathBuf,
+    /// In-memory cache of book metadata.
+    pub books: Mutex<Vec<Book>>,
+    /// Virtual libraries, saved searches and custom column values.
+    pub search_index: Mutex<Arc<SearchIndex>>,
+    /// Read-only connection to the library's `metadata.db`, reused on reloads.
+    metadata_db: Mutex<Option<Connection>>,
+    pub progress_db: Arc<DbPool>,
+    pub annotations_db: Arc<DbPool>,
+}
+
+impl Library {
+    /// Opens a registered library and reads its books.
+    ///
+    /// A library that cannot be read (e.g. on an unplugged drive) is still
+    /// opened, with no books, so it stays registered.
+    pub fn open(config: &LibraryConfig, app_data_dir: &Path) -> Result<Library, AppError> {
+        let library = Library::new(
+            config,
+            config.id.clone(),
+            app_data_dir.join("libraries").join(&config.id),
+        )?;
+        if let Err(e) = library.reload() {
+            error!(
+                "Failed to read library {} at {}: {}",
+                library.id, library.path, e
+            );
+        }
+        Ok(library)
+    }
+
+    /// Opens the library picked with "Select Library" and reads its books,
+    /// failing if it cannot be read.
+    ///
+    /// Its data is kept by path, so picking another library starts afresh
+    /// and picking this one again finds its progress and annotations. The
+    /// databases kept directly in `app_data_dir` before that belong to
+    /// whichever library is picked first, and are moved there.
+    pub fn open_default(path: &str, app_data_dir: &Path) -> Result<Library, AppError> {
+        let conn = db::open_metadata_db(path)?;
+        let digest = format!("{:x}", Sha256::digest(path.as_bytes()));
+        let scope_id = format!("{}-{}", DEFAULT_LIBRARY_ID, &digest[..16]);
+        let data_dir = app_data_dir.join("libraries").join(&scope_id);
+        if !data_dir.exists() {
+            std::fs::create_dir_all(&data_dir)?;
+            adopt_legacy_databases(app_data_dir, &data_dir)?;
+        }
+        let config = LibraryConfig {
+            id: DEFAULT_LIBRARY_ID.to_string(),
+            name: folder_name(path),
+            path: path.to_string(),
+        };
+        let library = Library::new(&config, scope_id, data_dir)?;
+        *library
+            .metadata_db
+            .lock()
+            .map_err(|_| AppError::Unknown("Failed to lock library database".to_string()))? =
+            Some(conn);
+        library.reload()?;
+        Ok(library)
+    }
+
+    fn new(
+        config: &LibraryConfig,
+        scope_id: String,
+        data_dir: PathBuf,
+    ) -> Result<Library, AppError> {
+        std::fs::create_dir_all(&data_dir)?;
+        let progress_dir = data_dir.clone();
+        let annotations_dir = data_dir.clone();
+        let library = Library {
+            id: config.id.clone(),
+            scope_id,
+            name: config.name.clone(),
+            path: config.path.clone(),
+            data_dir,
+            books: Mutex::new(Vec::new()),
+            search_index: Mutex::new(Arc::default()),
+            metadata_db: Mutex::new(None),
+            progress_db: DbPool::new(move || progress::open_progress_db(&progress_dir)),
+            annotations_db: DbPool::new(move || annotations::open_annotations_db(&annotations_dir)),
+        };
+        // Runs pending migrations now, rather than failing the first request
+        for pool in [&library.progress_db, &library.annotations_db] {
+            if let Err(e) = pool.get() {
+                error!(
+                    "Failed to open the databases of library {}: {}",
+                    library.id, e
+                );
+            }
+        }
+        Ok(library)
+    }
+
+    /// Re-reads the books from `metadata.db`. The connection is kept between
+    /// reloads, and reopened after a failed read.
+    pub fn reload(&self) -> Result<Vec<Book>, AppError> {
+        let mut metadata_db = self
+            .metadata_db
+            .lock()
+            .map_err(|_| AppError::Unknown("Failed to lock library database".to_string()))?;
+        let conn = match metadata_db.take() {
+            Some(conn) => conn,
+            None => db::open_metadata_db(&self.path)?,
+        };
+        let books = db::read_books(&conn)?;
+        let index = db::read_search_index(&conn).unwrap_or_else(|e| {
+            error!("Failed to read searches of library {}: {}", self.id, e);
+            SearchIndex::default()
+        });
+        *metadata_db = Some(conn);
+        *self.books()? = books.clone();
+        if let Ok(mut search_index) = self.search_index.lock() {
+            *search_index = Arc::new(index);
+        }
+        Ok(books)
+    }
+
+    pub fn books(&self) -> Result<MutexGuard<'_, Vec<Book>>, AppError> {
+        self.books
+            .lock()
+            .map_err(|_| AppError::Unknown("Failed to lock books cache".to_string()))
+    }
+
+    pub fn config(&self) -> LibraryConfig {
+        LibraryConfig {
+            id: self.id.clone(),
+            name: self.name.clone(),
+            path: self.path.clone(),
+        }
+    }
+
+    pub fn info(&self) -> LibraryInfo {
+        let (book_count, revision) = match self.books.lock() {
+            Ok(books) => (books.len(), discovery::library_revision(&books)),
+            Err(_) => (0, discovery::library_revision(&[])),
+        };
+        LibraryInfo {
+            id: self.id.clone(),
+            name: self.name.clone(),
+            book_count,
+            revision,
+            default: self.id == DEFAULT_LIBRARY_ID,
+            scope_id: self.scope_id.clone(),
+        }
+    }
+}
+
+/// Moves `progress.db` and `annotations.db`, with their WAL files, from
+/// `app_data_dir` into `data_dir`.
+fn adopt_legacy_databases(app_data_dir: &Path, data_dir: &Path) -> Result<(), AppError> {
+    for name in ["progress.db", "annotations.db"] {
+        for suffix in ["", "-wal", "-shm"] {
+            let file = format!("{}{}", name, suffix);
+            let legacy = app_data_dir.join(&file);
+            if legacy.exists() {
+                std::fs::rename(&legacy, data_dir.join(&file))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Display name for a library: its folder name.
+pub fn folder_name(path: &str) -> String {
+    Path::new(path)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| path.to_string())
+}
+
+/// A short random ID for a newly registered library.
+pub fn new_library_id() -> String {
+    uuid::Uuid::new_v4().simple().to_string()[..8].to_string()
+}
+
+/// Reads the registered libraries; none if the file is missing or unreadable.
+pub fn load_configs(app_data_dir: &Path) -> Vec<LibraryConfig> {
+    let path = app_data_dir.join(LIBRARIES_FILE);
+    let Ok(content) = std::fs::read_to_string(&path) else {
+        return Vec::new();
+    };
+    serde_json::from_str(&content).unwrap_or_else(|e| {
+        error!("Ignoring malformed {:?}: {}", path, e);
+        Vec::new()
+    })
+}
+
+pub fn save_configs(app_data_dir: &Path, configs: &[LibraryConfig]) -> Result<(), AppError> {
+    let json = serde_json::to_string_pretty(configs).map_err(|e| AppError::Other(e.to_string()))?;
+    std::fs::write(app_data_dir.join(LIBRARIES_FILE), json)?;
+    Ok(())
+}
@@ -0,0 +1,190 @@
+use crate::core::discovery::{self, Advertise, DiscoveryState, DiscoveryUpdate, MdnsState, Notify};
+use log::{error, info, warn};
+use mdns_sd::{ServiceDaemon, ServiceEvent};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+/// How often the host checks whether its interface addresses changed.
+const INTERFACE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How often addresses are re-checked while waiting to retry, so a network
+/// change (e.g. joining Wi-Fi) retries straight away.
+const RETRY_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+const MIN_BACKOFF: Duration = Duration::from_secs(2);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// A run lasting this long counts as healthy and resets the backoff.
+const STABLE_RUN: Duration = Duration::from_secs(120);
+
+/// Delay before retry number `attempt` (from 0): doubling from
+/// `MIN_BACKOFF` up to `MAX_BACKOFF`.
+pub fn backoff(attempt: u32) -> Duration {
+    MIN_BACKOFF
+        .saturating_mul(2u32.saturating_pow(attempt.min(16)))
+        .min(MAX_BACKOFF)
+}
+
+/// Announces this host and browses for others over mDNS, restarting the
+/// responder with backoff whenever it fails instead of giving up.
+///
+/// Runs forever. Status changes are recorded in `discovery` and reported
+/// through `notify`, as are hosts appearing and disappearing.
+pub async fn supervise(
+    discovery: Arc<DiscoveryState>,
+    advertise: Advertise,
+    mut changes: watch::Receiver<()>,
+    port: u16,
+    notify: Notify,
+) {
+    let mut attempt = 0;
+    loop {
+        let started = Instant::now();
+        let error = match run(&discovery, &advertise, &mut changes, port, &notify).await {
+            Ok(()) => "mDNS responder stopped".to_string(),
+            Err(e) => e.to_string(),
+        };
+        if started.elapsed() >= STABLE_RUN {
+            attempt = 0;
+        }
+        let delay = backoff(attempt);
+        attempt = attempt.saturating_add(1);
+
+        error!("mDNS discovery failed ({}), retrying in {:?}", error, delay);
+        if discovery.update_status(|status| {
+            status.mdns = MdnsState::Failed;
+            status.error = Some(error);
+            status.retry_in_secs = Some(delay.as_secs());
+        }) {
+            notify(DiscoveryUpdate::Status);
+        }
+        wait_for_retry(delay).await;
+
+        if discovery.update_status(|status| {
+            status.mdns = MdnsState::Starting;
+            status.retry_in_secs = None;
+        }) {
+            notify(DiscoveryUpdate::Status);
+        }
+    }
+}
+
+/// Sleeps for `delay`, or less if the machine's addresses change meanwhile.
+async fn wait_for_retry(delay: Duration) {
+    let addresses = discovery::local_addresses();
+    let deadline = tokio::time::Instant::now() + delay;
+    loop {
+        let now = tokio::time::Instant::now();
+        if now >= deadline {
+            return;
+        }
+        tokio::time::sleep((deadline - now).min(RETRY_CHECK_INTERVAL)).await;
+        if discovery::local_addresses() != addresses {
+            info!("Network addresses changed, retrying mDNS now");
+            return;
+        }
+    }
+}
+
+/// One run of the responder, from start until it fails.
+async fn run(
+    discovery: &DiscoveryState,
+    advertise: &Advertise,
+    changes: &mut watch::Receiver<()>,
+    port: u16,
+    notify: &Notify,
+) -> Result<(), mdns_sd::Error> {
+    let mdns = ServiceDaemon::new()?;
+    let result = announce_and_browse(&mdns, discovery, advertise, changes, port, notify).await;
+    if let Err(e) = mdns.shutdown() {
+        warn!("Failed to shut down mDNS daemon: {}", e);
+    }
+    result
+}
+
+async fn announce_and_browse(
+    mdns: &ServiceDaemon,
+    discovery: &DiscoveryState,
+    advertise: &Advertise,
+    changes: &mut watch::Receiver<()>,
+    port: u16,
+    notify: &Notify,
+) -> Result<(), mdns_sd::Error> {
+    let machine_name = hostname::get()
+        .map(|h| h.to_string_lossy().to_string())
+        .unwrap_or_else(|_| "ShelfSync-Host".to_string());
+    let register = |addresses: &[IpAddr]| {
+        mdns.register(discovery::service_info(
+            &machine_name,
+            addresses,
+            port,
+            &advertise(),
+        )?)
+    };
+
+    let mut addresses = discovery::local_addresses();
+    register(&addresses)?;
+    changes.mark_unchanged();
+
+    // Interfaces come and go (Wi-Fi roaming, VPNs), so their addresses are re-checked
+    let mut interfaces = tokio::time::interval(INTERFACE_CHECK_INTERVAL);
+    interfaces.tick().await;
+
+    let receiver = mdns.browse(discovery::SERVICE_TYPE)?;
+    if discovery.update_status(|status| {
+        status.mdns = MdnsState::Running;
+        status.error = None;
+        status.retry_in_secs = None;
+    }) {
+        notify(DiscoveryUpdate::Status);
+    }
+
+    loop {
+        let event = tokio::select! {
+            event = receiver.recv_async() => match event {
+                Ok(event) => event,
+                Err(_) => return Ok(()),
+            },
+            Ok(()) = changes.changed() => {
+                register(&addresses)?;
+                continue;
+            }
+            _ = interfaces.tick() => {
+                let current = discovery::local_addresses();
+                if current != addresses {
+                    info!("Network addresses changed: {:?}", current);
+                    addresses = current;
+                    register(&addresses)?;
+                }
+                continue;
+            }
+        };
+
+        let updated = match event {
+            ServiceEvent::ServiceResolved(info) => {
+                discovery.resolved(discovery::connection_info(&info))
+            }
+            ServiceEvent::ServiceRemoved(_type, name) => discovery.removed(&name),
+            _ => false,
+        };
+        if updated {
+            notify(DiscoveryUpdate::Hosts);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_doubles_up_to_cap() {
+        assert_eq!(backoff(0), Duration::from_secs(2));
+        assert_eq!(backoff(1), Duration::from_secs(4));
+        assert_eq!(backoff(4), Duration::from_secs(32));
+        assert_eq!(backoff(8), MAX_BACKOFF);
+        assert_eq!(backoff(u32::MAX), MAX_BACKOFF);
+    }
+}
@@ -0,0 +1,195 @@
+use crate::error::AppError;
+use rusqlite::Connection;
+
+/// One step in the schema history of a ShelfSync-owned database.
+///
+/// Versions start at 1 and increase by one. The database's `PRAGMA user_version`
+/// records the last step applied, so each step runs exactly once.
+pub struct Migration {
+    pub version: i64,
+    pub description: &'static str,
+    pub apply: fn(&Connection) -> Result<(), AppError>,
+}
+
+pub fn user_version(conn: &Connection) -> Result<i64, AppError> {
+    Ok(conn.query_row("PRAGMA user_version", [], |row| row.get(0))?)
+}
+
+/// Brings `conn` up to the latest version in `migrations`.
+///
+/// Pending steps run in a single write transaction, so a failing step leaves
+/// the database at its previous version, and two processes opening the same
+/// file never apply a step twice. Databases written by a newer ShelfSync are
+/// refused rather than modified.
+///
+/// # Returns
+///
+/// Returns the version the database is at afterwards.
+pub fn migrate(conn: &Connection, name: &str, migrations: &[Migration]) -> Result<i64, AppError> {
+    debug_assert!(
+        migrations
+            .iter()
+            .enumerate()
+            .all(|(i, m)| m.version == i as i64 + 1),
+        "migrations for {} must be numbered 1, 2, 3, ...",
+        name
+    );
+    let latest = migrations.last().map_or(0, |m| m.version);
+
+    // Cheap check so the common, up-to-date case takes no write lock
+    let current = user_version(conn)?;
+    if current == latest {
+        return Ok(current);
+    }
+    if current > latest {
+        return Err(newer_version(name, current, latest));
+    }
+
+    conn.execute_batch("BEGIN IMMEDIATE")?;
+    match apply_pending(conn, name, migrations, latest) {
+        Ok(version) => {
+            conn.execute_batch("COMMIT")?;
+            Ok(version)
+        }
+        Err(e) => {
+            conn.execute_batch("ROLLBACK").ok();
+            Err(e)
+        }
+    }
+}
+
+fn apply_pending(
+    conn: &Connection,
+    name: &str,
+    migrations: &[Migration],
+    latest: i64,
+) -> Result<i64, AppError> {
+    // Re-read under the lock in case another connection migrated meanwhile
+    let current = user_version(conn)?;
+    if current > latest {
+        return Err(newer_version(name, current, latest));
+    }
+
+    for migration in migrations.iter().filter(|m| m.version > current) {
+        log::info!(
+            "Migrating {} to version {}: {}",
+            name,
+            migration.version,
+            migration.description
+        );
+        (migration.apply)(conn).map_err(|e| {
+            AppError::Other(format!(
+                "Migrating {} to version {} failed: {}",
+                name, migration.version, e
+            ))
+        })?;
+        // PRAGMA does not take bound parameters
+        conn.execute_batch(&format!("PRAGMA user_version = {}", migration.version))?;
+    }
+    Ok(latest)
+}
+
+fn newer_version(name: &str, current: i64, latest: i64) -> AppError {
+    AppError::Other(format!(
+        "{} is at schema version {}, but this version of ShelfSync only knows up to {}; please update ShelfSync",
+        name, current, latest
+    ))
+}
+
+/// Adds a column unless it already exists.
+///
+/// Databases created before versioning all report version 0 whatever columns
+/// they have, so the early migrations use this to stay safe to re-run.
+pub fn ensure_column(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<(), AppError> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let exists = stmt
+        .query_map([], |row| row.get::<_, String>(1))?
+        .collect::<Result<Vec<_>, _>>()?
+        .iter()
+        .any(|name| name == column);
+
+    if !exists {
+        conn.execute(
+            &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition),
+            [],
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    const MIGRATIONS: &[Migration] = &[
+        Migration {
+            version: 1,
+            description: "create items",
+            apply: |conn| {
+                conn.execute("CREATE TABLE items (id INTEGER PRIMARY KEY)", [])?;
+                Ok(())
+            },
+        },
+        Migration {
+            version: 2,
+            description: "add name",
+            apply: |conn| ensure_column(conn, "items", "name", "TEXT"),
+        },
+    ];
+
+    #[test]
+    fn test_applies_pending_steps_once() {
+        let dir = tempdir().unwrap();
+        let conn = Connection::open(dir.path().join("test.db")).unwrap();
+
+        assert_eq!(migrate(&conn, "test.db", &MIGRATIONS[..1]).unwrap(), 1);
+        conn.execute("INSERT INTO items (id) VALUES (1)", [])
+            .unwrap();
+        assert_eq!(migrate(&conn, "test.db", MIGRATIONS).unwrap(), 2);
+        assert_eq!(migrate(&conn, "test.db", MIGRATIONS).unwrap(), 2);
+        conn.execute("UPDATE items SET name = 'kept' WHERE id = 1", [])
+            .unwrap();
+
+        // A database from a newer release is left alone
+        let err = migrate(&conn, "test.db", &MIGRATIONS[..1]).unwrap_err();
+        assert!(err.to_string().contains("schema version 2"));
+    }
+
+    #[test]
+    fn test_failed_step_rolls_back() {
+        let dir = tempdir().unwrap();
+        let conn = Connection::open(dir.path().join("test.db")).unwrap();
+        let broken = [
+            Migration {
+                version: 1,
+                description: "create items",
+                apply: MIGRATIONS[0].apply,
+            },
+            Migration {
+                version: 2,
+                description: "broken",
+                apply: |conn| {
+                    conn.execute("ALTER TABLE missing ADD COLUMN x TEXT", [])?;
+                    Ok(())
+                },
+            },
+        ];
+
+        assert!(migrate(&conn, "test.db", &broken).is_err());
+        assert_eq!(user_version(&conn).unwrap(), 0);
+        let tables: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM sqlite_master WHERE name = 'items'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(tables, 0);
+    }
+}
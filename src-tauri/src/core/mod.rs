@@ -1,3 +1,23 @@
+pub mod annotations;
+pub mod beacon;
+pub mod bundle;
 pub mod db;
+pub mod devices;
+pub mod discovery;
+pub mod epub;
+pub mod formats;
+pub mod importers;
+pub mod inbox;
+pub mod kepub;
+pub mod kosync;
+pub mod libraries;
+pub mod mdns;
+pub mod migrations;
+pub mod pairing;
+pub mod pool;
 pub mod progress;
+pub mod rules;
+pub mod search;
 pub mod sync;
+pub mod writeback;
+pub mod zipstream;
@@ -0,0 +1,394 @@
+use crate::core::discovery::{self, Probe};
+use crate::error::AppError;
+use crate::models::ConnectionInfo;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use qrcode::QrCode;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Start of every pairing URI; the query string follows.
+pub const URI_PREFIX: &str = "shelfsync://pair?";
+
+/// How long a pairing code stays usable after it is created.
+pub const PAIRING_TTL_SECS: i64 = 300;
+
+/// Smallest side of a rendered QR code, in pixels.
+const QR_MIN_SIZE: u32 = 256;
+
+const PAIR_TIMEOUT: Duration = Duration::from_secs(10);
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// What a pairing URI tells a client: where the host is, and a one-time
+/// secret that stands in for the PIN.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct PairingPayload {
+    pub host_id: String,
+    pub addresses: Vec<String>,
+    pub port: u16,
+    pub tls_fingerprint: Option<String>,
+    pub secret: String,
+    /// Unix timestamp after which the host refuses the secret.
+    pub expires_at: i64,
+}
+
+impl PairingPayload {
+    /// The signed part of the URI, with fields in a fixed order so the host
+    /// can recompute it.
+    fn query(&self) -> String {
+        let mut query = format!(
+            "v=1&id={}&addr={}&port={}",
+            self.host_id,
+            self.addresses.join(","),
+            self.port
+        );
+        if let Some(tls) = &self.tls_fingerprint {
+            query.push_str(&format!("&tls={}", tls));
+        }
+        query.push_str(&format!("&secret={}&exp={}", self.secret, self.expires_at));
+        query
+    }
+
+    /// The host as discovery would list it, before any probe.
+    pub fn connection_info(&self) -> ConnectionInfo {
+        ConnectionInfo {
+            ip: self.addresses.first().cloned().unwrap_or_default(),
+            addresses: self.addresses.clone(),
+            port: self.port,
+            hostname: discovery::url_authority(
+                self.addresses
+                    .first()
+                    .map(String::as_str)
+                    .unwrap_or_default(),
+                self.port,
+            ),
+            compatible: true,
+            advertisement: discovery::HostAdvertisement {
+                host_id: Some(self.host_id.clone()),
+                tls_fingerprint: self.tls_fingerprint.clone(),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+}
+
+/// Splits a pairing URI into its payload and signature, without checking
+/// the signature (only the host that issued it can).
+pub fn parse_uri(uri: &str) -> Result<(PairingPayload, Vec<u8>), AppError> {
+    let invalid = |reason: &str| AppError::Other(format!("Invalid pairing code: {}", reason));
+    let query = uri
+        .trim()
+        .strip_prefix(URI_PREFIX)
+        .ok_or_else(|| invalid("not a ShelfSync pairing link"))?;
+    let (signed, signature) = query
+        .rsplit_once("&sig=")
+        .ok_or_else(|| invalid("missing signature"))?;
+    let signature = URL_SAFE_NO_PAD
+        .decode(signature)
+        .map_err(|_| invalid("malformed signature"))?;
+
+    let mut fields = HashMap::new();
+    for pair in signed.split('&') {
+        let (key, value) = pair
+            .split_once('=')
+            .ok_or_else(|| invalid("malformed field"))?;
+        // Values are never escaped, so anything unusual is tampering or a typo
+        if !value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.:,".contains(c))
+        {
+            return Err(invalid(&format!("bad value for '{}'", key)));
+        }
+        fields.insert(key, value);
+    }
+    let field = |key: &str| {
+        fields
+            .get(key)
+            .copied()
+            .ok_or_else(|| invalid(&format!("missing '{}'", key)))
+    };
+    if field("v")? != "1" {
+        return Err(invalid("made by an unsupported version of ShelfSync"));
+    }
+
+    let payload = PairingPayload {
+        host_id: field("id")?.to_string(),
+        addresses: field("addr")?
+            .split(',')
+            .filter(|a| !a.is_empty())
+            .map(str::to_string)
+            .collect(),
+        port: field("port")?.parse().map_err(|_| invalid("bad port"))?,
+        tls_fingerprint: fields.get("tls").map(|tls| tls.to_string()),
+        secret: field("secret")?.to_string(),
+        expires_at: field("exp")?.parse().map_err(|_| invalid("bad expiry"))?,
+    };
+    if payload.addresses.is_empty() {
+        return Err(invalid("no addresses"));
+    }
+    Ok((payload, signature))
+}
+
+/// A pairing URI with its QR code, as shown by the host.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct PairingCode {
+    pub uri: String,
+    pub expires_at: i64,
+    /// The URI as a QR code, in SVG.
+    pub svg: String,
+}
+
+/// Body of `POST /api/pair`.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct PairRequest {
+    pub uri: String,
+    /// Name of the device pairing, shown to the host's user.
+    #[serde(default)]
+    pub device: Option<String>,
+}
+
+/// Outcome of pairing with a URI.
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct Paired {
+    /// The host, as probed.
+    pub host: ConnectionInfo,
+    /// Bearer token the host issued.
+    pub token: String,
+}
+
+/// Pairing URIs issued by this host, each good for a single device.
+///
+/// The signing key lives only in memory, so codes issued before a restart
+/// stop working; they expire within minutes anyway.
+pub struct PairingSecrets {
+    key: [u8; 32],
+    /// Secrets not yet used, with when each expires.
+    issued: Mutex<HashMap<String, i64>>,
+}
+
+impl Default for PairingSecrets {
+    fn default() -> Self {
+        PairingSecrets {
+            key: rand::random(),
+            issued: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl PairingSecrets {
+    /// Creates a signed pairing URI with a fresh one-time secret.
+    pub fn issue(
+        &self,
+        host_id: &str,
+        addresses: Vec<String>,
+        port: u16,
+        tls_fingerprint: Option<String>,
+        now: i64,
+    ) -> Result<(PairingPayload, String), AppError> {
+        let payload = PairingPayload {
+            host_id: host_id.to_string(),
+            addresses,
+            port,
+            tls_fingerprint,
+            secret: URL_SAFE_NO_PAD.encode(rand::random::<[u8; 16]>()),
+            expires_at: now + PAIRING_TTL_SECS,
+        };
+        let query = payload.query();
+        let signature = URL_SAFE_NO_PAD.encode(self.mac(&query)?.finalize().into_bytes());
+
+        let mut issued = self
+            .issued
+            .lock()
+            .map_err(|_| AppError::Unknown("Failed to lock pairing secrets".to_string()))?;
+        issued.retain(|_, expires_at| *expires_at > now);
+        issued.insert(payload.secret.clone(), payload.expires_at);
+
+        Ok((
+            payload,
+            format!("{}{}&sig={}", URI_PREFIX, query, signature),
+        ))
+    }
+
+    /// Checks a URI this host issued and uses up its secret, so the same
+    /// code cannot pair a second device.
+    pub fn redeem(&self, uri: &str, now: i64) -> Result<PairingPayload, AppError> {
+        let (payload, signature) = parse_uri(uri)?;
+        self.mac(&payload.query())?
+            .verify_slice(&signature)
+            .map_err(|_| AppError::Other("Pairing code was not issued by this host".to_string()))?;
+
+        let mut issued = self
+            .issued
+            .lock()
+            .map_err(|_| AppError::Unknown("Failed to lock pairing secrets".to_string()))?;
+        match issued.remove(&payload.secret) {
+            Some(expires_at) if expires_at > now => Ok(payload),
+            Some(_) => Err(AppError::Other("Pairing code has expired".to_string())),
+            None => Err(AppError::Other(
+                "Pairing code has already been used or has expired".to_string(),
+            )),
+        }
+    }
+
+    fn mac(&self, message: &str) -> Result<HmacSha256, AppError> {
+        let mut mac =
+            HmacSha256::new_from_slice(&self.key).map_err(|e| AppError::Other(e.to_string()))?;
+        mac.update(message.as_bytes());
+        Ok(mac)
+    }
+}
+
+/// Renders `uri` as an SVG QR code.
+pub fn qr_svg(uri: &str) -> Result<String, AppError> {
+    let code = QrCode::new(uri.as_bytes()).map_err(|e| AppError::Other(e.to_string()))?;
+    Ok(code
+        .render::<qrcode::render::svg::Color>()
+        .min_dimensions(QR_MIN_SIZE, QR_MIN_SIZE)
+        .build())
+}
+
+/// Renders `uri` as a PNG QR code.
+pub fn qr_png(uri: &str) -> Result<Vec<u8>, AppError> {
+    let code = QrCode::new(uri.as_bytes()).map_err(|e| AppError::Other(e.to_string()))?;
+    let image = code
+        .render::<image::Luma<u8>>()
+        .min_dimensions(QR_MIN_SIZE, QR_MIN_SIZE)
+        .build();
+    let mut png = Vec::new();
+    image
+        .write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png)
+        .map_err(|e| AppError::Other(e.to_string()))?;
+    Ok(png)
+}
+
+/// Pairs with the host a pairing URI points at, in one step.
+///
+/// Tries the host's addresses in turn, makes sure the one answering is the
+/// host that issued the code, then trades the secret for a bearer token.
+pub async fn pair_with_uri(
+    client: &reqwest::Client,
+    uri: &str,
+    device: Option<String>,
+    now: i64,
+) -> Result<Paired, AppError> {
+    let (payload, _) = parse_uri(uri)?;
+    if payload.expires_at <= now {
+        return Err(AppError::Other("Pairing code has expired".to_string()));
+    }
+
+    let host = payload.connection_info();
+    let probe: Probe = discovery::probe_host(client, &host)
+        .await
+        .ok_or_else(|| AppError::Other("Could not reach the host at any address".to_string()))?;
+    let advertisement = probe.health.as_ref().map(|h| &h.advertisement);
+    if advertisement.and_then(|a| a.host_id.as_deref()) != Some(payload.host_id.as_str()) {
+        return Err(AppError::Other(format!(
+            "The host at {} is not the one that made this pairing code",
+            probe.ip
+        )));
+    }
+    if payload.tls_fingerprint.is_some()
+        && advertisement.and_then(|a| a.tls_fingerprint.as_ref())
+            != payload.tls_fingerprint.as_ref()
+    {
+        return Err(AppError::Other(
+            "The host's TLS fingerprint does not match the pairing code".to_string(),
+        ));
+    }
+
+    let url = format!(
+        "http://{}/api/pair",
+        discovery::url_authority(&probe.ip, payload.port)
+    );
+    let response = client
+        .post(&url)
+        .json(&PairRequest {
+            uri: uri.trim().to_string(),
+            device,
+        })
+        .timeout(PAIR_TIMEOUT)
+        .send()
+        .await
+        .map_err(|e| AppError::Other(format!("Pairing request failed: {}", e)))?;
+    if !response.status().is_success() {
+        let message = response.text().await.unwrap_or_default();
+        return Err(AppError::Other(format!(
+            "Host refused pairing: {}",
+            message
+        )));
+    }
+
+    #[derive(serde::Deserialize)]
+    struct AuthResponse {
+        token: String,
+    }
+    let AuthResponse { token } = response
+        .json()
+        .await
+        .map_err(|e| AppError::Other(e.to_string()))?;
+    Ok(Paired {
+        host: discovery::apply_probe(host, Some(probe)),
+        token,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn issue(secrets: &PairingSecrets, now: i64) -> (PairingPayload, String) {
+        secrets
+            .issue(
+                "desk",
+                vec!["192.168.1.20".to_string(), "2001:db8::5".to_string()],
+                8080,
+                None,
+                now,
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn test_pairing_uri_round_trip() {
+        let secrets = PairingSecrets::default();
+        let (payload, uri) = issue(&secrets, 1_000);
+        assert!(uri.starts_with(URI_PREFIX));
+
+        let (parsed, _) = parse_uri(&uri).unwrap();
+        assert_eq!(parsed, payload);
+        assert_eq!(parsed.connection_info().ip, "192.168.1.20");
+        assert!(qr_svg(&uri).unwrap().contains("<svg"));
+        assert!(qr_png(&uri).unwrap().starts_with(b"\x89PNG"));
+
+        assert!(parse_uri("http://example.com").is_err());
+        assert!(parse_uri(&uri.replace("&sig=", "&x=")).is_err());
+    }
+
+    #[test]
+    fn test_pairing_secret_is_single_use_and_signed() {
+        let secrets = PairingSecrets::default();
+
+        let (_, uri) = issue(&secrets, 1_000);
+        assert!(secrets.redeem(&uri, 1_010).is_ok());
+        assert!(secrets.redeem(&uri, 1_010).is_err());
+
+        let (_, uri) = issue(&secrets, 1_000);
+        assert!(secrets.redeem(&uri, 1_000 + PAIRING_TTL_SECS).is_err());
+
+        // Pointing the code at another address breaks the signature
+        let (_, uri) = issue(&secrets, 1_000);
+        let tampered = uri.replace("192.168.1.20", "192.168.1.66");
+        assert!(secrets.redeem(&tampered, 1_010).is_err());
+        assert!(secrets.redeem(&uri, 1_010).is_ok());
+
+        // Codes from another host (or before a restart) are refused
+        let (_, uri) = issue(&PairingSecrets::default(), 1_000);
+        assert!(secrets.redeem(&uri, 1_010).is_err());
+    }
+}
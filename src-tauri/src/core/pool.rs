@@ -0,0 +1,158 @@
+use crate::error::AppError;
+use rusqlite::Connection;
+use std::ops::Deref;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Idle connections kept per pool; more are opened under load and closed after use.
+const MAX_IDLE: usize = 4;
+
+/// How long a writer waits for another connection's write to finish.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Opens a ShelfSync-owned database in WAL mode, so readers never block the
+/// writer (or each other) across pooled connections.
+pub fn open_wal(path: &Path) -> Result<Connection, AppError> {
+    let conn = Connection::open(path)?;
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+    conn.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get::<_, String>(0))?;
+    // Safe with WAL and avoids an fsync on every commit
+    conn.pragma_update(None, "synchronous", "NORMAL")?;
+    Ok(conn)
+}
+
+type Opener = Box<dyn Fn() -> Result<Connection, AppError> + Send + Sync>;
+
+/// A small pool of long-lived SQLite connections to one database file.
+///
+/// Connections are opened on first use with `open` (which also sets pragmas
+/// and runs migrations) and returned to the pool when dropped, so requests
+/// skip the cost of opening and migrating the file each time.
+pub struct DbPool {
+    open: Opener,
+    idle: Mutex<Vec<Connection>>,
+}
+
+impl DbPool {
+    pub fn new(
+        open: impl Fn() -> Result<Connection, AppError> + Send + Sync + 'static,
+    ) -> Arc<Self> {
+        Arc::new(DbPool {
+            open: Box::new(open),
+            idle: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Takes an idle connection, or opens a new one if none is free.
+    pub fn get(self: &Arc<Self>) -> Result<PooledConnection, AppError> {
+        let idle = self
+            .idle
+            .lock()
+            .map_err(|_| AppError::Unknown("Failed to lock connection pool".to_string()))?
+            .pop();
+        let conn = match idle {
+            Some(conn) => conn,
+            None => (self.open)()?,
+        };
+        Ok(PooledConnection {
+            conn: Some(conn),
+            pool: self.clone(),
+        })
+    }
+
+    /// Runs `f` with a pooled connection on the blocking thread pool, keeping
+    /// rusqlite calls off the async runtime.
+    pub async fn run<T, E>(
+        self: &Arc<Self>,
+        f: impl FnOnce(&Connection) -> Result<T, E> + Send + 'static,
+    ) -> Result<T, E>
+    where
+        T: Send + 'static,
+        E: From<AppError> + Send + 'static,
+    {
+        let pool = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let conn = pool.get()?;
+            f(&conn)
+        })
+        .await
+        .map_err(|e| E::from(AppError::Unknown(e.to_string())))?
+    }
+}
+
+/// A connection borrowed from a [`DbPool`], returned to it on drop.
+pub struct PooledConnection {
+    conn: Option<Connection>,
+    pool: Arc<DbPool>,
+}
+
+impl Deref for PooledConnection {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.conn
+            .as_ref()
+            .expect("connection is present until drop")
+    }
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        let Some(conn) = self.conn.take() else {
+            return;
+        };
+        // A connection left inside a transaction (e.g. after a panic) is not reused
+        if !conn.is_autocommit() {
+            return;
+        }
+        if let Ok(mut idle) = self.pool.idle.lock() {
+            if idle.len() < MAX_IDLE {
+                idle.push(conn);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn test_reuses_connections() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("test.db");
+        let opened = Arc::new(AtomicUsize::new(0));
+        let counter = opened.clone();
+        let pool = DbPool::new(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(Connection::open(&path)?)
+        });
+
+        {
+            let first = pool.get().unwrap();
+            let second = pool.get().unwrap();
+            first.execute("CREATE TABLE t (x INTEGER)", []).unwrap();
+            second.execute("INSERT INTO t VALUES (1)", []).unwrap();
+        }
+        assert_eq!(opened.load(Ordering::SeqCst), 2);
+
+        let count: i64 = pool
+            .run(|conn| {
+                Ok::<_, AppError>(conn.query_row("SELECT COUNT(*) FROM t", [], |row| row.get(0))?)
+            })
+            .await
+            .unwrap();
+        assert_eq!(count, 1);
+        assert_eq!(opened.load(Ordering::SeqCst), 2);
+
+        // Connections stuck in a transaction are discarded
+        pool.get().unwrap().execute_batch("BEGIN").unwrap();
+        pool.get().unwrap();
+        pool.get().unwrap();
+        assert_eq!(opened.load(Ordering::SeqCst), 2);
+        assert_eq!(pool.idle.lock().unwrap().len(), 1);
+    }
+}
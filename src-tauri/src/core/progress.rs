@@ -1,69 +1,1057 @@
+use crate::core::kosync;
+use crate::core::migrations::{self, ensure_column, Migration};
+use crate::core::pool;
 use crate::error::AppError;
-use rusqlite::Connection;
+use crate::models::Book;
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use rusqlite::{Connection, Transaction, TransactionBehavior};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::path::Path;
 
-#[derive(serde::Serialize)]
+/// Where a reader is with a book. Stored and serialized in kebab-case.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum ReadingStatus {
+    #[default]
+    Unread,
+    Reading,
+    Finished,
+    Abandoned,
+    WantToRead,
+}
+
+impl ReadingStatus {
+    pub const ALL: [ReadingStatus; 5] = [
+        ReadingStatus::Unread,
+        ReadingStatus::Reading,
+        ReadingStatus::Finished,
+        ReadingStatus::Abandoned,
+        ReadingStatus::WantToRead,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReadingStatus::Unread => "unread",
+            ReadingStatus::Reading => "reading",
+            ReadingStatus::Finished => "finished",
+            ReadingStatus::Abandoned => "abandoned",
+            ReadingStatus::WantToRead => "want-to-read",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<ReadingStatus> {
+        Self::ALL
+            .into_iter()
+            .find(|status| status.as_str() == value)
+    }
+
+    /// Maps free-text statuses written before the status was validated
+    /// (e.g. `"Read"`, `"in_progress"`, `"dnf"`) onto a known status.
+    fn from_legacy(value: &str) -> ReadingStatus {
+        let normalized = value.trim().to_lowercase().replace(['_', ' '], "-");
+        if let Some(status) = Self::parse(&normalized) {
+            return status;
+        }
+        match normalized.as_str() {
+            "read" | "done" | "complete" | "completed" => ReadingStatus::Finished,
+            "in-progress" | "started" | "current" | "currently-reading" => ReadingStatus::Reading,
+            "dnf" | "dropped" | "did-not-finish" => ReadingStatus::Abandoned,
+            "to-read" | "wishlist" | "want" | "planned" => ReadingStatus::WantToRead,
+            _ => ReadingStatus::Unread,
+        }
+    }
+}
+
+impl ToSql for ReadingStatus {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.as_str()))
+    }
+}
+
+impl FromSql for ReadingStatus {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        let text = value.as_str()?;
+        ReadingStatus::parse(text)
+            .ok_or_else(|| FromSqlError::Other(format!("Unknown reading status: {}", text).into()))
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
 pub struct ProgressRecord {
     pub book_id: i64,
-    pub status: String,    // 'unread', 'reading', 'finished'
+    pub status: ReadingStatus,
     pub last_updated: i64, // Unix timestamp
+    /// How far through the book the reader is, from 0.0 to 100.0.
+    pub percentage: Option<f64>,
+    /// Exact position: an EPUB CFI (`epubcfi(...)`) or a serialized Readium locator.
+    pub locator: Option<String>,
+    /// Current page (1-based) for paginated formats such as PDFs and comics.
+    pub page: Option<i64>,
+    pub page_count: Option<i64>,
+    pub chapter: Option<String>,
+    /// Name of the device that reported this position.
+    pub device: Option<String>,
 }
 
-pub fn init_progress_db(app_data_dir: &Path) -> Result<(), AppError> {
-    let db_path = app_data_dir.join("progress.db");
-    let conn = Connection::open(db_path)?;
+/// A progress report sent by a client. Only `book_id` and `status` are required;
+/// readers fill in whichever position fields make sense for the format.
+#[derive(serde::Deserialize, Debug, Clone, Default)]
+pub struct ProgressUpdate {
+    pub book_id: i64,
+    pub status: ReadingStatus,
+    pub percentage: Option<f64>,
+    pub locator: Option<String>,
+    pub page: Option<i64>,
+    pub page_count: Option<i64>,
+    pub chapter: Option<String>,
+    pub device: Option<String>,
+    /// Client-side Unix timestamp of when the position was recorded. Defaults to
+    /// the time the server received the update.
+    pub updated_at: Option<i64>,
+    /// Accept a move away from "finished" (e.g. a re-read) instead of reporting it
+    /// as a conflict.
+    #[serde(default)]
+    pub force: bool,
+}
 
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS progress (
-            book_id INTEGER PRIMARY KEY,
-            status TEXT NOT NULL,
-            last_updated INTEGER NOT NULL
-        )",
-        [],
-    )?;
+/// A reported field outside its allowed range: the field and why it was refused.
+pub type InvalidField = (&'static str, String);
 
+impl ProgressUpdate {
+    /// Checks the reported position is in range. Every source of progress, HTTP
+    /// clients and KOReader alike, is checked before it is merged.
+    pub fn validate(&self) -> Result<(), InvalidField> {
+        check_percentage("percentage", self.percentage)?;
+        for (field, value) in [("page", self.page), ("page_count", self.page_count)] {
+            if value.is_some_and(|v| v < 1) {
+                return Err((field, format!("{} must be at least 1", field)));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Checks a percentage is between 0 and 100.
+pub fn check_percentage(field: &'static str, value: Option<f64>) -> Result<(), InvalidField> {
+    match value {
+        Some(p) if !(0.0..=100.0).contains(&p) => {
+            Err((field, format!("{} must be between 0 and 100", field)))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Outcome of merging an update into the stored progress for a book.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct MergeResult {
+    /// The position every device should now show.
+    pub record: ProgressRecord,
+    /// Positions from devices that disagree with `record`, so the client can ask
+    /// the user which one to keep. Empty when there is nothing to resolve.
+    pub conflicts: Vec<ProgressRecord>,
+}
+
+/// A stretch of reading reported by a client when the reader closes a book or
+/// goes idle.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ReadingSession {
+    /// Assigned by the server; ignored when a session is submitted.
+    #[serde(default)]
+    pub id: i64,
+    pub book_id: i64,
+    pub device: Option<String>,
+    /// Unix timestamps of when reading started and stopped.
+    pub started_at: i64,
+    pub ended_at: i64,
+    /// Position at the start and end of the session, from 0.0 to 100.0.
+    pub start_percentage: Option<f64>,
+    pub end_percentage: Option<f64>,
+    pub pages_read: Option<i64>,
+}
+
+/// Filters for [`get_reading_stats`].
+#[derive(serde::Deserialize, Debug, Clone, Default)]
+pub struct StatsQuery {
+    /// Only count sessions starting at or after this Unix timestamp.
+    pub from: Option<i64>,
+    /// Only count sessions starting before this Unix timestamp.
+    pub to: Option<i64>,
+    /// Offset of the reader's time zone from UTC, used to decide which day a
+    /// session belongs to.
+    #[serde(default)]
+    pub utc_offset_minutes: i32,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ReadingStats {
+    pub total_seconds: i64,
+    pub sessions: i64,
+    pub per_day: Vec<DayStats>,
+    pub per_book: Vec<BookStats>,
+    pub per_author: Vec<AuthorStats>,
+    /// Books marked finished, by year. Not limited by `from`/`to`.
+    pub finished_per_year: Vec<YearStats>,
+    /// Consecutive days with reading, ending today or yesterday.
+    pub current_streak: i64,
+    pub longest_streak: i64,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct DayStats {
+    /// `YYYY-MM-DD` in the requested time zone.
+    pub date: String,
+    pub seconds: i64,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct BookStats {
+    pub book_id: i64,
+    pub title: Option<String>,
+    pub seconds: i64,
+    pub sessions: i64,
+    pub pages_read: i64,
+    /// Sum of the percentage advanced across sessions.
+    pub percentage_read: f64,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct AuthorStats {
+    pub author: String,
+    pub seconds: i64,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct YearStats {
+    pub year: i32,
+    pub finished: i64,
+}
+
+/// Device name used when a client does not identify itself.
+pub const UNKNOWN_DEVICE: &str = "unknown";
+
+/// Devices further ahead than the merged position by more than this many
+/// percentage points are reported as conflicts.
+pub const CONFLICT_THRESHOLD: f64 = 5.0;
+
+/// How far ahead of the server clock a client timestamp may be before it is
+/// clamped, so one device with a wrong clock cannot pin the position forever.
+const MAX_CLOCK_SKEW_SECS: i64 = 5 * 60;
+
+/// Schema history of `progress.db`.
+///
+/// Steps 1 to 5 reproduce the schema as it was before versioning, when every
+/// existing database reported version 0, so they must stay safe to re-run.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "progress table with reading positions",
+        apply: |conn| {
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS progress (
+                    book_id INTEGER PRIMARY KEY,
+                    status TEXT NOT NULL,
+                    last_updated INTEGER NOT NULL
+                )",
+                [],
+            )?;
+            ensure_column(conn, "progress", "percentage", "REAL")?;
+            ensure_column(conn, "progress", "locator", "TEXT")?;
+            ensure_column(conn, "progress", "page", "INTEGER")?;
+            ensure_column(conn, "progress", "page_count", "INTEGER")?;
+            ensure_column(conn, "progress", "chapter", "TEXT")
+        },
+    },
+    Migration {
+        version: 2,
+        description: "per-device progress",
+        apply: |conn| {
+            ensure_column(conn, "progress", "device", "TEXT")?;
+            // Latest position reported by each device, with the client's own timestamp
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS device_progress (
+                    book_id INTEGER NOT NULL,
+                    device TEXT NOT NULL,
+                    status TEXT NOT NULL,
+                    updated_at INTEGER NOT NULL,
+                    percentage REAL,
+                    locator TEXT,
+                    page INTEGER,
+                    page_count INTEGER,
+                    chapter TEXT,
+                    PRIMARY KEY (book_id, device)
+                )",
+                [],
+            )?;
+            Ok(())
+        },
+    },
+    Migration {
+        version: 3,
+        description: "normalize free-text statuses",
+        apply: normalize_statuses,
+    },
+    Migration {
+        version: 4,
+        description: "KOReader sync tables",
+        apply: kosync::ensure_schema,
+    },
+    Migration {
+        version: 5,
+        description: "reading sessions",
+        apply: |conn| {
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS reading_sessions (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    book_id INTEGER NOT NULL,
+                    device TEXT NOT NULL,
+                    started_at INTEGER NOT NULL,
+                    ended_at INTEGER NOT NULL,
+                    start_percentage REAL,
+                    end_percentage REAL,
+                    pages_read INTEGER
+                )",
+                [],
+            )?;
+            conn.execute(
+                "CREATE INDEX IF NOT EXISTS reading_sessions_started ON reading_sessions (started_at)",
+                [],
+            )?;
+            Ok(())
+        },
+    },
+    Migration {
+        version: 6,
+        description: "KOReader documents matching no book",
+        apply: kosync::ensure_miss_schema,
+    },
+    Migration {
+        version: 7,
+        description: "KOReader accounts paired as devices",
+        apply: kosync::ensure_device_schema,
+    },
+];
+
+/// Opens `progress.db` in the app data dir, creating or migrating the schema as needed.
+pub fn open_progress_db(app_data_dir: &Path) -> Result<Connection, AppError> {
+    let conn = pool::open_wal(&app_data_dir.join("progress.db"))?;
+    migrations::migrate(&conn, "progress.db", MIGRATIONS)?;
+    Ok(conn)
+}
+
+/// Rewrites free-text statuses stored by older versions into [`ReadingStatus`] values.
+fn normalize_statuses(conn: &Connection) -> Result<(), AppError> {
+    let known = ReadingStatus::ALL
+        .iter()
+        .map(|status| format!("'{}'", status.as_str()))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    for table in ["progress", "device_progress"] {
+        let legacy = conn
+            .prepare(&format!(
+                "SELECT DISTINCT status FROM {} WHERE status NOT IN ({})",
+                table, known
+            ))?
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        for value in legacy {
+            conn.execute(
+                &format!("UPDATE {} SET status = ?1 WHERE status = ?2", table),
+                rusqlite::params![ReadingStatus::from_legacy(&value), value],
+            )?;
+        }
+    }
     Ok(())
 }
 
-pub fn update_progress(app_data_dir: &Path, book_id: i64, status: &str) -> Result<(), AppError> {
-    let db_path = app_data_dir.join("progress.db");
-    let conn = Connection::open(db_path)?;
-    let now = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .unwrap()
-        .as_secs() as i64;
+/// Records an update from one device and recomputes the book's merged position.
+///
+/// Each device keeps its own row, ordered by the client's timestamp, so a delayed
+/// upload never overwrites a newer position from the same device. The merged
+/// position follows these rules:
+///
+/// 1. The most recently recorded position across all devices wins.
+/// 2. A book marked "finished" is never moved back to another status unless the
+///    update sets `force`; the regressing position is returned as a conflict.
+/// 3. Any other device more than [`CONFLICT_THRESHOLD`] points further ahead
+///    than the winner is returned as a conflict, unless the update set `force`
+///    because the user already chose this position.
+///
+/// Runs in a `BEGIN IMMEDIATE` transaction (unless the caller already opened one), so
+/// concurrent updates from pooled connections cannot interleave and merge stale rows.
+pub fn update_progress(
+    conn: &Connection,
+    update: &ProgressUpdate,
+) -> Result<MergeResult, AppError> {
+    if !conn.is_autocommit() {
+        return merge_progress(conn, update);
+    }
+    let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+    let result = merge_progress(&tx, update)?;
+    tx.commit()?;
+    Ok(result)
+}
+
+fn merge_progress(conn: &Connection, update: &ProgressUpdate) -> Result<MergeResult, AppError> {
+    let now = now();
+    let updated_at = update
+        .updated_at
+        .unwrap_or(now)
+        .min(now + MAX_CLOCK_SKEW_SECS);
+    let device = update.device.as_deref().unwrap_or(UNKNOWN_DEVICE);
 
     conn.execute(
-        "INSERT INTO progress (book_id, status, last_updated) 
-         VALUES (?1, ?2, ?3)
-         ON CONFLICT(book_id) DO UPDATE SET 
+        "INSERT INTO device_progress (book_id, device, status, updated_at, percentage, locator, page, page_count, chapter)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+         ON CONFLICT(book_id, device) DO UPDATE SET
             status = excluded.status,
-            last_updated = excluded.last_updated
-         WHERE excluded.last_updated >= last_updated",
-        rusqlite::params![book_id, status, now],
+            updated_at = excluded.updated_at,
+            percentage = excluded.percentage,
+            locator = excluded.locator,
+            page = excluded.page,
+            page_count = excluded.page_count,
+            chapter = excluded.chapter
+         WHERE excluded.updated_at >= updated_at",
+        rusqlite::params![
+            update.book_id,
+            device,
+            update.status,
+            updated_at,
+            update.percentage,
+            update.locator,
+            update.page,
+            update.page_count,
+            update.chapter,
+        ],
     )?;
 
-    Ok(())
-}
+    let devices = get_device_progress(conn, update.book_id)?;
+    let previous = get_progress(conn, update.book_id)?;
+
+    // Devices are sorted newest first, so the first row is the latest position
+    let latest = devices
+        .first()
+        .cloned()
+        .expect("device row was just written");
 
-pub fn get_all_progress(app_data_dir: &Path) -> Result<Vec<ProgressRecord>, AppError> {
-    let db_path = app_data_dir.join("progress.db");
-    if !db_path.exists() {
-        return Ok(Vec::new());
+    let mut conflicts = Vec::new();
+    let record = match previous {
+        Some(previous)
+            if previous.status == ReadingStatus::Finished
+                && latest.status != ReadingStatus::Finished
+                && !update.force =>
+        {
+            conflicts.push(latest);
+            previous
+        }
+        _ => latest,
+    };
+
+    if !update.force {
+        let merged_percentage = record.percentage.unwrap_or(0.0);
+        for other in &devices {
+            if other.device == record.device || conflicts.contains(other) {
+                continue;
+            }
+            if other.percentage.unwrap_or(0.0) - merged_percentage > CONFLICT_THRESHOLD {
+                conflicts.push(other.clone());
+            }
+        }
     }
 
-    let conn = Connection::open(db_path)?;
-    let mut stmt = conn.prepare("SELECT book_id, status, last_updated FROM progress")?;
+    conn.execute(
+        "INSERT OR REPLACE INTO progress (book_id, status, last_updated, percentage, locator, page, page_count, chapter, device)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        rusqlite::params![
+            record.book_id,
+            record.status,
+            record.last_updated,
+            record.percentage,
+            record.locator,
+            record.page,
+            record.page_count,
+            record.chapter,
+            record.device,
+        ],
+    )?;
+
+    Ok(MergeResult { record, conflicts })
+}
+
+/// Returns the merged position for one book.
+pub fn get_progress(conn: &Connection, book_id: i64) -> Result<Option<ProgressRecord>, AppError> {
+    let mut stmt = conn.prepare(
+        "SELECT book_id, status, last_updated, percentage, locator, page, page_count, chapter, device
+         FROM progress WHERE book_id = ?1",
+    )?;
+    let mut rows = stmt.query_map([book_id], map_record)?;
+    Ok(rows.next().transpose()?)
+}
+
+/// Returns the latest position from each device for one book, newest first.
+pub fn get_device_progress(
+    conn: &Connection,
+    book_id: i64,
+) -> Result<Vec<ProgressRecord>, AppError> {
+    let mut stmt = conn.prepare(
+        "SELECT book_id, status, updated_at, percentage, locator, page, page_count, chapter, device
+         FROM device_progress WHERE book_id = ?1
+         ORDER BY updated_at DESC, percentage DESC",
+    )?;
+    let records = stmt
+        .query_map([book_id], map_record)?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(records)
+}
+
+pub fn get_all_progress(conn: &Connection) -> Result<Vec<ProgressRecord>, AppError> {
+    let mut stmt = conn.prepare(
+        "SELECT book_id, status, last_updated, percentage, locator, page, page_count, chapter, device
+         FROM progress",
+    )?;
 
     let records = stmt
+        .query_map([], map_record)?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(records)
+}
+
+fn map_record(row: &rusqlite::Row) -> rusqlite::Result<ProgressRecord> {
+    Ok(ProgressRecord {
+        book_id: row.get(0)?,
+        status: row.get(1)?,
+        last_updated: row.get(2)?,
+        percentage: row.get(3)?,
+        locator: row.get(4)?,
+        page: row.get(5)?,
+        page_count: row.get(6)?,
+        chapter: row.get(7)?,
+        device: row.get(8)?,
+    })
+}
+
+/// Stores a reading session and returns its ID.
+pub fn record_session(conn: &Connection, session: &ReadingSession) -> Result<i64, AppError> {
+    conn.execute(
+        "INSERT INTO reading_sessions (book_id, device, started_at, ended_at, start_percentage, end_percentage, pages_read)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        rusqlite::params![
+            session.book_id,
+            session.device.as_deref().unwrap_or(UNKNOWN_DEVICE),
+            session.started_at,
+            session.ended_at,
+            session.start_percentage,
+            session.end_percentage,
+            session.pages_read,
+        ],
+    )?;
+    Ok(conn.last_insert_rowid())
+}
+
+/// Aggregates reading sessions into time read per day, book and author, plus
+/// finished books per year and reading streaks.
+///
+/// `books` is used to attach titles and authors; sessions for books that are no
+/// longer in the library still count towards the totals. Sessions and finished
+/// books with an ID in `hidden` are left out entirely, streaks included.
+pub fn get_reading_stats(
+    conn: &Connection,
+    books: &[Book],
+    hidden: &HashSet<i64>,
+    query: &StatsQuery,
+) -> Result<ReadingStats, AppError> {
+    let offset = query.utc_offset_minutes as i64 * 60;
+    let day_of = |timestamp: i64| {
+        chrono::DateTime::from_timestamp(timestamp + offset, 0)
+            .unwrap_or_default()
+            .date_naive()
+    };
+    let books_by_id: HashMap<i64, &Book> = books.iter().map(|b| (b.id, b)).collect();
+
+    let mut stmt = conn.prepare(
+        "SELECT id, book_id, device, started_at, ended_at, start_percentage, end_percentage, pages_read
+         FROM reading_sessions ORDER BY started_at",
+    )?;
+    let sessions = stmt
         .query_map([], |row| {
-            Ok(ProgressRecord {
-                book_id: row.get(0)?,
-                status: row.get(1)?,
-                last_updated: row.get(2)?,
+            Ok(ReadingSession {
+                id: row.get(0)?,
+                book_id: row.get(1)?,
+                device: row.get(2)?,
+                started_at: row.get(3)?,
+                ended_at: row.get(4)?,
+                start_percentage: row.get(5)?,
+                end_percentage: row.get(6)?,
+                pages_read: row.get(7)?,
             })
         })?
         .collect::<Result<Vec<_>, _>>()?;
 
-    Ok(records)
+    let mut stats = ReadingStats::default();
+    let mut per_day: BTreeMap<chrono::NaiveDate, i64> = BTreeMap::new();
+    let mut per_book: BTreeMap<i64, BookStats> = BTreeMap::new();
+    let mut per_author: HashMap<String, i64> = HashMap::new();
+    let mut reading_days = BTreeSet::new();
+
+    for session in sessions.iter().filter(|s| !hidden.contains(&s.book_id)) {
+        let day = day_of(session.started_at);
+        // Streaks cover all history, not just the requested range
+        reading_days.insert(day);
+
+        let in_range = query.from.is_none_or(|from| session.started_at >= from)
+            && query.to.is_none_or(|to| session.started_at < to);
+        if !in_range {
+            continue;
+        }
+
+        let seconds = (session.ended_at - session.started_at).max(0);
+        stats.total_seconds += seconds;
+        stats.sessions += 1;
+        *per_day.entry(day).or_default() += seconds;
+
+        let book = books_by_id.get(&session.book_id);
+        let entry = per_book
+            .entry(session.book_id)
+            .or_insert_with(|| BookStats {
+                book_id: session.book_id,
+                title: book.map(|b| b.title.clone()),
+                seconds: 0,
+                sessions: 0,
+                pages_read: 0,
+                percentage_read: 0.0,
+            });
+        entry.seconds += seconds;
+        entry.sessions += 1;
+        entry.pages_read += session.pages_read.unwrap_or(0);
+        if let (Some(start), Some(end)) = (session.start_percentage, session.end_percentage) {
+            entry.percentage_read += (end - start).max(0.0);
+        }
+
+        if let Some(book) = book {
+            for author in book.authors.split(", ").filter(|a| !a.is_empty()) {
+                *per_author.entry(author.to_string()).or_default() += seconds;
+            }
+        }
+    }
+
+    stats.per_day = per_day
+        .into_iter()
+        .map(|(date, seconds)| DayStats {
+            date: date.format("%Y-%m-%d").to_string(),
+            seconds,
+        })
+        .collect();
+    stats.per_book = per_book.into_values().collect();
+    stats.per_book.sort_by_key(|b| std::cmp::Reverse(b.seconds));
+    stats.per_author = per_author
+        .into_iter()
+        .map(|(author, seconds)| AuthorStats { author, seconds })
+        .collect();
+    stats
+        .per_author
+        .sort_by(|a, b| b.seconds.cmp(&a.seconds).then(a.author.cmp(&b.author)));
+
+    let mut finished: BTreeMap<i32, i64> = BTreeMap::new();
+    let mut stmt = conn.prepare("SELECT book_id, last_updated FROM progress WHERE status = ?1")?;
+    for row in stmt.query_map([ReadingStatus::Finished], |row| {
+        Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?))
+    })? {
+        let (book_id, timestamp) = row?;
+        if !hidden.contains(&book_id) {
+            *finished
+                .entry(chrono::Datelike::year(&day_of(timestamp)))
+                .or_default() += 1;
+        }
+    }
+    stats.finished_per_year = finished
+        .into_iter()
+        .map(|(year, finished)| YearStats { year, finished })
+        .collect();
+
+    let (current, longest) = streaks(&reading_days, day_of(now()));
+    stats.current_streak = current;
+    stats.longest_streak = longest;
+
+    Ok(stats)
+}
+
+/// Returns the current streak (consecutive days ending today or yesterday) and
+/// the longest streak in `days`.
+fn streaks(days: &BTreeSet<chrono::NaiveDate>, today: chrono::NaiveDate) -> (i64, i64) {
+    let mut longest = 0;
+    let mut run = 0;
+    let mut previous: Option<chrono::NaiveDate> = None;
+    for &day in days {
+        run = match previous {
+            Some(p) if p.succ_opt() == Some(day) => run + 1,
+            _ => 1,
+        };
+        longest = longest.max(run);
+        previous = Some(day);
+    }
+
+    let yesterday = today.pred_opt().unwrap_or(today);
+    let mut current = 0;
+    let mut day = if days.contains(&today) {
+        today
+    } else {
+        yesterday
+    };
+    while days.contains(&day) {
+        current += 1;
+        match day.pred_opt() {
+            Some(p) => day = p,
+            None => break,
+        }
+    }
+
+    (current, longest)
+}
+
+fn now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_upgrades_original_schema() {
+        let dir = tempdir().unwrap();
+        {
+            // progress.db as created by the first release
+            let conn = Connection::open(dir.path().join("progress.db")).unwrap();
+            conn.execute(
+                "CREATE TABLE progress (book_id INTEGER PRIMARY KEY, status TEXT NOT NULL, last_updated INTEGER NOT NULL)",
+                [],
+            )
+            .unwrap();
+            conn.execute(
+                "INSERT INTO progress (book_id, status, last_updated) VALUES (7, 'Read', 100), (8, 'in_progress', 100), (9, 'bogus', 100)",
+                [],
+            )
+            .unwrap();
+        }
+
+        let conn = open_progress_db(dir.path()).unwrap();
+        let records = get_all_progress(&conn).unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].book_id, 7);
+        assert_eq!(records[0].status, ReadingStatus::Finished);
+        assert_eq!(records[0].percentage, None);
+        assert_eq!(records[1].status, ReadingStatus::Reading);
+        assert_eq!(records[2].status, ReadingStatus::Unread);
+    }
+
+    #[test]
+    fn test_migrates_every_past_schema() {
+        // progress.db as left by each release before versioning, oldest first
+        let fixtures = [
+            "CREATE TABLE progress (book_id INTEGER PRIMARY KEY, status TEXT NOT NULL, last_updated INTEGER NOT NULL);
+             INSERT INTO progress VALUES (1, 'Reading', 100);",
+            "CREATE TABLE progress (book_id INTEGER PRIMARY KEY, status TEXT NOT NULL, last_updated INTEGER NOT NULL,
+                percentage REAL, locator TEXT, page INTEGER, page_count INTEGER, chapter TEXT);
+             INSERT INTO progress VALUES (1, 'Reading', 100, 12.5, '/4/2', NULL, NULL, NULL);",
+            "CREATE TABLE progress (book_id INTEGER PRIMARY KEY, status TEXT NOT NULL, last_updated INTEGER NOT NULL,
+                percentage REAL, locator TEXT, page INTEGER, page_count INTEGER, chapter TEXT, device TEXT);
+             CREATE TABLE device_progress (book_id INTEGER NOT NULL, device TEXT NOT NULL, status TEXT NOT NULL,
+                updated_at INTEGER NOT NULL, percentage REAL, locator TEXT, page INTEGER, page_count INTEGER,
+                chapter TEXT, PRIMARY KEY (book_id, device));
+             INSERT INTO progress VALUES (1, 'Reading', 100, 12.5, '/4/2', NULL, NULL, NULL, 'Phone');
+             INSERT INTO device_progress VALUES (1, 'Phone', 'Reading', 100, 12.5, '/4/2', NULL, NULL, NULL);",
+            "CREATE TABLE progress (book_id INTEGER PRIMARY KEY, status TEXT NOT NULL, last_updated INTEGER NOT NULL,
+                percentage REAL, locator TEXT, page INTEGER, page_count INTEGER, chapter TEXT, device TEXT);
+             CREATE TABLE device_progress (book_id INTEGER NOT NULL, device TEXT NOT NULL, status TEXT NOT NULL,
+                updated_at INTEGER NOT NULL, percentage REAL, locator TEXT, page INTEGER, page_count INTEGER,
+                chapter TEXT, PRIMARY KEY (book_id, device));
+             CREATE TABLE kosync_users (username TEXT PRIMARY KEY, userkey TEXT NOT NULL, created_at INTEGER NOT NULL);
+             CREATE TABLE reading_sessions (id INTEGER PRIMARY KEY AUTOINCREMENT, book_id INTEGER NOT NULL,
+                device TEXT NOT NULL, started_at INTEGER NOT NULL, ended_at INTEGER NOT NULL,
+                start_percentage REAL, end_percentage REAL, pages_read INTEGER);
+             INSERT INTO progress VALUES (1, 'reading', 100, 12.5, '/4/2', NULL, NULL, NULL, 'Phone');
+             INSERT INTO device_progress VALUES (1, 'Phone', 'reading', 100, 12.5, '/4/2', NULL, NULL, NULL);
+             INSERT INTO kosync_users VALUES ('reader', 'key', 100);
+             INSERT INTO reading_sessions (book_id, device, started_at, ended_at) VALUES (1, 'Phone', 100, 200);",
+        ];
+
+        for (i, fixture) in fixtures.iter().enumerate() {
+            let dir = tempdir().unwrap();
+            Connection::open(dir.path().join("progress.db"))
+                .unwrap()
+                .execute_batch(fixture)
+                .unwrap();
+
+            let conn = open_progress_db(dir.path()).unwrap();
+            assert_eq!(
+                migrations::user_version(&conn).unwrap(),
+                MIGRATIONS.len() as i64,
+                "fixture {}",
+                i
+            );
+            let record = get_progress(&conn, 1).unwrap().unwrap();
+            assert_eq!(record.status, ReadingStatus::Reading, "fixture {}", i);
+            assert_eq!(record.last_updated, 100, "fixture {}", i);
+
+            // Every table of the current schema is usable
+            update_progress(
+                &conn,
+                &ProgressUpdate {
+                    book_id: 1,
+                    status: ReadingStatus::Finished,
+                    device: Some("Phone".to_string()),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            record_session(
+                &conn,
+                &ReadingSession {
+                    book_id: 1,
+                    started_at: 300,
+                    ended_at: 400,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            // The newest fixture's user has no device yet, so it can be claimed
+            let created = kosync::create_user(&conn, "reader", "key", "token").unwrap();
+            assert!(created, "fixture {}", i);
+            assert!(!kosync::create_user(&conn, "reader", "key", "other").unwrap());
+        }
+    }
+
+    #[test]
+    fn test_status_serialization() {
+        assert_eq!(
+            serde_json::to_string(&ReadingStatus::WantToRead).unwrap(),
+            "\"want-to-read\""
+        );
+        assert!(serde_json::from_str::<ReadingStatus>("\"read\"").is_err());
+        assert_eq!(
+            ReadingStatus::from_legacy("Want To Read"),
+            ReadingStatus::WantToRead
+        );
+    }
+
+    #[test]
+    fn test_update_progress_stores_position() {
+        let dir = tempdir().unwrap();
+        let conn = open_progress_db(dir.path()).unwrap();
+
+        update_progress(
+            &conn,
+            &ProgressUpdate {
+                book_id: 1,
+                status: ReadingStatus::Reading,
+                percentage: Some(42.5),
+                locator: Some("epubcfi(/6/4!/4/2/1:0)".to_string()),
+                chapter: Some("Chapter 3".to_string()),
+                device: Some("Pixel".to_string()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let records = get_all_progress(&conn).unwrap();
+        assert_eq!(records[0].percentage, Some(42.5));
+        assert_eq!(
+            records[0].locator.as_deref(),
+            Some("epubcfi(/6/4!/4/2/1:0)")
+        );
+        assert_eq!(records[0].chapter.as_deref(), Some("Chapter 3"));
+        assert_eq!(records[0].device.as_deref(), Some("Pixel"));
+    }
+
+    fn update(
+        device: &str,
+        status: ReadingStatus,
+        percentage: f64,
+        updated_at: i64,
+    ) -> ProgressUpdate {
+        ProgressUpdate {
+            book_id: 1,
+            status,
+            percentage: Some(percentage),
+            device: Some(device.to_string()),
+            updated_at: Some(updated_at),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_concurrent_updates_merge_the_newest_position() {
+        let dir = tempdir().unwrap();
+        open_progress_db(dir.path()).unwrap();
+
+        let writers: Vec<_> = ["kobo", "phone"]
+            .into_iter()
+            .enumerate()
+            .map(|(offset, device)| {
+                let path = dir.path().to_path_buf();
+                std::thread::spawn(move || {
+                    let conn = open_progress_db(&path).unwrap();
+                    for i in 0..50 {
+                        let at = 1000 + i * 2 + offset as i64;
+                        update_progress(&conn, &update(device, ReadingStatus::Reading, 1.0, at))
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        let conn = open_progress_db(dir.path()).unwrap();
+        let newest = get_device_progress(&conn, 1).unwrap()[0].last_updated;
+        assert_eq!(
+            get_progress(&conn, 1).unwrap().unwrap().last_updated,
+            newest
+        );
+    }
+
+    #[test]
+    fn test_most_recent_device_wins_and_reports_further_devices() {
+        let dir = tempdir().unwrap();
+        let conn = open_progress_db(dir.path()).unwrap();
+
+        update_progress(&conn, &update("kobo", ReadingStatus::Reading, 60.0, 100)).unwrap();
+        let result =
+            update_progress(&conn, &update("phone", ReadingStatus::Reading, 20.0, 200)).unwrap();
+
+        assert_eq!(result.record.device.as_deref(), Some("phone"));
+        assert_eq!(result.record.last_updated, 200);
+        assert_eq!(result.conflicts.len(), 1);
+        assert_eq!(result.conflicts[0].device.as_deref(), Some("kobo"));
+
+        // A delayed upload from the phone does not override its newer position
+        let result =
+            update_progress(&conn, &update("phone", ReadingStatus::Reading, 10.0, 150)).unwrap();
+        assert_eq!(result.record.percentage, Some(20.0));
+        assert_eq!(get_device_progress(&conn, 1).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_finished_is_not_regressed_without_force() {
+        let dir = tempdir().unwrap();
+        let conn = open_progress_db(dir.path()).unwrap();
+
+        update_progress(&conn, &update("kobo", ReadingStatus::Finished, 100.0, 100)).unwrap();
+        let result =
+            update_progress(&conn, &update("phone", ReadingStatus::Reading, 30.0, 200)).unwrap();
+
+        assert_eq!(result.record.status, ReadingStatus::Finished);
+        assert_eq!(result.conflicts.len(), 1);
+        assert_eq!(result.conflicts[0].device.as_deref(), Some("phone"));
+        assert_eq!(
+            get_progress(&conn, 1).unwrap().unwrap().status,
+            ReadingStatus::Finished
+        );
+
+        let mut reread = update("phone", ReadingStatus::Reading, 1.0, 300);
+        reread.force = true;
+        let result = update_progress(&conn, &reread).unwrap();
+        assert_eq!(result.record.status, ReadingStatus::Reading);
+        assert!(result.conflicts.is_empty());
+    }
+
+    #[test]
+    fn test_reading_stats() {
+        let dir = tempdir().unwrap();
+        let conn = open_progress_db(dir.path()).unwrap();
+        let books = vec![Book {
+            authors: "Terry Pratchett, Neil Gaiman".to_string(),
+            ..Book::test(1, "Good Omens")
+        }];
+
+        let day = 24 * 60 * 60;
+        let today = now();
+        let session = |started_at: i64, minutes: i64, start: f64, end: f64| ReadingSession {
+            book_id: 1,
+            started_at,
+            ended_at: started_at + minutes * 60,
+            start_percentage: Some(start),
+            end_percentage: Some(end),
+            pages_read: Some(10),
+            ..Default::default()
+        };
+        // Three days in a row ending today, then a gap, then one older day
+        record_session(&conn, &session(today - 10 * day, 60, 0.0, 5.0)).unwrap();
+        record_session(&conn, &session(today - 2 * day, 30, 5.0, 10.0)).unwrap();
+        record_session(&conn, &session(today - day, 30, 10.0, 20.0)).unwrap();
+        record_session(&conn, &session(today, 15, 20.0, 25.0)).unwrap();
+        record_session(
+            &conn,
+            &ReadingSession {
+                book_id: 2,
+                started_at: today,
+                ended_at: today + 600,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let stats =
+            get_reading_stats(&conn, &books, &HashSet::new(), &StatsQuery::default()).unwrap();
+        assert_eq!(stats.sessions, 5);
+        assert_eq!(stats.total_seconds, (60 + 30 + 30 + 15 + 10) * 60);
+        assert_eq!(stats.current_streak, 3);
+        assert_eq!(stats.longest_streak, 3);
+        assert_eq!(stats.per_book[0].book_id, 1);
+        assert_eq!(stats.per_book[0].title.as_deref(), Some("Good Omens"));
+        assert_eq!(stats.per_book[0].pages_read, 40);
+        assert_eq!(stats.per_book[0].percentage_read, 25.0);
+        assert_eq!(stats.per_book[1].title, None);
+        assert_eq!(stats.per_author.len(), 2);
+        assert_eq!(stats.per_author[0].seconds, (60 + 30 + 30 + 15) * 60);
+
+        // Limiting the range leaves streaks untouched
+        let recent = StatsQuery {
+            from: Some(today - 3 * day),
+            ..Default::default()
+        };
+        let stats = get_reading_stats(&conn, &books, &HashSet::new(), &recent).unwrap();
+        assert_eq!(stats.sessions, 4);
+        assert_eq!(stats.longest_streak, 3);
+    }
+
+    #[test]
+    fn test_finished_per_year() {
+        let dir = tempdir().unwrap();
+        let conn = open_progress_db(dir.path()).unwrap();
+        for (book_id, updated_at) in [(1, 1_600_000_000), (2, 1_700_000_000), (3, 1_700_100_000)] {
+            update_progress(
+                &conn,
+                &ProgressUpdate {
+                    book_id,
+                    status: ReadingStatus::Finished,
+                    updated_at: Some(updated_at),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        }
+
+        let stats = get_reading_stats(&conn, &[], &HashSet::new(), &StatsQuery::default()).unwrap();
+        assert_eq!(
+            stats.finished_per_year,
+            vec![
+                YearStats {
+                    year: 2020,
+                    finished: 1
+                },
+                YearStats {
+                    year: 2023,
+                    finished: 2
+                },
+            ]
+        );
+    }
 }
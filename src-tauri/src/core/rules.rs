@@ -0,0 +1,314 @@
+use crate::core::progress::{ProgressRecord, ReadingStatus};
+use crate::error::AppError;
+use crate::models::Book;
+use std::collections::{HashMap, HashSet};
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+const BYTES_PER_MEGABYTE: f64 = 1024.0 * 1024.0;
+
+/// A saved rule choosing which of a host's books to keep on this device,
+/// instead of picking them by hand.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct SyncRule {
+    pub id: String,
+    pub name: String,
+    /// Library on the host the rule applies to; `None` for its default one.
+    #[serde(default)]
+    pub library_id: Option<String>,
+    /// A book must meet every condition. A rule without conditions matches
+    /// nothing, rather than the whole library.
+    pub conditions: Vec<Condition>,
+    /// Re-run the rule whenever the host's library changes.
+    #[serde(default)]
+    pub auto_sync: bool,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Condition {
+    /// Books with the tag, or a tag nested under it.
+    Tag { tag: String },
+    /// Books being read in a series, and the next `count` unread volumes
+    /// after the furthest volume started.
+    SeriesInProgress { count: usize },
+    /// Books added to the library in the last `days` days. Books without a
+    /// known date do not match.
+    AddedWithin { days: u32 },
+    /// Books no larger than `megabytes`. Books of unknown size match, as
+    /// hosts from before sizes were listed do not report them.
+    MaxSize { megabytes: f64 },
+    /// Books with the reading status, e.g. `want-to-read`.
+    Status { status: ReadingStatus },
+}
+
+/// What running rules would download, shown before and after running them.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
+pub struct SyncPlan {
+    /// Matching books not yet on this device, in manifest order.
+    pub books: Vec<Book>,
+    /// How many matching books are already on this device.
+    pub already_synced: usize,
+    /// Combined size of `books` in bytes, counting those of known size.
+    pub total_size: u64,
+}
+
+/// Books matching any of `rules`, in manifest order. `now` is a Unix
+/// timestamp.
+pub fn matching_books(
+    rules: &[SyncRule],
+    books: &[Book],
+    progress: &[ProgressRecord],
+    now: i64,
+) -> Vec<Book> {
+    let statuses: HashMap<i64, ReadingStatus> = progress
+        .iter()
+        .map(|record| (record.book_id, record.status))
+        .collect();
+    let mut matched = HashSet::new();
+    for rule in rules.iter().filter(|rule| !rule.conditions.is_empty()) {
+        let sets: Vec<Option<HashSet<i64>>> = rule
+            .conditions
+            .iter()
+            .map(|condition| match condition {
+                Condition::SeriesInProgress { count } => {
+                    Some(series_in_progress(books, &statuses, *count))
+                }
+                _ => None,
+            })
+            .collect();
+        matched.extend(
+            books
+                .iter()
+                .filter(|book| {
+                    rule.conditions
+                        .iter()
+                        .zip(&sets)
+                        .all(|(condition, set)| match set {
+                            Some(ids) => ids.contains(&book.id),
+                            None => meets(condition, book, &statuses, now),
+                        })
+                })
+                .map(|book| book.id),
+        );
+    }
+    books
+        .iter()
+        .filter(|book| matched.contains(&book.id))
+        .cloned()
+        .collect()
+}
+
+/// Splits `matched` books into those to download and those `is_synced`
+/// says are already on this device.
+pub fn plan(matched: Vec<Book>, is_synced: impl Fn(&Book) -> bool) -> SyncPlan {
+    let (synced, books): (Vec<Book>, Vec<Book>) = matched.into_iter().partition(is_synced);
+    SyncPlan {
+        total_size: books.iter().filter_map(|book| book.size).sum(),
+        already_synced: synced.len(),
+        books,
+    }
+}
+
+/// Fetches the manifest and reading progress of a host's library, from
+/// `api_base` as built by `discovery::api_base`.
+pub async fn fetch_library(
+    client: &reqwest::Client,
+    api_base: &str,
+    token: &str,
+) -> Result<(Vec<Book>, Vec<ProgressRecord>), AppError> {
+    let books = fetch_json(client, &format!("{}/manifest", api_base), token).await?;
+    let progress = fetch_json(client, &format!("{}/progress", api_base), token).await?;
+    Ok((books, progress))
+}
+
+async fn fetch_json<T: serde::de::DeserializeOwned>(
+    client: &reqwest::Client,
+    url: &str,
+    token: &str,
+) -> Result<T, AppError> {
+    let response = client
+        .get(url)
+        .bearer_auth(token)
+        .send()
+        .await
+        .map_err(|e| AppError::Other(format!("Request to {} failed: {}", url, e)))?;
+    if !response.status().is_success() {
+        return Err(AppError::Other(format!(
+            "Host answered {} for {}",
+            response.status(),
+            url
+        )));
+    }
+    response
+        .json()
+        .await
+        .map_err(|e| AppError::Other(format!("Malformed response from {}: {}", url, e)))
+}
+
+fn meets(
+    condition: &Condition,
+    book: &Book,
+    statuses: &HashMap<i64, ReadingStatus>,
+    now: i64,
+) -> bool {
+    match condition {
+        Condition::Tag { tag } => book.has_tag(tag),
+        Condition::SeriesInProgress { .. } => false,
+        Condition::AddedWithin { days } => book
+            .added_at
+            .is_some_and(|added| added >= now - i64::from(*days) * SECONDS_PER_DAY),
+        Condition::MaxSize { megabytes } => book
+            .size
+            .is_none_or(|size| size as f64 <= megabytes * BYTES_PER_MEGABYTE),
+        Condition::Status { status } => {
+            statuses.get(&book.id).copied().unwrap_or_default() == *status
+        }
+    }
+}
+
+/// IDs of the books being read in each series, and of the next `count`
+/// unread volumes after the furthest volume started.
+fn series_in_progress(
+    books: &[Book],
+    statuses: &HashMap<i64, ReadingStatus>,
+    count: usize,
+) -> HashSet<i64> {
+    let status = |book: &Book| statuses.get(&book.id).copied().unwrap_or_default();
+    let mut series: HashMap<String, Vec<&Book>> = HashMap::new();
+    for book in books {
+        if let Some(name) = &book.series {
+            series.entry(name.to_lowercase()).or_default().push(book);
+        }
+    }
+
+    let mut ids = HashSet::new();
+    for volumes in series.values_mut() {
+        volumes.sort_by(|a, b| a.series_index.total_cmp(&b.series_index));
+        let Some(furthest) = volumes.iter().rposition(|book| {
+            matches!(
+                status(book),
+                ReadingStatus::Reading | ReadingStatus::Finished
+            )
+        }) else {
+            continue;
+        };
+        ids.extend(
+            volumes
+                .iter()
+                .filter(|book| status(book) == ReadingStatus::Reading)
+                .map(|book| book.id),
+        );
+        ids.extend(
+            volumes[furthest + 1..]
+                .iter()
+                .filter(|book| {
+                    matches!(
+                        status(book),
+                        ReadingStatus::Unread | ReadingStatus::WantToRead
+                    )
+                })
+                .take(count)
+                .map(|book| book.id),
+        );
+    }
+    ids
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: i64 = 1_700_000_000;
+
+    fn book(id: i64, series: Option<(&str, f64)>, tags: &[&str]) -> Book {
+        Book {
+            series: series.map(|(name, _)| name.to_string()),
+            series_index: series.map_or(1.0, |(_, index)| index),
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+            added_at: Some(NOW - 60 * SECONDS_PER_DAY),
+            size: Some(1024 * 1024),
+            ..Book::test(id, &format!("Book {}", id))
+        }
+    }
+
+    fn progress(book_id: i64, status: ReadingStatus) -> ProgressRecord {
+        ProgressRecord {
+            book_id,
+            status,
+            last_updated: NOW,
+            percentage: None,
+            locator: None,
+            page: None,
+            page_count: None,
+            chapter: None,
+            device: None,
+        }
+    }
+
+    fn rule(conditions: Vec<Condition>) -> SyncRule {
+        SyncRule {
+            id: "rule".to_string(),
+            name: "Rule".to_string(),
+            library_id: None,
+            conditions,
+            auto_sync: false,
+        }
+    }
+
+    fn ids(books: &[Book]) -> Vec<i64> {
+        books.iter().map(|book| book.id).collect()
+    }
+
+    #[test]
+    fn test_rules_select_books() {
+        let mut books: Vec<Book> = (1..=5)
+            .map(|i| book(i, Some(("Dune", i as f64)), &[]))
+            .collect();
+        books.push(book(6, None, &["To Read.Sci-Fi"]));
+        books.push(book(7, None, &["To Read"]));
+        books[6].added_at = Some(NOW - SECONDS_PER_DAY);
+        books[6].size = Some(80 * 1024 * 1024);
+        let progress = vec![
+            progress(1, ReadingStatus::Finished),
+            progress(2, ReadingStatus::Reading),
+            progress(3, ReadingStatus::Abandoned),
+            progress(6, ReadingStatus::WantToRead),
+        ];
+
+        let series = rule(vec![Condition::SeriesInProgress { count: 1 }]);
+        assert_eq!(
+            ids(&matching_books(&[series], &books, &progress, NOW)),
+            vec![2, 4]
+        );
+
+        let tagged = rule(vec![Condition::Tag {
+            tag: "to read".to_string(),
+        }]);
+        let small = rule(vec![
+            Condition::Tag {
+                tag: "To Read".to_string(),
+            },
+            Condition::MaxSize { megabytes: 50.0 },
+        ]);
+        let recent = rule(vec![Condition::AddedWithin { days: 30 }]);
+        let wanted = rule(vec![Condition::Status {
+            status: ReadingStatus::WantToRead,
+        }]);
+        assert_eq!(
+            ids(&matching_books(&[tagged], &books, &progress, NOW)),
+            vec![6, 7]
+        );
+        assert_eq!(
+            ids(&matching_books(&[small], &books, &progress, NOW)),
+            vec![6]
+        );
+        let matched = matching_books(&[recent, wanted], &books, &progress, NOW);
+        assert_eq!(ids(&matched), vec![6, 7]);
+        assert!(matching_books(&[rule(vec![])], &books, &progress, NOW).is_empty());
+
+        let plan = plan(matched, |book| book.id == 6);
+        assert_eq!(ids(&plan.books), vec![7]);
+        assert_eq!(plan.already_synced, 1);
+        assert_eq!(plan.total_size, 80 * 1024 * 1024);
+    }
+}
@@ -0,0 +1,599 @@
+use crate::error::AppError;
+use crate::models::Book;
+use regex::{Regex, RegexBuilder};
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+
+/// How deep `search:` and `vl:` references are followed, so searches that
+/// refer to each other cannot recurse forever.
+const MAX_REFERENCE_DEPTH: usize = 8;
+
+/// How deep parentheses and `not`s may nest, so a crafted search cannot
+/// overflow the stack while being parsed or evaluated.
+const MAX_NESTING_DEPTH: usize = 64;
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SearchKind {
+    VirtualLibrary,
+    SavedSearch,
+}
+
+/// A virtual library or saved search defined in Calibre.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct SavedSearch {
+    pub name: String,
+    pub kind: SearchKind,
+    /// The search in Calibre's syntax, e.g. `tags:"=Fiction" and not #read:true`.
+    pub query: String,
+}
+
+/// Values of one Calibre custom column.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CustomValues {
+    /// Calibre's type for the column, e.g. `text`, `bool` or `datetime`.
+    pub datatype: String,
+    /// Values by book ID. Yes/No columns only hold `yes`, for the books
+    /// where they are set to yes.
+    pub values: HashMap<i64, Vec<String>>,
+}
+
+/// What searches are evaluated against besides the books themselves: the
+/// library's named searches and its custom column values, read along with
+/// its books.
+#[derive(Debug, Clone, Default)]
+pub struct SearchIndex {
+    pub searches: Vec<SavedSearch>,
+    /// Custom columns by lookup name, lowercased and without the `#`.
+    pub columns: HashMap<String, CustomValues>,
+}
+
+impl SearchIndex {
+    pub fn find(&self, kind: SearchKind, name: &str) -> Option<&SavedSearch> {
+        self.searches
+            .iter()
+            .find(|search| search.kind == kind && search.name.eq_ignore_ascii_case(name))
+    }
+
+    /// IDs of the `books` matching `query`.
+    pub fn matching_ids(&self, query: &str, books: &[Book]) -> Result<HashSet<i64>, AppError> {
+        let expr = self.compile(query, 0)?;
+        Ok(books
+            .iter()
+            .filter(|book| self.matches(&expr, book))
+            .map(|book| book.id)
+            .collect())
+    }
+
+    /// Parses `query`, inlining the searches it refers to.
+    fn compile(&self, query: &str, depth: usize) -> Result<Expr, AppError> {
+        if depth > MAX_REFERENCE_DEPTH {
+            return Err(AppError::InvalidSearch(
+                "searches refer to each other too deeply".to_string(),
+            ));
+        }
+        self.resolve(parse(query)?, depth)
+    }
+
+    fn resolve(&self, expr: Expr, depth: usize) -> Result<Expr, AppError> {
+        Ok(match expr {
+            Expr::And(terms) => Expr::And(self.resolve_all(terms, depth)?),
+            Expr::Or(terms) => Expr::Or(self.resolve_all(terms, depth)?),
+            Expr::Not(a) => Expr::Not(Box::new(self.resolve(*a, depth)?)),
+            Expr::Reference(kind, name) => {
+                let search = self.find(kind, &name).ok_or_else(|| {
+                    AppError::InvalidSearch(format!(
+                        "no saved search or virtual library {:?}",
+                        name
+                    ))
+                })?;
+                self.compile(&search.query, depth + 1)?
+            }
+            Expr::Term(Field::Custom(label), pattern) => {
+                let is_bool = self
+                    .columns
+                    .get(&label)
+                    .is_some_and(|column| column.datatype == "bool");
+                Expr::Term(Field::Custom(label), bool_pattern(pattern, is_bool))
+            }
+            expr => expr,
+        })
+    }
+
+    fn resolve_all(&self, terms: Vec<Expr>, depth: usize) -> Result<Vec<Expr>, AppError> {
+        terms
+            .into_iter()
+            .map(|term| self.resolve(term, depth))
+            .collect()
+    }
+
+    fn matches(&self, expr: &Expr, book: &Book) -> bool {
+        match expr {
+            Expr::All => true,
+            Expr::And(terms) => terms.iter().all(|term| self.matches(term, book)),
+            Expr::Or(terms) => terms.iter().any(|term| self.matches(term, book)),
+            Expr::Not(a) => !self.matches(a, book),
+            Expr::Term(field, pattern) => pattern.matches(&self.values(field, book)),
+            // Inlined by `resolve`
+            Expr::Reference(..) => false,
+        }
+    }
+
+    fn values<'a>(&'a self, field: &Field, book: &'a Book) -> Vec<Cow<'a, str>> {
+        let borrowed = |values: &'a [String]| values.iter().map(|v| Cow::from(v.as_str()));
+        match field {
+            Field::Any => std::iter::once(Cow::from(book.title.as_str()))
+                .chain(authors(book).map(Cow::from))
+                .chain(borrowed(&book.tags))
+                .chain(book.series.as_deref().map(Cow::from))
+                .chain(book.publisher.as_deref().map(Cow::from))
+                .collect(),
+            Field::Title => vec![Cow::from(book.title.as_str())],
+            Field::Authors => authors(book).map(Cow::from).collect(),
+            Field::Tags => borrowed(&book.tags).collect(),
+            Field::Series => book.series.as_deref().map(Cow::from).into_iter().collect(),
+            Field::SeriesIndex => vec![Cow::from(book.series_index.to_string())],
+            Field::Publisher => book
+                .publisher
+                .as_deref()
+                .map(Cow::from)
+                .into_iter()
+                .collect(),
+            Field::Formats => borrowed(&book.formats).collect(),
+            Field::Custom(label) => self
+                .columns
+                .get(label)
+                .and_then(|column| column.values.get(&book.id))
+                .map(|values| borrowed(values).collect())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+fn authors(book: &Book) -> impl Iterator<Item = &str> {
+    book.authors.split(", ").filter(|a| !a.is_empty())
+}
+
+/// Yes/No columns are searched with `true`/`false` or `yes`/`no`; `false`
+/// also matches books where the column is empty.
+fn bool_pattern(pattern: Pattern, is_bool: bool) -> Pattern {
+    if !is_bool {
+        return pattern;
+    }
+    let value = match &pattern {
+        Pattern::Contains(value) | Pattern::Exact(value) => value.as_str(),
+        _ => return pattern,
+    };
+    match value {
+        "yes" | "checked" => Pattern::Present(true),
+        "no" | "unchecked" => Pattern::Present(false),
+        _ => pattern,
+    }
+}
+
+#[derive(Debug)]
+enum Expr {
+    /// The empty search, matching every book.
+    All,
+    /// Every term matches. Chains are kept flat rather than nested, so a long
+    /// search does not recurse once per term.
+    And(Vec<Expr>),
+    /// Any term matches.
+    Or(Vec<Expr>),
+    Not(Box<Expr>),
+    Term(Field, Pattern),
+    /// `search:name` or `vl:name`, replaced by the referenced search.
+    Reference(SearchKind, String),
+}
+
+#[derive(Debug)]
+enum Field {
+    /// A bare word, matched against title, authors, tags, series and publisher.
+    Any,
+    Title,
+    Authors,
+    Tags,
+    Series,
+    SeriesIndex,
+    Publisher,
+    Formats,
+    /// A custom column, by lowercased lookup name without the `#`.
+    Custom(String),
+}
+
+#[derive(Debug)]
+enum Pattern {
+    /// Case-insensitive substring, the default.
+    Contains(String),
+    /// `=value`: the whole value, case-insensitive, or the same number.
+    Exact(String),
+    /// `.value`: a tag and every tag nested under it, e.g. `Fiction.Fantasy`.
+    Hierarchy(String),
+    /// `~regex`, case-insensitive.
+    Regex(Regex),
+    /// `true` or `false`: whether the field has a value at all.
+    Present(bool),
+    /// `>value`, `>=value`, `<value` or `<=value`, numerically when both
+    /// sides are numbers and alphabetically otherwise (so ISO dates work).
+    Compare(Vec<Ordering>, String),
+}
+
+impl Pattern {
+    fn parse(value: &str) -> Result<Pattern, AppError> {
+        let lower = value.to_lowercase();
+        for (prefix, orderings) in [
+            (">=", vec![Ordering::Greater, Ordering::Equal]),
+            ("<=", vec![Ordering::Less, Ordering::Equal]),
+            (">", vec![Ordering::Greater]),
+            ("<", vec![Ordering::Less]),
+        ] {
+            if let Some(rest) = lower.strip_prefix(prefix) {
+                return Ok(Pattern::Compare(orderings, rest.to_string()));
+            }
+        }
+        if let Some(rest) = lower.strip_prefix('=') {
+            return Ok(Pattern::Exact(rest.to_string()));
+        }
+        if let Some(rest) = value.strip_prefix('~') {
+            let regex = RegexBuilder::new(rest)
+                .case_insensitive(true)
+                .build()
+                .map_err(|e| AppError::InvalidSearch(e.to_string()))?;
+            return Ok(Pattern::Regex(regex));
+        }
+        if let Some(rest) = lower.strip_prefix('.') {
+            return Ok(Pattern::Hierarchy(rest.to_string()));
+        }
+        Ok(match lower.as_str() {
+            "true" => Pattern::Present(true),
+            "false" => Pattern::Present(false),
+            _ => Pattern::Contains(lower),
+        })
+    }
+
+    fn matches(&self, values: &[Cow<str>]) -> bool {
+        match self {
+            Pattern::Present(present) => values.iter().any(|v| !v.is_empty()) == *present,
+            _ => values.iter().any(|value| self.matches_value(value)),
+        }
+    }
+
+    fn matches_value(&self, value: &str) -> bool {
+        match self {
+            Pattern::Contains(needle) => value.to_lowercase().contains(needle.as_str()),
+            Pattern::Exact(expected) => compare(value, expected) == Ordering::Equal,
+            Pattern::Hierarchy(parent) => {
+                let value = value.to_lowercase();
+                value
+                    .strip_prefix(parent.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+            }
+            Pattern::Regex(regex) => regex.is_match(value),
+            Pattern::Compare(orderings, other) => orderings.contains(&compare(value, other)),
+            Pattern::Present(_) => !value.is_empty(),
+        }
+    }
+}
+
+/// Compares a book's value with a searched one (already lowercased).
+fn compare(value: &str, searched: &str) -> Ordering {
+    match (value.trim().parse::<f64>(), searched.trim().parse::<f64>()) {
+        (Ok(a), Ok(b)) => a.partial_cmp(&b).unwrap_or(Ordering::Equal),
+        _ => value.to_lowercase().as_str().cmp(searched),
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Open,
+    Close,
+    /// A word as written, quotes included.
+    Word(String),
+}
+
+fn tokenize(query: &str) -> Result<Vec<Token>, AppError> {
+    let mut tokens = Vec::new();
+    let mut word = String::new();
+    let mut in_quotes = false;
+    let mut chars = query.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                in_quotes = !in_quotes;
+                word.push(c);
+            }
+            '\\' if in_quotes => {
+                word.push(c);
+                if let Some(escaped) = chars.next() {
+                    word.push(escaped);
+                }
+            }
+            _ if in_quotes => word.push(c),
+            '(' | ')' => {
+                if !word.is_empty() {
+                    tokens.push(Token::Word(std::mem::take(&mut word)));
+                }
+                tokens.push(if c == '(' { Token::Open } else { Token::Close });
+            }
+            _ if c.is_whitespace() => {
+                if !word.is_empty() {
+                    tokens.push(Token::Word(std::mem::take(&mut word)));
+                }
+            }
+            _ => word.push(c),
+        }
+    }
+    if in_quotes {
+        return Err(AppError::InvalidSearch("unclosed quote".to_string()));
+    }
+    if !word.is_empty() {
+        tokens.push(Token::Word(word));
+    }
+    Ok(tokens)
+}
+
+/// Removes the quotes from a word and resolves `\"` and `\\` escapes.
+fn unquote(raw: &str) -> String {
+    let mut value = String::new();
+    let mut chars = raw.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {}
+            '\\' => value.extend(chars.next()),
+            _ => value.push(c),
+        }
+    }
+    value
+}
+
+/// Parses the common subset of Calibre's search syntax: `field:value` terms
+/// (`title`, `authors`, `tags`, `series`, `series_index`, `publisher`,
+/// `formats`, `#custom`, and `search:`/`vl:` references), bare words, `and`,
+/// `or`, `not` (also implicit `and` between terms) and parentheses.
+fn parse(query: &str) -> Result<Expr, AppError> {
+    let tokens = tokenize(query)?;
+    if tokens.is_empty() {
+        return Ok(Expr::All);
+    }
+    let mut parser = Parser {
+        tokens,
+        pos: 0,
+        depth: 0,
+    };
+    let expr = parser.or()?;
+    if parser.pos < parser.tokens.len() {
+        return Err(AppError::InvalidSearch(
+            "unbalanced parentheses".to_string(),
+        ));
+    }
+    Ok(expr)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    /// Parentheses and `not`s currently open.
+    depth: usize,
+}
+
+impl Parser {
+    fn peek_operator(&self) -> Option<&str> {
+        match self.tokens.get(self.pos) {
+            Some(Token::Word(word)) => ["and", "or", "not"]
+                .into_iter()
+                .find(|op| word.eq_ignore_ascii_case(op)),
+            _ => None,
+        }
+    }
+
+    /// Runs `parse` one nesting level deeper.
+    fn nested(
+        &mut self,
+        parse: impl FnOnce(&mut Self) -> Result<Expr, AppError>,
+    ) -> Result<Expr, AppError> {
+        if self.depth == MAX_NESTING_DEPTH {
+            return Err(AppError::InvalidSearch(format!(
+                "nested more than {} levels deep",
+                MAX_NESTING_DEPTH
+            )));
+        }
+        self.depth += 1;
+        let expr = parse(self);
+        self.depth -= 1;
+        expr
+    }
+
+    fn or(&mut self) -> Result<Expr, AppError> {
+        let mut terms = vec![self.and()?];
+        while self.peek_operator() == Some("or") {
+            self.pos += 1;
+            terms.push(self.and()?);
+        }
+        Ok(flatten(terms, Expr::Or))
+    }
+
+    fn and(&mut self) -> Result<Expr, AppError> {
+        let mut terms = vec![self.not()?];
+        loop {
+            match (self.peek_operator(), self.tokens.get(self.pos)) {
+                (Some("and"), _) => self.pos += 1,
+                (Some("or"), _) | (_, None) | (_, Some(Token::Close)) => break,
+                // Terms next to each other must all match
+                _ => {}
+            }
+            terms.push(self.not()?);
+        }
+        Ok(flatten(terms, Expr::And))
+    }
+
+    fn not(&mut self) -> Result<Expr, AppError> {
+        if self.peek_operator() == Some("not") {
+            self.pos += 1;
+            return Ok(Expr::Not(Box::new(self.nested(Self::not)?)));
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expr, AppError> {
+        let token = self.tokens.get(self.pos);
+        self.pos += 1;
+        match token {
+            Some(Token::Open) => {
+                let expr = self.nested(Self::or)?;
+                if self.tokens.get(self.pos) != Some(&Token::Close) {
+                    return Err(AppError::InvalidSearch(
+                        "unbalanced parentheses".to_string(),
+                    ));
+                }
+                self.pos += 1;
+                Ok(expr)
+            }
+            Some(Token::Word(word)) => term(word),
+            _ => Err(AppError::InvalidSearch("incomplete search".to_string())),
+        }
+    }
+}
+
+/// A single term as is, several joined with `join`.
+fn flatten(mut terms: Vec<Expr>, join: fn(Vec<Expr>) -> Expr) -> Expr {
+    if terms.len() == 1 {
+        terms.remove(0)
+    } else {
+        join(terms)
+    }
+}
+
+fn term(word: &str) -> Result<Expr, AppError> {
+    // A field name comes before any quote, e.g. `tags:"=Science Fiction"`
+    let field_end = word.find(':').filter(|&i| !word[..i].contains('"'));
+    let Some(i) = field_end else {
+        return Ok(Expr::Term(Field::Any, Pattern::parse(&unquote(word))?));
+    };
+    let name = word[..i].to_lowercase();
+    let value = unquote(&word[i + 1..]);
+    let field = match name.as_str() {
+        "title" => Field::Title,
+        "author" | "authors" => Field::Authors,
+        "tag" | "tags" => Field::Tags,
+        "series" => Field::Series,
+        "series_index" => Field::SeriesIndex,
+        "publisher" => Field::Publisher,
+        "format" | "formats" => Field::Formats,
+        "search" => return Ok(Expr::Reference(SearchKind::SavedSearch, value)),
+        "vl" => return Ok(Expr::Reference(SearchKind::VirtualLibrary, value)),
+        _ => match name.strip_prefix('#') {
+            Some(label) if !label.is_empty() => Field::Custom(label.to_string()),
+            _ => {
+                return Err(AppError::InvalidSearch(format!(
+                    "unknown field {:?}",
+                    &word[..i]
+                )))
+            }
+        },
+    };
+    Ok(Expr::Term(field, Pattern::parse(&value)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn book(id: i64, title: &str, authors: &str, tags: &[&str], series: Option<&str>) -> Book {
+        Book {
+            authors: authors.to_string(),
+            series: series.map(str::to_string),
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+            ..Book::test(id, title)
+        }
+    }
+
+    fn library() -> (SearchIndex, Vec<Book>) {
+        let books = vec![
+            book(
+                1,
+                "Dune",
+                "Frank Herbert",
+                &["Fiction.Science Fiction"],
+                Some("Dune"),
+            ),
+            book(
+                2,
+                "The Hobbit",
+                "J. R. R. Tolkien",
+                &["Fiction.Fantasy", "Kids"],
+                None,
+            ),
+            book(3, "Cosmos", "Carl Sagan", &["Science"], None),
+        ];
+        let mut read = CustomValues {
+            datatype: "bool".to_string(),
+            ..Default::default()
+        };
+        read.values.insert(1, vec!["yes".to_string()]);
+        let index = SearchIndex {
+            searches: vec![
+                SavedSearch {
+                    name: "Unread".to_string(),
+                    kind: SearchKind::SavedSearch,
+                    query: "#read:false".to_string(),
+                },
+                SavedSearch {
+                    name: "Fiction".to_string(),
+                    kind: SearchKind::VirtualLibrary,
+                    query: "tags:.fiction".to_string(),
+                },
+            ],
+            columns: HashMap::from([("read".to_string(), read)]),
+        };
+        (index, books)
+    }
+
+    fn ids(index: &SearchIndex, books: &[Book], query: &str) -> Vec<i64> {
+        let mut ids: Vec<i64> = index
+            .matching_ids(query, books)
+            .unwrap()
+            .into_iter()
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn test_search_grammar() {
+        let (index, books) = library();
+        assert_eq!(ids(&index, &books, ""), vec![1, 2, 3]);
+        assert_eq!(ids(&index, &books, "tags:fiction"), vec![1, 2]);
+        assert_eq!(ids(&index, &books, "tags:\"=Kids\""), vec![2]);
+        assert_eq!(ids(&index, &books, "tags:=science"), vec![3]);
+        assert_eq!(
+            ids(&index, &books, "authors:tolkien or series:dune"),
+            vec![1, 2]
+        );
+        assert_eq!(ids(&index, &books, "fiction not kids"), vec![1]);
+        assert_eq!(
+            ids(&index, &books, "not (tags:kids or title:~^cos)"),
+            vec![1]
+        );
+        assert_eq!(ids(&index, &books, "series:false"), vec![2, 3]);
+        assert_eq!(ids(&index, &books, "#read:true"), vec![1]);
+        assert_eq!(ids(&index, &books, "#read:no"), vec![2, 3]);
+        assert_eq!(ids(&index, &books, "search:unread and vl:Fiction"), vec![2]);
+
+        assert!(index.matching_ids("tags:(fiction", &books).is_err());
+        assert!(index.matching_ids("rating:>3", &books).is_err());
+        assert!(index.matching_ids("search:missing", &books).is_err());
+
+        let nested = |depth: usize| format!("{}fiction{}", "(".repeat(depth), ")".repeat(depth));
+        assert_eq!(ids(&index, &books, &nested(64)), vec![1, 2]);
+        assert!(matches!(
+            index.matching_ids(&nested(100_000), &books),
+            Err(AppError::InvalidSearch(_))
+        ));
+        assert!(index.matching_ids(&"not ".repeat(100_000), &books).is_err());
+
+        assert_eq!(ids(&index, &books, &"o ".repeat(100_000)), vec![1, 2, 3]);
+        let chained = format!("{}kids", "title:zzz or ".repeat(100_000));
+        assert_eq!(ids(&index, &books, &chained), vec![2]);
+    }
+}
@@ -1,17 +1,22 @@
+use crate::core::discovery;
 use crate::models::Book;
 use futures_util::StreamExt;
 use reqwest::Client;
 use serde::Serialize;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use tauri::{AppHandle, Emitter, Runtime};
 use tokio::sync::mpsc;
 
 #[derive(Serialize, Clone, Debug)]
 pub struct SyncProgress {
+    /// The download's `SyncTask::key`, as book IDs repeat across hosts and libraries.
+    pub key: String,
     pub book_id: i64,
     pub title: String,
+    /// Where the book is being saved.
+    pub path: PathBuf,
     pub progress: f64,  // 0.0 to 1.0
     pub status: String, // "downloading", "completed", "error"
     pub error: Option<String>,
@@ -25,13 +30,64 @@ pub struct SyncTask {
     pub host_ip: String,
     pub host_port: u16,
     pub token: String,
+    /// Stable identity of the host, its ID or else its address.
+    pub host_key: String,
+    /// Library on the host the book is from; `None` for the default one.
+    pub library_id: Option<String>,
     pub destination_root: PathBuf,
 }
 
+impl SyncTask {
+    /// Identifies the download across every host and library.
+    pub fn key(&self) -> String {
+        queue_key(&self.host_key, self.library_id.as_deref(), self.book.id)
+    }
+
+    /// Where the book is saved, inside its host and library's own folder.
+    pub fn destination(&self) -> PathBuf {
+        library_dir(
+            &self.destination_root,
+            &self.host_key,
+            self.library_id.as_deref(),
+        )
+        .join(&self.book.path)
+    }
+}
+
+/// Key of the download of `book_id` from a host's library.
+pub fn queue_key(host_key: &str, library_id: Option<&str>, book_id: i64) -> String {
+    format!(
+        "{}/{}/{}",
+        host_key,
+        library_id.unwrap_or_default(),
+        book_id
+    )
+}
+
+/// Folder under `root` holding the books synced from a host's library, so
+/// books at the same path in different libraries do not overwrite each other.
+pub fn library_dir(root: &Path, host_key: &str, library_id: Option<&str>) -> PathBuf {
+    let folder = |name: &str| -> String {
+        name.chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect()
+    };
+    root.join("hosts")
+        .join(folder(host_key))
+        .join(library_id.map_or_else(|| "default".to_string(), folder))
+}
+
 #[derive(Clone)]
 pub struct SyncManager {
     sender: mpsc::Sender<SyncTask>,
-    pub active_queue: Arc<Mutex<Vec<Book>>>,
+    /// Keys of the downloads waiting or in progress, the current one first.
+    pub active_queue: Arc<Mutex<Vec<String>>>,
 }
 
 impl SyncManager {
@@ -69,7 +125,7 @@ impl SyncManager {
             tasks
                 .into_iter()
                 .inspect(|task| {
-                    queue.push(task.book.clone());
+                    queue.push(task.key());
                 })
                 .collect()
         }; // Lock is dropped here
@@ -85,16 +141,17 @@ async fn process_task<R: Runtime>(
     app: &AppHandle<R>,
     client: &Client,
     task: &SyncTask,
-    queue: &Arc<Mutex<Vec<Book>>>,
+    queue: &Arc<Mutex<Vec<String>>>,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let book = &task.book;
     let url = format!(
-        "http://{}:{}/api/download/{}/best",
-        task.host_ip, task.host_port, book.id
+        "{}/download/{}/best",
+        discovery::api_base(&task.host_ip, task.host_port, task.library_id.as_deref()),
+        book.id
     );
 
     // Create destination dir
-    let dest_path = task.destination_root.join(&book.path);
+    let dest_path = task.destination();
     if let Some(parent) = dest_path.parent() {
         fs::create_dir_all(parent)?;
     }
@@ -108,7 +165,7 @@ async fn process_task<R: Runtime>(
     if !response.status().is_success() {
         emit_progress(
             app,
-            book,
+            task,
             0.0,
             "error",
             Some("Server returned error".to_string()),
@@ -122,7 +179,7 @@ async fn process_task<R: Runtime>(
     let mut stream = response.bytes_stream();
     let mut file = fs::File::create(&dest_path)?;
 
-    emit_progress(app, book, 0.0, "downloading", None, queue);
+    emit_progress(app, task, 0.0, "downloading", None, queue);
 
     while let Some(item) = stream.next().await {
         let chunk = item?;
@@ -131,21 +188,21 @@ async fn process_task<R: Runtime>(
 
         if total_size > 0 {
             let progress = downloaded as f64 / total_size as f64;
-            emit_progress(app, book, progress, "downloading", None, queue);
+            emit_progress(app, task, progress, "downloading", None, queue);
         }
     }
 
-    emit_progress(app, book, 1.0, "completed", None, queue);
+    emit_progress(app, task, 1.0, "completed", None, queue);
     Ok(())
 }
 
 fn emit_progress<R: Runtime>(
     app: &AppHandle<R>,
-    book: &Book,
+    task: &SyncTask,
     progress: f64,
     status: &str,
     error: Option<String>,
-    queue: &Arc<Mutex<Vec<Book>>>,
+    queue: &Arc<Mutex<Vec<String>>>,
 ) {
     let (pos, total) = {
         let q = queue.lock().unwrap();
@@ -155,8 +212,10 @@ fn emit_progress<R: Runtime>(
     let _ = app.emit(
         "sync-progress",
         SyncProgress {
-            book_id: book.id,
-            title: book.title.clone(),
+            key: task.key(),
+            book_id: task.book.id,
+            title: task.book.title.clone(),
+            path: task.destination(),
             progress,
             status: status.to_string(),
             error,
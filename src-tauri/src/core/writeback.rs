@@ -0,0 +1,559 @@
+use crate::core::progress::{ProgressRecord, ReadingStatus};
+use crate::error::AppError;
+use rusqlite::{Connection, ErrorCode, OpenFlags, OptionalExtension};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Settings file for Calibre write-back, stored in the app data dir.
+const CONFIG_FILE: &str = "calibre_writeback.json";
+
+/// Number of `metadata.db` backups kept in `app_data_dir/calibre_backups`.
+const BACKUPS_TO_KEEP: usize = 5;
+
+/// How long SQLite waits on a lock before reporting `SQLITE_BUSY`.
+const BUSY_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Attempts at taking the database lock before giving up.
+const WRITE_ATTEMPTS: u32 = 3;
+const RETRY_DELAY: Duration = Duration::from_millis(250);
+
+/// Calibre programs that keep a library open and write its metadata back.
+const CALIBRE_PROCESSES: &[&str] = &["calibre", "calibre-server", "calibredb"];
+
+/// Which Calibre custom columns to fill from ShelfSync progress. Columns are
+/// referenced by their lookup name without the `#` (e.g. `read` for `#read`).
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct WritebackConfig {
+    pub enabled: bool,
+    /// Yes/No column set when a book is finished.
+    pub read_column: Option<String>,
+    /// Integer (or float) column holding the percentage read.
+    pub progress_column: Option<String>,
+    /// Date column holding when the book was last read.
+    pub last_read_column: Option<String>,
+}
+
+/// A Calibre custom column that can receive progress.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct CustomColumn {
+    pub id: i64,
+    pub label: String,
+    pub name: String,
+    pub datatype: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct WritebackReport {
+    /// Books whose columns were written.
+    pub updated: usize,
+    /// Progress records for books that are no longer in the library.
+    pub skipped: usize,
+    pub backup: Option<PathBuf>,
+}
+
+pub fn load_config(app_data_dir: &Path) -> Result<WritebackConfig, AppError> {
+    let path = app_data_dir.join(CONFIG_FILE);
+    if !path.exists() {
+        return Ok(WritebackConfig::default());
+    }
+    let content = std::fs::read_to_string(path)?;
+    serde_json::from_str(&content).map_err(|e| AppError::Other(e.to_string()))
+}
+
+pub fn save_config(app_data_dir: &Path, config: &WritebackConfig) -> Result<(), AppError> {
+    let json = serde_json::to_vec_pretty(config).map_err(|e| AppError::Other(e.to_string()))?;
+    std::fs::write(app_data_dir.join(CONFIG_FILE), json)?;
+    Ok(())
+}
+
+/// Lists custom columns whose type can hold reading state (Yes/No, integer,
+/// float and date columns).
+pub fn list_columns(library_path: &Path) -> Result<Vec<CustomColumn>, AppError> {
+    let conn =
+        Connection::open_with_flags(metadata_db(library_path)?, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let mut stmt = conn.prepare(
+        "SELECT id, label, name, datatype FROM custom_columns
+         WHERE mark_for_delete = 0 AND datatype IN ('bool', 'int', 'float', 'datetime')
+         ORDER BY name",
+    )?;
+    let columns = stmt
+        .query_map([], |row| {
+            Ok(CustomColumn {
+                id: row.get(0)?,
+                label: row.get(1)?,
+                name: row.get(2)?,
+                datatype: row.get(3)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(columns)
+}
+
+/// Writes progress into the configured Calibre custom columns.
+///
+/// Calibre keeps metadata in memory while it runs and would overwrite ours, so
+/// write-back is refused while Calibre is running. `metadata.db` is backed up
+/// first, then every value is written in a single `BEGIN IMMEDIATE` transaction
+/// so Calibre never sees a partial update. If another process still holds the
+/// database, the write is retried a few times and then abandoned with an error;
+/// nothing is changed.
+///
+/// Updated books are marked in `metadata_dirtied`, so Calibre refreshes their
+/// `metadata.opf` backups the next time it opens the library.
+pub fn write_back(
+    library_path: &Path,
+    app_data_dir: &Path,
+    config: &WritebackConfig,
+    records: &[ProgressRecord],
+) -> Result<WritebackReport, AppError> {
+    if calibre_running() {
+        return Err(AppError::Other(
+            "Calibre is running; close Calibre and try again".to_string(),
+        ));
+    }
+    let db_path = metadata_db(library_path)?;
+    let conn = Connection::open_with_flags(&db_path, OpenFlags::SQLITE_OPEN_READ_WRITE)?;
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+
+    let (read, progress, last_read) = with_retry(|| {
+        Ok((
+            resolve_column(&conn, config.read_column.as_deref(), &["bool"])?,
+            resolve_column(&conn, config.progress_column.as_deref(), &["int", "float"])?,
+            resolve_column(&conn, config.last_read_column.as_deref(), &["datetime"])?,
+        ))
+    })?;
+    if read.is_none() && progress.is_none() && last_read.is_none() {
+        return Err(AppError::Other(
+            "No Calibre columns selected for write-back".to_string(),
+        ));
+    }
+
+    let backup = with_retry(|| backup_metadata(&conn, app_data_dir))?;
+
+    with_retry(|| Ok(conn.execute_batch("BEGIN IMMEDIATE")?))?;
+    match write_records(
+        &conn,
+        records,
+        read.as_ref(),
+        progress.as_ref(),
+        last_read.as_ref(),
+    ) {
+        Ok((updated, skipped)) => {
+            conn.execute_batch("COMMIT")?;
+            Ok(WritebackReport {
+                updated,
+                skipped,
+                backup: Some(backup),
+            })
+        }
+        Err(e) => {
+            let _ = conn.execute_batch("ROLLBACK");
+            Err(e)
+        }
+    }
+}
+
+/// Writes every record's values, returning how many books were updated and
+/// how many records were skipped because the book no longer exists.
+fn write_records(
+    conn: &Connection,
+    records: &[ProgressRecord],
+    read: Option<&CustomColumn>,
+    progress: Option<&CustomColumn>,
+    last_read: Option<&CustomColumn>,
+) -> Result<(usize, usize), AppError> {
+    let (mut updated, mut skipped) = (0, 0);
+
+    for record in records {
+        let exists: bool = conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM books WHERE id = ?1)",
+            [record.book_id],
+            |row| row.get(0),
+        )?;
+        if !exists {
+            skipped += 1;
+            continue;
+        }
+
+        if let Some(column) = read {
+            let finished = record.status == ReadingStatus::Finished;
+            set_value(conn, column, record.book_id, &finished)?;
+        }
+        if let Some(column) = progress {
+            let percentage = match (record.percentage, record.status) {
+                (Some(p), _) => p,
+                (None, ReadingStatus::Finished) => 100.0,
+                (None, _) => 0.0,
+            };
+            if column.datatype == "int" {
+                set_value(conn, column, record.book_id, &(percentage.round() as i64))?;
+            } else {
+                set_value(conn, column, record.book_id, &percentage)?;
+            }
+        }
+        if let Some(column) = last_read {
+            if record.status != ReadingStatus::Unread {
+                let value = calibre_datetime(record.last_updated);
+                set_value(conn, column, record.book_id, &value)?;
+            }
+        }
+        conn.execute(
+            "INSERT OR IGNORE INTO metadata_dirtied (book) VALUES (?1)",
+            [record.book_id],
+        )?;
+        updated += 1;
+    }
+
+    Ok((updated, skipped))
+}
+
+fn metadata_db(library_path: &Path) -> Result<PathBuf, AppError> {
+    let db_path = library_path.join("metadata.db");
+    if !db_path.exists() {
+        return Err(AppError::LibraryNotFound(
+            library_path.to_string_lossy().to_string(),
+        ));
+    }
+    Ok(db_path)
+}
+
+/// Looks up a column by label and checks that its type suits the value written to it.
+fn resolve_column(
+    conn: &Connection,
+    label: Option<&str>,
+    datatypes: &[&str],
+) -> Result<Option<CustomColumn>, AppError> {
+    let Some(label) = label.map(|l| l.trim_start_matches('#')) else {
+        return Ok(None);
+    };
+
+    let column = conn
+        .query_row(
+            "SELECT id, label, name, datatype FROM custom_columns WHERE label = ?1 AND mark_for_delete = 0",
+            [label],
+            |row| {
+                Ok(CustomColumn {
+                    id: row.get(0)?,
+                    label: row.get(1)?,
+                    name: row.get(2)?,
+                    datatype: row.get(3)?,
+                })
+            },
+        )
+        .optional()?
+        .ok_or_else(|| AppError::Other(format!("Calibre column #{} not found", label)))?;
+
+    if !datatypes.contains(&column.datatype.as_str()) {
+        return Err(AppError::Other(format!(
+            "Calibre column #{} has type {}, expected {}",
+            label,
+            column.datatype,
+            datatypes.join(" or ")
+        )));
+    }
+    Ok(Some(column))
+}
+
+fn set_value(
+    conn: &Connection,
+    column: &CustomColumn,
+    book_id: i64,
+    value: &dyn rusqlite::ToSql,
+) -> Result<(), AppError> {
+    // Columns of these types are stored one row per book in custom_column_<id>
+    conn.execute(
+        &format!(
+            "INSERT INTO custom_column_{} (book, value) VALUES (?1, ?2)
+             ON CONFLICT(book) DO UPDATE SET value = excluded.value",
+            column.id
+        ),
+        rusqlite::params![book_id, value],
+    )?;
+    Ok(())
+}
+
+/// Whether a Calibre program that writes to libraries is running on this machine.
+fn calibre_running() -> bool {
+    running_process_names()
+        .iter()
+        .any(|name| is_calibre_process(name))
+}
+
+/// Whether `name`, a process name or executable path, is one of [`CALIBRE_PROCESSES`].
+fn is_calibre_process(name: &str) -> bool {
+    let file_name = name
+        .rsplit(['/', '\\'])
+        .next()
+        .unwrap_or(name)
+        .to_lowercase();
+    let file_name = file_name.strip_suffix(".exe").unwrap_or(&file_name);
+    CALIBRE_PROCESSES.contains(&file_name)
+}
+
+#[cfg(target_os = "linux")]
+fn running_process_names() -> Vec<String> {
+    let Ok(entries) = std::fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(|entry| std::fs::read_to_string(entry.ok()?.path().join("comm")).ok())
+        .map(|comm| comm.trim_end().to_string())
+        .collect()
+}
+
+#[cfg(windows)]
+fn running_process_names() -> Vec<String> {
+    use std::os::windows::process::CommandExt;
+    // Keeps tasklist from flashing a console window
+    const CREATE_NO_WINDOW: u32 = 0x0800_0000;
+
+    let Ok(output) = std::process::Command::new("tasklist")
+        .args(["/FO", "CSV", "/NH"])
+        .creation_flags(CREATE_NO_WINDOW)
+        .output()
+    else {
+        return Vec::new();
+    };
+    // Each line starts with the quoted image name, e.g. "calibre.exe","1234",...
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| line.split('"').nth(1).map(|name| name.to_string()))
+        .collect()
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+fn running_process_names() -> Vec<String> {
+    let Ok(output) = std::process::Command::new("ps")
+        .args(["-A", "-o", "comm="])
+        .output()
+    else {
+        return Vec::new();
+    };
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(|line| line.trim().to_string())
+        .collect()
+}
+
+/// Runs `op`, retrying while another process holds the database.
+fn with_retry<T>(mut op: impl FnMut() -> Result<T, AppError>) -> Result<T, AppError> {
+    let mut attempt = 1;
+    loop {
+        match op() {
+            Err(AppError::Database(e)) if is_busy(&e) => {
+                if attempt >= WRITE_ATTEMPTS {
+                    return Err(AppError::Other(
+                        "Calibre library is locked; close Calibre and try again".to_string(),
+                    ));
+                }
+                log::warn!("Calibre library is busy, retrying write-back ({})", attempt);
+                attempt += 1;
+                std::thread::sleep(RETRY_DELAY);
+            }
+            result => return result,
+        }
+    }
+}
+
+fn is_busy(e: &rusqlite::Error) -> bool {
+    matches!(
+        e.sqlite_error_code(),
+        Some(ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked)
+    )
+}
+
+/// Copies `metadata.db` into `app_data_dir/calibre_backups`, keeping the most
+/// recent [`BACKUPS_TO_KEEP`] copies.
+fn backup_metadata(conn: &Connection, app_data_dir: &Path) -> Result<PathBuf, AppError> {
+    let dir = app_data_dir.join("calibre_backups");
+    std::fs::create_dir_all(&dir)?;
+
+    let dest = dir.join(format!(
+        "metadata-{}.db",
+        chrono::Utc::now().format("%Y%m%d-%H%M%S%.3f")
+    ));
+    // VACUUM INTO produces a consistent copy even if the file is being written to
+    conn.execute("VACUUM INTO ?1", [dest.to_string_lossy()])?;
+
+    let mut backups: Vec<PathBuf> = std::fs::read_dir(&dir)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "db"))
+        .collect();
+    backups.sort();
+    let excess = backups.len().saturating_sub(BACKUPS_TO_KEEP);
+    for old in &backups[..excess] {
+        std::fs::remove_file(old)?;
+    }
+
+    Ok(dest)
+}
+
+/// Formats a Unix timestamp the way Calibre stores dates.
+fn calibre_datetime(timestamp: i64) -> String {
+    chrono::DateTime::from_timestamp(timestamp, 0)
+        .unwrap_or_default()
+        .format("%Y-%m-%d %H:%M:%S+00:00")
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn create_library(path: &Path) {
+        let conn = Connection::open(path.join("metadata.db")).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE books (id INTEGER PRIMARY KEY, title TEXT);
+             INSERT INTO books (id, title) VALUES (1, 'Dune'), (2, 'Emma');
+             CREATE TABLE custom_columns (
+                id INTEGER PRIMARY KEY, label TEXT, name TEXT, datatype TEXT,
+                mark_for_delete BOOL DEFAULT 0
+             );
+             INSERT INTO custom_columns (id, label, name, datatype) VALUES
+                (1, 'read', 'Read', 'bool'),
+                (2, 'progress', 'Progress %', 'int'),
+                (3, 'lastread', 'Last read', 'datetime'),
+                (4, 'notes', 'Notes', 'comments');
+             CREATE TABLE custom_column_1 (id INTEGER PRIMARY KEY, book INTEGER, value BOOL NOT NULL, UNIQUE(book));
+             CREATE TABLE custom_column_2 (id INTEGER PRIMARY KEY, book INTEGER, value INTEGER NOT NULL, UNIQUE(book));
+             CREATE TABLE custom_column_3 (id INTEGER PRIMARY KEY, book INTEGER, value TIMESTAMP NOT NULL, UNIQUE(book));
+             CREATE TABLE metadata_dirtied (id INTEGER PRIMARY KEY, book INTEGER NOT NULL, UNIQUE(book));",
+        )
+        .unwrap();
+    }
+
+    fn record(book_id: i64, status: ReadingStatus, percentage: Option<f64>) -> ProgressRecord {
+        ProgressRecord {
+            book_id,
+            status,
+            last_updated: 1_700_000_000,
+            percentage,
+            locator: None,
+            page: None,
+            page_count: None,
+            chapter: None,
+            device: None,
+        }
+    }
+
+    fn config() -> WritebackConfig {
+        WritebackConfig {
+            enabled: true,
+            read_column: Some("#read".to_string()),
+            progress_column: Some("progress".to_string()),
+            last_read_column: Some("lastread".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_list_columns_skips_unsuitable_types() {
+        let dir = tempdir().unwrap();
+        create_library(dir.path());
+        let labels: Vec<String> = list_columns(dir.path())
+            .unwrap()
+            .into_iter()
+            .map(|c| c.label)
+            .collect();
+        assert_eq!(labels, vec!["lastread", "progress", "read"]);
+    }
+
+    #[test]
+    fn test_write_back_updates_columns() {
+        let dir = tempdir().unwrap();
+        create_library(dir.path());
+
+        let records = vec![
+            record(1, ReadingStatus::Reading, Some(42.4)),
+            record(2, ReadingStatus::Finished, None),
+            record(99, ReadingStatus::Reading, Some(1.0)),
+        ];
+        let report = write_back(dir.path(), dir.path(), &config(), &records).unwrap();
+        assert_eq!(report.updated, 2);
+        assert_eq!(report.skipped, 1);
+        assert!(report.backup.unwrap().exists());
+
+        let conn = Connection::open(dir.path().join("metadata.db")).unwrap();
+        let progress: i64 = conn
+            .query_row(
+                "SELECT value FROM custom_column_2 WHERE book = 1",
+                [],
+                |r| r.get(0),
+            )
+            .unwrap();
+        assert_eq!(progress, 42);
+        let read: bool = conn
+            .query_row(
+                "SELECT value FROM custom_column_1 WHERE book = 2",
+                [],
+                |r| r.get(0),
+            )
+            .unwrap();
+        assert!(read);
+        let last_read: String = conn
+            .query_row(
+                "SELECT value FROM custom_column_3 WHERE book = 2",
+                [],
+                |r| r.get(0),
+            )
+            .unwrap();
+        assert_eq!(last_read, "2023-11-14 22:13:20+00:00");
+        let dirtied: Vec<i64> = conn
+            .prepare("SELECT book FROM metadata_dirtied ORDER BY book")
+            .unwrap()
+            .query_map([], |r| r.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(dirtied, vec![1, 2]);
+
+        // Running again updates rows in place
+        write_back(dir.path(), dir.path(), &config(), &records[..1]).unwrap();
+        let rows: i64 = conn
+            .query_row("SELECT COUNT(*) FROM custom_column_2", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(rows, 2);
+    }
+
+    #[test]
+    fn test_is_calibre_process() {
+        assert!(is_calibre_process("calibre"));
+        assert!(is_calibre_process("Calibre.exe"));
+        assert!(is_calibre_process(
+            "/Applications/calibre.app/Contents/MacOS/calibre-server"
+        ));
+        assert!(!is_calibre_process("calibre-parallel-helper"));
+        assert!(!is_calibre_process("shelfsync"));
+    }
+
+    #[test]
+    fn test_write_back_rejects_wrong_column_type() {
+        let dir = tempdir().unwrap();
+        create_library(dir.path());
+        let config = WritebackConfig {
+            enabled: true,
+            read_column: Some("notes".to_string()),
+            ..Default::default()
+        };
+        assert!(write_back(dir.path(), dir.path(), &config, &[]).is_err());
+    }
+
+    #[test]
+    fn test_write_back_gives_up_when_locked() {
+        let dir = tempdir().unwrap();
+        create_library(dir.path());
+
+        let holder = Connection::open(dir.path().join("metadata.db")).unwrap();
+        holder.execute_batch("BEGIN EXCLUSIVE").unwrap();
+
+        let records = vec![record(1, ReadingStatus::Reading, Some(10.0))];
+        let result = write_back(dir.path(), dir.path(), &config(), &records);
+        assert!(matches!(result, Err(AppError::Other(msg)) if msg.contains("locked")));
+
+        holder.execute_batch("ROLLBACK").unwrap();
+        let rows: i64 = holder
+            .query_row("SELECT COUNT(*) FROM custom_column_2", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(rows, 0);
+    }
+}
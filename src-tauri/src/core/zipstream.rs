@@ -0,0 +1,368 @@
+use crate::error::AppError;
+use chrono::{Datelike, Timelike};
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, Write};
+use std::path::Path;
+use zip::ZipArchive;
+
+const LOCAL_HEADER: u32 = 0x0403_4b50;
+const CENTRAL_HEADER: u32 = 0x0201_4b50;
+const END_OF_CENTRAL_DIRECTORY: u32 = 0x0605_4b50;
+const ZIP64_END_OF_CENTRAL_DIRECTORY: u32 = 0x0606_4b50;
+const ZIP64_LOCATOR: u32 = 0x0706_4b50;
+const ZIP64_EXTRA: u16 = 0x0001;
+/// General purpose flag marking names as UTF-8.
+const UTF8_NAMES: u16 = 1 << 11;
+const METHOD_STORED: u16 = 0;
+const VERSION_DEFAULT: u16 = 20;
+const VERSION_ZIP64: u16 = 45;
+/// Sizes and offsets from this on only fit in a Zip64 extra field.
+const ZIP64_LIMIT: u64 = 0xFFFF_FFFF;
+/// Entry counts from this on only fit in the Zip64 end record.
+const ZIP64_COUNT_LIMIT: usize = 0xFFFF;
+
+/// Writes a zip front to back, without ever seeking back to patch a header.
+///
+/// `zip::ZipWriter` needs a seekable destination, so a zip written with it has
+/// to be spooled to disk before it can be sent. This writes each entry's
+/// header with its checksum and sizes already known, so the zip can be
+/// streamed to a client while it is being written.
+pub struct ZipStream<W: Write> {
+    out: W,
+    /// Bytes written so far, i.e. where the next entry starts.
+    offset: u64,
+    entries: Vec<CentralEntry>,
+    /// MS-DOS (date, time) given to entries that have none of their own.
+    modified: (u16, u16),
+}
+
+/// What the central directory records about an entry already written.
+struct CentralEntry {
+    name: String,
+    method: u16,
+    crc: u32,
+    compressed_size: u64,
+    size: u64,
+    offset: u64,
+    modified: (u16, u16),
+}
+
+impl CentralEntry {
+    fn needs_zip64(&self) -> bool {
+        self.size >= ZIP64_LIMIT || self.compressed_size >= ZIP64_LIMIT
+    }
+}
+
+impl<W: Write> ZipStream<W> {
+    pub fn new(out: W) -> Self {
+        ZipStream {
+            out,
+            offset: 0,
+            entries: Vec::new(),
+            modified: dos_time(chrono::Local::now().naive_local()),
+        }
+    }
+
+    /// Adds `data` uncompressed.
+    pub fn add_bytes(&mut self, name: &str, data: &[u8]) -> Result<(), AppError> {
+        let size = data.len() as u64;
+        let entry = self.entry(name, METHOD_STORED, crc32fast::hash(data), size, size, None);
+        self.write_local_header(&entry)?;
+        self.out.write_all(data)?;
+        self.offset += size;
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    /// Adds the file at `path` uncompressed.
+    ///
+    /// The file is read twice: once for its checksum, which goes in the header
+    /// before the data, then again to copy it.
+    pub fn add_file(&mut self, name: &str, path: &Path) -> Result<(), AppError> {
+        let mut file = BufReader::new(File::open(path)?);
+        let mut hasher = crc32fast::Hasher::new();
+        let mut size = 0u64;
+        let mut buf = vec![0u8; 64 * 1024];
+        loop {
+            let n = file.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+            size += n as u64;
+        }
+        file.rewind()?;
+
+        let entry = self.entry(name, METHOD_STORED, hasher.finalize(), size, size, None);
+        self.write_local_header(&entry)?;
+        let copied = io::copy(&mut file.take(size), &mut self.out)?;
+        self.offset += copied;
+        if copied != size {
+            return Err(AppError::Other(format!(
+                "{:?} changed while it was being zipped",
+                path
+            )));
+        }
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    /// Copies entry `index` of another zip without recompressing it.
+    pub fn add_raw<R: Read + Seek>(
+        &mut self,
+        archive: &mut ZipArchive<R>,
+        index: usize,
+    ) -> Result<(), AppError> {
+        let mut file = archive.by_index_raw(index)?;
+        #[allow(deprecated)]
+        let method = file.compression().to_u16();
+        let modified = file.last_modified().map(|t| (t.datepart(), t.timepart()));
+        let entry = self.entry(
+            file.name(),
+            method,
+            file.crc32(),
+            file.compressed_size(),
+            file.size(),
+            modified,
+        );
+        self.write_local_header(&entry)?;
+        let copied = io::copy(&mut file, &mut self.out)?;
+        self.offset += copied;
+        if copied != entry.compressed_size {
+            return Err(AppError::Other(format!(
+                "{} is shorter than its header says",
+                entry.name
+            )));
+        }
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    /// Writes the central directory and returns the destination.
+    pub fn finish(mut self) -> Result<W, AppError> {
+        let directory_offset = self.offset;
+        let mut directory = Vec::new();
+        for entry in &self.entries {
+            let mut extra = Vec::new();
+            if entry.size >= ZIP64_LIMIT {
+                put_u64(&mut extra, entry.size);
+            }
+            if entry.compressed_size >= ZIP64_LIMIT {
+                put_u64(&mut extra, entry.compressed_size);
+            }
+            if entry.offset >= ZIP64_LIMIT {
+                put_u64(&mut extra, entry.offset);
+            }
+            let version = if extra.is_empty() {
+                VERSION_DEFAULT
+            } else {
+                VERSION_ZIP64
+            };
+
+            put_u32(&mut directory, CENTRAL_HEADER);
+            put_u16(&mut directory, version);
+            put_u16(&mut directory, version);
+            put_u16(&mut directory, UTF8_NAMES);
+            put_u16(&mut directory, entry.method);
+            put_u16(&mut directory, entry.modified.1);
+            put_u16(&mut directory, entry.modified.0);
+            put_u32(&mut directory, entry.crc);
+            put_u32(
+                &mut directory,
+                entry.compressed_size.min(ZIP64_LIMIT) as u32,
+            );
+            put_u32(&mut directory, entry.size.min(ZIP64_LIMIT) as u32);
+            put_u16(&mut directory, entry.name.len() as u16);
+            put_u16(&mut directory, zip64_extra_len(&extra));
+            // Comment length, disk number, internal and external attributes
+            put_u16(&mut directory, 0);
+            put_u16(&mut directory, 0);
+            put_u16(&mut directory, 0);
+            put_u32(&mut directory, 0);
+            put_u32(&mut directory, entry.offset.min(ZIP64_LIMIT) as u32);
+            directory.extend_from_slice(entry.name.as_bytes());
+            put_zip64_extra(&mut directory, &extra);
+        }
+        let directory_size = directory.len() as u64;
+        let count = self.entries.len();
+
+        let mut end = Vec::new();
+        if count >= ZIP64_COUNT_LIMIT
+            || directory_size >= ZIP64_LIMIT
+            || directory_offset >= ZIP64_LIMIT
+        {
+            let record_offset = directory_offset + directory_size;
+            put_u32(&mut end, ZIP64_END_OF_CENTRAL_DIRECTORY);
+            // Size of the rest of the record
+            put_u64(&mut end, 44);
+            put_u16(&mut end, VERSION_ZIP64);
+            put_u16(&mut end, VERSION_ZIP64);
+            // This disk, and the disk the directory starts on
+            put_u32(&mut end, 0);
+            put_u32(&mut end, 0);
+            put_u64(&mut end, count as u64);
+            put_u64(&mut end, count as u64);
+            put_u64(&mut end, directory_size);
+            put_u64(&mut end, directory_offset);
+
+            put_u32(&mut end, ZIP64_LOCATOR);
+            put_u32(&mut end, 0);
+            put_u64(&mut end, record_offset);
+            // Total number of disks
+            put_u32(&mut end, 1);
+        }
+        put_u32(&mut end, END_OF_CENTRAL_DIRECTORY);
+        put_u16(&mut end, 0);
+        put_u16(&mut end, 0);
+        put_u16(&mut end, count.min(ZIP64_COUNT_LIMIT) as u16);
+        put_u16(&mut end, count.min(ZIP64_COUNT_LIMIT) as u16);
+        put_u32(&mut end, directory_size.min(ZIP64_LIMIT) as u32);
+        put_u32(&mut end, directory_offset.min(ZIP64_LIMIT) as u32);
+        // Comment length
+        put_u16(&mut end, 0);
+
+        self.out.write_all(&directory)?;
+        self.out.write_all(&end)?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    fn entry(
+        &self,
+        name: &str,
+        method: u16,
+        crc: u32,
+        compressed_size: u64,
+        size: u64,
+        modified: Option<(u16, u16)>,
+    ) -> CentralEntry {
+        CentralEntry {
+            name: name.to_string(),
+            method,
+            crc,
+            compressed_size,
+            size,
+            offset: self.offset,
+            modified: modified.unwrap_or(self.modified),
+        }
+    }
+
+    fn write_local_header(&mut self, entry: &CentralEntry) -> Result<(), AppError> {
+        if entry.name.len() > u16::MAX as usize {
+            return Err(AppError::Other(format!(
+                "Zip entry name is too long: {}",
+                entry.name
+            )));
+        }
+        let mut extra = Vec::new();
+        if entry.needs_zip64() {
+            // The local Zip64 field always has both sizes
+            put_u64(&mut extra, entry.size);
+            put_u64(&mut extra, entry.compressed_size);
+        }
+        let (version, sizes) = if entry.needs_zip64() {
+            (VERSION_ZIP64, (ZIP64_LIMIT as u32, ZIP64_LIMIT as u32))
+        } else {
+            (
+                VERSION_DEFAULT,
+                (entry.compressed_size as u32, entry.size as u32),
+            )
+        };
+
+        let mut header = Vec::with_capacity(30 + entry.name.len() + extra.len() + 4);
+        put_u32(&mut header, LOCAL_HEADER);
+        put_u16(&mut header, version);
+        put_u16(&mut header, UTF8_NAMES);
+        put_u16(&mut header, entry.method);
+        put_u16(&mut header, entry.modified.1);
+        put_u16(&mut header, entry.modified.0);
+        put_u32(&mut header, entry.crc);
+        put_u32(&mut header, sizes.0);
+        put_u32(&mut header, sizes.1);
+        put_u16(&mut header, entry.name.len() as u16);
+        put_u16(&mut header, zip64_extra_len(&extra));
+        header.extend_from_slice(entry.name.as_bytes());
+        put_zip64_extra(&mut header, &extra);
+
+        self.out.write_all(&header)?;
+        self.offset += header.len() as u64;
+        Ok(())
+    }
+}
+
+/// Length of the extra field holding `values`, with its own header.
+fn zip64_extra_len(values: &[u8]) -> u16 {
+    if values.is_empty() {
+        0
+    } else {
+        4 + values.len() as u16
+    }
+}
+
+fn put_zip64_extra(buf: &mut Vec<u8>, values: &[u8]) {
+    if !values.is_empty() {
+        put_u16(buf, ZIP64_EXTRA);
+        put_u16(buf, values.len() as u16);
+        buf.extend_from_slice(values);
+    }
+}
+
+fn put_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u64(buf: &mut Vec<u8>, value: u64) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+/// MS-DOS (date, time) of `time`, which cannot go before 1980.
+fn dos_time(time: chrono::NaiveDateTime) -> (u16, u16) {
+    if time.year() < 1980 {
+        return ((1 << 5) | 1, 0);
+    }
+    let date =
+        (((time.year() - 1980) as u16) << 9) | ((time.month() as u16) << 5) | time.day() as u16;
+    let time =
+        ((time.hour() as u16) << 11) | ((time.minute() as u16) << 5) | (time.second() as u16 / 2);
+    (date, time)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_zip_stream_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("book.cbz");
+        std::fs::write(&path, "volume one").unwrap();
+
+        let mut zip = ZipStream::new(Vec::new());
+        zip.add_bytes("manifest.json", b"{}").unwrap();
+        zip.add_file("Berserk/ベルセルク.cbz", &path).unwrap();
+        let first = zip.finish().unwrap();
+
+        // Raw copies keep the data and checksum of the source entry
+        let mut source = ZipArchive::new(Cursor::new(first)).unwrap();
+        let mut zip = ZipStream::new(Vec::new());
+        for i in 0..source.len() {
+            zip.add_raw(&mut source, i).unwrap();
+        }
+        let copy = zip.finish().unwrap();
+
+        let mut archive = ZipArchive::new(Cursor::new(copy)).unwrap();
+        assert_eq!(archive.len(), 2);
+        let mut content = String::new();
+        archive
+            .by_name("Berserk/ベルセルク.cbz")
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, "volume one");
+    }
+}
@@ -8,6 +8,15 @@ pub enum AppError {
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
 
+    #[error("Zip error: {0}")]
+    Zip(#[from] zip::result::ZipError),
+
+    #[error("Invalid EPUB: {0}")]
+    InvalidEpub(String),
+
+    #[error("Invalid search: {0}")]
+    InvalidSearch(String),
+
     #[error("Library not found: {0}")]
     LibraryNotFound(String),
 
@@ -0,0 +1,642 @@
+use crate::core::kosync;
+use crate::core::libraries::Library;
+use crate::error::AppError;
+use crate::http::server::{self, AddressedLibrary, LibraryScope, Permission, SharedState};
+use axum::{
+    extract::{Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Json, Response},
+    routing::{get, post, put},
+    Router,
+};
+use rusqlite::Connection;
+use serde_json::json;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+/// Media type KOReader sends in `Accept` and expects back.
+const KOSYNC_CONTENT_TYPE: &str = "application/vnd.koreader.v1+json";
+
+// Error codes from the reference koreader-sync-server
+const ERROR_INTERNAL: u32 = 2000;
+const ERROR_UNAUTHORIZED_USER: u32 = 2001;
+const ERROR_USER_EXISTS: u32 = 2002;
+const ERROR_INVALID_FIELDS: u32 = 2003;
+const ERROR_DOCUMENT_FIELD_MISSING: u32 = 2004;
+
+/// Routes implementing the KOReader progress sync (kosync) protocol.
+///
+/// Pointing KOReader's "Progress sync" plugin at
+/// `http://<host>:8080/api/libraries/{library_id}/kosync` makes the host act
+/// as its sync server for that library: positions reported by KOReader are
+/// merged into the library's reading progress, and positions from other
+/// clients are offered back when KOReader can navigate to them.
+/// `http://<host>:8080/kosync` serves the default library.
+///
+/// Each library keeps its own accounts, in its progress database.
+/// Registration requires the host PIN as the password, so only someone who can
+/// see the host can create an account, and pairs the account as a device:
+/// it only syncs the libraries and books that device may see, and only
+/// records progress if it may write.
+pub fn router() -> Router<SharedState> {
+    Router::new()
+        .route("/users/create", post(create_user))
+        .route("/users/auth", get(auth_user))
+        .route("/syncs/progress", put(update_progress))
+        .route("/syncs/progress/{document}", get(get_progress))
+}
+
+#[derive(serde::Deserialize)]
+struct CreateUser {
+    username: Option<String>,
+    /// MD5 of the password typed into KOReader.
+    password: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct DocumentPath {
+    document: String,
+}
+
+#[derive(serde::Deserialize)]
+struct ProgressBody {
+    document: Option<String>,
+    /// XPointer string, or a page number for fixed-layout documents.
+    progress: Option<serde_json::Value>,
+    percentage: Option<f64>,
+    device: Option<String>,
+    device_id: Option<String>,
+}
+
+/// Error reply in the kosync format (`{"code": ..., "message": ...}`).
+struct KosyncError {
+    status: StatusCode,
+    code: u32,
+    message: &'static str,
+}
+
+impl KosyncError {
+    fn unauthorized() -> Self {
+        KosyncError {
+            status: StatusCode::UNAUTHORIZED,
+            code: ERROR_UNAUTHORIZED_USER,
+            message: "Unauthorized",
+        }
+    }
+
+    /// The device the account is paired as may not do this.
+    fn forbidden() -> Self {
+        KosyncError {
+            status: StatusCode::FORBIDDEN,
+            code: ERROR_UNAUTHORIZED_USER,
+            message: "Forbidden",
+        }
+    }
+
+    fn invalid_request() -> Self {
+        KosyncError {
+            status: StatusCode::FORBIDDEN,
+            code: ERROR_INVALID_FIELDS,
+            message: "Invalid request",
+        }
+    }
+
+    fn internal(e: impl std::fmt::Display) -> Self {
+        log::error!("kosync error: {}", e);
+        KosyncError {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            code: ERROR_INTERNAL,
+            message: "Unknown server error.",
+        }
+    }
+}
+
+impl IntoResponse for KosyncError {
+    fn into_response(self) -> Response {
+        kosync_response(
+            self.status,
+            json!({ "code": self.code, "message": self.message }),
+        )
+    }
+}
+
+fn kosync_response(status: StatusCode, body: serde_json::Value) -> Response {
+    (
+        status,
+        [(header::CONTENT_TYPE, KOSYNC_CONTENT_TYPE)],
+        Json(body),
+    )
+        .into_response()
+}
+
+impl From<AppError> for KosyncError {
+    fn from(e: AppError) -> Self {
+        KosyncError::internal(e)
+    }
+}
+
+/// Checks the `x-auth-user`/`x-auth-key` headers, and that the device the
+/// user is paired as may use `library` for `permission`.
+///
+/// # Returns
+///
+/// Returns the username and the library as the device sees it.
+async fn authorize(
+    state: &SharedState,
+    headers: &HeaderMap,
+    library: Arc<Library>,
+    permission: Permission,
+) -> Result<(String, LibraryScope), KosyncError> {
+    let header_value = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+    let (Some(username), Some(userkey)) = (header_value("x-auth-user"), header_value("x-auth-key"))
+    else {
+        return Err(KosyncError::unauthorized());
+    };
+
+    let (name, key) = (username.to_string(), userkey.to_string());
+    let device_token = library
+        .progress_db
+        .run(move |conn| kosync::authenticate(conn, &name, &key))
+        .await?;
+    // Unpaired devices lose their accounts too
+    let Some(access) = device_token.and_then(|token| state.paired_scope(&token)) else {
+        return Err(KosyncError::unauthorized());
+    };
+    let scope = LibraryScope::new(state.clone(), library, access).await;
+    if let Some((error, message)) = server::refusal(&scope, permission) {
+        log::info!(
+            "Refused kosync request from {}: {} ({})",
+            username,
+            message,
+            error
+        );
+        return Err(KosyncError::forbidden());
+    }
+    Ok((username.to_string(), scope))
+}
+
+/// Maps a KOReader document hash to a book in `library` the device may see,
+/// if any. Hidden books are treated like documents the library does not have.
+fn find_book(
+    library: &Library,
+    scope: &LibraryScope,
+    conn: &Connection,
+    document: &str,
+) -> Option<i64> {
+    let books = library.books().ok()?.clone();
+    match kosync::find_book(conn, std::path::Path::new(&library.path), &books, document) {
+        Ok(book_id) => book_id.filter(|&book_id| scope.has_book(book_id)),
+        Err(e) => {
+            log::warn!("Could not match kosync document {}: {}", document, e);
+            None
+        }
+    }
+}
+
+/// Handler for `POST /users/create`.
+///
+/// The account is paired with the host as a device, limited to the default
+/// scope like any other newly paired device.
+async fn create_user(
+    State(state): State<SharedState>,
+    AddressedLibrary(library): AddressedLibrary,
+    body: Option<Json<CreateUser>>,
+) -> Result<Response, KosyncError> {
+    let Some(Json(CreateUser {
+        username: Some(username),
+        password: Some(password),
+    })) = body
+    else {
+        return Err(KosyncError::invalid_request());
+    };
+    if username.is_empty() {
+        return Err(KosyncError::invalid_request());
+    }
+
+    // Registering pairs KOReader with the host, so it needs pairing open
+    // like any other device. KOReader sends md5(password), and the password
+    // must be the host PIN.
+    if !state.pairing_open.load(Ordering::Relaxed)
+        || !password.eq_ignore_ascii_case(&kosync::md5_hex(state.pin.as_bytes()))
+    {
+        return Err(KosyncError::unauthorized());
+    }
+    if !state.pairing_scope()?.can_access(&library.scope_id) {
+        return Err(KosyncError::forbidden());
+    }
+
+    let device_token = uuid::Uuid::new_v4().to_string();
+    let (name, key, token) = (
+        username.clone(),
+        password.to_lowercase(),
+        device_token.clone(),
+    );
+    let created = library
+        .progress_db
+        .run(move |conn| kosync::create_user(conn, &name, &key, &token))
+        .await?;
+    if !created {
+        return Err(KosyncError {
+            status: StatusCode::PAYMENT_REQUIRED,
+            code: ERROR_USER_EXISTS,
+            message: "Username is already registered.",
+        });
+    }
+    // An account without its device could never sign in nor be registered
+    // again, so it is removed if pairing fails
+    if let Err(e) = state.pair_device(&device_token, Some(format!("KOReader ({})", username))) {
+        let (name, token) = (username.clone(), device_token.clone());
+        library
+            .progress_db
+            .run(move |conn| kosync::remove_user(conn, &name, &token))
+            .await?;
+        return Err(e.into());
+    }
+    Ok(kosync_response(
+        StatusCode::CREATED,
+        json!({ "username": username }),
+    ))
+}
+
+/// Handler for `GET /users/auth`.
+async fn auth_user(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+    AddressedLibrary(library): AddressedLibrary,
+) -> Result<Response, KosyncError> {
+    authorize(&state, &headers, library, Permission::Browse).await?;
+    Ok(kosync_response(
+        StatusCode::OK,
+        json!({ "authorized": "OK" }),
+    ))
+}
+
+/// Handler for `PUT /syncs/progress`.
+async fn update_progress(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+    AddressedLibrary(library): AddressedLibrary,
+    body: Option<Json<ProgressBody>>,
+) -> Result<Response, KosyncError> {
+    let Some(Json(body)) = body else {
+        return Err(KosyncError::invalid_request());
+    };
+    let Some(document) = body.document.filter(|d| !d.is_empty()) else {
+        return Err(KosyncError {
+            status: StatusCode::FORBIDDEN,
+            code: ERROR_DOCUMENT_FIELD_MISSING,
+            message: "Field 'document' not provided.",
+        });
+    };
+    let progress = match body.progress {
+        Some(serde_json::Value::String(s)) => s,
+        Some(serde_json::Value::Number(n)) => n.to_string(),
+        _ => return Err(KosyncError::invalid_request()),
+    };
+    let (Some(percentage), Some(device)) = (body.percentage, body.device) else {
+        return Err(KosyncError::invalid_request());
+    };
+
+    let (username, scope) = authorize(&state, &headers, library.clone(), Permission::Write).await?;
+    let update = kosync::KosyncProgress {
+        document: document.clone(),
+        progress,
+        percentage,
+        device_id: body.device_id.unwrap_or_else(|| device.clone()),
+        device,
+        timestamp: 0,
+    };
+    let timestamp = library
+        .progress_db
+        .run({
+            let library = library.clone();
+            move |conn| {
+                let book_id = find_book(&library, &scope, conn, &update.document);
+                kosync::save_progress(conn, &username, &update, book_id)
+            }
+        })
+        .await?;
+    Ok(kosync_response(
+        StatusCode::OK,
+        json!({ "document": document, "timestamp": timestamp }),
+    ))
+}
+
+/// Handler for `GET /syncs/progress/{document}`.
+///
+/// Returns `{}` when nothing is known about the document, as KOReader expects.
+async fn get_progress(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+    Path(DocumentPath { document }): Path<DocumentPath>,
+    AddressedLibrary(library): AddressedLibrary,
+) -> Result<Response, KosyncError> {
+    let (username, scope) =
+        authorize(&state, &headers, library.clone(), Permission::Browse).await?;
+    let progress = library
+        .progress_db
+        .run({
+            let library = library.clone();
+            move |conn| {
+                let book_id = find_book(&library, &scope, conn, &document);
+                kosync::get_progress(conn, &username, &document, book_id)
+            }
+        })
+        .await?;
+    Ok(match progress {
+        Some(progress) => kosync_response(StatusCode::OK, json!(progress)),
+        None => kosync_response(StatusCode::OK, json!({})),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::devices::Scope;
+    use crate::core::libraries::{LibraryConfig, DEFAULT_LIBRARY_ID};
+    use crate::core::progress;
+    use crate::http::server::ServerState;
+    use crate::models::Book;
+    use axum_test::TestServer;
+    use std::sync::Arc;
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn test_kosync_flow() {
+        let dir = tempdir().unwrap();
+        // Opened without a metadata.db, so their books are filled in by hand
+        let open_library = |id: &str, content: &str| {
+            let path = dir.path().join(id);
+            std::fs::create_dir_all(path.join("a/b")).unwrap();
+            std::fs::write(path.join("a/b/book.epub"), content).unwrap();
+            let library = Library::open(
+                &LibraryConfig {
+                    id: id.to_string(),
+                    name: id.to_string(),
+                    path: path.to_str().unwrap().to_string(),
+                },
+                dir.path(),
+            )
+            .unwrap();
+            *library.books().unwrap() = vec![Book {
+                path: "a/b".to_string(),
+                ..Book::test(5, "Book")
+            }];
+            Arc::new(library)
+        };
+        let state = Arc::new(ServerState::new(
+            dir.path().to_path_buf(),
+            "1234".to_string(),
+        ));
+        *state.library.lock().unwrap() = Some(open_library(DEFAULT_LIBRARY_ID, "koreader book"));
+        let kids = open_library("kids", "kids book");
+        state.libraries.lock().unwrap().push(kids.clone());
+
+        let app = Router::new()
+            .nest("/kosync", router())
+            .nest("/api/libraries/{library_id}/kosync", router())
+            .with_state(state.clone());
+        let server = TestServer::new(app).unwrap();
+        let key = kosync::md5_hex(b"1234");
+
+        // Registration needs the host PIN, and pairing to be open
+        state.set_pairing_open(false);
+        server
+            .post("/kosync/users/create")
+            .json(&json!({ "username": "reader", "password": key }))
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+        state.set_pairing_open(true);
+        server
+            .post("/kosync/users/create")
+            .json(&json!({ "username": "reader", "password": kosync::md5_hex(b"nope") }))
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+        server
+            .post("/kosync/users/create")
+            .json(&json!({ "username": "reader", "password": key }))
+            .await
+            .assert_status(StatusCode::CREATED);
+        server
+            .post("/kosync/users/create")
+            .json(&json!({ "username": "reader", "password": key }))
+            .await
+            .assert_status(StatusCode::PAYMENT_REQUIRED);
+
+        server
+            .get("/kosync/users/auth")
+            .add_header("x-auth-user", "reader")
+            .add_header("x-auth-key", key.clone())
+            .await
+            .assert_status_ok();
+        server
+            .get("/kosync/users/auth")
+            .add_header("x-auth-user", "reader")
+            .add_header("x-auth-key", "wrong")
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+
+        let document = kosync::md5_hex(b"koreader book");
+        server
+            .put("/kosync/syncs/progress")
+            .add_header("x-auth-user", "reader")
+            .add_header("x-auth-key", key.clone())
+            .json(&json!({
+                "document": document,
+                "progress": "/body/DocFragment[4]/body/p[1]/text().0",
+                "percentage": 0.5,
+                "device": "Kobo",
+                "device_id": "ABC"
+            }))
+            .await
+            .assert_status_ok();
+
+        // Mirrored into the shared progress for the matched book
+        let conn = state.default_library().unwrap().progress_db.get().unwrap();
+        let record = progress::get_progress(&conn, 5).unwrap().unwrap();
+        assert_eq!(record.percentage, Some(50.0));
+
+        let response = server
+            .get(&format!("/kosync/syncs/progress/{}", document))
+            .add_header("x-auth-user", "reader")
+            .add_header("x-auth-key", key.clone())
+            .await;
+        response.assert_status_ok();
+        let fetched = response.json::<kosync::KosyncProgress>();
+        assert_eq!(fetched.progress, "/body/DocFragment[4]/body/p[1]/text().0");
+        assert_eq!(fetched.device_id, "ABC");
+
+        let response = server
+            .get("/kosync/syncs/progress/unknown")
+            .add_header("x-auth-user", "reader")
+            .add_header("x-auth-key", key.clone())
+            .await;
+        response.assert_status_ok();
+        assert_eq!(response.json::<serde_json::Value>(), json!({}));
+
+        // Other libraries have their own accounts and progress
+        server
+            .post("/api/libraries/kids/kosync/users/create")
+            .json(&json!({ "username": "reader", "password": key }))
+            .await
+            .assert_status(StatusCode::CREATED);
+        server
+            .put("/api/libraries/kids/kosync/syncs/progress")
+            .add_header("x-auth-user", "reader")
+            .add_header("x-auth-key", key.clone())
+            .json(&json!({
+                "document": kosync::md5_hex(b"kids book"),
+                "progress": "3",
+                "percentage": 0.25,
+                "device": "Kobo"
+            }))
+            .await
+            .assert_status_ok();
+        let conn = kids.progress_db.get().unwrap();
+        let record = progress::get_progress(&conn, 5).unwrap().unwrap();
+        assert_eq!(record.percentage, Some(25.0));
+        assert_eq!(
+            progress::get_progress(
+                &state.default_library().unwrap().progress_db.get().unwrap(),
+                5
+            )
+            .unwrap()
+            .unwrap()
+            .percentage,
+            Some(50.0)
+        );
+        server
+            .get("/api/libraries/missing/kosync/users/auth")
+            .add_header("x-auth-user", "reader")
+            .add_header("x-auth-key", key.clone())
+            .await
+            .assert_status_not_found();
+    }
+
+    #[tokio::test]
+    async fn test_kosync_follows_device_scope() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("library");
+        std::fs::create_dir_all(path.join("a/b")).unwrap();
+        std::fs::write(path.join("a/b/book.epub"), "koreader book").unwrap();
+        let library = Library::open(
+            &LibraryConfig {
+                id: DEFAULT_LIBRARY_ID.to_string(),
+                name: "Library".to_string(),
+                path: path.to_str().unwrap().to_string(),
+            },
+            dir.path(),
+        )
+        .unwrap();
+        *library.books().unwrap() = vec![Book {
+            path: "a/b".to_string(),
+            tags: vec!["Adult".to_string()],
+            ..Book::test(5, "Book")
+        }];
+        let state = Arc::new(ServerState::new(
+            dir.path().to_path_buf(),
+            "1234".to_string(),
+        ));
+        *state.library.lock().unwrap() = Some(Arc::new(library));
+
+        let app = Router::new()
+            .nest("/kosync", router())
+            .with_state(state.clone());
+        let server = TestServer::new(app).unwrap();
+        let key = kosync::md5_hex(b"1234");
+
+        // New accounts get the default scope, so are refused libraries it excludes
+        state
+            .set_default_scope(Scope {
+                libraries: Some(Vec::new()),
+                ..Default::default()
+            })
+            .unwrap();
+        server
+            .post("/kosync/users/create")
+            .json(&json!({ "username": "reader", "password": key }))
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+        state.set_default_scope(Scope::default()).unwrap();
+        server
+            .post("/kosync/users/create")
+            .json(&json!({ "username": "reader", "password": key }))
+            .await
+            .assert_status(StatusCode::CREATED);
+        let device = state.devices().remove(0);
+        assert_eq!(device.name.as_deref(), Some("KOReader (reader)"));
+
+        let document = kosync::md5_hex(b"koreader book");
+        let push = |percentage: f64| {
+            server
+                .put("/kosync/syncs/progress")
+                .add_header("x-auth-user", "reader")
+                .add_header("x-auth-key", key.clone())
+                .json(&json!({
+                    "document": document,
+                    "progress": "/body/DocFragment[4]/body/p[1]/text().0",
+                    "percentage": percentage,
+                    "device": "Kobo"
+                }))
+        };
+        let shared_percentage = || {
+            let conn = state.default_library().unwrap().progress_db.get().unwrap();
+            progress::get_progress(&conn, 5)
+                .unwrap()
+                .map(|p| p.percentage)
+        };
+
+        // Read-only devices may fetch but not push
+        state
+            .set_device_scope(
+                &device.id,
+                Scope {
+                    can_write: false,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        push(0.5).await.assert_status(StatusCode::FORBIDDEN);
+        server
+            .get(&format!("/kosync/syncs/progress/{}", document))
+            .add_header("x-auth-user", "reader")
+            .add_header("x-auth-key", key.clone())
+            .await
+            .assert_status_ok();
+
+        // Hidden books are synced like documents the library does not have
+        state
+            .set_device_scope(
+                &device.id,
+                Scope {
+                    hidden_tags: vec!["Adult".to_string()],
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        push(0.5).await.assert_status_ok();
+        assert_eq!(shared_percentage(), None);
+
+        state
+            .set_device_scope(&device.id, Scope::default())
+            .unwrap();
+        push(0.5).await.assert_status_ok();
+        assert_eq!(shared_percentage(), Some(Some(50.0)));
+
+        state
+            .set_device_scope(
+                &device.id,
+                Scope {
+                    libraries: Some(vec!["other".to_string()]),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        server
+            .get("/kosync/users/auth")
+            .add_header("x-auth-user", "reader")
+            .add_header("x-auth-key", key)
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+    }
+}
@@ -1 +1,2 @@
+pub mod kosync;
 pub mod server;
@@ -1,33 +1,387 @@
+use crate::core::devices::{self, Device, DeviceRegistry, Scope};
+use crate::core::discovery::{self, HostAdvertisement};
+use crate::core::libraries::{self, Library, LibraryInfo};
+use crate::core::pairing::{self, PairingCode, PairingSecrets};
+use crate::core::pool::DbPool;
+use crate::core::search::{SavedSearch, SearchIndex, SearchKind};
+use crate::core::{annotations, bundle, db, epub, formats, inbox, kepub, progress};
+use crate::error::AppError;
+use crate::http::kosync;
 use crate::models::Book;
 use axum::{
     body::Body,
-    extract::{Path, State},
-    http::{header, StatusCode},
+    extract::{rejection::JsonRejection, FromRequestParts, Path, Query, RawPathParams, State},
+    http::{header, request::Parts, StatusCode},
     response::{IntoResponse, Json, Response},
     routing::get,
     Router,
 };
 use log::{error, info};
 use std::path::Path as FilePath;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
 use tokio::fs::File;
 use tokio_util::io::ReaderStream;
 use tower_http::cors::CorsLayer;
 
 /// Application state shared across all HTTP handlers.
 ///
-/// Contains the library path, book metadata, authentication PIN, and authorized tokens.
+/// Contains the libraries, authentication PIN, and authorized tokens.
 pub struct ServerState {
-    /// Path to the Calibre library (e.g., "/Users/name/Calibre Library").
-    pub library_path: Mutex<Option<String>>,
-    /// In-memory cache of book metadata.
-    pub books: Mutex<Vec<Book>>,
+    /// The library picked with "Select Library", also served on the
+    /// un-namespaced `/api/...` routes.
+    pub library: Mutex<Option<Arc<Library>>>,
     /// 4-digit PIN for initial device pairing.
     pub pin: String,
     /// Set of authorized bearer tokens.
     pub authorized_tokens: Mutex<std::collections::HashSet<String>>,
     /// Directory for storing application data (cache, settings, etc.).
     pub app_data_dir: std::path::PathBuf,
+    /// Persistent ID of this install, published over mDNS.
+    pub host_id: String,
+    /// Whether `POST /api/check-pin` accepts new devices.
+    pub pairing_open: AtomicBool,
+    /// Notified whenever the published advertisement changes, so the mDNS
+    /// registration can be refreshed.
+    pub advertisement_changed: tokio::sync::watch::Sender<()>,
+    /// One-time secrets behind the pairing codes shown as QR codes.
+    pub pairing: PairingSecrets,
+    /// Libraries served besides the default one, under `/api/libraries/{id}`.
+    pub libraries: Mutex<Vec<Arc<Library>>>,
+    /// Paired devices, by bearer token, saved in `devices.json`. Tokens
+    /// without an entry may use every library.
+    pub devices: Mutex<DeviceRegistry>,
+    /// Port the API is served on, as bound by [`run`].
+    pub port: AtomicU16,
+}
+
+impl ServerState {
+    pub fn new(app_data_dir: std::path::PathBuf, pin: String) -> Self {
+        let host_id = discovery::load_host_id(&app_data_dir);
+        ServerState {
+            library: Mutex::new(None),
+            pin,
+            authorized_tokens: Mutex::new(std::collections::HashSet::new()),
+            app_data_dir,
+            host_id,
+            pairing_open: AtomicBool::new(true),
+            advertisement_changed: tokio::sync::watch::channel(()).0,
+            pairing: PairingSecrets::default(),
+            libraries: Mutex::new(Vec::new()),
+            devices: Mutex::new(DeviceRegistry::default()),
+            port: AtomicU16::new(discovery::DEFAULT_PORT),
+        }
+    }
+
+    /// What this host publishes about itself over mDNS.
+    pub fn advertisement(&self) -> HostAdvertisement {
+        let libraries = self.library_infos();
+        let default = self.default_library().ok().map(|library| library.info());
+        let library_name = default.as_ref().map(|info| info.name.clone());
+        let book_count = default.as_ref().map(|info| info.book_count);
+        let library_revision = default.map(|info| info.revision);
+        HostAdvertisement {
+            host_id: Some(self.host_id.clone()),
+            version: Some(env!("CARGO_PKG_VERSION").to_string()),
+            api_version: Some(discovery::API_VERSION),
+            library_name,
+            book_count,
+            library_revision,
+            // The server only speaks plain HTTP so far
+            tls_fingerprint: None,
+            pairing_open: Some(self.pairing_open.load(Ordering::Relaxed)),
+            library_count: Some(libraries.len()),
+            library_ids: Some(libraries.into_iter().map(|info| info.id).collect()),
+        }
+    }
+
+    /// Opens the libraries saved in `libraries.json`.
+    pub fn load_saved_libraries(&self) -> Result<(), AppError> {
+        let opened = libraries::load_configs(&self.app_data_dir)
+            .iter()
+            .map(|config| Library::open(config, &self.app_data_dir).map(Arc::new))
+            .collect::<Result<Vec<_>, _>>()?;
+        *self
+            .libraries
+            .lock()
+            .map_err(|_| AppError::Unknown("Failed to lock libraries".to_string()))? = opened;
+        self.advertisement_changed.send_replace(());
+        Ok(())
+    }
+
+    /// The library picked with "Select Library".
+    pub fn default_library(&self) -> Result<Arc<Library>, AppError> {
+        self.library
+            .lock()
+            .map_err(|_| AppError::Unknown("Failed to lock library".to_string()))?
+            .clone()
+            .ok_or_else(|| AppError::Other("No library selected".to_string()))
+    }
+
+    /// A library served besides the default one.
+    pub fn library(&self, id: &str) -> Option<Arc<Library>> {
+        self.libraries
+            .lock()
+            .ok()?
+            .iter()
+            .find(|library| library.id == id)
+            .cloned()
+    }
+
+    /// Every library served, starting with the default one if selected.
+    pub fn library_infos(&self) -> Vec<LibraryInfo> {
+        let mut infos: Vec<LibraryInfo> = self
+            .default_library()
+            .ok()
+            .map(|library| library.info())
+            .into_iter()
+            .collect();
+        if let Ok(registered) = self.libraries.lock() {
+            infos.extend(registered.iter().map(|library| library.info()));
+        }
+        infos
+    }
+
+    /// Registers another Calibre library, served under
+    /// `/api/libraries/{id}` and remembered across restarts.
+    pub fn add_library(&self, path: &str, name: Option<String>) -> Result<LibraryInfo, AppError> {
+        // Fails early for folders that are not Calibre libraries
+        db::open_metadata_db(path)?;
+
+        let is_default = self
+            .default_library()
+            .is_ok_and(|library| library.path == path);
+        let mut registered = self
+            .libraries
+            .lock()
+            .map_err(|_| AppError::Unknown("Failed to lock libraries".to_string()))?;
+        if is_default || registered.iter().any(|library| library.path == path) {
+            return Err(AppError::Other(format!("{} is already being served", path)));
+        }
+
+        let config = libraries::LibraryConfig {
+            id: libraries::new_library_id(),
+            name: name
+                .map(|name| name.trim().to_string())
+                .filter(|name| !name.is_empty())
+                .unwrap_or_else(|| libraries::folder_name(path)),
+            path: path.to_string(),
+        };
+        let library = Arc::new(Library::open(&config, &self.app_data_dir)?);
+        registered.push(library.clone());
+        let configs: Vec<_> = registered.iter().map(|library| library.config()).collect();
+        drop(registered);
+
+        libraries::save_configs(&self.app_data_dir, &configs)?;
+        self.advertisement_changed.send_replace(());
+        Ok(library.info())
+    }
+
+    /// Stops serving a registered library. Its progress and annotations are
+    /// kept, in case it is added again. Returns whether it was registered.
+    pub fn remove_library(&self, id: &str) -> Result<bool, AppError> {
+        let mut registered = self
+            .libraries
+            .lock()
+            .map_err(|_| AppError::Unknown("Failed to lock libraries".to_string()))?;
+        let len_before = registered.len();
+        registered.retain(|library| library.id != id);
+        if registered.len() == len_before {
+            return Ok(false);
+        }
+        let configs: Vec<_> = registered.iter().map(|library| library.config()).collect();
+        drop(registered);
+
+        libraries::save_configs(&self.app_data_dir, &configs)?;
+        self.advertisement_changed.send_replace(());
+        Ok(true)
+    }
+
+    /// Restores the devices saved in `devices.json`, so their tokens keep
+    /// working.
+    pub fn load_saved_devices(&self) -> Result<(), AppError> {
+        let registry = devices::load_registry(&self.app_data_dir);
+        self.authorized_tokens
+            .lock()
+            .map_err(|_| AppError::Unknown("Failed to lock tokens".to_string()))?
+            .extend(registry.devices.keys().cloned());
+        *self.lock_devices()? = registry;
+        Ok(())
+    }
+
+    /// Paired devices, oldest first.
+    pub fn devices(&self) -> Vec<Device> {
+        let mut devices: Vec<Device> = self
+            .devices
+            .lock()
+            .map(|registry| registry.devices.values().cloned().collect())
+            .unwrap_or_default();
+        devices.sort_by_key(|device| device.paired_at);
+        devices
+    }
+
+    /// Replaces what a device may see and do.
+    pub fn set_device_scope(&self, device_id: &str, mut scope: Scope) -> Result<(), AppError> {
+        if let Ok(library) = self.default_library() {
+            scope.resolve_default_library(&library.scope_id);
+        }
+        let mut registry = self.lock_devices()?;
+        let device = registry
+            .devices
+            .values_mut()
+            .find(|device| device.id == device_id)
+            .ok_or_else(|| AppError::Other(format!("Device {} is not paired", device_id)))?;
+        device.scope = scope;
+        devices::save_registry(&self.app_data_dir, &registry)
+    }
+
+    /// Pairs a device under bearer `token`, with the default scope.
+    ///
+    /// The token only works once the device is saved in `devices.json`, so a
+    /// failed pairing leaves nothing behind.
+    pub fn pair_device(&self, token: &str, name: Option<String>) -> Result<(), AppError> {
+        let scope = self.pairing_scope()?;
+        let mut registry = self.lock_devices()?;
+        registry.devices.insert(
+            token.to_string(),
+            Device::new(name, chrono::Utc::now().timestamp(), scope),
+        );
+        if let Err(e) = devices::save_registry(&self.app_data_dir, &registry) {
+            registry.devices.remove(token);
+            return Err(e);
+        }
+        drop(registry);
+        self.authorized_tokens
+            .lock()
+            .map_err(|_| AppError::Unknown("Failed to lock tokens".to_string()))?
+            .insert(token.to_string());
+        Ok(())
+    }
+
+    /// The scope of the device paired under bearer `token`, if it is.
+    pub fn paired_scope(&self, token: &str) -> Option<Scope> {
+        if !self.authorized_tokens.lock().ok()?.contains(token) {
+            return None;
+        }
+        Some(self.lock_devices().ok()?.devices.get(token)?.scope.clone())
+    }
+
+    /// What newly paired devices may see and do.
+    pub fn default_scope(&self) -> Result<Scope, AppError> {
+        Ok(self.lock_devices()?.default_scope.clone())
+    }
+
+    /// The scope a device pairing now gets: the default scope, with the
+    /// default library resolved to the one selected now.
+    pub fn pairing_scope(&self) -> Result<Scope, AppError> {
+        let mut scope = self.default_scope()?;
+        if let Ok(library) = self.default_library() {
+            scope.resolve_default_library(&library.scope_id);
+        }
+        Ok(scope)
+    }
+
+    /// Resolves the default library left in device scopes, by devices that
+    /// paired before any library was selected, to the one selected now.
+    fn resolve_device_default_library(&self, scope_id: &str) -> Result<(), AppError> {
+        let mut registry = self.lock_devices()?;
+        let mut changed = false;
+        for device in registry.devices.values_mut() {
+            changed |= device.scope.resolve_default_library(scope_id);
+        }
+        if changed {
+            devices::save_registry(&self.app_data_dir, &registry)?;
+        }
+        Ok(())
+    }
+
+    /// Replaces what newly paired devices may see and do. Devices already
+    /// paired keep their scope.
+    pub fn set_default_scope(&self, scope: Scope) -> Result<(), AppError> {
+        let mut registry = self.lock_devices()?;
+        registry.default_scope = scope;
+        devices::save_registry(&self.app_data_dir, &registry)
+    }
+
+    /// Port the API is served on, for links that point devices at this host.
+    pub fn port(&self) -> u16 {
+        self.port.load(Ordering::Relaxed)
+    }
+
+    fn lock_devices(&self) -> Result<MutexGuard<'_, DeviceRegistry>, AppError> {
+        self.devices
+            .lock()
+            .map_err(|_| AppError::Unknown("Failed to lock devices".to_string()))
+    }
+
+    /// Opens or closes PIN pairing and republishes the advertisement.
+    pub fn set_pairing_open(&self, open: bool) {
+        if self.pairing_open.swap(open, Ordering::Relaxed) != open {
+            self.advertisement_changed.send_replace(());
+        }
+    }
+
+    /// Issues a one-time pairing code for a device to scan, pointing at
+    /// `addresses` and `port`.
+    pub fn pairing_code(&self, addresses: Vec<String>, port: u16) -> Result<PairingCode, AppError> {
+        if addresses.is_empty() {
+            return Err(AppError::Other(
+                "No network address to pair over".to_string(),
+            ));
+        }
+        let (payload, uri) = self.pairing.issue(
+            &self.host_id,
+            addresses,
+            port,
+            self.advertisement().tls_fingerprint,
+            chrono::Utc::now().timestamp(),
+        )?;
+        Ok(PairingCode {
+            svg: pairing::qr_svg(&uri)?,
+            uri,
+            expires_at: payload.expires_at,
+        })
+    }
+
+    /// Loads (or reloads) the books of a Calibre library and serves them as
+    /// the default library.
+    ///
+    /// Reloading the same library reuses its read-only connection to
+    /// `metadata.db`. A library already registered besides the default one
+    /// is refused, as it would be served twice with separate progress.
+    /// Devices that paired while no library was selected are granted this one.
+    pub fn load_library(&self, library_path: &str) -> Result<Vec<Book>, AppError> {
+        let mut selected = self
+            .library
+            .lock()
+            .map_err(|_| AppError::Unknown("Failed to lock library".to_string()))?;
+        let books = match selected.as_ref() {
+            Some(library) if library.path == library_path => library.reload()?,
+            _ => {
+                let registered = self
+                    .libraries
+                    .lock()
+                    .map_err(|_| AppError::Unknown("Failed to lock libraries".to_string()))?
+                    .iter()
+                    .any(|library| library.path == library_path);
+                if registered {
+                    return Err(AppError::Other(format!(
+                        "{} is already served as another library",
+                        library_path
+                    )));
+                }
+                let library = Library::open_default(library_path, &self.app_data_dir)?;
+                let books = library.books()?.clone();
+                let scope_id = library.scope_id.clone();
+                *selected = Some(Arc::new(library));
+                drop(selected);
+                self.resolve_device_default_library(&scope_id)?;
+                books
+            }
+        };
+        self.advertisement_changed.send_replace(());
+        Ok(books)
+    }
 }
 
 pub type SharedState = Arc<ServerState>;
@@ -37,7 +391,7 @@ pub type SharedState = Arc<ServerState>;
 /// # Arguments
 ///
 /// * `state` - The shared application state.
-/// * `port` - The port to listen on (typically 8080).
+/// * `port` - The port to listen on (typically [`discovery::DEFAULT_PORT`]).
 pub async fn run(state: SharedState, port: u16) {
     // Generate PIN if not already provided in state (though state is created here usually?)
     // Actually state is passed IN. We should modify how state is created in lib.rs or just read it here.
@@ -45,49 +399,403 @@ pub async fn run(state: SharedState, port: u16) {
     // I cannot change ServerState struct easily without updating initialization in lib.rs.
     // I will go to lib.rs to initialize the PIN.
 
-    let app = Router::new()
-        .route("/api/manifest", get(get_manifest))
-        .route("/api/cover/{book_id}", get(get_cover))
-        .route("/api/download/{book_id}/{format}", get(download_book))
-        .route("/api/check-pin", axum::routing::post(check_pin))
-        .route("/api/progress", get(get_progress).post(update_progress))
-        .layer(CorsLayer::permissive())
-        .with_state(state);
+    let app = router(state.clone());
 
-    let val = format!("0.0.0.0:{}", port);
-    let listener = match tokio::net::TcpListener::bind(&val).await {
+    let listener = match bind_dual_stack(port) {
         Ok(l) => l,
         Err(e) => {
-            error!("Failed to bind to address {}: {}", val, e);
+            error!("Failed to bind to port {}: {}", port, e);
             return;
         }
     };
 
     if let Ok(addr) = listener.local_addr() {
         info!("Server listening on {}", addr);
+        state.port.store(addr.port(), Ordering::Relaxed);
     }
 
     if let Err(e) = axum::serve(listener, app).await {
         error!("Server error: {}", e);
     }
 }
+/// Builds the HTTP API.
+pub fn router(state: SharedState) -> Router {
+    Router::new()
+        .nest("/api", library_routes())
+        .nest("/api/libraries/{library_id}", library_routes())
+        .route("/api/libraries", get(list_libraries))
+        .route("/api/upload", axum::routing::post(upload_book))
+        .route("/api/check-pin", axum::routing::post(check_pin))
+        .route("/api/pairing-code", get(get_pairing_code))
+        .route("/api/pair", axum::routing::post(pair))
+        .route("/api/health", get(health))
+        // Where KOReader was pointed before libraries had their own routes
+        .nest("/kosync", kosync::router())
+        .layer(CorsLayer::permissive())
+        .with_state(state)
+}
+
+/// Routes about one library's books. They are served under
+/// `/api/libraries/{library_id}`, and for the default library also directly
+/// under `/api`.
+fn library_routes() -> Router<SharedState> {
+    Router::new()
+        .route("/manifest", get(get_manifest))
+        .route("/cover/{book_id}", get(get_cover))
+        .route("/download/{book_id}/{format}", get(download_book))
+        .route("/bundle", get(download_bundle))
+        .route("/progress", get(get_progress).post(update_progress))
+        .route("/progress/{book_id}", get(get_book_progress))
+        .route("/progress/sessions", axum::routing::post(record_session))
+        .route("/stats", get(get_stats))
+        .route("/searches", get(list_searches))
+        .route(
+            "/annotations",
+            get(list_annotations).post(create_annotation),
+        )
+        .route(
+            "/annotations/{id}",
+            axum::routing::put(update_annotation).delete(delete_annotation),
+        )
+        .route("/annotations/export/{book_id}", get(export_annotations))
+        .nest("/kosync", kosync::router())
+}
+
+/// The library a request is addressed to: one registered under
+/// `/api/libraries/{library_id}`, or the default library on the
+/// un-namespaced routes.
+pub(crate) struct LibraryScope {
+    state: SharedState,
+    library: Arc<Library>,
+    /// What the requesting device may see and do; everything for tokens
+    /// without a device record.
+    access: Scope,
+    /// IDs of the books in the virtual library the device is limited to.
+    virtual_library: Option<std::collections::HashSet<i64>>,
+}
+
+impl LibraryScope {
+    /// `library` as seen by a device limited to `access`.
+    pub(crate) async fn new(state: SharedState, library: Arc<Library>, access: Scope) -> Self {
+        let mut scope = LibraryScope {
+            state,
+            library,
+            access,
+            virtual_library: None,
+        };
+        if let Some(name) = scope.access.virtual_library.clone() {
+            let index = scope.search_index();
+            let books = scope.all_books().clone();
+            // Searches may match a regex against every book, so keep them off the async runtime
+            let members = tokio::task::spawn_blocking(move || {
+                match index.find(SearchKind::VirtualLibrary, &name) {
+                    Some(vl) => index.matching_ids(&vl.query, &books).unwrap_or_else(|e| {
+                        error!("Cannot evaluate virtual library {}: {}", name, e);
+                        Default::default()
+                    }),
+                    None => Default::default(),
+                }
+            })
+            .await
+            .unwrap_or_else(|e| {
+                error!("Virtual library task failed: {}", e);
+                Default::default()
+            });
+            scope.virtual_library = Some(members);
+        }
+        scope
+    }
+
+    fn id(&self) -> &str {
+        &self.library.id
+    }
+
+    fn path(&self) -> &str {
+        &self.library.path
+    }
+
+    fn all_books(&self) -> MutexGuard<'_, Vec<Book>> {
+        self.library.books.lock().unwrap()
+    }
+
+    fn search_index(&self) -> Arc<SearchIndex> {
+        self.library.search_index.lock().unwrap().clone()
+    }
+
+    /// Whether the device may see `book`.
+    fn can_see(&self, book: &Book) -> bool {
+        self.access.can_see(book)
+            && self
+                .virtual_library
+                .as_ref()
+                .is_none_or(|ids| ids.contains(&book.id))
+    }
+
+    /// The books the device may see.
+    fn books(&self) -> Vec<Book> {
+        self.all_books()
+            .iter()
+            .filter(|b| self.can_see(b))
+            .cloned()
+            .collect()
+    }
+
+    fn find_book(&self, book_id: i64) -> Option<Book> {
+        self.all_books()
+            .iter()
+            .find(|b| b.id == book_id && self.can_see(b))
+            .cloned()
+    }
+
+    pub(crate) fn has_book(&self, book_id: i64) -> bool {
+        self.find_book(book_id).is_some()
+    }
+
+    /// IDs of the books in the library that are hidden from the device.
+    fn hidden_ids(&self) -> std::collections::HashSet<i64> {
+        self.all_books()
+            .iter()
+            .filter(|b| !self.can_see(b))
+            .map(|b| b.id)
+            .collect()
+    }
+
+    fn progress_db(&self) -> &Arc<DbPool> {
+        &self.library.progress_db
+    }
+
+    fn annotations_db(&self) -> &Arc<DbPool> {
+        &self.library.annotations_db
+    }
+
+    /// Where caches keyed by book ID are kept.
+    fn data_dir(&self) -> &std::path::Path {
+        &self.library.data_dir
+    }
+}
+
+/// The library a request is addressed to, without regard to the device:
+/// `{library_id}` in the path, or the default library on routes without one.
+pub(crate) struct AddressedLibrary(pub Arc<Library>);
+
+impl FromRequestParts<SharedState> for AddressedLibrary {
+    type Rejection = Response;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &SharedState,
+    ) -> Result<Self, Self::Rejection> {
+        // Routes without parameters have none to find
+        let params = RawPathParams::from_request_parts(parts, state).await.ok();
+        let library_id = params.as_ref().and_then(|params| {
+            params
+                .iter()
+                .find(|(key, _)| *key == "library_id")
+                .map(|(_, id)| id.to_string())
+        });
+        let library = match library_id {
+            Some(id) if id != libraries::DEFAULT_LIBRARY_ID => {
+                state.library(&id).ok_or_else(|| {
+                    api_error(
+                        StatusCode::NOT_FOUND,
+                        "library_not_found",
+                        format!("Library {} does not exist", id),
+                        None,
+                    )
+                    .into_response()
+                })?
+            }
+            _ => state.default_library().map_err(|_| {
+                api_error(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "library_not_selected",
+                    "No library is selected on this host".to_string(),
+                    None,
+                )
+                .into_response()
+            })?,
+        };
+        Ok(AddressedLibrary(library))
+    }
+}
+
+impl FromRequestParts<SharedState> for LibraryScope {
+    type Rejection = Response;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &SharedState,
+    ) -> Result<Self, Self::Rejection> {
+        let AddressedLibrary(library) = AddressedLibrary::from_request_parts(parts, state).await?;
+        let access = device_scope(&parts.headers, state);
+        Ok(LibraryScope::new(state.clone(), library, access).await)
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct BookPath {
+    book_id: i64,
+}
+
+#[derive(serde::Deserialize)]
+struct DownloadPath {
+    book_id: i64,
+    format: String,
+}
+
+#[derive(serde::Deserialize)]
+struct AnnotationPath {
+    id: String,
+}
+
+/// Binds `port` on all interfaces, accepting both IPv6 and IPv4 connections
+/// on one socket where the OS supports it, and IPv4 only otherwise.
+fn bind_dual_stack(port: u16) -> std::io::Result<tokio::net::TcpListener> {
+    use socket2::{Domain, Socket, Type};
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+    let bind = |addr: SocketAddr| -> std::io::Result<std::net::TcpListener> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+        if addr.is_ipv6() {
+            // Some platforms (e.g. Windows, OpenBSD) default to IPv6-only sockets
+            socket.set_only_v6(false)?;
+        }
+        socket.set_reuse_address(true)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&addr.into())?;
+        socket.listen(1024)?;
+        Ok(socket.into())
+    };
+
+    let listener = bind(SocketAddr::from((Ipv6Addr::UNSPECIFIED, port))).or_else(|e| {
+        info!("IPv6 unavailable ({}), listening on IPv4 only", e);
+        bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)))
+    })?;
+    tokio::net::TcpListener::from_std(listener)
+}
+
 // ...
 
+#[derive(serde::Deserialize, Default)]
+struct ManifestParams {
+    /// Name of a Calibre virtual library to list the books of.
+    virtual_library: Option<String>,
+    /// Name of a Calibre saved search to list the books of.
+    saved_search: Option<String>,
+    /// A search in Calibre's syntax, e.g. `tags:fiction and not #read:true`.
+    search: Option<String>,
+}
+
 /// Handler for `GET /api/manifest`.
 ///
-/// Returns the full list of books in the library.
+/// Returns the full list of books in the library, or with `virtual_library`,
+/// `saved_search` or `search`, the books matching a Calibre search. Unknown
+/// names are rejected with `404` and invalid searches with `400`.
 /// Requires `Authorization: Bearer <token>` header.
 async fn get_manifest(
     header_map: header::HeaderMap,
-    State(state): State<SharedState>,
+    Query(params): Query<ManifestParams>,
+    library: LibraryScope,
 ) -> impl IntoResponse {
-    if !is_authorized(&header_map, &state) {
-        return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
+    if let Err(rejection) = authorize(&header_map, &library, Permission::Browse) {
+        return *rejection;
     }
 
+    let index = library.search_index();
+    let named = [
+        (SearchKind::VirtualLibrary, params.virtual_library),
+        (SearchKind::SavedSearch, params.saved_search),
+    ]
+    .into_iter()
+    .find_map(|(kind, name)| name.map(|name| (kind, name)));
+    let query = match (named, params.search) {
+        (Some((kind, name)), _) => match index.find(kind, &name) {
+            Some(search) => Some(search.query.clone()),
+            None => {
+                return api_error(
+                    StatusCode::NOT_FOUND,
+                    "search_not_found",
+                    format!("The library has no search named {}", name),
+                    None,
+                )
+                .into_response()
+            }
+        },
+        (None, search) => search,
+    };
+
     // Return cached books directly
-    let books = state.books.lock().unwrap();
-    Json(books.clone()).into_response()
+    let mut books = library.books();
+    if let Some(query) = query {
+        let searched = tokio::task::spawn_blocking(move || {
+            let ids = index.matching_ids(&query, &books)?;
+            books.retain(|book| ids.contains(&book.id));
+            Ok::<_, AppError>(books)
+        })
+        .await;
+        books = match searched {
+            Ok(Ok(books)) => books,
+            Ok(Err(e)) => {
+                return api_error(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_search",
+                    e.to_string(),
+                    None,
+                )
+                .into_response()
+            }
+            Err(e) => {
+                error!("Search task failed: {}", e);
+                return (StatusCode::INTERNAL_SERVER_ERROR, "Search failed").into_response();
+            }
+        };
+    }
+    Json(books).into_response()
+}
+
+/// A virtual library or saved search, as listed by `GET /api/searches`.
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+struct SearchSummary {
+    #[serde(flatten)]
+    search: SavedSearch,
+    /// How many of the books the device sees match; `None` when the search
+    /// uses syntax ShelfSync does not support.
+    book_count: Option<usize>,
+}
+
+/// Handler for `GET /api/searches`.
+///
+/// Lists the library's Calibre virtual libraries and saved searches, to browse
+/// with `GET /api/manifest?virtual_library=` or `?saved_search=`.
+/// Requires `Authorization: Bearer <token>` header.
+async fn list_searches(header_map: header::HeaderMap, library: LibraryScope) -> impl IntoResponse {
+    if let Err(rejection) = authorize(&header_map, &library, Permission::Browse) {
+        return *rejection;
+    }
+
+    let index = library.search_index();
+    let books = library.books();
+    let summaries = tokio::task::spawn_blocking(move || {
+        index
+            .searches
+            .iter()
+            .map(|search| SearchSummary {
+                search: search.clone(),
+                book_count: index
+                    .matching_ids(&search.query, &books)
+                    .ok()
+                    .map(|ids| ids.len()),
+            })
+            .collect::<Vec<_>>()
+    })
+    .await;
+    match summaries {
+        Ok(summaries) => Json(summaries).into_response(),
+        Err(e) => {
+            error!("Search task failed: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Search failed").into_response()
+        }
+    }
 }
 
 /// Handler for `GET /api/cover/{book_id}`.
@@ -97,32 +805,20 @@ async fn get_manifest(
 /// Requires `Authorization: Bearer <token>` header.
 async fn get_cover(
     header_map: header::HeaderMap,
-    Path(book_id): Path<i64>,
-    State(state): State<SharedState>,
+    Path(BookPath { book_id }): Path<BookPath>,
+    library: LibraryScope,
 ) -> impl IntoResponse {
-    if !is_authorized(&header_map, &state) {
-        return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
+    if let Err(rejection) = authorize(&header_map, &library, Permission::Browse) {
+        return *rejection;
     }
 
-    let library_path = {
-        let guard = state.library_path.lock().unwrap();
-        match &*guard {
-            Some(p) => p.clone(),
-            None => {
-                return (StatusCode::SERVICE_UNAVAILABLE, "Library path not set").into_response()
-            }
-        }
-    };
+    let library_path = library.path();
 
-    let book = {
-        let books = state.books.lock().unwrap();
-        match books.iter().find(|b| b.id == book_id) {
-            Some(b) => b.clone(),
-            None => return (StatusCode::NOT_FOUND, "Book not found").into_response(),
-        }
+    let Some(book) = library.find_book(book_id) else {
+        return (StatusCode::NOT_FOUND, "Book not found").into_response();
     };
 
-    let cover_path = FilePath::new(&library_path)
+    let cover_path = FilePath::new(library_path)
         .join(&book.path)
         .join("cover.jpg");
 
@@ -130,7 +826,7 @@ async fn get_cover(
         return (StatusCode::NOT_FOUND, "Cover not found").into_response();
     }
 
-    match get_cached_or_resized_cover(&state.app_data_dir, &cover_path, book_id).await {
+    match get_cached_or_resized_cover(library.data_dir(), &cover_path, book_id).await {
         Ok(bytes) => Response::builder()
             .header(header::CONTENT_TYPE, "image/jpeg")
             .body(Body::from(bytes))
@@ -139,126 +835,373 @@ async fn get_cover(
     }
 }
 
+#[derive(serde::Deserialize, Default)]
+struct DownloadParams {
+    /// Comma-separated list of formats the client can open, in order of preference.
+    accept: Option<String>,
+    /// When set, EPUBs are rewritten with the current Calibre metadata before being sent.
+    embed_metadata: Option<bool>,
+}
+
 /// Handler for `GET /api/download/{book_id}/{format}`.
 ///
 /// Downloads the book file in the requested format (e.g., "epub", "pdf").
-/// Searches for the file in the book's directory, falling back to other common formats if the requested one is not found.
+/// Searches for the file in the book's directory, falling back to the formats listed in
+/// the `accept` query parameter, or to every readable format in the registry.
+/// With `embed_metadata=true`, EPUBs are sent with their OPF and cover updated from Calibre;
+/// the rewrite is streamed as it is made, without a copy on disk.
+/// The `kepub` pseudo-format serves a native KEPUB if Calibre has one, or otherwise converts
+/// the EPUB for Kobo devices (cached on disk like covers).
 /// Requires `Authorization: Bearer <token>` header.
 async fn download_book(
     header_map: header::HeaderMap,
-    Path((book_id, format)): Path<(i64, String)>,
-    State(state): State<SharedState>,
+    Path(DownloadPath { book_id, format }): Path<DownloadPath>,
+    Query(params): Query<DownloadParams>,
+    library: LibraryScope,
 ) -> impl IntoResponse {
-    if !is_authorized(&header_map, &state) {
-        return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
+    if let Err(rejection) = authorize(&header_map, &library, Permission::Download) {
+        return *rejection;
     }
 
-    let library_path = {
-        let guard = state.library_path.lock().unwrap();
-        match &*guard {
-            Some(p) => p.clone(),
-            None => {
-                return (StatusCode::SERVICE_UNAVAILABLE, "Library path not set").into_response()
-            }
-        }
-    };
+    let library_path = library.path();
 
-    let book = {
-        let books = state.books.lock().unwrap();
-        match books.iter().find(|b| b.id == book_id) {
-            Some(b) => b.clone(),
-            None => return (StatusCode::NOT_FOUND, "Book not found").into_response(),
-        }
+    let Some(book) = library.find_book(book_id) else {
+        return (StatusCode::NOT_FOUND, "Book not found").into_response();
     };
 
-    let book_dir = FilePath::new(&library_path).join(&book.path);
+    let book_dir = FilePath::new(library_path).join(&book.path);
 
-    let (file_path, found_format) = match find_book_file(&book_dir, &format).await {
+    let accept: Option<Vec<String>> = params
+        .accept
+        .map(|a| a.split(',').map(|f| f.to_string()).collect());
+    let search_formats = formats::search_order(&format, accept.as_deref());
+
+    let (file_path, found_format) = match find_book_file(&book_dir, &search_formats).await {
         Some(res) => res,
         None => {
             return (
                 StatusCode::NOT_FOUND,
-                "Format not found (checked: epub, pdf, mobi, cbz)",
+                format!("Format not found (checked: {})", search_formats.join(", ")),
             )
                 .into_response()
         }
     };
 
-    match File::open(&file_path).await {
-        Ok(file) => {
-            let stream = ReaderStream::new(file);
-            let body = Body::from_stream(stream);
-
-            // Determine content type
-            let content_type = match found_format.as_str() {
-                "epub" => "application/epub+zip",
-                "pdf" => "application/pdf",
-                "mobi" => "application/x-mobipocket-ebook",
-                "cbz" => "application/vnd.comicbook+zip",
-                _ => "application/octet-stream",
-            };
-
-            // Set filename in content-disposition
-            let filename = file_path.file_name().unwrap().to_string_lossy().to_string();
-            let disposition = format!("attachment; filename=\"{}\"", filename);
+    // Kobo devices asked for a KEPUB but Calibre only has an EPUB: convert it
+    if format.eq_ignore_ascii_case("kepub") && found_format == "epub" {
+        return match get_cached_or_converted_kepub(library.data_dir(), &file_path, book_id).await {
+            Ok(kepub_path) => match File::open(&kepub_path).await {
+                Ok(file) => kepub_response(Body::from_stream(ReaderStream::new(file)), &file_path),
+                Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "File open error").into_response(),
+            },
+            Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
+        };
+    }
 
-            Response::builder()
-                .header(header::CONTENT_TYPE, content_type)
-                .header(header::CONTENT_DISPOSITION, disposition)
-                .body(body)
-                .unwrap()
+    // Rewrite the EPUB as it is sent when the client asked for fresh metadata.
+    // If the EPUB cannot be read (e.g. it is malformed) we still serve the original file.
+    let mut rewrite = None;
+    if params.embed_metadata.unwrap_or(false) && found_format == "epub" {
+        match embed_epub_metadata(&file_path, &book_dir, &book).await {
+            Ok(prepared) => rewrite = Some(prepared),
+            Err(e) => error!("Failed to embed metadata for book {}: {}", book_id, e),
         }
-        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "File open error").into_response(),
     }
+
+    let body = match rewrite {
+        Some(rewrite) => blocking_body(move |out| {
+            rewrite.write_to(out)?;
+            Ok(())
+        }),
+        None => match File::open(&file_path).await {
+            Ok(file) => Body::from_stream(ReaderStream::new(file)),
+            Err(_) => {
+                return (StatusCode::INTERNAL_SERVER_ERROR, "File open error").into_response()
+            }
+        },
+    };
+
+    if found_format == "kepub" {
+        return kepub_response(body, &file_path);
+    }
+
+    let content_type = formats::mime_type(&found_format);
+
+    // Set filename in content-disposition
+    let filename = file_path.file_name().unwrap().to_string_lossy().to_string();
+    let disposition = format!("attachment; filename=\"{}\"", filename);
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::CONTENT_DISPOSITION, disposition)
+        .body(body)
+        .unwrap()
 }
 
-// Helper functions
+#[derive(serde::Deserialize, Default)]
+struct BundleParams {
+    /// Comma-separated list of book IDs.
+    ids: Option<String>,
+    /// Exact series name (case-insensitive).
+    series: Option<String>,
+    /// Exact author name (case-insensitive).
+    author: Option<String>,
+    /// Preferred format for every book; defaults to the best available.
+    format: Option<String>,
+    /// Comma-separated list of formats the client can open, as for downloads.
+    accept: Option<String>,
+}
 
-/// Helper to retrieve a cover image from cache or resize it from the source.
-///
-/// # Arguments
-///
-/// * `app_data_dir` - The application data directory where cache is stored.
-/// * `cover_path` - The absolute path to the source cover image.
-/// * `book_id` - The unique ID of the book, used for cache filenames.
-///
-/// # Returns
+/// Handler for `GET /api/bundle`.
 ///
-/// Returns `Ok(Vec<u8>)` containing the JPEG bytes, or an error string.
-async fn get_cached_or_resized_cover(
-    app_data_dir: &std::path::Path,
-    cover_path: &std::path::Path,
-    book_id: i64,
-) -> Result<Vec<u8>, String> {
-    // Cache logic: app_data_dir/cache/covers/{book_id}.jpg
-    let cache_dir = app_data_dir.join("cache").join("covers");
-    let cache_file_path = cache_dir.join(format!("{}.jpg", book_id));
+/// Downloads several books as a single zip, selected by `ids`, `series` or `author`
+/// (checked in that order). Each book gets its own folder and the zip contains a
+/// `manifest.json` describing its contents. The zip is streamed as it is written,
+/// without a temporary copy on disk.
+/// Requires `Authorization: Bearer <token>` header.
+async fn download_bundle(
+    header_map: header::HeaderMap,
+    Query(params): Query<BundleParams>,
+    library: LibraryScope,
+) -> impl IntoResponse {
+    if let Err(rejection) = authorize(&header_map, &library, Permission::Download) {
+        return *rejection;
+    }
 
-    // 1. Try serving from cache first
-    if cache_file_path.exists() {
-        if let Ok(bytes) = tokio::fs::read(&cache_file_path).await {
-            return Ok(bytes);
+    let library_path = library.path();
+
+    let BundleSelection {
+        books,
+        unmatched,
+        name: bundle_name,
+    } = match select_bundle_books(&library.books(), &params) {
+        Ok(selection) => selection,
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
+
+    if books.is_empty() {
+        return (StatusCode::NOT_FOUND, "No matching books").into_response();
+    }
+
+    let accept: Option<Vec<String>> = params
+        .accept
+        .map(|a| a.split(',').map(|f| f.to_string()).collect());
+    let requested = params.format.unwrap_or_else(|| "best".to_string());
+    let search_formats = formats::search_order(&requested, accept.as_deref());
+
+    let mut entries = Vec::new();
+    // Requested IDs the device cannot see are missing like books without a file,
+    // so hidden books look the same as ones that do not exist
+    let mut missing = unmatched;
+    for book in books {
+        let book_dir = FilePath::new(library_path).join(&book.path);
+        match find_book_file(&book_dir, &search_formats).await {
+            Some((file_path, format)) => entries.push(bundle::BundleEntry {
+                book,
+                file_path,
+                format,
+            }),
+            None => missing.push(book.id),
         }
     }
 
-    // 2. If not in cache, resize and save
-    let cover_path_owned = cover_path.to_path_buf();
-    let cache_dir_owned = cache_dir.clone();
-    let cache_file_path_owned = cache_file_path.clone();
+    if entries.is_empty() {
+        return (
+            StatusCode::NOT_FOUND,
+            "No files found for the selected books",
+        )
+            .into_response();
+    }
 
-    // Offload CPU-intensive image resizing to a blocking thread
-    tokio::task::spawn_blocking(move || {
-        // Ensure cache directory exists
-        std::fs::create_dir_all(&cache_dir_owned).map_err(|_| "Failed to create cache dir")?;
+    let body = blocking_body(move |out| {
+        bundle::write_bundle(out, &entries, &missing)?;
+        Ok(())
+    });
+    let file_name = format!("{}.zip", bundle::sanitize_file_name(&bundle_name));
+    Response::builder()
+        .header(header::CONTENT_TYPE, "application/zip")
+        .header(
+            header::CONTENT_DISPOSITION,
+            attachment_disposition(&file_name),
+        )
+        .body(body)
+        .unwrap_or_else(|e| {
+            error!("Failed to build bundle response: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Failed to build bundle").into_response()
+        })
+}
 
-        let img = image::open(&cover_path_owned).map_err(|_| "Failed to open image")?;
-        // Resize to a reasonable thumbnail size (maintaining aspect ratio)
-        // 300x450 is good for the UI cards
-        let resized = img.resize(300, 450, image::imageops::FilterType::Lanczos3);
+#[derive(serde::Deserialize, Default)]
+struct UploadParams {
+    /// Original file name, used to determine the format.
+    filename: String,
+    title: Option<String>,
+    authors: Option<String>,
+    series: Option<String>,
+    series_index: Option<f64>,
+    /// Comma-separated list of tags.
+    tags: Option<String>,
+}
 
-        // Save to cache
-        resized
-            .save(&cache_file_path_owned)
+/// Handler for `POST /api/upload`.
+///
+/// Accepts a raw e-book file as the request body, with its file name and optional
+/// metadata in the query string. The file is staged in the host's inbox
+/// (`app_data_dir/inbox`) for the host to review and import into Calibre.
+/// Rejects files over [`inbox::MAX_UPLOAD_BYTES`] (413) and files whose extension
+/// or content does not match a known format (415).
+/// Requires `Authorization: Bearer <token>` header.
+async fn upload_book(
+    header_map: header::HeaderMap,
+    Query(params): Query<UploadParams>,
+    State(state): State<SharedState>,
+    body: Body,
+) -> impl IntoResponse {
+    if !is_authorized(&header_map, &state) {
+        return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
+    }
+    if !device_scope(&header_map, &state).can_write {
+        return api_error(
+            StatusCode::FORBIDDEN,
+            "read_only",
+            "This device has read-only access".to_string(),
+            None,
+        )
+        .into_response();
+    }
+
+    let file_name = bundle::sanitize_file_name(&params.filename);
+    let format = match file_name
+        .rsplit_once('.')
+        .and_then(|(_, ext)| formats::lookup(ext))
+    {
+        Some(f) => f.name,
+        None => {
+            return (
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "Unsupported book format",
+            )
+                .into_response()
+        }
+    };
+
+    let declared_size = header_map
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    if declared_size.is_some_and(|size| size > inbox::MAX_UPLOAD_BYTES) {
+        return (StatusCode::PAYLOAD_TOO_LARGE, "File too large").into_response();
+    }
+
+    let id = uuid::Uuid::new_v4().to_string();
+    let item_dir = match inbox::item_dir(&state.app_data_dir, &id) {
+        Ok(dir) => dir,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    };
+    let file_path = item_dir.join(&file_name);
+
+    let size = match write_upload(&item_dir, &file_path, body).await {
+        Ok(size) => size,
+        Err(status) => {
+            tokio::fs::remove_dir_all(&item_dir).await.ok();
+            return (status, "Upload failed").into_response();
+        }
+    };
+
+    // Check the content actually matches the claimed format. A single read may
+    // return fewer bytes than asked for, so read until the header or the file ends.
+    let mut header_bytes = Vec::with_capacity(68);
+    if let Ok(file) = File::open(&file_path).await {
+        use tokio::io::AsyncReadExt;
+        file.take(68).read_to_end(&mut header_bytes).await.ok();
+    }
+    if !formats::sniff(format, &header_bytes) {
+        tokio::fs::remove_dir_all(&item_dir).await.ok();
+        return (
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "File content does not match its format",
+        )
+            .into_response();
+    }
+
+    let item = inbox::InboxItem {
+        id,
+        file_name,
+        format: format.to_string(),
+        size,
+        title: params.title,
+        authors: params.authors,
+        series: params.series,
+        series_index: params.series_index,
+        tags: params
+            .tags
+            .map(|t| t.split(',').map(|t| t.trim().to_string()).collect())
+            .unwrap_or_default(),
+        uploaded_at: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64,
+    };
+
+    if let Err(e) = inbox::save_item(&state.app_data_dir, &item) {
+        tokio::fs::remove_dir_all(&item_dir).await.ok();
+        return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
+    }
+
+    info!(
+        "Staged upload {} ({} bytes) in inbox",
+        item.file_name, item.size
+    );
+    (StatusCode::CREATED, Json(item)).into_response()
+}
+
+// Helper functions
+
+/// Helper to retrieve a cover image from cache or resize it from the source.
+///
+/// # Arguments
+///
+/// * `app_data_dir` - The application data directory where cache is stored.
+/// * `cover_path` - The absolute path to the source cover image.
+/// * `book_id` - The unique ID of the book, used for cache filenames.
+///
+/// # Returns
+///
+/// Returns `Ok(Vec<u8>)` containing the JPEG bytes, or an error string.
+async fn get_cached_or_resized_cover(
+    app_data_dir: &std::path::Path,
+    cover_path: &std::path::Path,
+    book_id: i64,
+) -> Result<Vec<u8>, String> {
+    // Cache logic: app_data_dir/cache/covers/{book_id}.jpg
+    let cache_dir = app_data_dir.join("cache").join("covers");
+    let cache_file_path = cache_dir.join(format!("{}.jpg", book_id));
+
+    // 1. Try serving from cache first
+    if cache_file_path.exists() {
+        if let Ok(bytes) = tokio::fs::read(&cache_file_path).await {
+            return Ok(bytes);
+        }
+    }
+
+    // 2. If not in cache, resize and save
+    let cover_path_owned = cover_path.to_path_buf();
+    let cache_dir_owned = cache_dir.clone();
+    let cache_file_path_owned = cache_file_path.clone();
+
+    // Offload CPU-intensive image resizing to a blocking thread
+    tokio::task::spawn_blocking(move || {
+        // Ensure cache directory exists
+        std::fs::create_dir_all(&cache_dir_owned).map_err(|_| "Failed to create cache dir")?;
+
+        let img = image::open(&cover_path_owned).map_err(|_| "Failed to open image")?;
+        // Resize to a reasonable thumbnail size (maintaining aspect ratio)
+        // 300x450 is good for the UI cards
+        let resized = img.resize(300, 450, image::imageops::FilterType::Lanczos3);
+
+        // Save to cache
+        resized
+            .save(&cache_file_path_owned)
             .map_err(|_| "Failed to save to cache")?;
 
         // Write to bytes for response
@@ -274,26 +1217,323 @@ async fn get_cached_or_resized_cover(
     .map_err(|e| e.to_string())?
 }
 
+/// Builds a download response for a KEPUB, named `<stem>.kepub.epub` as Kobo
+/// firmware only treats files with that double extension as KEPUBs.
+fn kepub_response(body: Body, source_path: &std::path::Path) -> Response {
+    let stem = source_path
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_else(|| "book".to_string());
+    let stem = stem.strip_suffix(".kepub").unwrap_or(&stem);
+    let disposition = format!("attachment; filename=\"{}.kepub.epub\"", stem);
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, formats::mime_type("kepub"))
+        .header(header::CONTENT_DISPOSITION, disposition)
+        .body(body)
+        .unwrap()
+}
+
+/// Helper to retrieve a KEPUB conversion from cache or convert it from the source EPUB.
+///
+/// # Arguments
+///
+/// * `app_data_dir` - The application data directory where cache is stored.
+/// * `epub_path` - The absolute path to the source EPUB.
+/// * `book_id` - The unique ID of the book, used for cache filenames.
+///
+/// # Returns
+///
+/// Returns `Ok(PathBuf)` pointing at the cached KEPUB, or an error string.
+/// The cache is rebuilt whenever the source EPUB is newer than the cached copy.
+async fn get_cached_or_converted_kepub(
+    app_data_dir: &std::path::Path,
+    epub_path: &std::path::Path,
+    book_id: i64,
+) -> Result<std::path::PathBuf, String> {
+    // Cache logic: app_data_dir/cache/kepub/{book_id}.kepub.epub
+    let cache_dir = app_data_dir.join("cache").join("kepub");
+    let cache_file_path = cache_dir.join(format!("{}.kepub.epub", book_id));
+
+    // 1. Try serving from cache first
+    let modified = |p: &std::path::Path| std::fs::metadata(p).and_then(|m| m.modified()).ok();
+    if let (Some(cached), Some(source)) = (modified(&cache_file_path), modified(epub_path)) {
+        if cached >= source {
+            return Ok(cache_file_path);
+        }
+    }
+
+    // 2. If not in cache (or stale), convert and save
+    let epub_path_owned = epub_path.to_path_buf();
+    let cache_file_path_owned = cache_file_path.clone();
+
+    // Offload the zip rewrite to a blocking thread
+    tokio::task::spawn_blocking(move || {
+        std::fs::create_dir_all(&cache_dir).map_err(|_| "Failed to create cache dir")?;
+
+        // Convert into a temporary name so a concurrent request never sees a partial file
+        let partial = cache_dir.join(format!("{}.{}.partial", book_id, uuid::Uuid::new_v4()));
+        if let Err(e) = kepub::convert_to_kepub(&epub_path_owned, &partial) {
+            std::fs::remove_file(&partial).ok();
+            return Err(format!("Failed to convert to KEPUB: {}", e));
+        }
+        std::fs::rename(&partial, &cache_file_path_owned).map_err(|_| "Failed to save to cache")?;
+
+        Ok::<std::path::PathBuf, String>(cache_file_path_owned)
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+/// Helper to prepare an EPUB rewrite with metadata and cover refreshed from Calibre.
+///
+/// Only the OPF and the cover are read here; the rest of the EPUB is copied as the
+/// response is streamed (see [`epub::MetadataRewrite::write_to`]), so no copy of
+/// the book is written to disk.
+///
+/// # Returns
+///
+/// Returns the prepared rewrite, or an error string if the EPUB cannot be read.
+async fn embed_epub_metadata(
+    source: &std::path::Path,
+    book_dir: &std::path::Path,
+    book: &Book,
+) -> Result<epub::MetadataRewrite, String> {
+    let cover = book_dir.join("cover.jpg");
+    let source = source.to_path_buf();
+    let book = book.clone();
+
+    tokio::task::spawn_blocking(move || {
+        epub::embed_metadata(&source, &book, Some(&cover)).map_err(|e| e.to_string())
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+/// Bytes gathered before they are passed on to a [`blocking_body`].
+const BODY_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Streams what `write` writes as a response body.
+///
+/// `write` runs on a blocking thread and only gets a few chunks ahead of the
+/// client. If it fails part way, the body ends in an error so the client sees
+/// a failed download rather than a truncated file.
+fn blocking_body<F>(write: F) -> Body
+where
+    F: FnOnce(&mut BodyWriter) -> Result<(), AppError> + Send + 'static,
+{
+    let (tx, rx) = tokio::sync::mpsc::channel(4);
+    tokio::task::spawn_blocking(move || {
+        let mut writer = BodyWriter {
+            tx,
+            buf: Vec::with_capacity(BODY_CHUNK_SIZE),
+        };
+        let result = write(&mut writer).and_then(|()| Ok(writer.send_chunk()?));
+        if let Err(e) = result {
+            // Nothing to report if the client has gone away
+            if !writer.tx.is_closed() {
+                error!("Failed to stream response: {}", e);
+                writer
+                    .tx
+                    .blocking_send(Err(std::io::Error::other(e.to_string())))
+                    .ok();
+            }
+        }
+    });
+    Body::from_stream(futures_util::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    }))
+}
+
+/// The writer handed to a [`blocking_body`], sending what it is given on in chunks.
+struct BodyWriter {
+    tx: tokio::sync::mpsc::Sender<std::io::Result<axum::body::Bytes>>,
+    buf: Vec<u8>,
+}
+
+impl BodyWriter {
+    fn send_chunk(&mut self) -> std::io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let chunk = std::mem::replace(&mut self.buf, Vec::with_capacity(BODY_CHUNK_SIZE));
+        self.tx
+            .blocking_send(Ok(chunk.into()))
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::BrokenPipe))
+    }
+}
+
+impl std::io::Write for BodyWriter {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        let n = data.len().min(BODY_CHUNK_SIZE - self.buf.len());
+        self.buf.extend_from_slice(&data[..n]);
+        if self.buf.len() == BODY_CHUNK_SIZE {
+            self.send_chunk()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.send_chunk()
+    }
+}
+
+/// Builds an `attachment` Content-Disposition for `file_name`, with a plain
+/// ASCII `filename` for older clients and the exact name as an RFC 5987
+/// `filename*`. Quotes and control characters are replaced first, so the
+/// result is always a valid header value.
+fn attachment_disposition(file_name: &str) -> String {
+    let name = bundle::sanitize_file_name(file_name);
+    let fallback: String = name
+        .chars()
+        .map(|c| if c.is_ascii() { c } else { '_' })
+        .collect();
+    let mut encoded = String::with_capacity(name.len());
+    for byte in name.bytes() {
+        if byte.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        fallback, encoded
+    )
+}
+
+/// Helper to stream an upload body to disk, enforcing the upload size limit.
+///
+/// # Returns
+///
+/// Returns the number of bytes written, or the status code to reply with.
+async fn write_upload(
+    item_dir: &std::path::Path,
+    file_path: &std::path::Path,
+    body: Body,
+) -> Result<u64, StatusCode> {
+    use futures_util::StreamExt;
+    use tokio::io::AsyncWriteExt;
+
+    tokio::fs::create_dir_all(item_dir)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let mut file = File::create(file_path)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let mut size: u64 = 0;
+    let mut stream = body.into_data_stream();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|_| StatusCode::BAD_REQUEST)?;
+        size += chunk.len() as u64;
+        if size > inbox::MAX_UPLOAD_BYTES {
+            return Err(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        file.write_all(&chunk)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    }
+    file.flush()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if size == 0 {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    Ok(size)
+}
+
+/// Books picked for a bundle by [`select_bundle_books`].
+struct BundleSelection {
+    /// Series in reading order, otherwise by title.
+    books: Vec<Book>,
+    /// Requested IDs that matched none of the given books.
+    unmatched: Vec<i64>,
+    /// Name for the zip file.
+    name: String,
+}
+
+/// Helper to pick the books for a bundle from the cached library.
+///
+/// # Returns
+///
+/// Returns the selection, or an error string if the selection parameters are invalid.
+fn select_bundle_books(books: &[Book], params: &BundleParams) -> Result<BundleSelection, String> {
+    if let Some(ids) = &params.ids {
+        let ids = ids
+            .split(',')
+            .map(|id| id.trim().parse::<i64>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| "Invalid book ID list".to_string())?;
+        // A book asked for twice is bundled once
+        let mut seen = std::collections::HashSet::new();
+        let mut selected = Vec::new();
+        let mut unmatched = Vec::new();
+        for id in ids.into_iter().filter(|id| seen.insert(*id)) {
+            match books.iter().find(|b| b.id == id) {
+                Some(book) => selected.push(book.clone()),
+                None => unmatched.push(id),
+            }
+        }
+        return Ok(BundleSelection {
+            books: selected,
+            unmatched,
+            name: "shelfsync-bundle".to_string(),
+        });
+    }
+
+    if let Some(series) = &params.series {
+        let mut selected: Vec<Book> = books
+            .iter()
+            .filter(|b| {
+                b.series
+                    .as_deref()
+                    .is_some_and(|s| s.eq_ignore_ascii_case(series))
+            })
+            .cloned()
+            .collect();
+        selected.sort_by(|a, b| a.series_index.total_cmp(&b.series_index));
+        return Ok(BundleSelection {
+            books: selected,
+            unmatched: Vec::new(),
+            name: series.clone(),
+        });
+    }
+
+    if let Some(author) = &params.author {
+        let mut selected: Vec<Book> = books
+            .iter()
+            .filter(|b| {
+                b.authors
+                    .split(", ")
+                    .any(|a| a.trim().eq_ignore_ascii_case(author))
+            })
+            .cloned()
+            .collect();
+        selected.sort_by(|a, b| a.title.cmp(&b.title));
+        return Ok(BundleSelection {
+            books: selected,
+            unmatched: Vec::new(),
+            name: author.clone(),
+        });
+    }
+
+    Err("One of ids, series or author is required".to_string())
+}
+
 /// Helper to find a book file in the specified directory.
 ///
-/// Searches for files matching the requested format (case-insensitive).
-/// If the requested format i not found, searches for a set of fallback formats (epub, pdf, mobi, cbz).
+/// Searches for files matching each of `search_formats` in turn (case-insensitive),
+/// as built by [`formats::search_order`].
 ///
 /// # Returns
 ///
 /// Returns `Some((PathBuf, String))` containing the path and the found format, or `None`.
 async fn find_book_file(
     book_dir: &std::path::Path,
-    requested_format: &str,
+    search_formats: &[String],
 ) -> Option<(std::path::PathBuf, String)> {
-    // Fallback order: requested -> epub -> pdf -> mobi -> cbz
-    let mut search_formats = vec![requested_format.to_lowercase()];
-    for f in ["epub", "pdf", "mobi", "cbz"] {
-        if !search_formats.contains(&f.to_string()) {
-            search_formats.push(f.to_string());
-        }
-    }
-
     let mut found_path = None;
     let mut found_format = String::new();
 
@@ -307,9 +1547,9 @@ async fn find_book_file(
         while let Ok(Some(entry)) = dir_entries.next_entry().await {
             let path = entry.path();
             if let Some(ext) = path.extension() {
-                if ext.to_string_lossy().to_lowercase() == fmt {
+                if ext.to_string_lossy().to_lowercase() == *fmt {
                     found_path = Some(path);
-                    found_format = fmt;
+                    found_format = fmt.clone();
                     break;
                 }
             }
@@ -325,6 +1565,9 @@ async fn find_book_file(
 #[derive(serde::Deserialize)]
 struct PinRequest {
     pin: String,
+    /// Name of the device pairing, shown to the host's user.
+    #[serde(default)]
+    device: Option<String>,
 }
 
 #[derive(serde::Serialize)]
@@ -332,6 +1575,38 @@ struct AuthResponse {
     token: String,
 }
 
+/// Handler for `GET /api/libraries`.
+///
+/// Lists the libraries this device may use; each is served under
+/// `/api/libraries/{id}`. Requires `Authorization: Bearer <token>` header.
+async fn list_libraries(
+    header_map: header::HeaderMap,
+    State(state): State<SharedState>,
+) -> impl IntoResponse {
+    if !is_authorized(&header_map, &state) {
+        return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
+    }
+
+    let access = device_scope(&header_map, &state);
+    let infos: Vec<LibraryInfo> = state
+        .library_infos()
+        .into_iter()
+        .filter(|library| access.can_access(&library.scope_id))
+        .collect();
+    Json(infos).into_response()
+}
+
+/// Handler for `GET /api/health`.
+///
+/// Reports that the host is up, with its version, host ID and library
+/// revision. Unauthenticated, so clients can probe hosts before pairing.
+async fn health(State(state): State<SharedState>) -> Json<discovery::Health> {
+    Json(discovery::Health {
+        status: "ok".to_string(),
+        advertisement: state.advertisement(),
+    })
+}
+
 /// Handler for `POST /api/check-pin`.
 ///
 /// Verifies the 4-digit PIN provided by the client.
@@ -340,66 +1615,136 @@ async fn check_pin(
     State(state): State<SharedState>,
     Json(payload): Json<PinRequest>,
 ) -> impl IntoResponse {
+    if !state.pairing_open.load(Ordering::Relaxed) {
+        return (StatusCode::FORBIDDEN, "Pairing is closed on this host").into_response();
+    }
     if payload.pin == state.pin {
-        let token = uuid::Uuid::new_v4().to_string();
-        state
-            .authorized_tokens
-            .lock()
-            .unwrap()
-            .insert(token.clone());
-        (StatusCode::OK, Json(AuthResponse { token })).into_response()
+        issued_token_response(&state, payload.device)
     } else {
         (StatusCode::UNAUTHORIZED, "Invalid PIN").into_response()
     }
 }
 
-/// Handler for `GET /api/progress`.
+fn issue_token(state: &ServerState, device: Option<String>) -> Result<AuthResponse, AppError> {
+    let token = uuid::Uuid::new_v4().to_string();
+    state.pair_device(&token, device)?;
+    Ok(AuthResponse { token })
+}
+
+/// Pairs a device and replies with its token, or with a 500 if it could not be paired.
+fn issued_token_response(state: &ServerState, device: Option<String>) -> Response {
+    match issue_token(state, device) {
+        Ok(auth) => (StatusCode::OK, Json(auth)).into_response(),
+        Err(e) => {
+            error!("Failed to pair device: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Failed to pair device").into_response()
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+enum PairingCodeFormat {
+    #[default]
+    Json,
+    Svg,
+    Png,
+}
+
+#[derive(serde::Deserialize)]
+struct PairingCodeQuery {
+    #[serde(default)]
+    format: PairingCodeFormat,
+}
+
+/// Handler for `GET /api/pairing-code`.
 ///
-/// Returns current reading progress for all books.
+/// Issues a one-time pairing code, so a paired device can pass the pairing
+/// on to another. `?format=svg` or `?format=png` returns just the QR code;
+/// otherwise the URI, expiry and SVG are returned as JSON.
 /// Requires `Authorization: Bearer <token>` header.
-async fn get_progress(
+async fn get_pairing_code(
     header_map: header::HeaderMap,
     State(state): State<SharedState>,
+    Query(query): Query<PairingCodeQuery>,
 ) -> impl IntoResponse {
     if !is_authorized(&header_map, &state) {
         return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
     }
-
-    match crate::core::progress::get_all_progress(&state.app_data_dir) {
-        Ok(records) => Json(records).into_response(),
-        Err(e) => (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("DB Error: {}", e),
+    // Devices paired with the code get the default scope, which may grant
+    // more than a restricted device has, so only unrestricted ones hand them out
+    if !device_scope(&header_map, &state).is_unrestricted() {
+        return api_error(
+            StatusCode::FORBIDDEN,
+            "scope_restricted",
+            "Only devices with full access may pair others".to_string(),
+            None,
         )
-            .into_response(),
+        .into_response();
+    }
+    let addresses = discovery::local_addresses()
+        .iter()
+        .map(|ip| ip.to_string())
+        .collect();
+    let code = match state.pairing_code(addresses, state.port()) {
+        Ok(code) => code,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    };
+    match query.format {
+        PairingCodeFormat::Json => Json(code).into_response(),
+        PairingCodeFormat::Svg => {
+            ([(header::CONTENT_TYPE, "image/svg+xml")], code.svg).into_response()
+        }
+        PairingCodeFormat::Png => match pairing::qr_png(&code.uri) {
+            Ok(png) => ([(header::CONTENT_TYPE, "image/png")], png).into_response(),
+            Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+        },
     }
 }
 
-#[derive(serde::Deserialize)]
-struct ProgressUpdate {
-    book_id: i64,
-    status: String,
+/// Handler for `POST /api/pair`.
+///
+/// Trades the one-time secret of a pairing URI for a bearer token, like
+/// `POST /api/check-pin` does for the PIN.
+async fn pair(
+    State(state): State<SharedState>,
+    Json(payload): Json<pairing::PairRequest>,
+) -> impl IntoResponse {
+    if !state.pairing_open.load(Ordering::Relaxed) {
+        return (StatusCode::FORBIDDEN, "Pairing is closed on this host").into_response();
+    }
+    match state
+        .pairing
+        .redeem(&payload.uri, chrono::Utc::now().timestamp())
+    {
+        Ok(_) => issued_token_response(&state, payload.device),
+        Err(e) => {
+            info!("Refused pairing code: {}", e);
+            (
+                StatusCode::UNAUTHORIZED,
+                "Pairing code is invalid, expired or already used",
+            )
+                .into_response()
+        }
+    }
 }
 
-/// Handler for `POST /api/progress`.
+/// Handler for `GET /api/progress`.
 ///
-/// Updates the reading progress/status for a specific book.
+/// Returns current reading progress for all books.
 /// Requires `Authorization: Bearer <token>` header.
-async fn update_progress(
-    header_map: header::HeaderMap,
-    State(state): State<SharedState>,
-    Json(payload): Json<ProgressUpdate>,
-) -> impl IntoResponse {
-    if !is_authorized(&header_map, &state) {
-        return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
+async fn get_progress(header_map: header::HeaderMap, library: LibraryScope) -> impl IntoResponse {
+    if let Err(rejection) = authorize(&header_map, &library, Permission::Browse) {
+        return *rejection;
     }
 
-    match crate::core::progress::update_progress(
-        &state.app_data_dir,
-        payload.book_id,
-        &payload.status,
-    ) {
-        Ok(_) => StatusCode::OK.into_response(),
+    let result = library.progress_db().run(progress::get_all_progress).await;
+    match result {
+        Ok(mut records) => {
+            let hidden = library.hidden_ids();
+            records.retain(|record| !hidden.contains(&record.book_id));
+            Json(records).into_response()
+        }
         Err(e) => (
             StatusCode::INTERNAL_SERVER_ERROR,
             format!("DB Error: {}", e),
@@ -408,36 +1753,705 @@ async fn update_progress(
     }
 }
 
-/// Validates the `Authorization` header against the set of authorized tokens.
-fn is_authorized(headers: &header::HeaderMap, state: &SharedState) -> bool {
-    if let Some(auth_header) = headers.get(header::AUTHORIZATION) {
-        if let Ok(auth_str) = auth_header.to_str() {
-            if let Some(token) = auth_str.strip_prefix("Bearer ") {
-                return state.authorized_tokens.lock().unwrap().contains(token);
-            }
-        }
-    }
-    false
+/// Error body returned for rejected API requests.
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+struct ApiError {
+    /// Machine-readable error code, e.g. `invalid_payload`.
+    error: String,
+    message: String,
+    /// Path of the offending JSON field, when the error concerns one.
+    field: Option<String>,
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::core::db;
-    use axum_test::TestServer;
-    use rusqlite::Connection;
-    use std::fs;
-    use std::path::Path;
-    use tempfile::tempdir;
+fn api_error(
+    status: StatusCode,
+    error: &str,
+    message: String,
+    field: Option<&str>,
+) -> (StatusCode, Json<ApiError>) {
+    (
+        status,
+        Json(ApiError {
+            error: error.to_string(),
+            message,
+            field: field.map(|f| f.to_string()),
+        }),
+    )
+}
 
-    // Helper to generate a mini mock lib for server tests (similar to gen_mock_lib but smaller/scoped)
-    fn setup_mock_lib(path: &Path) {
-        fs::create_dir_all(path).unwrap();
+type ApiRejection = (StatusCode, Json<ApiError>);
+
+fn invalid_field(field: &str, message: String) -> ApiRejection {
+    api_error(
+        StatusCode::UNPROCESSABLE_ENTITY,
+        "invalid_payload",
+        message,
+        Some(field),
+    )
+}
+
+/// Deserializes a JSON body, reporting the failing field as a 422.
+fn parse_json<T: serde::de::DeserializeOwned>(
+    payload: Result<Json<serde_json::Value>, JsonRejection>,
+) -> Result<T, ApiRejection> {
+    let Json(value) = payload.map_err(|rejection| {
+        api_error(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "invalid_payload",
+            rejection.body_text(),
+            None,
+        )
+    })?;
+
+    serde_path_to_error::deserialize(value).map_err(|e| {
+        let field = e.path().to_string();
+        api_error(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "invalid_payload",
+            e.inner().to_string(),
+            (field != ".").then_some(field.as_str()),
+        )
+    })
+}
+
+fn check_percentage(field: &'static str, value: Option<f64>) -> Result<(), ApiRejection> {
+    progress::check_percentage(field, value)
+        .map_err(|(field, message)| invalid_field(field, message))
+}
+
+fn parse_progress_update(
+    payload: Result<Json<serde_json::Value>, JsonRejection>,
+) -> Result<progress::ProgressUpdate, ApiRejection> {
+    let update: progress::ProgressUpdate = parse_json(payload)?;
+    update
+        .validate()
+        .map_err(|(field, message)| invalid_field(field, message))?;
+    Ok(update)
+}
+
+/// Longest reading session accepted, to catch clients that never closed one.
+const MAX_SESSION_SECS: i64 = 24 * 60 * 60;
+
+fn parse_session(
+    payload: Result<Json<serde_json::Value>, JsonRejection>,
+) -> Result<progress::ReadingSession, ApiRejection> {
+    let session: progress::ReadingSession = parse_json(payload)?;
+
+    let duration = session.ended_at - session.started_at;
+    if duration < 0 {
+        return Err(invalid_field(
+            "ended_at",
+            "ended_at must not be before started_at".to_string(),
+        ));
+    }
+    if duration > MAX_SESSION_SECS {
+        return Err(invalid_field(
+            "ended_at",
+            "sessions may not be longer than 24 hours".to_string(),
+        ));
+    }
+    check_percentage("start_percentage", session.start_percentage)?;
+    check_percentage("end_percentage", session.end_percentage)?;
+    if session.pages_read.is_some_and(|p| p < 0) {
+        return Err(invalid_field(
+            "pages_read",
+            "pages_read must not be negative".to_string(),
+        ));
+    }
+
+    Ok(session)
+}
+
+/// Handler for `POST /api/progress`.
+///
+/// Records the reading status and position (percentage, locator, page, chapter)
+/// reported by a device and returns the merged position plus any conflicting
+/// device positions. Requires `Authorization: Bearer <token>` header.
+///
+/// Malformed payloads are rejected with `422` and an [`ApiError`] body naming the
+/// field; books that are not in the library are rejected with `404`.
+async fn update_progress(
+    header_map: header::HeaderMap,
+    library: LibraryScope,
+    payload: Result<Json<serde_json::Value>, JsonRejection>,
+) -> impl IntoResponse {
+    if let Err(rejection) = authorize(&header_map, &library, Permission::Write) {
+        return *rejection;
+    }
+
+    let payload = match parse_progress_update(payload) {
+        Ok(payload) => payload,
+        Err(rejection) => return rejection.into_response(),
+    };
+
+    if !library.has_book(payload.book_id) {
+        return api_error(
+            StatusCode::NOT_FOUND,
+            "book_not_found",
+            format!("Book {} is not in the library", payload.book_id),
+            Some("book_id"),
+        )
+        .into_response();
+    }
+
+    let result = library
+        .progress_db()
+        .run(move |conn| progress::update_progress(conn, &payload))
+        .await;
+    match result {
+        Ok(merge) => Json(merge).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("DB Error: {}", e),
+        )
+            .into_response(),
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct BookProgress {
+    record: Option<progress::ProgressRecord>,
+    devices: Vec<progress::ProgressRecord>,
+}
+
+/// Handler for `GET /api/progress/{book_id}`.
+///
+/// Returns the merged position for a book together with the latest position
+/// reported by each device. Requires `Authorization: Bearer <token>` header.
+async fn get_book_progress(
+    header_map: header::HeaderMap,
+    Path(BookPath { book_id }): Path<BookPath>,
+    library: LibraryScope,
+) -> impl IntoResponse {
+    if let Err(rejection) = authorize(&header_map, &library, Permission::Browse) {
+        return *rejection;
+    }
+
+    if !library.has_book(book_id) {
+        return api_error(
+            StatusCode::NOT_FOUND,
+            "book_not_found",
+            format!("Book {} is not in the library", book_id),
+            None,
+        )
+        .into_response();
+    }
+
+    let result = library
+        .progress_db()
+        .run(move |conn| {
+            Ok::<_, AppError>(BookProgress {
+                record: progress::get_progress(conn, book_id)?,
+                devices: progress::get_device_progress(conn, book_id)?,
+            })
+        })
+        .await;
+    match result {
+        Ok(book_progress) => Json(book_progress).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("DB Error: {}", e),
+        )
+            .into_response(),
+    }
+}
+
+/// Handler for `POST /api/progress/sessions`.
+///
+/// Records a reading session (book, device, start and end time, position
+/// advanced) used for reading statistics. Requires `Authorization: Bearer <token>` header.
+async fn record_session(
+    header_map: header::HeaderMap,
+    library: LibraryScope,
+    payload: Result<Json<serde_json::Value>, JsonRejection>,
+) -> impl IntoResponse {
+    if let Err(rejection) = authorize(&header_map, &library, Permission::Write) {
+        return *rejection;
+    }
+
+    let mut session = match parse_session(payload) {
+        Ok(session) => session,
+        Err(rejection) => return rejection.into_response(),
+    };
+
+    if !library.has_book(session.book_id) {
+        return api_error(
+            StatusCode::NOT_FOUND,
+            "book_not_found",
+            format!("Book {} is not in the library", session.book_id),
+            Some("book_id"),
+        )
+        .into_response();
+    }
+
+    let to_record = session.clone();
+    let result = library
+        .progress_db()
+        .run(move |conn| progress::record_session(conn, &to_record))
+        .await;
+    match result {
+        Ok(id) => {
+            session.id = id;
+            (StatusCode::CREATED, Json(session)).into_response()
+        }
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("DB Error: {}", e),
+        )
+            .into_response(),
+    }
+}
+
+/// Handler for `GET /api/stats`.
+///
+/// Returns reading statistics, optionally limited with `from`/`to` Unix
+/// timestamps; `utc_offset_minutes` sets the time zone used for days.
+/// Requires `Authorization: Bearer <token>` header.
+async fn get_stats(
+    header_map: header::HeaderMap,
+    Query(query): Query<progress::StatsQuery>,
+    library: LibraryScope,
+) -> impl IntoResponse {
+    if let Err(rejection) = authorize(&header_map, &library, Permission::Browse) {
+        return *rejection;
+    }
+
+    let books = library.books();
+    let hidden = library.hidden_ids();
+    let result = library
+        .progress_db()
+        .run(move |conn| progress::get_reading_stats(conn, &books, &hidden, &query))
+        .await;
+    match result {
+        Ok(stats) => Json(stats).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("DB Error: {}", e),
+        )
+            .into_response(),
+    }
+}
+
+/// Longest annotation ID accepted; clients normally send UUIDs.
+const MAX_ANNOTATION_ID_LEN: usize = 64;
+
+fn parse_annotation_change(
+    payload: Result<Json<serde_json::Value>, JsonRejection>,
+) -> Result<annotations::AnnotationChange, ApiRejection> {
+    let change: annotations::AnnotationChange = parse_json(payload)?;
+
+    if change
+        .id
+        .as_ref()
+        .is_some_and(|id| id.is_empty() || id.len() > MAX_ANNOTATION_ID_LEN)
+    {
+        return Err(invalid_field(
+            "id",
+            format!("id must be 1 to {} characters", MAX_ANNOTATION_ID_LEN),
+        ));
+    }
+    let is_empty = |value: &Option<String>| value.as_deref().is_none_or(str::is_empty);
+    if !change.deleted
+        && is_empty(&change.text)
+        && is_empty(&change.cfi_range)
+        && is_empty(&change.note)
+    {
+        return Err(invalid_field(
+            "text",
+            "an annotation needs text, cfi_range or note".to_string(),
+        ));
+    }
+    if change.color.as_ref().is_some_and(|c| c.len() > 32) {
+        return Err(invalid_field(
+            "color",
+            "color must be at most 32 characters".to_string(),
+        ));
+    }
+
+    Ok(change)
+}
+
+/// Loads the stored annotation `id`, if the device may see its book.
+///
+/// Annotations on hidden books are reported as missing, so a device cannot
+/// learn of them or change them.
+async fn visible_annotation(
+    library: &LibraryScope,
+    id: &str,
+) -> Result<Option<annotations::Annotation>, Response> {
+    let to_load = id.to_string();
+    let result = library
+        .annotations_db()
+        .run(move |conn| annotations::get_annotation(conn, &to_load))
+        .await;
+    match result {
+        Ok(Some(annotation)) if !library.has_book(annotation.book_id) => Err(api_error(
+            StatusCode::NOT_FOUND,
+            "annotation_not_found",
+            format!("Annotation {} does not exist", id),
+            None,
+        )
+        .into_response()),
+        Ok(annotation) => Ok(annotation),
+        Err(e) => Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("DB Error: {}", e),
+        )
+            .into_response()),
+    }
+}
+
+/// Stores an annotation change and replies with the merged annotation.
+///
+/// An existing annotation stays on its book; changes moving it to another
+/// book are rejected.
+async fn save_annotation(
+    library: &LibraryScope,
+    change: annotations::AnnotationChange,
+) -> Response {
+    if let Some(id) = &change.id {
+        match visible_annotation(library, id).await {
+            Ok(Some(stored)) if stored.book_id != change.book_id => {
+                return api_error(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "book_mismatch",
+                    format!(
+                        "Annotation {} belongs to book {}, not {}",
+                        id, stored.book_id, change.book_id
+                    ),
+                    Some("book_id"),
+                )
+                .into_response();
+            }
+            Ok(_) => {}
+            Err(response) => return response,
+        }
+    }
+    if !library.has_book(change.book_id) {
+        return api_error(
+            StatusCode::NOT_FOUND,
+            "book_not_found",
+            format!("Book {} is not in the library", change.book_id),
+            Some("book_id"),
+        )
+        .into_response();
+    }
+
+    let result = library
+        .annotations_db()
+        .run(move |conn| annotations::save_annotation(conn, &change))
+        .await;
+    match result {
+        Ok(saved) if saved.created => (StatusCode::CREATED, Json(saved)).into_response(),
+        Ok(saved) => Json(saved).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("DB Error: {}", e),
+        )
+            .into_response(),
+    }
+}
+
+/// Handler for `GET /api/annotations`.
+///
+/// Lists annotations, optionally for one `book_id`. With `since` (a previous
+/// `synced_at`), returns only changes received since then, deletions included,
+/// so devices can sync incrementally. Requires `Authorization: Bearer <token>` header.
+async fn list_annotations(
+    header_map: header::HeaderMap,
+    Query(query): Query<annotations::AnnotationQuery>,
+    library: LibraryScope,
+) -> impl IntoResponse {
+    if let Err(rejection) = authorize(&header_map, &library, Permission::Browse) {
+        return *rejection;
+    }
+
+    let result = library
+        .annotations_db()
+        .run(move |conn| annotations::list_annotations(conn, &query))
+        .await;
+    match result {
+        Ok(mut list) => {
+            let hidden = library.hidden_ids();
+            list.retain(|annotation| !hidden.contains(&annotation.book_id));
+            Json(list).into_response()
+        }
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("DB Error: {}", e),
+        )
+            .into_response(),
+    }
+}
+
+/// Handler for `POST /api/annotations`.
+///
+/// Creates an annotation, or merges it into an existing one with the same
+/// client-generated `id`; the most recent edit wins. Replies `201` when the
+/// annotation is new. Requires `Authorization: Bearer <token>` header.
+async fn create_annotation(
+    header_map: header::HeaderMap,
+    library: LibraryScope,
+    payload: Result<Json<serde_json::Value>, JsonRejection>,
+) -> impl IntoResponse {
+    if let Err(rejection) = authorize(&header_map, &library, Permission::Write) {
+        return *rejection;
+    }
+
+    match parse_annotation_change(payload) {
+        Ok(change) => save_annotation(&library, change).await,
+        Err(rejection) => rejection.into_response(),
+    }
+}
+
+/// Handler for `PUT /api/annotations/{id}`.
+///
+/// Replaces an annotation with the version sent, unless the store already
+/// holds a newer edit (then `applied` is false and the newer edit is returned).
+/// Requires `Authorization: Bearer <token>` header.
+async fn update_annotation(
+    header_map: header::HeaderMap,
+    Path(AnnotationPath { id }): Path<AnnotationPath>,
+    library: LibraryScope,
+    payload: Result<Json<serde_json::Value>, JsonRejection>,
+) -> impl IntoResponse {
+    if let Err(rejection) = authorize(&header_map, &library, Permission::Write) {
+        return *rejection;
+    }
+
+    match parse_annotation_change(payload) {
+        Ok(change) => {
+            save_annotation(
+                &library,
+                annotations::AnnotationChange {
+                    id: Some(id),
+                    ..change
+                },
+            )
+            .await
+        }
+        Err(rejection) => rejection.into_response(),
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct DeleteParams {
+    device: Option<String>,
+    /// Client time of the deletion; defaults to now.
+    deleted_at: Option<i64>,
+}
+
+/// Handler for `DELETE /api/annotations/{id}`.
+///
+/// Marks an annotation as deleted. The tombstone is kept so other devices
+/// learn of the deletion. Requires `Authorization: Bearer <token>` header.
+async fn delete_annotation(
+    header_map: header::HeaderMap,
+    Path(AnnotationPath { id }): Path<AnnotationPath>,
+    Query(params): Query<DeleteParams>,
+    library: LibraryScope,
+) -> impl IntoResponse {
+    if let Err(rejection) = authorize(&header_map, &library, Permission::Write) {
+        return *rejection;
+    }
+
+    match visible_annotation(&library, &id).await {
+        Ok(Some(_)) => {}
+        Ok(None) => {
+            return api_error(
+                StatusCode::NOT_FOUND,
+                "annotation_not_found",
+                format!("Annotation {} does not exist", id),
+                None,
+            )
+            .into_response()
+        }
+        Err(response) => return response,
+    }
+
+    let to_delete = id.clone();
+    let result = library
+        .annotations_db()
+        .run(move |conn| {
+            annotations::delete_annotation(
+                conn,
+                &to_delete,
+                params.device.as_deref(),
+                params.deleted_at,
+            )
+        })
+        .await;
+    match result {
+        Ok(Some(saved)) => Json(saved).into_response(),
+        Ok(None) => api_error(
+            StatusCode::NOT_FOUND,
+            "annotation_not_found",
+            format!("Annotation {} does not exist", id),
+            None,
+        )
+        .into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("DB Error: {}", e),
+        )
+            .into_response(),
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct ExportParams {
+    #[serde(default)]
+    format: annotations::ExportFormat,
+}
+
+/// Handler for `GET /api/annotations/export/{book_id}`.
+///
+/// Downloads a book's highlights and notes in reading order, as Markdown
+/// (default) or JSON with `?format=json`. Requires `Authorization: Bearer <token>` header.
+async fn export_annotations(
+    header_map: header::HeaderMap,
+    Path(BookPath { book_id }): Path<BookPath>,
+    Query(params): Query<ExportParams>,
+    library: LibraryScope,
+) -> impl IntoResponse {
+    if let Err(rejection) = authorize(&header_map, &library, Permission::Browse) {
+        return *rejection;
+    }
+
+    let Some(book) = library.find_book(book_id) else {
+        return api_error(
+            StatusCode::NOT_FOUND,
+            "book_not_found",
+            format!("Book {} is not in the library", book_id),
+            None,
+        )
+        .into_response();
+    };
+
+    let to_export = book.clone();
+    let result = library
+        .annotations_db()
+        .run(move |conn| annotations::export_book(conn, &to_export, params.format))
+        .await;
+    match result {
+        Ok((document, mime)) => {
+            let extension = match params.format {
+                annotations::ExportFormat::Markdown => "md",
+                annotations::ExportFormat::Json => "json",
+            };
+            let file_name = bundle::sanitize_file_name(&format!("{} - annotations", book.title));
+            let disposition = format!("attachment; filename=\"{}.{}\"", file_name, extension);
+            (
+                [
+                    (header::CONTENT_TYPE, format!("{}; charset=utf-8", mime)),
+                    (header::CONTENT_DISPOSITION, disposition),
+                ],
+                document,
+            )
+                .into_response()
+        }
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("DB Error: {}", e),
+        )
+            .into_response(),
+    }
+}
+
+fn bearer_token(headers: &header::HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+}
+
+/// Validates the `Authorization` header against the set of authorized tokens.
+fn is_authorized(headers: &header::HeaderMap, state: &SharedState) -> bool {
+    bearer_token(headers)
+        .is_some_and(|token| state.authorized_tokens.lock().unwrap().contains(token))
+}
+
+/// The scope of the device the bearer token was issued to; full access for
+/// tokens without a device record.
+fn device_scope(headers: &header::HeaderMap, state: &ServerState) -> Scope {
+    bearer_token(headers)
+        .and_then(|token| state.devices.lock().unwrap().devices.get(token).cloned())
+        .map(|device| device.scope)
+        .unwrap_or_default()
+}
+
+/// What a request does, to check against the device's [`Scope`].
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum Permission {
+    /// Lists books, covers, progress, statistics or annotations.
+    Browse,
+    /// Downloads book files.
+    Download,
+    /// Records progress, sessions or annotations.
+    Write,
+}
+
+/// Validates the `Authorization` header, and that the device was granted
+/// access to `library` and `permission`.
+fn authorize(
+    headers: &header::HeaderMap,
+    library: &LibraryScope,
+    permission: Permission,
+) -> Result<(), Box<Response>> {
+    if !is_authorized(headers, &library.state) {
+        return Err(Box::new(
+            (StatusCode::UNAUTHORIZED, "Unauthorized").into_response(),
+        ));
+    }
+    match refusal(library, permission) {
+        Some((error, message)) => Err(Box::new(
+            api_error(StatusCode::FORBIDDEN, error, message, None).into_response(),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Why the device may not use `library` for `permission`, as an error code
+/// and message; `None` if it may.
+pub(crate) fn refusal(
+    library: &LibraryScope,
+    permission: Permission,
+) -> Option<(&'static str, String)> {
+    if !library.access.can_access(&library.library.scope_id) {
+        Some((
+            "library_forbidden",
+            format!("This device has no access to library {}", library.id()),
+        ))
+    } else if permission == Permission::Download && !library.access.can_download {
+        Some((
+            "download_forbidden",
+            "This device may browse but not download books".to_string(),
+        ))
+    } else if permission == Permission::Write && !library.access.can_write {
+        Some(("read_only", "This device has read-only access".to_string()))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum_test::TestServer;
+    use rusqlite::Connection;
+    use std::fs;
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    // Helper to generate a mini mock lib for server tests (similar to gen_mock_lib but smaller/scoped)
+    fn setup_mock_lib(path: &Path) {
+        fs::create_dir_all(path).unwrap();
         let db_path = path.join("metadata.db");
         let conn = Connection::open(&db_path).unwrap();
 
         // Create all tables required by get_calibre_metadata query
-        conn.execute("CREATE TABLE books (id INTEGER PRIMARY KEY, title TEXT, path TEXT, series INTEGER, series_index REAL)", []).unwrap();
+        conn.execute("CREATE TABLE books (id INTEGER PRIMARY KEY, title TEXT, path TEXT, series INTEGER, series_index REAL, timestamp TIMESTAMP)", []).unwrap();
         conn.execute(
             "CREATE TABLE authors (id INTEGER PRIMARY KEY, name TEXT)",
             [],
@@ -463,7 +2477,7 @@ mod tests {
         .unwrap();
         conn.execute("CREATE TABLE books_publishers_link (id INTEGER PRIMARY KEY, book INTEGER, publisher INTEGER)", []).unwrap();
         conn.execute(
-            "CREATE TABLE data (id INTEGER PRIMARY KEY, book INTEGER, format TEXT)",
+            "CREATE TABLE data (id INTEGER PRIMARY KEY, book INTEGER, format TEXT, uncompressed_size INTEGER)",
             [],
         )
         .unwrap();
@@ -486,28 +2500,99 @@ mod tests {
         fs::write(book_dir.join("cover.jpg"), "fake cover").unwrap();
     }
 
+    /// Server keeping its data in `dir` and serving the library there as its
+    /// default one, to which the bearer token `test-token` has access.
+    fn test_state(dir: &Path) -> Arc<ServerState> {
+        let state = Arc::new(ServerState::new(dir.to_path_buf(), "1234".to_string()));
+        state.load_library(dir.to_str().unwrap()).unwrap();
+        state
+            .authorized_tokens
+            .lock()
+            .unwrap()
+            .insert("test-token".to_string());
+        state
+    }
+
+    #[test]
+    fn test_load_library_keeps_connection() {
+        let dir = tempdir().unwrap();
+        setup_mock_lib(dir.path());
+        let library = dir.path().to_str().unwrap();
+        let state = ServerState::new(dir.path().to_path_buf(), "1234".to_string());
+
+        assert!(state.load_library("/does/not/exist").is_err());
+        assert!(state.default_library().is_err());
+
+        let changes = state.advertisement_changed.subscribe();
+        let books = state.load_library(library).unwrap();
+        assert_eq!(books[0].title, "Server Test Book");
+        assert_eq!(state.default_library().unwrap().path, library);
+        assert!(changes.has_changed().unwrap());
+        let first = state.advertisement();
+        assert_eq!(first.book_count, Some(1));
+        assert_eq!(first.host_id.as_deref(), Some(state.host_id.as_str()));
+
+        // Reloading sees new books through the kept connection
+        Connection::open(dir.path().join("metadata.db"))
+            .unwrap()
+            .execute(
+                "INSERT INTO books (id, title, path) VALUES (2, 'Second Book', 'b')",
+                [],
+            )
+            .unwrap();
+        assert_eq!(state.load_library(library).unwrap().len(), 2);
+        assert_eq!(state.default_library().unwrap().books().unwrap().len(), 2);
+        let second = state.advertisement();
+        assert_eq!(second.book_count, Some(2));
+        assert_ne!(second.library_revision, first.library_revision);
+    }
+
+    #[test]
+    fn test_switching_default_library_keeps_progress_apart() {
+        let data_dir = tempdir().unwrap();
+        let (first, second) = (tempdir().unwrap(), tempdir().unwrap());
+        setup_mock_lib(first.path());
+        setup_mock_lib(second.path());
+        let (first, second) = (
+            first.path().to_str().unwrap(),
+            second.path().to_str().unwrap(),
+        );
+        // Progress kept from before libraries had their own folders
+        progress::update_progress(
+            &progress::open_progress_db(data_dir.path()).unwrap(),
+            &progress::ProgressUpdate {
+                book_id: 1,
+                status: progress::ReadingStatus::Reading,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let state = ServerState::new(data_dir.path().to_path_buf(), "1234".to_string());
+
+        let has_progress = |state: &ServerState| {
+            let library = state.default_library().unwrap();
+            let conn = library.progress_db.get().unwrap();
+            progress::get_progress(&conn, 1).unwrap().is_some()
+        };
+        state.load_library(first).unwrap();
+        assert!(has_progress(&state));
+        state.load_library(second).unwrap();
+        assert!(!has_progress(&state));
+        state.load_library(first).unwrap();
+        assert!(has_progress(&state));
+
+        // A registered library cannot also be the default one
+        state.add_library(second, None).unwrap();
+        assert!(state.load_library(second).is_err());
+        assert_eq!(state.default_library().unwrap().path, first);
+    }
+
     #[tokio::test]
     async fn test_manifest() {
         let dir = tempdir().unwrap();
         setup_mock_lib(dir.path());
 
-        let state = Arc::new(ServerState {
-            library_path: Mutex::new(Some(dir.path().to_str().unwrap().to_string())),
-            books: Mutex::new(Vec::new()),
-            pin: "1234".to_string(),
-            authorized_tokens: Mutex::new({
-                let mut set = std::collections::HashSet::new();
-                set.insert("test-token".to_string());
-                set
-            }),
-            app_data_dir: dir.path().to_path_buf(),
-        });
-
-        // Pre-populate cache because get_manifest now reads from cache!
-        {
-            let mut books = state.books.lock().unwrap();
-            *books = db::get_calibre_metadata(dir.path().to_str().unwrap()).unwrap();
-        }
+        let state = test_state(dir.path());
 
         let app = Router::new()
             .route("/api/manifest", get(get_manifest))
@@ -530,23 +2615,7 @@ mod tests {
         let dir = tempdir().unwrap();
         setup_mock_lib(dir.path());
 
-        let state = Arc::new(ServerState {
-            library_path: Mutex::new(Some(dir.path().to_str().unwrap().to_string())),
-            books: Mutex::new(Vec::new()),
-            pin: "1234".to_string(),
-            authorized_tokens: Mutex::new({
-                let mut set = std::collections::HashSet::new();
-                set.insert("test-token".to_string());
-                set
-            }),
-            app_data_dir: dir.path().to_path_buf(),
-        });
-
-        // Pre-populate cache
-        {
-            let mut books = state.books.lock().unwrap();
-            *books = db::get_calibre_metadata(dir.path().to_str().unwrap()).unwrap();
-        }
+        let state = test_state(dir.path());
 
         let app = Router::new()
             .route("/api/download/{book_id}/{format}", get(download_book))
@@ -563,6 +2632,1013 @@ mod tests {
         response.assert_header("content-type", "application/epub+zip");
     }
 
+    #[tokio::test]
+    async fn test_download_book_falls_back_by_registry() {
+        let dir = tempdir().unwrap();
+        setup_mock_lib(dir.path());
+
+        let book_dir = dir.path().join("test/book");
+        fs::remove_file(book_dir.join("book.epub")).unwrap();
+        fs::write(book_dir.join("book.azw3"), "kindle content").unwrap();
+
+        let state = test_state(dir.path());
+
+        let app = Router::new()
+            .route("/api/download/{book_id}/{format}", get(download_book))
+            .with_state(state);
+
+        let server = TestServer::new(app).unwrap();
+        let response = server
+            .get("/api/download/1/best")
+            .add_header(header::AUTHORIZATION, "Bearer test-token")
+            .await;
+
+        response.assert_status_ok();
+        response.assert_text("kindle content");
+        response.assert_header("content-type", "application/vnd.amazon.mobi8-ebook");
+
+        // A client that can only open PDFs should not be handed the AZW3
+        let response = server
+            .get("/api/download/1/best?accept=pdf")
+            .add_header(header::AUTHORIZATION, "Bearer test-token")
+            .await;
+
+        response.assert_status_not_found();
+    }
+
+    #[tokio::test]
+    async fn test_download_book_embeds_metadata() {
+        let dir = tempdir().unwrap();
+        setup_mock_lib(dir.path());
+        epub::tests::write_test_epub(&dir.path().join("test/book/book.epub"));
+
+        let state = test_state(dir.path());
+
+        let app = Router::new()
+            .route("/api/download/{book_id}/{format}", get(download_book))
+            .with_state(state);
+
+        let server = TestServer::new(app).unwrap();
+        let response = server
+            .get("/api/download/1/epub?embed_metadata=true")
+            .add_header(header::AUTHORIZATION, "Bearer test-token")
+            .await;
+
+        response.assert_status_ok();
+        response.assert_header("content-type", "application/epub+zip");
+
+        let mut archive =
+            zip::ZipArchive::new(std::io::Cursor::new(response.into_bytes())).unwrap();
+        let opf = epub::read_entry_to_string(&mut archive, "OEBPS/content.opf").unwrap();
+        assert!(opf.contains("<dc:title>Server Test Book</dc:title>"));
+        assert!(opf.contains("<dc:creator>Tester</dc:creator>"));
+
+        // The rewrite is streamed without a temporary copy
+        assert!(!dir.path().join("cache").join("downloads").exists());
+    }
+
+    #[tokio::test]
+    async fn test_download_book_converts_kepub() {
+        let dir = tempdir().unwrap();
+        setup_mock_lib(dir.path());
+        epub::tests::write_test_epub(&dir.path().join("test/book/book.epub"));
+
+        let state = test_state(dir.path());
+        let data_dir = state.default_library().unwrap().data_dir.clone();
+
+        let app = Router::new()
+            .route("/api/download/{book_id}/{format}", get(download_book))
+            .with_state(state);
+
+        let server = TestServer::new(app).unwrap();
+        let response = server
+            .get("/api/download/1/kepub")
+            .add_header(header::AUTHORIZATION, "Bearer test-token")
+            .await;
+
+        response.assert_status_ok();
+        response.assert_header("content-type", "application/kepub+zip");
+        response.assert_header(
+            "content-disposition",
+            "attachment; filename=\"book.kepub.epub\"",
+        );
+
+        let mut archive =
+            zip::ZipArchive::new(std::io::Cursor::new(response.into_bytes())).unwrap();
+        let chapter = epub::read_entry_to_string(&mut archive, "OEBPS/chapter1.xhtml").unwrap();
+        assert!(chapter.contains("koboSpan"));
+
+        // The conversion is cached next to the cover cache
+        assert!(data_dir.join("cache/kepub/1.kepub.epub").exists());
+    }
+
+    #[tokio::test]
+    async fn test_download_bundle() {
+        let dir = tempdir().unwrap();
+        setup_mock_lib(dir.path());
+
+        let state = test_state(dir.path());
+
+        let app = Router::new()
+            .route("/api/bundle", get(download_bundle))
+            .with_state(state);
+
+        let server = TestServer::new(app).unwrap();
+        let response = server
+            .get("/api/bundle?author=tester")
+            .add_header(header::AUTHORIZATION, "Bearer test-token")
+            .await;
+
+        response.assert_status_ok();
+        response.assert_header("content-type", "application/zip");
+        response.assert_header(
+            "content-disposition",
+            "attachment; filename=\"tester.zip\"; filename*=UTF-8''tester.zip",
+        );
+
+        let mut archive =
+            zip::ZipArchive::new(std::io::Cursor::new(response.into_bytes())).unwrap();
+        let manifest = epub::read_entry_to_string(&mut archive, bundle::MANIFEST_NAME).unwrap();
+        assert!(manifest.contains("Server Test Book"));
+        let content =
+            epub::read_entry_to_string(&mut archive, "Tester - Server Test Book/book.epub")
+                .unwrap();
+        assert_eq!(content, "dummy content");
+
+        // A book asked for twice is bundled once
+        let response = server
+            .get("/api/bundle?ids=1,1")
+            .add_header(header::AUTHORIZATION, "Bearer test-token")
+            .await;
+        response.assert_status_ok();
+        let archive = zip::ZipArchive::new(std::io::Cursor::new(response.into_bytes())).unwrap();
+        assert_eq!(archive.len(), 2);
+
+        // IDs that match no book are listed as missing
+        let response = server
+            .get("/api/bundle?ids=1,99")
+            .add_header(header::AUTHORIZATION, "Bearer test-token")
+            .await;
+        response.assert_status_ok();
+        let mut archive =
+            zip::ZipArchive::new(std::io::Cursor::new(response.into_bytes())).unwrap();
+        let manifest: bundle::BundleManifest = serde_json::from_str(
+            &epub::read_entry_to_string(&mut archive, bundle::MANIFEST_NAME).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(manifest.books.len(), 1);
+        assert_eq!(manifest.missing, vec![99]);
+
+        let response = server
+            .get("/api/bundle?series=Nothing")
+            .add_header(header::AUTHORIZATION, "Bearer test-token")
+            .await;
+        response.assert_status_not_found();
+
+        let response = server
+            .get("/api/bundle")
+            .add_header(header::AUTHORIZATION, "Bearer test-token")
+            .await;
+        response.assert_status_bad_request();
+    }
+
+    #[test]
+    fn test_attachment_disposition() {
+        assert_eq!(
+            attachment_disposition("Ça \"va\"\r\n.zip"),
+            "attachment; filename=\"_a _va___.zip\"; filename*=UTF-8''%C3%87a%20_va___.zip"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_upload_book() {
+        let dir = tempdir().unwrap();
+
+        let state = Arc::new(ServerState::new(
+            dir.path().to_path_buf(),
+            "1234".to_string(),
+        ));
+        state
+            .authorized_tokens
+            .lock()
+            .unwrap()
+            .insert("test-token".to_string());
+
+        let app = Router::new()
+            .route("/api/upload", axum::routing::post(upload_book))
+            .with_state(state);
+
+        let server = TestServer::new(app).unwrap();
+        let response = server
+            .post("/api/upload?filename=Found%20It.pdf&title=Found%20It&tags=phone,new")
+            .add_header(header::AUTHORIZATION, "Bearer test-token")
+            .bytes("%PDF-1.7 fake pdf".into())
+            .await;
+
+        response.assert_status(StatusCode::CREATED);
+        let item = response.json::<inbox::InboxItem>();
+        assert_eq!(item.format, "pdf");
+        assert_eq!(item.tags, vec!["phone", "new"]);
+
+        let items = inbox::list_items(dir.path()).unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].title.as_deref(), Some("Found It"));
+
+        // Wrong content for the extension
+        let response = server
+            .post("/api/upload?filename=fake.epub")
+            .add_header(header::AUTHORIZATION, "Bearer test-token")
+            .bytes("not a zip".into())
+            .await;
+        response.assert_status(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+        // Unknown extension
+        let response = server
+            .post("/api/upload?filename=virus.exe")
+            .add_header(header::AUTHORIZATION, "Bearer test-token")
+            .bytes("MZ".into())
+            .await;
+        response.assert_status(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+        // Rejected uploads leave nothing behind
+        assert_eq!(inbox::list_items(dir.path()).unwrap().len(), 1);
+        assert_eq!(
+            fs::read_dir(inbox::inbox_dir(dir.path())).unwrap().count(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_progress_round_trip() {
+        let dir = tempdir().unwrap();
+        setup_mock_lib(dir.path());
+
+        let state = test_state(dir.path());
+
+        let app = Router::new()
+            .route("/api/progress", get(get_progress).post(update_progress))
+            .route("/api/progress/{book_id}", get(get_book_progress))
+            .with_state(state);
+
+        let server = TestServer::new(app).unwrap();
+        server
+            .post("/api/progress")
+            .add_header(header::AUTHORIZATION, "Bearer test-token")
+            .json(&serde_json::json!({
+                "book_id": 1,
+                "status": "reading",
+                "percentage": 37.5,
+                "locator": "epubcfi(/6/8!/4/2/12:0)",
+                "chapter": "Chapter 4",
+                "device": "Kobo Clara",
+                "updated_at": 1700000000
+            }))
+            .await
+            .assert_status_ok();
+
+        let response = server
+            .get("/api/progress")
+            .add_header(header::AUTHORIZATION, "Bearer test-token")
+            .await;
+        response.assert_status_ok();
+        let records = response.json::<Vec<progress::ProgressRecord>>();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].percentage, Some(37.5));
+        assert_eq!(
+            records[0].locator.as_deref(),
+            Some("epubcfi(/6/8!/4/2/12:0)")
+        );
+        assert_eq!(records[0].device.as_deref(), Some("Kobo Clara"));
+
+        let response = server
+            .post("/api/progress")
+            .add_header(header::AUTHORIZATION, "Bearer test-token")
+            .json(&serde_json::json!({
+                "book_id": 1,
+                "status": "reading",
+                "percentage": 12.0,
+                "device": "Phone"
+            }))
+            .await;
+        response.assert_status_ok();
+        let merge = response.json::<progress::MergeResult>();
+        assert_eq!(merge.record.device.as_deref(), Some("Phone"));
+        assert_eq!(merge.conflicts.len(), 1);
+
+        let response = server
+            .get("/api/progress/1")
+            .add_header(header::AUTHORIZATION, "Bearer test-token")
+            .await;
+        response.assert_status_ok();
+        let book_progress = response.json::<BookProgress>();
+        assert_eq!(book_progress.devices.len(), 2);
+
+        // Out of range percentages are rejected
+        let response = server
+            .post("/api/progress")
+            .add_header(header::AUTHORIZATION, "Bearer test-token")
+            .json(&serde_json::json!({ "book_id": 1, "status": "reading", "percentage": 140.0 }))
+            .await;
+        response.assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(
+            response.json::<ApiError>().field.as_deref(),
+            Some("percentage")
+        );
+
+        // Unknown statuses name the offending field
+        let response = server
+            .post("/api/progress")
+            .add_header(header::AUTHORIZATION, "Bearer test-token")
+            .json(&serde_json::json!({ "book_id": 1, "status": "halfway" }))
+            .await;
+        response.assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+        let error = response.json::<ApiError>();
+        assert_eq!(error.error, "invalid_payload");
+        assert_eq!(error.field.as_deref(), Some("status"));
+
+        // Books that are not in the library
+        server
+            .post("/api/progress")
+            .add_header(header::AUTHORIZATION, "Bearer test-token")
+            .json(&serde_json::json!({ "book_id": 999, "status": "reading" }))
+            .await
+            .assert_status(StatusCode::NOT_FOUND);
+        server
+            .get("/api/progress/999")
+            .add_header(header::AUTHORIZATION, "Bearer test-token")
+            .await
+            .assert_status(StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_sessions_and_stats() {
+        let dir = tempdir().unwrap();
+        setup_mock_lib(dir.path());
+
+        let state = test_state(dir.path());
+
+        let app = Router::new()
+            .route(
+                "/api/progress/sessions",
+                axum::routing::post(record_session),
+            )
+            .route("/api/stats", get(get_stats))
+            .with_state(state);
+
+        let server = TestServer::new(app).unwrap();
+        let response = server
+            .post("/api/progress/sessions")
+            .add_header(header::AUTHORIZATION, "Bearer test-token")
+            .json(&serde_json::json!({
+                "book_id": 1,
+                "device": "Phone",
+                "started_at": 1_700_000_000,
+                "ended_at": 1_700_001_800,
+                "start_percentage": 10.0,
+                "end_percentage": 15.0
+            }))
+            .await;
+        response.assert_status(StatusCode::CREATED);
+        assert!(response.json::<progress::ReadingSession>().id > 0);
+
+        let response = server
+            .post("/api/progress/sessions")
+            .add_header(header::AUTHORIZATION, "Bearer test-token")
+            .json(&serde_json::json!({
+                "book_id": 1,
+                "started_at": 1_700_001_800,
+                "ended_at": 1_700_000_000
+            }))
+            .await;
+        response.assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(
+            response.json::<ApiError>().field.as_deref(),
+            Some("ended_at")
+        );
+
+        let response = server
+            .get("/api/stats?from=1699999999")
+            .add_header(header::AUTHORIZATION, "Bearer test-token")
+            .await;
+        response.assert_status_ok();
+        let stats = response.json::<progress::ReadingStats>();
+        assert_eq!(stats.total_seconds, 1800);
+        assert_eq!(stats.per_day[0].date, "2023-11-14");
+        assert_eq!(stats.per_author[0].author, "Tester");
+    }
+
+    #[tokio::test]
+    async fn test_annotations_crud_and_export() {
+        let dir = tempdir().unwrap();
+        setup_mock_lib(dir.path());
+
+        let state = test_state(dir.path());
+
+        let app = Router::new()
+            .route(
+                "/api/annotations",
+                get(list_annotations).post(create_annotation),
+            )
+            .route(
+                "/api/annotations/{id}",
+                axum::routing::put(update_annotation).delete(delete_annotation),
+            )
+            .route("/api/annotations/export/{book_id}", get(export_annotations))
+            .with_state(state);
+        let server = TestServer::new(app).unwrap();
+
+        let response = server
+            .post("/api/annotations")
+            .add_header(header::AUTHORIZATION, "Bearer test-token")
+            .json(&serde_json::json!({
+                "id": "h1",
+                "book_id": 1,
+                "text": "A highlighted line",
+                "cfi_range": "epubcfi(/6/4!/4/2,/1:0,/1:18)",
+                "color": "yellow",
+                "device": "Phone",
+                "updated_at": 1_700_000_000
+            }))
+            .await;
+        response.assert_status(StatusCode::CREATED);
+
+        // Empty annotations and unknown books are rejected
+        let response = server
+            .post("/api/annotations")
+            .add_header(header::AUTHORIZATION, "Bearer test-token")
+            .json(&serde_json::json!({ "book_id": 1 }))
+            .await;
+        response.assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+        server
+            .post("/api/annotations")
+            .add_header(header::AUTHORIZATION, "Bearer test-token")
+            .json(&serde_json::json!({ "book_id": 99, "note": "x" }))
+            .await
+            .assert_status(StatusCode::NOT_FOUND);
+
+        let response = server
+            .put("/api/annotations/h1")
+            .add_header(header::AUTHORIZATION, "Bearer test-token")
+            .json(&serde_json::json!({
+                "book_id": 1,
+                "text": "A highlighted line",
+                "note": "Remember this",
+                "device": "Tablet",
+                "updated_at": 1_700_000_100
+            }))
+            .await;
+        response.assert_status_ok();
+        assert!(response.json::<annotations::SaveResult>().applied);
+        // An annotation cannot be moved to another book
+        let response = server
+            .put("/api/annotations/h1")
+            .add_header(header::AUTHORIZATION, "Bearer test-token")
+            .json(&serde_json::json!({ "book_id": 2, "note": "Moved" }))
+            .await;
+        response.assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(response.json::<ApiError>().error, "book_mismatch");
+
+        let response = server
+            .get("/api/annotations/export/1")
+            .add_header(header::AUTHORIZATION, "Bearer test-token")
+            .await;
+        response.assert_status_ok();
+        assert!(response
+            .text()
+            .contains("> A highlighted line\n\nRemember this\n"));
+
+        server
+            .delete("/api/annotations/h1?device=Phone")
+            .add_header(header::AUTHORIZATION, "Bearer test-token")
+            .await
+            .assert_status_ok();
+        let response = server
+            .get("/api/annotations?book_id=1")
+            .add_header(header::AUTHORIZATION, "Bearer test-token")
+            .await;
+        assert!(response.json::<Vec<annotations::Annotation>>().is_empty());
+        let response = server
+            .get("/api/annotations?since=0")
+            .add_header(header::AUTHORIZATION, "Bearer test-token")
+            .await;
+        assert!(response.json::<Vec<annotations::Annotation>>()[0].deleted);
+    }
+
+    #[tokio::test]
+    async fn test_health_probe_of_manual_host() {
+        let dir = tempdir().unwrap();
+        setup_mock_lib(dir.path());
+        let state = test_state(dir.path());
+        let host_id = state.host_id.clone();
+
+        let app = Router::new()
+            .route("/api/health", get(health))
+            .with_state(state);
+        let server = TestServer::builder().http_transport().build(app).unwrap();
+        let port = server.server_address().unwrap().port().unwrap();
+
+        let client_dir = tempdir().unwrap();
+        let discovery = discovery::DiscoveryState::new(client_dir.path());
+        discovery.add_manual("127.0.0.1", port).unwrap();
+        // Nothing listens on port 9 (discard) in the test environment
+        discovery.add_manual("127.0.0.1", 9).unwrap();
+        assert!(discovery.probe_all(&reqwest::Client::new()).await);
+
+        let hosts = discovery.hosts();
+        let online = hosts.iter().find(|h| h.port == port).unwrap();
+        assert_eq!(online.online, Some(true));
+        assert!(online.latency_ms.is_some());
+        assert_eq!(online.key(), host_id);
+        assert_eq!(online.advertisement.book_count, Some(1));
+        let offline = hosts.iter().find(|h| h.port == 9).unwrap();
+        assert_eq!(offline.online, Some(false));
+
+        // Manual hosts survive a restart, and are removed by key
+        let reloaded = discovery::DiscoveryState::new(client_dir.path());
+        assert_eq!(reloaded.hosts().len(), 2);
+        assert!(discovery.remove_manual(&host_id).unwrap());
+        assert_eq!(
+            discovery::DiscoveryState::new(client_dir.path())
+                .hosts()
+                .len(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_libraries_are_namespaced_and_granted_per_device() {
+        let dir = tempdir().unwrap();
+        let default_library = dir.path().join("Calibre Library");
+        let kids_library = dir.path().join("Kids");
+        setup_mock_lib(&default_library);
+        setup_mock_lib(&kids_library);
+        let data_dir = dir.path().join("data");
+        fs::create_dir_all(&data_dir).unwrap();
+
+        let state = Arc::new(ServerState::new(data_dir.clone(), "1234".to_string()));
+        state
+            .load_library(default_library.to_str().unwrap())
+            .unwrap();
+        let kids = state
+            .add_library(kids_library.to_str().unwrap(), None)
+            .unwrap();
+        assert_eq!(kids.name, "Kids");
+        assert!(state
+            .add_library(kids_library.to_str().unwrap(), None)
+            .is_err());
+        assert_eq!(state.advertisement().library_count, Some(2));
+        assert_eq!(
+            state.advertisement().library_ids,
+            Some(vec!["default".to_string(), kids.id.clone()])
+        );
+
+        let server = TestServer::new(router(state.clone())).unwrap();
+        let auth = format!(
+            "Bearer {}",
+            issue_token(&state, Some("Tablet".to_string()))
+                .unwrap()
+                .token
+        );
+        let kids_route = |route: &str| format!("/api/libraries/{}{}", kids.id, route);
+
+        let listed = server
+            .get("/api/libraries")
+            .add_header(header::AUTHORIZATION, &auth)
+            .await
+            .json::<Vec<LibraryInfo>>();
+        assert_eq!(listed.len(), 2);
+        assert!(listed[0].default);
+
+        // Both libraries have a book 1; progress on one is not seen by the other
+        server
+            .post(&kids_route("/progress"))
+            .add_header(header::AUTHORIZATION, &auth)
+            .json(&serde_json::json!({ "book_id": 1, "status": "reading" }))
+            .await
+            .assert_status_ok();
+        for (route, expected) in [
+            (kids_route("/progress"), 1),
+            ("/api/progress".to_string(), 0),
+            ("/api/libraries/default/progress".to_string(), 0),
+        ] {
+            let records = server
+                .get(&route)
+                .add_header(header::AUTHORIZATION, &auth)
+                .await
+                .json::<Vec<serde_json::Value>>();
+            assert_eq!(records.len(), expected, "{}", route);
+        }
+        server
+            .get("/api/libraries/missing/manifest")
+            .add_header(header::AUTHORIZATION, &auth)
+            .await
+            .assert_status_not_found();
+
+        let device = state.devices().remove(0);
+        assert_eq!(device.name.as_deref(), Some("Tablet"));
+        state
+            .set_device_scope(
+                &device.id,
+                Scope {
+                    libraries: Some(vec![kids.id.clone()]),
+                    ..Scope::default()
+                },
+            )
+            .unwrap();
+        server
+            .get("/api/manifest")
+            .add_header(header::AUTHORIZATION, &auth)
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+        server
+            .get(&kids_route("/manifest"))
+            .add_header(header::AUTHORIZATION, &auth)
+            .await
+            .assert_status_ok();
+        let listed = server
+            .get("/api/libraries")
+            .add_header(header::AUTHORIZATION, &auth)
+            .await
+            .json::<Vec<LibraryInfo>>();
+        assert_eq!(listed.len(), 1);
+
+        // A device granted the default library keeps that library when
+        // another one is selected
+        state
+            .set_default_scope(Scope {
+                libraries: Some(vec![libraries::DEFAULT_LIBRARY_ID.to_string()]),
+                ..Scope::default()
+            })
+            .unwrap();
+        let phone_auth = format!(
+            "Bearer {}",
+            issue_token(&state, Some("Phone".to_string()))
+                .unwrap()
+                .token
+        );
+        let phone = state
+            .devices()
+            .into_iter()
+            .find(|device| device.name.as_deref() == Some("Phone"))
+            .unwrap();
+        let default_scope_id = state.default_library().unwrap().scope_id.clone();
+        assert_eq!(phone.scope.libraries, Some(vec![default_scope_id]));
+        server
+            .get("/api/manifest")
+            .add_header(header::AUTHORIZATION, &phone_auth)
+            .await
+            .assert_status_ok();
+        let other_library = dir.path().join("Other");
+        setup_mock_lib(&other_library);
+        state.load_library(other_library.to_str().unwrap()).unwrap();
+        server
+            .get("/api/manifest")
+            .add_header(header::AUTHORIZATION, &phone_auth)
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+
+        // Registered libraries are served again after a restart
+        let restarted = ServerState::new(data_dir, "1234".to_string());
+        restarted.load_saved_libraries().unwrap();
+        let reopened = restarted.library(&kids.id).unwrap();
+        assert_eq!(reopened.books().unwrap().len(), 1);
+        assert!(restarted.remove_library(&kids.id).unwrap());
+        assert!(restarted.library(&kids.id).is_none());
+    }
+
+    #[test]
+    fn test_paired_devices_survive_restart() {
+        let dir = tempdir().unwrap();
+        let state = ServerState::new(dir.path().to_path_buf(), "1234".to_string());
+        let browse_only = Scope {
+            can_download: false,
+            ..Scope::default()
+        };
+        state.set_default_scope(browse_only.clone()).unwrap();
+        let token = issue_token(&state, Some("Tablet".to_string()))
+            .unwrap()
+            .token;
+        let device = state.devices().remove(0);
+        assert_eq!(device.scope, browse_only);
+
+        let restarted = Arc::new(ServerState::new(
+            dir.path().to_path_buf(),
+            "1234".to_string(),
+        ));
+        restarted.load_saved_devices().unwrap();
+        assert_eq!(restarted.devices(), vec![device]);
+        assert_eq!(restarted.default_scope().unwrap(), browse_only);
+        let mut headers = header::HeaderMap::new();
+        headers.insert(
+            header::AUTHORIZATION,
+            format!("Bearer {}", token).parse().unwrap(),
+        );
+        assert!(is_authorized(&headers, &restarted));
+        assert_eq!(device_scope(&headers, &restarted), browse_only);
+    }
+
+    #[tokio::test]
+    async fn test_device_scope_hides_tags_and_limits_access() {
+        let dir = tempdir().unwrap();
+        setup_mock_lib(dir.path());
+        let conn = Connection::open(dir.path().join("metadata.db")).unwrap();
+        conn.execute_batch(
+            "INSERT INTO books (id, title, path, series, series_index) VALUES (2, 'Picture Book', 'test/picture', NULL, 1.0);
+             INSERT INTO books_authors_link (book, author) VALUES (2, 1);
+             INSERT INTO data (book, format) VALUES (2, 'EPUB');
+             INSERT INTO tags (id, name) VALUES (1, 'Adult.Horror');
+             INSERT INTO books_tags_link (book, tag) VALUES (1, 1);",
+        )
+        .unwrap();
+        let picture_dir = dir.path().join("test/picture");
+        fs::create_dir_all(&picture_dir).unwrap();
+        fs::write(picture_dir.join("book.epub"), "dummy content").unwrap();
+
+        let state = test_state(dir.path());
+        let server = TestServer::new(router(state.clone())).unwrap();
+        let auth = format!("Bearer {}", issue_token(&state, None).unwrap().token);
+        let device = state.devices().remove(0);
+        state
+            .set_device_scope(
+                &device.id,
+                Scope {
+                    libraries: None,
+                    hidden_tags: vec!["adult".to_string()],
+                    virtual_library: None,
+                    can_download: false,
+                    can_write: false,
+                },
+            )
+            .unwrap();
+
+        let books = server
+            .get("/api/manifest")
+            .add_header(header::AUTHORIZATION, &auth)
+            .await
+            .json::<Vec<Book>>();
+        assert_eq!(books.iter().map(|b| b.id).collect::<Vec<_>>(), vec![2]);
+
+        // Reading stats leave out the hidden book
+        {
+            let conn = state.default_library().unwrap().progress_db.get().unwrap();
+            let now = chrono::Utc::now().timestamp();
+            for book_id in [1, 2] {
+                progress::record_session(
+                    &conn,
+                    &progress::ReadingSession {
+                        book_id,
+                        started_at: now - 600,
+                        ended_at: now,
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+            }
+        }
+        let stats = server
+            .get("/api/stats")
+            .add_header(header::AUTHORIZATION, &auth)
+            .await
+            .json::<progress::ReadingStats>();
+        assert_eq!(stats.sessions, 1);
+        assert_eq!(
+            stats.per_book.iter().map(|b| b.book_id).collect::<Vec<_>>(),
+            vec![2]
+        );
+
+        server
+            .get("/api/cover/1")
+            .add_header(header::AUTHORIZATION, &auth)
+            .await
+            .assert_status_not_found();
+
+        let refusal = |response: axum_test::TestResponse| {
+            response.assert_status(StatusCode::FORBIDDEN);
+            response.json::<ApiError>().error
+        };
+        assert_eq!(
+            refusal(
+                server
+                    .get("/api/download/2/epub")
+                    .add_header(header::AUTHORIZATION, &auth)
+                    .await
+            ),
+            "download_forbidden"
+        );
+        assert_eq!(
+            refusal(
+                server
+                    .post("/api/progress")
+                    .add_header(header::AUTHORIZATION, &auth)
+                    .json(&serde_json::json!({ "book_id": 2, "status": "reading" }))
+                    .await
+            ),
+            "read_only"
+        );
+        assert_eq!(
+            refusal(
+                server
+                    .get("/api/pairing-code")
+                    .add_header(header::AUTHORIZATION, &auth)
+                    .await
+            ),
+            "scope_restricted"
+        );
+
+        state
+            .set_device_scope(&device.id, Scope::default())
+            .unwrap();
+        server
+            .get("/api/download/2/epub")
+            .add_header(header::AUTHORIZATION, &auth)
+            .await
+            .assert_status_ok();
+    }
+
+    #[tokio::test]
+    async fn test_annotations_on_hidden_books_are_not_found() {
+        let dir = tempdir().unwrap();
+        setup_mock_lib(dir.path());
+        let conn = Connection::open(dir.path().join("metadata.db")).unwrap();
+        conn.execute_batch(
+            "INSERT INTO tags (id, name) VALUES (1, 'Adult');
+             INSERT INTO books_tags_link (book, tag) VALUES (1, 1);",
+        )
+        .unwrap();
+
+        let state = test_state(dir.path());
+        let server = TestServer::new(router(state.clone())).unwrap();
+        let auth = format!("Bearer {}", issue_token(&state, None).unwrap().token);
+        server
+            .post("/api/annotations")
+            .add_header(header::AUTHORIZATION, &auth)
+            .json(&serde_json::json!({ "id": "h1", "book_id": 1, "note": "Scary" }))
+            .await
+            .assert_status(StatusCode::CREATED);
+
+        let device = state.devices().remove(0);
+        state
+            .set_device_scope(
+                &device.id,
+                Scope {
+                    hidden_tags: vec!["adult".to_string()],
+                    ..Scope::default()
+                },
+            )
+            .unwrap();
+        server
+            .put("/api/annotations/h1")
+            .add_header(header::AUTHORIZATION, &auth)
+            .json(&serde_json::json!({ "book_id": 1, "note": "Changed" }))
+            .await
+            .assert_status_not_found();
+        server
+            .delete("/api/annotations/h1")
+            .add_header(header::AUTHORIZATION, &auth)
+            .await
+            .assert_status_not_found();
+        let stored = state
+            .default_library()
+            .unwrap()
+            .annotations_db
+            .run(|conn| annotations::get_annotation(conn, "h1"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!stored.deleted);
+    }
+
+    #[tokio::test]
+    async fn test_virtual_libraries_and_saved_searches() {
+        let dir = tempdir().unwrap();
+        setup_mock_lib(dir.path());
+        let conn = Connection::open(dir.path().join("metadata.db")).unwrap();
+        conn.execute_batch(
+            r#"INSERT INTO books (id, title, path, series, series_index) VALUES (2, 'Picture Book', 'test/picture', NULL, 1.0);
+               INSERT INTO books_authors_link (book, author) VALUES (2, 1);
+               INSERT INTO tags (id, name) VALUES (1, 'Kids');
+               INSERT INTO books_tags_link (book, tag) VALUES (2, 1);
+               CREATE TABLE preferences (id INTEGER PRIMARY KEY, key TEXT NOT NULL, val TEXT NOT NULL);
+               INSERT INTO preferences (key, val) VALUES
+                   ('virtual_libraries', '{"Children": "tags:=kids"}'),
+                   ('saved_searches', '{"Grown-up": "not tags:kids", "Broken": "rating:>3"}');"#,
+        )
+        .unwrap();
+
+        let state = test_state(dir.path());
+        let server = TestServer::new(router(state.clone())).unwrap();
+        let auth = format!("Bearer {}", issue_token(&state, None).unwrap().token);
+        let ids = |books: Vec<Book>| books.iter().map(|b| b.id).collect::<Vec<_>>();
+
+        let searches = server
+            .get("/api/searches")
+            .add_header(header::AUTHORIZATION, &auth)
+            .await
+            .json::<Vec<SearchSummary>>();
+        let counts: Vec<_> = searches
+            .iter()
+            .map(|s| (s.search.name.as_str(), s.book_count))
+            .collect();
+        assert_eq!(
+            counts,
+            vec![
+                ("Children", Some(1)),
+                ("Broken", None),
+                ("Grown-up", Some(1))
+            ]
+        );
+
+        let books = server
+            .get("/api/manifest?virtual_library=Children")
+            .add_header(header::AUTHORIZATION, &auth)
+            .await
+            .json::<Vec<Book>>();
+        assert_eq!(ids(books), vec![2]);
+        let books = server
+            .get("/api/manifest?saved_search=Grown-up")
+            .add_header(header::AUTHORIZATION, &auth)
+            .await
+            .json::<Vec<Book>>();
+        assert_eq!(ids(books), vec![1]);
+        server
+            .get("/api/manifest?saved_search=Missing")
+            .add_header(header::AUTHORIZATION, &auth)
+            .await
+            .assert_status_not_found();
+        server
+            .get("/api/manifest?search=rating:%3E3")
+            .add_header(header::AUTHORIZATION, &auth)
+            .await
+            .assert_status(StatusCode::BAD_REQUEST);
+
+        let device = state.devices().remove(0);
+        state
+            .set_device_scope(
+                &device.id,
+                Scope {
+                    virtual_library: Some("Children".to_string()),
+                    ..Scope::default()
+                },
+            )
+            .unwrap();
+        let books = server
+            .get("/api/manifest")
+            .add_header(header::AUTHORIZATION, &auth)
+            .await
+            .json::<Vec<Book>>();
+        assert_eq!(ids(books), vec![2]);
+        server
+            .get("/api/cover/1")
+            .add_header(header::AUTHORIZATION, &auth)
+            .await
+            .assert_status_not_found();
+    }
+
+    #[tokio::test]
+    async fn test_pair_with_uri() {
+        let dir = tempdir().unwrap();
+        let state = Arc::new(ServerState::new(
+            dir.path().to_path_buf(),
+            "1234".to_string(),
+        ));
+        let app = Router::new()
+            .route("/api/health", get(health))
+            .route("/api/pair", axum::routing::post(pair))
+            .with_state(state.clone());
+        let server = TestServer::builder().http_transport().build(app).unwrap();
+        let port = server.server_address().unwrap().port().unwrap();
+        state.port.store(port, Ordering::Relaxed);
+        let client = reqwest::Client::new();
+        let now = chrono::Utc::now().timestamp();
+
+        let code = state
+            .pairing_code(vec!["127.0.0.1".to_string()], state.port())
+            .unwrap();
+        assert!(code.svg.contains("<svg"));
+        let paired = pairing::pair_with_uri(&client, &code.uri, None, now)
+            .await
+            .unwrap();
+        assert_eq!(paired.host.key(), state.host_id);
+        assert_eq!(paired.host.online, Some(true));
+        assert!(state
+            .authorized_tokens
+            .lock()
+            .unwrap()
+            .contains(&paired.token));
+
+        // The secret is used up
+        let again = pairing::pair_with_uri(&client, &code.uri, None, now).await;
+        assert!(again.unwrap_err().to_string().contains("refused"));
+
+        state.set_pairing_open(false);
+        let code = state
+            .pairing_code(vec!["127.0.0.1".to_string()], state.port())
+            .unwrap();
+        assert!(pairing::pair_with_uri(&client, &code.uri, None, now)
+            .await
+            .is_err());
+    }
+
     #[tokio::test]
     async fn test_get_cover() {
         let dir = tempdir().unwrap();
@@ -573,23 +3649,7 @@ mod tests {
         let cover_path = dir.path().join("test/book/cover.jpg");
         img.save(cover_path).unwrap();
 
-        let state = Arc::new(ServerState {
-            library_path: Mutex::new(Some(dir.path().to_str().unwrap().to_string())),
-            books: Mutex::new(Vec::new()),
-            pin: "1234".to_string(),
-            authorized_tokens: Mutex::new({
-                let mut set = std::collections::HashSet::new();
-                set.insert("test-token".to_string());
-                set
-            }),
-            app_data_dir: dir.path().to_path_buf(),
-        });
-
-        // Pre-populate cache
-        {
-            let mut books = state.books.lock().unwrap();
-            *books = db::get_calibre_metadata(dir.path().to_str().unwrap()).unwrap();
-        }
+        let state = test_state(dir.path());
 
         let app = Router::new()
             .route("/api/cover/{book_id}", get(get_cover))
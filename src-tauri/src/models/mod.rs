@@ -1,6 +1,7 @@
+use crate::core::discovery::HostAdvertisement;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct Book {
     pub id: i64,
     pub title: String,
@@ -12,12 +13,72 @@ pub struct Book {
     pub series_index: f64,
     pub tags: Vec<String>,
     pub publisher: Option<String>,
+    /// Unix timestamp of when the book was added to the library.
+    #[serde(default)]
+    pub added_at: Option<i64>,
+    /// Size in bytes of the book's largest format.
+    #[serde(default)]
+    pub size: Option<u64>,
 }
 
-#[derive(Serialize, Clone, Debug)]
+impl Book {
+    /// Whether the book has `tag` or a tag nested under it (Calibre's
+    /// `Parent.Child`). Tags are compared case-insensitively.
+    pub fn has_tag(&self, tag: &str) -> bool {
+        let wanted = tag.trim().to_lowercase();
+        !wanted.is_empty()
+            && self.tags.iter().any(|tag| {
+                tag.to_lowercase()
+                    .strip_prefix(&wanted)
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+            })
+    }
+
+    /// An EPUB by "Author" for tests, which set any other fields they
+    /// depend on with struct update syntax.
+    #[cfg(test)]
+    pub fn test(id: i64, title: &str) -> Book {
+        Book {
+            id,
+            title: title.to_string(),
+            authors: "Author".to_string(),
+            path: format!("Author/{} ({})", title, id),
+            formats: vec!["EPUB".to_string()],
+            series_index: 1.0,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq, Default)]
 pub struct ConnectionInfo {
+    /// Preferred address; the first of `addresses`.
     pub ip: String,
+    /// Every address the host is reachable at, in the order to try them.
+    pub addresses: Vec<String>,
     pub port: u16,
     pub hostname: String,
     pub pin: Option<String>,
+    /// Details the host published in its mDNS TXT record.
+    #[serde(flatten)]
+    pub advertisement: HostAdvertisement,
+    /// Whether this build speaks the host's API version.
+    pub compatible: bool,
+    /// Added by the user by address rather than found over mDNS.
+    pub manual: bool,
+    /// Result of the last `/api/health` probe; `None` until probed.
+    pub online: Option<bool>,
+    /// Round trip of the last successful health probe, in milliseconds.
+    pub latency_ms: Option<u64>,
+}
+
+impl ConnectionInfo {
+    /// Identifies the host across address changes: its published host ID, or
+    /// for hosts too old to publish one, its mDNS service name.
+    pub fn key(&self) -> &str {
+        self.advertisement
+            .host_id
+            .as_deref()
+            .unwrap_or(&self.hostname)
+    }
 }
@@ -2,6 +2,7 @@ import React, { useState } from 'react';
 import { Card, Box, Image, Heading, Text, VStack, Button, Icon, HStack, Badge, Checkbox, Progress } from "@chakra-ui/react";
 import { Book as BookIcon } from "lucide-react";
 import { Book } from "@/types";
+import { libraryApi } from "@/context/DiscoveryContext";
 
 interface Host {
   ip: string;
@@ -11,6 +12,8 @@ interface Host {
 interface BookCardProps {
   book: Book;
   host?: Host | null;
+  // Library on the host the book is from; the default one when missing
+  libraryId?: string | null;
   variant: "remote" | "local" | "host-view";
   onAction?: (book: Book) => void;
   onToggleStatus?: (book: Book) => void;
@@ -25,6 +28,7 @@ interface BookCardProps {
 export const BookCard: React.FC<BookCardProps> = ({ 
   book, 
   host, 
+  libraryId,
   variant, 
   onAction, 
   onToggleStatus,
@@ -39,7 +43,7 @@ export const BookCard: React.FC<BookCardProps> = ({
 
   // Construct cover URL if we have a host
   const coverUrl = host 
-    ? `http://${host.ip}:${host.port}/api/cover/${book.id}`
+    ? `${libraryApi(host, libraryId)}/cover/${book.id}`
     : undefined;
 
   const getStatusColor = (status?: string) => {
@@ -4,7 +4,7 @@ import { Download, CheckCircle, AlertCircle } from "lucide-react";
 
 
 interface QueueOverlayProps {
-    progress: Record<number, any>;
+    progress: Record<string, any>;
     onClose?: () => void;
 }
 
@@ -59,7 +59,7 @@ export const QueueOverlay: React.FC<QueueOverlayProps> = ({ progress }) => {
 
                 <VStack p={2} gap={2} align="stretch" overflowY="auto">
                     {items.slice(0, 5).map((p: any) => (
-                        <Box key={p.book_id} p={3} borderRadius="lg" bg="bg.muted">
+                        <Box key={p.key} p={3} borderRadius="lg" bg="bg.muted">
                             <VStack align="stretch" gap={2}>
                                 <HStack justify="space-between" w="full">
                                     <Text fontSize="xs" fontWeight="semibold" truncate maxW="200px">
@@ -1,18 +1,61 @@
-import React, { createContext, useContext, useState, useEffect, ReactNode } from "react";
+import React, { createContext, useContext, useState, useEffect, useRef, ReactNode } from "react";
 import { listen } from "@tauri-apps/api/event";
 import { load } from "@tauri-apps/plugin-store";
 import { api } from "@/services/api";
-import { ConnectionInfo } from "@/types";
+import { ConnectionInfo, DiscoveryStatus } from "@/types";
 
 // Reuse ConnectionInfo type for Hosts
 export type Host = ConnectionInfo;
 
+/**
+ * Identifies a host across IP changes: its persistent host ID, or the address
+ * for older hosts and manual connections that have none.
+ */
+export const hostKey = (host: Host) => host.host_id ?? `${host.ip}:${host.port}`;
+
+/** Base URL of a host at `ip` (its preferred address by default), bracketing IPv6 addresses. */
+export const hostOrigin = (host: Pick<Host, "ip" | "port">, ip = host.ip) =>
+  `http://${ip.includes(":") ? `[${ip}]` : ip}:${host.port}`;
+
+/** Base URL of a library's API on a host; the default library's when `libraryId` is missing. */
+export const libraryApi = (host: Pick<Host, "ip" | "port">, libraryId?: string | null) =>
+  libraryId ? `${hostOrigin(host)}/api/libraries/${encodeURIComponent(libraryId)}` : `${hostOrigin(host)}/api`;
+
+const PROBE_TIMEOUT_MS = 2000;
+
+/**
+ * Tries the host's addresses in order and returns the first that answers,
+ * falling back to the preferred address if none does.
+ */
+export const pickReachableAddress = async (host: Host): Promise<string> => {
+  const candidates = host.addresses?.length ? host.addresses : [host.ip];
+  for (const ip of candidates) {
+    try {
+      // Any HTTP response, even 401, means the address is reachable
+      await fetch(`${hostOrigin(host, ip)}/api/manifest`, { signal: AbortSignal.timeout(PROBE_TIMEOUT_MS) });
+      return ip;
+    } catch {
+      // Unreachable from here (other subnet, VPN down, no IPv6 route); try the next one
+    }
+  }
+  return host.ip;
+};
+
+/** Whether `saved` is the same host as `host`, including entries saved by address before the host published an ID. */
+const isSameHost = (saved: Host, host: Host) =>
+  hostKey(saved) === hostKey(host) || (!saved.host_id && saved.ip === host.ip && saved.port === host.port);
+
 interface DiscoveryContextType {
   hosts: Host[];
   knownHosts: Host[];
   myConnectionInfo: ConnectionInfo | null;
   scanning: boolean;
+  status: DiscoveryStatus | null;
+  showIncompatible: boolean;
+  setShowIncompatible: (show: boolean) => void;
   scan: () => Promise<void>;
+  addManualHost: (address: string, port?: number) => Promise<Host>;
+  removeManualHost: (host: Host) => Promise<void>;
   refreshConnectionInfo: () => Promise<void>;
 }
 
@@ -23,6 +66,11 @@ export const DiscoveryProvider: React.FC<{ children: ReactNode }> = ({ children
   const [scanning, setScanning] = useState(false);
   const [knownHosts, setKnownHosts] = useState<Host[]>([]);
   const [activeHosts, setActiveHosts] = useState<Host[]>([]);
+  const [showIncompatible, setShowIncompatible] = useState(false);
+  const [status, setStatus] = useState<DiscoveryStatus | null>(null);
+  // The event listener is registered once, so it reads the toggle through a ref
+  const showIncompatibleRef = useRef(showIncompatible);
+  showIncompatibleRef.current = showIncompatible;
 
   const refreshConnectionInfo = async () => {
     try {
@@ -41,9 +89,14 @@ export const DiscoveryProvider: React.FC<{ children: ReactNode }> = ({ children
         
         let changed = false;
         for (const h of newHosts) {
-            if (!merged.find(m => m.ip === h.ip)) {
+            const index = merged.findIndex(m => isSameHost(m, h));
+            if (index === -1) {
                 merged.push(h);
                 changed = true;
+            } else if (JSON.stringify(merged[index]) !== JSON.stringify(h)) {
+                // Known host re-resolved, possibly at a new address
+                merged[index] = h;
+                changed = true;
             }
         }
 
@@ -60,7 +113,7 @@ export const DiscoveryProvider: React.FC<{ children: ReactNode }> = ({ children
   const scan = async () => {
     setScanning(true);
     try {
-      const results = await api.network.discoverHosts();
+      const results = await api.network.discoverHosts(showIncompatibleRef.current);
       setActiveHosts(results);
       updateKnownHosts(results);
     } catch (e) {
@@ -70,6 +123,22 @@ export const DiscoveryProvider: React.FC<{ children: ReactNode }> = ({ children
     }
   };
 
+  const addManualHost = async (address: string, port?: number) => {
+    const host = await api.network.addManualHost(address, port);
+    await scan();
+    return host;
+  };
+
+  const removeManualHost = async (host: Host) => {
+    try {
+      // The host keeps manual entries by host ID, or by address until it has one
+      await api.network.removeManualHost(host.host_id ?? host.hostname);
+      await scan();
+    } catch (e) {
+      console.error("Failed to remove host", e);
+    }
+  };
+
   useEffect(() => {
      refreshConnectionInfo();
      scan();
@@ -86,15 +155,24 @@ export const DiscoveryProvider: React.FC<{ children: ReactNode }> = ({ children
     loadInitial();
 
     const unlistenPromise = listen<Host[]>("discovery-update", (event) => {
-      setActiveHosts(event.payload);
-      updateKnownHosts(event.payload);
+      const visible = event.payload.filter(h => h.compatible || showIncompatibleRef.current);
+      setActiveHosts(visible);
+      updateKnownHosts(visible);
     });
 
+    api.network.getDiscoveryStatus().then(setStatus).catch(e => console.error("Failed to get discovery status", e));
+    const unlistenStatus = listen<DiscoveryStatus>("discovery-status", (event) => setStatus(event.payload));
+
     return () => {
       unlistenPromise.then((unlisten) => unlisten());
+      unlistenStatus.then((unlisten) => unlisten());
     };
   }, []);
 
+  useEffect(() => {
+    scan();
+  }, [showIncompatible]);
+
   return (
     <DiscoveryContext.Provider 
       value={{ 
@@ -102,7 +180,12 @@ export const DiscoveryProvider: React.FC<{ children: ReactNode }> = ({ children
         knownHosts,
         myConnectionInfo, 
         scanning, 
+        status,
+        showIncompatible,
+        setShowIncompatible,
         scan, 
+        addManualHost,
+        removeManualHost,
         refreshConnectionInfo 
       }}
     >
@@ -1,18 +1,22 @@
-import React, { createContext, useContext, useState, useEffect, ReactNode } from "react";
+import React, { createContext, useContext, useState, useEffect, useRef, ReactNode } from "react";
 import { load } from "@tauri-apps/plugin-store";
 import { open } from "@tauri-apps/plugin-dialog";
 import { openPath } from "@tauri-apps/plugin-opener";
-import { appDataDir, join } from "@tauri-apps/api/path";
-import { invoke } from "@tauri-apps/api/core";
+import { appDataDir } from "@tauri-apps/api/path";
 import { listen } from "@tauri-apps/api/event";
 import { isPermissionGranted, requestPermission, sendNotification } from '@tauri-apps/plugin-notification';
-import { Book } from "@/types";
+import { Book, LibraryInfo, SavedSearch, SyncPlan, SyncRule } from "@/types";
+import { api, SyncTarget } from "@/services/api";
 import { initDB, getLocalBooks, saveBook as saveLocalBook } from "@/services/local-db";
-import { Host } from "./DiscoveryContext";
-import { useHostManifest, useLocalLibrary, useCheckPin } from "@/hooks/useLibraryQuery";
+import { Host, hostKey, libraryApi, pickReachableAddress, useDiscovery } from "./DiscoveryContext";
+import { useHostManifest, useHostLibraries, useHostSearches, useLocalLibrary, useCheckPin } from "@/hooks/useLibraryQuery";
 
 const STORE_PATH = "shelfsync_settings.json";
 
+/** Key of a book's download, as book IDs repeat across hosts and their libraries. */
+export const syncKey = (host: Host, libraryId: string | null, bookId: number) =>
+  `${hostKey(host)}/${libraryId ?? ""}/${bookId}`;
+
 /**
  * Defines the shape of the Library Context.
  * Manages application mode, book data, host connections, and synchronization state.
@@ -27,21 +31,37 @@ interface LibraryContextType {
   error: string | null;
   libraryPath: string;
   connectedHost: Host | null;
+  // Libraries of the connected host this device may use; empty for single-library hosts
+  hostLibraries: LibraryInfo[];
+  // Library being browsed on the connected host; null for its default library
+  hostLibraryId: string | null;
+  // Virtual libraries and saved searches of the library being browsed
+  hostSearches: SavedSearch[];
+  // Search whose books are listed; null for every book
+  hostSearch: SavedSearch | null;
   authRequired: boolean;
   pairingHost: Host | null;
   authTokens: Record<string, string>;
-  syncProgress: Record<number, any>; // bookId -> progress data
+  syncProgress: Record<string, any>; // syncKey -> progress data
+  // Sync rules saved for the connected host
+  syncRules: SyncRule[];
 
   // Actions
   setAppMode: (mode: AppMode) => Promise<void>;
   connectToHost: (host: Host) => Promise<void>;
+  selectHostLibrary: (id: string | null) => void;
+  selectHostSearch: (search: SavedSearch | null) => void;
   pair: (pin: string) => Promise<void>;
+  pairWithUri: (uri: string) => Promise<void>;
   disconnect: () => void;
   syncBook: (book: Book) => Promise<void>;
   syncBooks: (books: Book[]) => Promise<void>;
   selectLibraryFolder: () => Promise<void>;
   openLocalBook: (path: string) => Promise<void>;
   toggleReadStatus: (book: Book) => Promise<void>;
+  saveSyncRules: (rules: SyncRule[]) => Promise<void>;
+  previewSyncRules: (rules: SyncRule[], libraryId: string | null) => Promise<SyncPlan>;
+  runSyncRules: (rules: SyncRule[], libraryId: string | null) => Promise<SyncPlan>;
 }
 
 const LibraryContext = createContext<LibraryContextType | undefined>(undefined);
@@ -55,15 +75,30 @@ export const LibraryProvider: React.FC<{ children: ReactNode }> = ({ children })
   const [libraryPath, setLibraryPath] = useState<string>("");
   const [localBooks, setLocalBooks] = useState<Book[]>([]);
   const [connectedHost, setConnectedHost] = useState<Host | null>(null);
+  const [hostLibraryId, setHostLibraryId] = useState<string | null>(null);
+  const [hostSearch, setHostSearch] = useState<SavedSearch | null>(null);
   const [authTokens, setAuthTokens] = useState<Record<string, string>>({}); 
-  const [syncProgress, setSyncProgress] = useState<Record<number, any>>({});
+  const [syncProgress, setSyncProgress] = useState<Record<string, any>>({});
+  // Sync rules by host key
+  const [allSyncRules, setAllSyncRules] = useState<Record<string, SyncRule[]>>({});
+  // Books queued for download by syncKey, for when they are not in the listed manifest
+  const queuedBooks = useRef<Record<string, Book>>({});
+  // Library revision each host library's auto-sync rules last ran at
+  const ruleRevisions = useRef<Record<string, string>>({});
+  const { hosts: discoveredHosts } = useDiscovery();
+
+  // Tokens are keyed by host ID; ones saved before hosts had IDs are keyed by address
+  const tokenFor = (host: Host) => authTokens[hostKey(host)] ?? authTokens[`${host.ip}:${host.port}`];
 
   // Derived credentials
-  const hostKey = connectedHost ? `${connectedHost.ip}:${connectedHost.port}` : "";
-  const token = authTokens[hostKey];
+  const token = connectedHost ? tokenFor(connectedHost) : undefined;
 
   // --- Queries & Mutations ---
-  const remoteQuery = useHostManifest(connectedHost, token, appMode === "client");
+  const remoteQuery = useHostManifest(connectedHost, token, appMode === "client", hostLibraryId, hostSearch);
+  const hostLibrariesQuery = useHostLibraries(connectedHost, token, appMode === "client");
+  const hostLibraries = hostLibrariesQuery.data || [];
+  const hostSearchesQuery = useHostSearches(connectedHost, token, appMode === "client", hostLibraryId);
+  const hostSearches = hostSearchesQuery.data || [];
   const localQuery = useLocalLibrary(appMode === "host" ? libraryPath : null);
   const checkPinMutation = useCheckPin();
 
@@ -108,6 +143,9 @@ export const LibraryProvider: React.FC<{ children: ReactNode }> = ({ children })
         const savedTokens = await store.get<Record<string, string>>("auth_tokens");
         if (savedTokens) setAuthTokens(savedTokens);
 
+        const savedRules = await store.get<Record<string, SyncRule[]>>("sync_rules");
+        if (savedRules) setAllSyncRules(savedRules);
+
         // Always init local DB just in case
         if (savedMode === "client") {
             await initDB();
@@ -127,16 +165,14 @@ export const LibraryProvider: React.FC<{ children: ReactNode }> = ({ children })
      const setup = async () => {
          unlisten = await listen("sync-progress", async (event: any) => {
              const prog = event.payload;
-             setSyncProgress(prev => ({ ...prev, [prog.book_id]: prog }));
+             setSyncProgress(prev => ({ ...prev, [prog.key]: prog }));
 
              if (prog.status === "completed") {
                 // Update local DB since the file is now there
-                const path = await join(await appDataDir(), `${prog.title.replace(/[^a-z0-9]/gi, '_')}.epub`);
-                // We need the original book object here, but we can't easily get it.
-                // For now, let's assume we find it in the current books state.
-                const fullBook = books.find(b => b.id === prog.book_id);
+                const fullBook = queuedBooks.current[prog.key];
+                delete queuedBooks.current[prog.key];
                 if (fullBook) {
-                    await saveLocalBook(fullBook, path);
+                    await saveLocalBook(fullBook, prog.path);
                     const stored = await getLocalBooks();
                     setLocalBooks(stored);
                 }
@@ -149,7 +185,7 @@ export const LibraryProvider: React.FC<{ children: ReactNode }> = ({ children })
      };
      setup();
      return () => unlisten?.then((f: any) => f());
-  }, [books]); // Re-subscribe if books change to ensure closure captures new books... ideally we shouldn't rely on this closure
+  }, []);
 
   // Sync Progress on Connect
   // When remoteQuery succeeds, fetch progress and update local DB
@@ -157,7 +193,7 @@ export const LibraryProvider: React.FC<{ children: ReactNode }> = ({ children })
       async function syncProgressEffect() {
         if (appMode === "client" && connectedHost && token && remoteQuery.isSuccess) {
              try {
-                const response = await fetch(`http://${connectedHost.ip}:${connectedHost.port}/api/progress`, {
+                const response = await fetch(`${libraryApi(connectedHost, hostLibraryId)}/progress`, {
                     headers: { "Authorization": `Bearer ${token}` }
                 });
                 if (response.ok) {
@@ -175,7 +211,7 @@ export const LibraryProvider: React.FC<{ children: ReactNode }> = ({ children })
         }
       }
       syncProgressEffect();
-  }, [appMode, connectedHost, token, remoteQuery.isSuccess]);
+  }, [appMode, connectedHost, hostLibraryId, token, remoteQuery.isSuccess]);
 
 
   const setAppMode = async (mode: AppMode) => {
@@ -197,9 +233,42 @@ export const LibraryProvider: React.FC<{ children: ReactNode }> = ({ children })
     }
   };
 
+  // Follow the connected host to its new address when it re-resolves after an IP change
+  useEffect(() => {
+      if (!connectedHost?.host_id) return;
+      const current = discoveredHosts.find(h => h.host_id === connectedHost.host_id);
+      if (current && (!(current.addresses ?? [current.ip]).includes(connectedHost.ip) || current.port !== connectedHost.port)) {
+          connectToHost(current);
+      }
+  }, [discoveredHosts, connectedHost]);
+
   const connectToHost = async (host: Host) => {
-    // Just setting the host triggers the query
-    setConnectedHost(host);
+    // Use the first address that answers from this network; setting the host triggers the query
+    const ip = await pickReachableAddress(host);
+    // Library IDs are per host, so only keep the selection when following the same host
+    if (!connectedHost || hostKey(connectedHost) !== hostKey(host)) {
+        setHostLibraryId(null);
+        setHostSearch(null);
+    }
+    setConnectedHost({ ...host, ip });
+  };
+
+  // Devices without access to the host's default library start on the first one they may use
+  useEffect(() => {
+      if (hostLibraryId || hostLibraries.length === 0) return;
+      if (!hostLibraries.some(l => l.default)) {
+          setHostLibraryId(hostLibraries[0].id);
+      }
+  }, [hostLibraries, hostLibraryId]);
+
+  // Searches belong to a library, so switching libraries lists every book again
+  const selectHostLibrary = (id: string | null) => {
+      setHostLibraryId(id);
+      setHostSearch(null);
+  };
+
+  const selectHostSearch = (search: SavedSearch | null) => {
+      setHostSearch(search);
   };
 
   const pair = async (pin: string) => {
@@ -208,8 +277,7 @@ export const LibraryProvider: React.FC<{ children: ReactNode }> = ({ children })
       try {
           const newToken = await checkPinMutation.mutateAsync({ host: pairingHost, pin });
           
-          const hostKey = `${pairingHost.ip}:${pairingHost.port}`;
-          const newTokens = { ...authTokens, [hostKey]: newToken };
+          const newTokens = { ...authTokens, [hostKey(pairingHost)]: newToken };
           setAuthTokens(newTokens);
           
           const store = await load(STORE_PATH);
@@ -223,6 +291,20 @@ export const LibraryProvider: React.FC<{ children: ReactNode }> = ({ children })
       }
   };
 
+  // Pairs and connects in one step using a link from the host's QR code
+  const pairWithUri = async (uri: string) => {
+      const { host, token } = await api.network.pairWithUri(uri);
+
+      const newTokens = { ...authTokens, [hostKey(host)]: token };
+      setAuthTokens(newTokens);
+
+      const store = await load(STORE_PATH);
+      await store.set("auth_tokens", newTokens);
+      await store.save();
+
+      await connectToHost(host);
+  };
+
   const disconnect = () => {
       setConnectedHost(null);
   };
@@ -232,20 +314,11 @@ export const LibraryProvider: React.FC<{ children: ReactNode }> = ({ children })
   };
 
   const syncBooks = async (booksToSync: Book[]) => {
-      if (!connectedHost) return;
-      const hostKey = `${connectedHost.ip}:${connectedHost.port}`;
-      const token = authTokens[hostKey];
-      if (!token) return;
+      if (!connectedHost || !tokenFor(connectedHost)) return;
 
+      for (const book of booksToSync) queuedBooks.current[syncKey(connectedHost, hostLibraryId, book.id)] = book;
       try {
-          const destRoot = await appDataDir();
-          await invoke("start_bulk_sync", {
-              books: booksToSync,
-              hostIp: connectedHost.ip,
-              hostPort: connectedHost.port,
-              token: token,
-              destinationRoot: destRoot
-          });
+          await api.library.startBulkSync(booksToSync, await syncTarget(hostLibraryId));
 
           // Request notification permission if needed
           let permission = await isPermissionGranted();
@@ -259,6 +332,62 @@ export const LibraryProvider: React.FC<{ children: ReactNode }> = ({ children })
       }
   };
 
+  const syncRules = connectedHost ? allSyncRules[hostKey(connectedHost)] ?? [] : [];
+
+  const saveSyncRules = async (rules: SyncRule[]) => {
+      if (!connectedHost) return;
+      const next = { ...allSyncRules, [hostKey(connectedHost)]: rules };
+      setAllSyncRules(next);
+
+      const store = await load(STORE_PATH);
+      await store.set("sync_rules", next);
+      await store.save();
+  };
+
+  const syncTarget = async (libraryId: string | null): Promise<SyncTarget> => {
+      const token = connectedHost ? tokenFor(connectedHost) : undefined;
+      if (!connectedHost || !token) throw new Error("Not connected to a host");
+      return {
+          hostIp: connectedHost.ip,
+          hostPort: connectedHost.port,
+          token,
+          hostKey: hostKey(connectedHost),
+          libraryId,
+          destinationRoot: await appDataDir(),
+      };
+  };
+
+  const previewSyncRules = async (rules: SyncRule[], libraryId: string | null) => {
+      return api.library.previewSyncRules(rules, await syncTarget(libraryId));
+  };
+
+  const runSyncRules = async (rules: SyncRule[], libraryId: string | null) => {
+      const host = connectedHost;
+      const plan = await api.library.runSyncRules(rules, await syncTarget(libraryId));
+      if (host) for (const book of plan.books) queuedBooks.current[syncKey(host, libraryId, book.id)] = book;
+      return plan;
+  };
+
+  // Re-run auto-sync rules whenever their library's revision changes, and once on connecting
+  useEffect(() => {
+      if (appMode !== "client" || !connectedHost || !token) return;
+      const key = hostKey(connectedHost);
+      const advertised = discoveredHosts.find(h => hostKey(h) === key)?.library_revision
+          ?? connectedHost.library_revision;
+      const autoRules = syncRules.filter(r => r.auto_sync);
+      const libraryIds = [...new Set(autoRules.map(r => r.library_id))];
+      for (const libraryId of libraryIds) {
+          const library = hostLibraries.find(l => libraryId ? l.id === libraryId : l.default);
+          // Hosts serving a single library only advertise the revision of their default one
+          const revision = library?.revision ?? (libraryId ? undefined : advertised);
+          const revisionKey = `${key}/${libraryId ?? ""}`;
+          if (!revision || ruleRevisions.current[revisionKey] === revision) continue;
+          ruleRevisions.current[revisionKey] = revision;
+          runSyncRules(autoRules.filter(r => r.library_id === libraryId), libraryId)
+              .catch(e => console.error("Auto sync failed:", e));
+      }
+  }, [appMode, connectedHost, token, allSyncRules, hostLibraries, discoveredHosts]);
+
   const selectLibraryFolder = async () => {
     try {
       const selected = await open({
@@ -301,9 +430,8 @@ export const LibraryProvider: React.FC<{ children: ReactNode }> = ({ children })
 
           // Push to Host if connected
           if (connectedHost) {
-              const hostKey = `${connectedHost.ip}:${connectedHost.port}`;
-              const token = authTokens[hostKey];
-              fetch(`http://${connectedHost.ip}:${connectedHost.port}/api/progress`, {
+              const token = tokenFor(connectedHost);
+              fetch(`${libraryApi(connectedHost, hostLibraryId)}/progress`, {
                   method: "POST",
                   headers: { 
                       "Content-Type": "application/json",
@@ -327,19 +455,30 @@ export const LibraryProvider: React.FC<{ children: ReactNode }> = ({ children })
         error: error || null,
         libraryPath,
         connectedHost,
+        hostLibraries,
+        hostLibraryId,
+        hostSearches,
+        hostSearch,
         authRequired,
         pairingHost,
         authTokens,
         syncProgress,
+        syncRules,
         setAppMode,
         connectToHost,
+        selectHostLibrary,
+        selectHostSearch,
         pair,
+        pairWithUri,
         disconnect,
         syncBook,
         syncBooks,
         selectLibraryFolder,
         openLocalBook,
-        toggleReadStatus
+        toggleReadStatus,
+        saveSyncRules,
+        previewSyncRules,
+        runSyncRules
       }}
     >
       {children}
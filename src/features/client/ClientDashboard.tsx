@@ -14,9 +14,10 @@ import { SearchBar } from "@/components/SearchBar";
 import { SortMenu, SortOption } from "@/components/SortMenu";
 import { LoadingSpinner } from "@/components/Feedback/LoadingSpinner";
 import { Toaster } from "@/components/ui/toaster";
-import { useLibrary } from "@/context/LibraryContext";
+import { syncKey, useLibrary } from "@/context/LibraryContext";
 import { QueueOverlay } from "@/components/QueueOverlay";
 import { EmptyState } from "@/components/EmptyState";
+import { SyncRules } from "./SyncRules";
 
 interface Host {
   ip: string;
@@ -51,7 +52,7 @@ export const ClientDashboard: React.FC<ClientDashboardProps> = ({
   onToggleStatus,
   onChangeRole,
 }) => {
-  const { syncProgress, syncBooks } = useLibrary();
+  const { syncProgress, syncBooks, hostLibraries, hostLibraryId, selectHostLibrary, hostSearches, hostSearch, selectHostSearch } = useLibrary();
   const [searchTerm, setSearchTerm] = React.useState("");
   const [sortOption, setSortOption] = React.useState<SortOption>("title");
   const [selectionMode, setSelectionMode] = React.useState(false);
@@ -189,18 +190,83 @@ export const ClientDashboard: React.FC<ClientDashboardProps> = ({
                   </HStack>
               </HStack>
 
+              {hostLibraries.length > 1 && (
+                  <HStack gap={2} wrap="wrap">
+                      {hostLibraries.map((library) => {
+                          const active = library.default ? !hostLibraryId : hostLibraryId === library.id;
+                          return (
+                              <Button
+                                  key={library.id}
+                                  size="xs"
+                                  variant={active ? "solid" : "outline"}
+                                  colorPalette={active ? "blue" : "gray"}
+                                  onClick={() => {
+                                      selectHostLibrary(library.default ? null : library.id);
+                                      setSelectedIds(new Set());
+                                  }}
+                              >
+                                  {library.name}
+                                  <Badge size="xs" variant="surface">{library.book_count}</Badge>
+                              </Button>
+                          );
+                      })}
+                  </HStack>
+              )}
+
+              {hostSearches.length > 0 && (
+                  <HStack gap={2} wrap="wrap">
+                      <Button
+                          size="xs"
+                          variant={hostSearch ? "outline" : "solid"}
+                          colorPalette={hostSearch ? "gray" : "purple"}
+                          onClick={() => {
+                              selectHostSearch(null);
+                              setSelectedIds(new Set());
+                          }}
+                      >
+                          All books
+                      </Button>
+                      {hostSearches.map((search) => {
+                          const active = hostSearch?.kind === search.kind && hostSearch.name === search.name;
+                          return (
+                              <Button
+                                  key={`${search.kind}:${search.name}`}
+                                  size="xs"
+                                  variant={active ? "solid" : "outline"}
+                                  colorPalette={active ? "purple" : "gray"}
+                                  disabled={search.book_count === null}
+                                  title={search.book_count === null ? "Uses search syntax ShelfSync does not support" : search.query}
+                                  onClick={() => {
+                                      selectHostSearch(search);
+                                      setSelectedIds(new Set());
+                                  }}
+                              >
+                                  {search.kind === "saved_search" && <Icon asChild><Search /></Icon>}
+                                  {search.name}
+                                  {search.book_count !== null && (
+                                      <Badge size="xs" variant="surface">{search.book_count}</Badge>
+                                  )}
+                              </Button>
+                          );
+                      })}
+                  </HStack>
+              )}
+
+              <SyncRules />
+
                 <SimpleGrid columns={{ base: 1, md: 2, lg: 3 }} gap={4}>
                     {filteredRemoteBooks.map((book) => (
                     <BookCard 
                         key={book.id} 
                         book={book} 
                         host={connectedHost}
+                        libraryId={hostLibraryId}
                         variant="remote"
                         onAction={handleSync}
                         selected={selectedIds.has(book.id)}
                         selectable={selectionMode}
                         onSelect={() => toggleSelection(book.id)}
-                        syncStatus={syncProgress[book.id]}
+                        syncStatus={connectedHost ? syncProgress[syncKey(connectedHost, hostLibraryId, book.id)] : undefined}
                         actionLabel="Sync to Replica"
                         actionColor="blue"
                     />
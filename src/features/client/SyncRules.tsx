@@ -0,0 +1,205 @@
+import React, { useState } from "react";
+import { Box, Heading, Text, Button, HStack, VStack, Icon, IconButton, Badge, Input } from "@chakra-ui/react";
+import { ListFilter, Play, Plus, X } from "lucide-react";
+import { SyncCondition, SyncPlan, SyncRule } from "@/types";
+import { useLibrary } from "@/context/LibraryContext";
+
+// Conditions a new rule can start from
+const CONDITION_TEMPLATES: { label: string; condition: SyncCondition }[] = [
+  { label: "Tag", condition: { type: "tag", tag: "" } },
+  { label: "Series in progress", condition: { type: "series_in_progress", count: 3 } },
+  { label: "Recently added", condition: { type: "added_within", days: 30 } },
+  { label: "Size limit", condition: { type: "max_size", megabytes: 50 } },
+  { label: "Want to read", condition: { type: "status", status: "want-to-read" } },
+];
+
+const describe = (condition: SyncCondition) => {
+  switch (condition.type) {
+    case "tag": return `Tagged ${condition.tag}`;
+    case "series_in_progress": return `Next ${condition.count} in started series`;
+    case "added_within": return `Added in the last ${condition.days} days`;
+    case "max_size": return `Under ${condition.megabytes} MB`;
+    case "status": return `Marked ${condition.status.replace(/-/g, " ")}`;
+  }
+};
+
+const formatSize = (bytes: number) => `${(bytes / (1024 * 1024)).toFixed(1)} MB`;
+
+/**
+ * Saved rules choosing which books of the library being browsed to keep on
+ * this device, with a preview of what they would download.
+ */
+export const SyncRules: React.FC = () => {
+  const { syncRules, hostLibraryId, saveSyncRules, previewSyncRules, runSyncRules } = useLibrary();
+  const [name, setName] = useState("");
+  const [conditions, setConditions] = useState<SyncCondition[]>([]);
+  const [plans, setPlans] = useState<Record<string, SyncPlan>>({});
+  const [error, setError] = useState<string | null>(null);
+
+  const rules = syncRules.filter(r => r.library_id === hostLibraryId);
+
+  const updateCondition = (index: number, value: string) => {
+    setConditions(prev => prev.map((condition, i) => {
+      if (i !== index) return condition;
+      switch (condition.type) {
+        case "tag": return { ...condition, tag: value };
+        case "series_in_progress": return { ...condition, count: Number(value) };
+        case "added_within": return { ...condition, days: Number(value) };
+        case "max_size": return { ...condition, megabytes: Number(value) };
+        case "status": return condition;
+      }
+    }));
+  };
+
+  const handleAdd = async () => {
+    const rule: SyncRule = {
+      id: crypto.randomUUID(),
+      name: name.trim() || conditions.map(describe).join(", "),
+      library_id: hostLibraryId,
+      conditions,
+      auto_sync: false,
+    };
+    await saveSyncRules([...syncRules, rule]);
+    setName("");
+    setConditions([]);
+  };
+
+  const handleRemove = (rule: SyncRule) => saveSyncRules(syncRules.filter(r => r.id !== rule.id));
+
+  const toggleAuto = (rule: SyncRule) =>
+    saveSyncRules(syncRules.map(r => r.id === rule.id ? { ...r, auto_sync: !r.auto_sync } : r));
+
+  const handlePlan = async (rule: SyncRule, run: boolean) => {
+    setError(null);
+    try {
+      const plan = run
+        ? await runSyncRules([rule], rule.library_id)
+        : await previewSyncRules([rule], rule.library_id);
+      setPlans(prev => ({ ...prev, [rule.id]: plan }));
+    } catch (e) {
+      setError(String(e));
+    }
+  };
+
+  const canAdd = conditions.length > 0 &&
+    conditions.every(c => c.type !== "tag" || c.tag.trim().length > 0);
+
+  return (
+    <Box bg="bg.subtle" p={6} borderRadius="xl" borderWidth="1px" borderColor="border">
+      <Heading size="md" display="flex" alignItems="center" gap={2} mb={4}>
+        <Icon color="accent" asChild><ListFilter /></Icon>
+        Sync Rules
+      </Heading>
+
+      <VStack align="stretch" gap={3}>
+        {rules.map((rule) => {
+          const plan = plans[rule.id];
+          return (
+            <VStack key={rule.id} align="stretch" gap={1} p={2} bg="bg.muted" borderRadius="md">
+              <HStack justify="space-between">
+                <Text fontSize="sm" fontWeight="medium">{rule.name}</Text>
+                <HStack gap={1}>
+                  <Button
+                    size="2xs"
+                    variant={rule.auto_sync ? "solid" : "outline"}
+                    colorPalette={rule.auto_sync ? "blue" : "gray"}
+                    onClick={() => toggleAuto(rule)}
+                    title="Re-run whenever the host's library changes"
+                  >
+                    {rule.auto_sync ? "Auto" : "Manual"}
+                  </Button>
+                  <Button size="2xs" variant="outline" onClick={() => handlePlan(rule, false)}>
+                    Preview
+                  </Button>
+                  <IconButton aria-label="Run rule" size="2xs" variant="ghost" onClick={() => handlePlan(rule, true)}>
+                    <Play />
+                  </IconButton>
+                  <IconButton aria-label="Delete rule" size="2xs" variant="ghost" onClick={() => handleRemove(rule)}>
+                    <X />
+                  </IconButton>
+                </HStack>
+              </HStack>
+              <HStack gap={1} wrap="wrap">
+                {rule.conditions.map((condition, i) => (
+                  <Badge key={i} size="xs" variant="surface">{describe(condition)}</Badge>
+                ))}
+              </HStack>
+              {plan && (
+                <Text fontSize="xs" color="fg.muted">
+                  {plan.books.length} to download ({formatSize(plan.total_size)}), {plan.already_synced} already on this device
+                  {plan.books.length > 0 && `: ${plan.books.slice(0, 5).map(b => b.title).join(", ")}`}
+                  {plan.books.length > 5 && "…"}
+                </Text>
+              )}
+            </VStack>
+          );
+        })}
+        {rules.length === 0 && (
+          <Text fontSize="sm" color="fg.muted">
+            No rules yet. Rules pick the books to keep on this device, e.g. the next volumes of series you are reading.
+          </Text>
+        )}
+      </VStack>
+
+      <VStack align="stretch" gap={2} mt={4}>
+        <HStack gap={1} wrap="wrap">
+          {CONDITION_TEMPLATES.map(({ label, condition }) => (
+            <Button
+              key={condition.type}
+              size="2xs"
+              variant="surface"
+              onClick={() => setConditions(prev => [...prev, condition])}
+            >
+              <Icon asChild><Plus /></Icon>
+              {label}
+            </Button>
+          ))}
+        </HStack>
+        {conditions.map((condition, i) => (
+          <HStack key={i} gap={2}>
+            <Text fontSize="xs" flexShrink={0}>{describe(condition)}</Text>
+            {condition.type !== "status" && (
+              <Input
+                size="xs"
+                type={condition.type === "tag" ? "text" : "number"}
+                placeholder={condition.type === "tag" ? "e.g. To Read" : undefined}
+                value={
+                  condition.type === "tag" ? condition.tag
+                    : condition.type === "series_in_progress" ? condition.count
+                    : condition.type === "added_within" ? condition.days
+                    : condition.megabytes
+                }
+                onChange={(e) => updateCondition(i, e.target.value)}
+                bg="bg.muted"
+              />
+            )}
+            <IconButton
+              aria-label="Remove condition"
+              size="2xs"
+              variant="ghost"
+              onClick={() => setConditions(prev => prev.filter((_, j) => j !== i))}
+            >
+              <X />
+            </IconButton>
+          </HStack>
+        ))}
+        {conditions.length > 0 && (
+          <HStack gap={2}>
+            <Input
+              size="xs"
+              placeholder="Rule name"
+              value={name}
+              onChange={(e) => setName(e.target.value)}
+              bg="bg.muted"
+            />
+            <Button size="xs" onClick={handleAdd} disabled={!canAdd}>
+              Save rule
+            </Button>
+          </HStack>
+        )}
+      </VStack>
+
+      {error && <Text fontSize="sm" color="red.500" mt={2}>{error}</Text>}
+    </Box>
+  );
+};
@@ -1,32 +1,49 @@
 import React, { useState } from 'react';
-import { Search, Globe, ChevronRight, RefreshCw, Plus, WifiOff } from 'lucide-react';
-import { Box, Heading, Button, HStack, Input, VStack, Text, Card, Icon, Spinner, Badge } from "@chakra-ui/react";
-import { useDiscovery } from "@/context/DiscoveryContext";
+import { Search, Globe, ChevronRight, RefreshCw, Plus, WifiOff, X, Link } from 'lucide-react';
+import { Alert, Box, Heading, Button, HStack, Input, VStack, Text, Card, Icon, Spinner, Badge, IconButton } from "@chakra-ui/react";
+import { Host, hostKey, useDiscovery } from "@/context/DiscoveryContext";
+import { useLibrary } from "@/context/LibraryContext";
 import { EmptyState } from "@/components/EmptyState";
 import { LoadingSpinner } from "@/components/Feedback/LoadingSpinner";
 
-interface Host {
-  ip: string;
-  port: number;
-  hostname: string;
-}
-
 interface DiscoveryProps {
   onConnect: (host: Host) => void;
 }
 
 export const Discovery: React.FC<DiscoveryProps> = ({ onConnect }) => {
-  const { hosts, scanning, scan, knownHosts } = useDiscovery();
+  const { hosts, scanning, scan, status, knownHosts, showIncompatible, setShowIncompatible, addManualHost, removeManualHost } = useDiscovery();
   const [manualIp, setManualIp] = useState("");
   const [manualPort, setManualPort] = useState("8080");
+  const [manualError, setManualError] = useState<string | null>(null);
+  const { pairWithUri } = useLibrary();
+  const [pairingLink, setPairingLink] = useState("");
+  const [pairingLinkError, setPairingLinkError] = useState<string | null>(null);
+  const [pairingWithLink, setPairingWithLink] = useState(false);
 
-  const handleManualConnect = () => {
-    if (manualIp) {
-      onConnect({
-        ip: manualIp,
-        port: parseInt(manualPort),
-        hostname: "Manual Connection"
-      });
+  const handlePairWithLink = async () => {
+    if (!pairingLink) return;
+    setPairingLinkError(null);
+    setPairingWithLink(true);
+    try {
+      await pairWithUri(pairingLink.trim());
+      setPairingLink("");
+    } catch (e) {
+      setPairingLinkError(String(e));
+    } finally {
+      setPairingWithLink(false);
+    }
+  };
+
+  const handleManualConnect = async () => {
+    if (!manualIp) return;
+    setManualError(null);
+    try {
+      // Saved on the host list so it is probed and offered again next time
+      const host = await addManualHost(manualIp, parseInt(manualPort) || undefined);
+      setManualIp("");
+      onConnect(host);
+    } catch (e) {
+      setManualError(String(e));
     }
   };
 
@@ -37,24 +54,49 @@ export const Discovery: React.FC<DiscoveryProps> = ({ onConnect }) => {
             <Icon color="success" asChild><Search /></Icon>
             Discover Hosts
         </Heading>
-        <Button 
-            onClick={scan}
-            disabled={scanning}
-            variant="surface"
-            size="sm"
-        >
-            {scanning ? <Spinner size="sm" /> : <Icon asChild><RefreshCw /></Icon>}
-            Refresh
-        </Button>
+        <HStack gap={2}>
+          <Button
+              onClick={() => setShowIncompatible(!showIncompatible)}
+              variant={showIncompatible ? "subtle" : "ghost"}
+              size="sm"
+          >
+              {showIncompatible ? "Hide incompatible" : "Show incompatible"}
+          </Button>
+          <Button 
+              onClick={scan}
+              disabled={scanning}
+              variant="surface"
+              size="sm"
+          >
+              {scanning ? <Spinner size="sm" /> : <Icon asChild><RefreshCw /></Icon>}
+              Refresh
+          </Button>
+        </HStack>
       </HStack>
 
+      {status?.mdns === "failed" && (
+        <Alert.Root status="warning">
+          <Alert.Indicator />
+          <Alert.Content>
+            <Alert.Title>Automatic discovery is unavailable</Alert.Title>
+            <Alert.Description>
+              mDNS failed{status.error ? ` (${status.error})` : ""}
+              {status.retry_in_secs !== undefined && `; retrying in ${status.retry_in_secs}s`}.
+              {status.beacon_listening
+                ? " Hosts broadcasting on this network will still appear."
+                : " Add hosts by address below."}
+            </Alert.Description>
+          </Alert.Content>
+        </Alert.Root>
+      )}
+
       <VStack gap={4} align="stretch">
         {scanning && hosts.length === 0 ? (
             <LoadingSpinner message="Searching for local hosts..." />
         ) : hosts.length > 0 ? (
             hosts.map((host) => (
                 <Card.Root
-                    key={`${host.ip}:${host.port}`}
+                    key={hostKey(host)}
                     onClick={() => onConnect(host)}
                     cursor="pointer"
                     _hover={{ bg: "bg.muted" }}
@@ -70,10 +112,36 @@ export const Discovery: React.FC<DiscoveryProps> = ({ onConnect }) => {
                             </Box>
                             <Box>
                                 <Text fontWeight="semibold">{host.hostname}</Text>
+                                {host.library_name && (
+                                    <Text fontSize="sm" color="fg.muted">
+                                        {host.library_name}
+                                        {host.book_count !== undefined && ` · ${host.book_count} books`}
+                                        {host.library_count !== undefined && host.library_count > 1 && ` · ${host.library_count} libraries`}
+                                    </Text>
+                                )}
                                 <Text fontSize="xs" color="fg.muted" fontFamily="mono">{host.ip}:{host.port}</Text>
                             </Box>
                         </HStack>
-                        <Icon color="fg.subtle" asChild><ChevronRight /></Icon>
+                        <HStack gap={2}>
+                            {host.online === false && <Badge size="xs" colorPalette="gray">Offline</Badge>}
+                            {host.online && host.latency_ms !== undefined && (
+                                <Badge size="xs" colorPalette="green">{host.latency_ms} ms</Badge>
+                            )}
+                            {host.manual && <Badge size="xs" variant="outline">Manual</Badge>}
+                            {!host.compatible && <Badge size="xs" colorPalette="red">Incompatible</Badge>}
+                            {host.pairing_open === false && <Badge size="xs" variant="surface">Pairing closed</Badge>}
+                            {host.manual && (
+                                <IconButton
+                                    aria-label="Remove host"
+                                    size="xs"
+                                    variant="ghost"
+                                    onClick={(e) => { e.stopPropagation(); removeManualHost(host); }}
+                                >
+                                    <X />
+                                </IconButton>
+                            )}
+                            <Icon color="fg.subtle" asChild><ChevronRight /></Icon>
+                        </HStack>
                       </HStack>
                     </Card.Body>
                 </Card.Root>
@@ -94,7 +162,7 @@ export const Discovery: React.FC<DiscoveryProps> = ({ onConnect }) => {
               <Heading size="xs" color="fg.muted">Previous Connections</Heading>
               {knownHosts.map(host => (
                   <Card.Root 
-                    key={`history-${host.ip}`} 
+                    key={`history-${hostKey(host)}`} 
                     size="sm" 
                     variant="subtle"
                     onClick={() => onConnect(host)}
@@ -122,7 +190,7 @@ export const Discovery: React.FC<DiscoveryProps> = ({ onConnect }) => {
         </Heading>
         <HStack gap={2}>
             <Input 
-                placeholder="IP Address" 
+                placeholder="IP address or hostname" 
                 value={manualIp}
                 onChange={(e) => setManualIp(e.target.value)}
                 flex={1}
@@ -145,6 +213,37 @@ export const Discovery: React.FC<DiscoveryProps> = ({ onConnect }) => {
                 Connect
             </Button>
         </HStack>
+        {manualError && <Text fontSize="sm" color="red.500" mt={2}>{manualError}</Text>}
+      </Box>
+
+      <Box>
+        <Heading size="xs" color="fg.muted" mb={3} display="flex" alignItems="center" gap={2}>
+            <Icon asChild><Link /></Icon>
+            Pair with Link
+        </Heading>
+        <HStack gap={2}>
+            <Input 
+                placeholder="shelfsync://pair?..." 
+                value={pairingLink}
+                onChange={(e) => setPairingLink(e.target.value)}
+                flex={1}
+                bg="bg.subtle"
+                borderColor="border"
+                fontFamily="mono"
+            />
+            <Button 
+                onClick={handlePairWithLink}
+                disabled={!pairingLink}
+                loading={pairingWithLink}
+                colorPalette="green"
+            >
+                Pair
+            </Button>
+        </HStack>
+        <Text fontSize="xs" color="fg.subtle" mt={2}>
+            Paste the link from the host's QR code to pair and connect in one step.
+        </Text>
+        {pairingLinkError && <Text fontSize="sm" color="red.500" mt={2}>{pairingLinkError}</Text>}
       </Box>
     </VStack>
   );
@@ -1,4 +1,4 @@
-import React from 'react';
+import React, { useEffect, useState } from 'react';
 import { 
   Box, Container, HStack, Heading, Text, Button, Icon, 
   SimpleGrid, Spinner, VStack, Grid, GridItem, Alert 
@@ -8,8 +8,10 @@ import QRCode from "react-qr-code";
 import { ColorModeButton } from "@/components/ui/color-mode";
 import { Footer } from "@/components/Footer";
 import { SkipLink } from "@/components/SkipLink";
-import { Book, ConnectionInfo } from "@/types";
+import { Book, ConnectionInfo, PairingCode } from "@/types";
+import { api } from "@/services/api";
 import { BookCard } from "@/components/BookCard";
+import { SharedLibraries } from "./SharedLibraries";
 
 interface HostDashboardProps {
   books: Book[];
@@ -30,6 +32,27 @@ export const HostDashboard: React.FC<HostDashboardProps> = ({
   onSelectFolder,
   onChangeRole,
 }) => {
+  const [pairingCode, setPairingCode] = useState<PairingCode | null>(null);
+
+  // Pairing links are single-use and short-lived, so a fresh one is fetched before each expires
+  useEffect(() => {
+    if (!connectionInfo) return;
+    let timer: ReturnType<typeof setTimeout>;
+    const refresh = async () => {
+      try {
+        const code = await api.network.createPairingCode();
+        setPairingCode(code);
+        timer = setTimeout(refresh, Math.max(code.expires_at * 1000 - Date.now() - 30_000, 10_000));
+      } catch (e) {
+        console.error("Failed to create pairing code", e);
+        setPairingCode(null);
+        timer = setTimeout(refresh, 30_000);
+      }
+    };
+    refresh();
+    return () => clearTimeout(timer);
+  }, [connectionInfo]);
+
   return (
     <>
     <SkipLink />
@@ -112,7 +135,7 @@ export const HostDashboard: React.FC<HostDashboardProps> = ({
                     <VStack align="stretch" gap={6}>
                        <Box bg="white" p={4} borderRadius="lg" display="flex" justifyContent="center">
                           <QRCode 
-                            value={JSON.stringify(connectionInfo)}
+                            value={pairingCode?.uri ?? JSON.stringify(connectionInfo)}
                             size={200}
                             style={{ height: "auto", maxWidth: "100%", width: "100%" }}
                             viewBox={`0 0 256 256`}
@@ -125,6 +148,11 @@ export const HostDashboard: React.FC<HostDashboardProps> = ({
                               <Box>
                                  <Text fontSize="xs" color="fg.subtle" textTransform="uppercase">Host IP</Text>
                                  <Text fontFamily="mono" fontSize="lg">{connectionInfo.ip}</Text>
+                                 {connectionInfo.addresses && connectionInfo.addresses.length > 1 && (
+                                    <Text fontFamily="mono" fontSize="xs" color="fg.muted">
+                                       Also {connectionInfo.addresses.slice(1).join(", ")}
+                                    </Text>
+                                 )}
                               </Box>
                            </HStack>
 
@@ -157,13 +185,15 @@ export const HostDashboard: React.FC<HostDashboardProps> = ({
                        </VStack>
                        
                        <Text fontSize="xs" color="fg.subtle" textAlign="center">
-                          Scan this QR code with the ShelfSync mobile app to connect.
+                          Scan this QR code with the ShelfSync mobile app to pair and connect. It changes every few minutes and works once.
                        </Text>
                     </VStack>
                  ) : (
                     <Text textAlign="center" py={10} color="fg.muted">Loading network info...</Text>
                  )}
               </Box>
+
+              <SharedLibraries libraryPath={libraryPath} />
            </GridItem>
         </Grid>
       </Container>
@@ -0,0 +1,244 @@
+import React, { useEffect, useState } from 'react';
+import { Box, Heading, Text, Button, HStack, VStack, Icon, IconButton, Badge, Input } from "@chakra-ui/react";
+import { Library, Plus, Smartphone, X } from "lucide-react";
+import { open } from "@tauri-apps/plugin-dialog";
+import { Device, DeviceScope, LibraryInfo } from "@/types";
+import { api } from "@/services/api";
+
+interface SharedLibrariesProps {
+  // Changes whenever the default library does, to refresh the list
+  libraryPath: string;
+}
+
+/**
+ * Lists the libraries this host serves besides the default one, and what
+ * each paired device, and each device paired from now on, may see and do.
+ */
+export const SharedLibraries: React.FC<SharedLibrariesProps> = ({ libraryPath }) => {
+  const [libraries, setLibraries] = useState<LibraryInfo[]>([]);
+  const [devices, setDevices] = useState<Device[]>([]);
+  const [defaultScope, setDefaultScope] = useState<DeviceScope | null>(null);
+  const [error, setError] = useState<string | null>(null);
+
+  const refresh = async () => {
+    try {
+      setLibraries(await api.library.listLibraries());
+      setDevices(await api.library.listDevices());
+      setDefaultScope(await api.library.getDefaultScope());
+    } catch (e) {
+      setError(String(e));
+    }
+  };
+
+  // Devices pair while this is shown, so they are re-listed periodically
+  useEffect(() => {
+    refresh();
+    const timer = setInterval(refresh, 10_000);
+    return () => clearInterval(timer);
+  }, [libraryPath]);
+
+  const handleAdd = async () => {
+    setError(null);
+    try {
+      const selected = await open({
+        directory: true,
+        multiple: false,
+        title: "Select Calibre Library Folder",
+      });
+      if (selected && typeof selected === "string") {
+        await api.library.addLibrary(selected);
+        await refresh();
+      }
+    } catch (e) {
+      setError(String(e));
+    }
+  };
+
+  const handleRemove = async (id: string) => {
+    setError(null);
+    try {
+      await api.library.removeLibrary(id);
+      await refresh();
+    } catch (e) {
+      setError(String(e));
+    }
+  };
+
+  // Merges a change into a scope and saves it with `save`
+  const updateScope = async (
+    current: DeviceScope,
+    change: Partial<DeviceScope>,
+    save: (scope: DeviceScope) => Promise<void>,
+  ) => {
+    const scope: DeviceScope = {
+      libraries: current.libraries,
+      hidden_tags: current.hidden_tags,
+      virtual_library: current.virtual_library,
+      can_download: current.can_download,
+      can_write: current.can_write,
+      ...change,
+    };
+    try {
+      await save(scope);
+      await refresh();
+    } catch (e) {
+      setError(String(e));
+    }
+  };
+
+  // Controls for what a device (or every newly paired one) may see and do.
+  // Paired devices refer to the default library by `scope_id`, newly paired
+  // ones by `id` until they pair.
+  const scopeControls = (
+    scope: DeviceScope,
+    save: (scope: DeviceScope) => Promise<void>,
+    libraryKey: (library: LibraryInfo) => string,
+  ) => {
+    const change = (update: Partial<DeviceScope>) => updateScope(scope, update, save);
+
+    // Toggles one library; granting every library stores no list
+    const toggleLibrary = (libraryId: string) => {
+      const granted = scope.libraries ?? libraries.map(libraryKey);
+      const next = granted.includes(libraryId)
+        ? granted.filter(id => id !== libraryId)
+        : [...granted, libraryId];
+      const all = libraries.every(l => next.includes(libraryKey(l)));
+      return change({ libraries: all ? undefined : next });
+    };
+
+    const setHiddenTags = (value: string) => {
+      const tags = value.split(",").map(t => t.trim()).filter(t => t.length > 0);
+      if (tags.join(",") === scope.hidden_tags.join(",")) return;
+      return change({ hidden_tags: tags });
+    };
+
+    const setVirtualLibrary = (value: string) => {
+      const name = value.trim() || undefined;
+      if (name === scope.virtual_library) return;
+      return change({ virtual_library: name });
+    };
+
+    return (
+      <>
+        {libraries.length > 1 && (
+          <HStack gap={1} wrap="wrap">
+            {libraries.map((library) => {
+              const granted = !scope.libraries || scope.libraries.includes(libraryKey(library));
+              return (
+                <Button
+                  key={library.id}
+                  size="2xs"
+                  variant={granted ? "solid" : "outline"}
+                  colorPalette={granted ? "green" : "gray"}
+                  onClick={() => toggleLibrary(libraryKey(library))}
+                >
+                  {library.name}
+                </Button>
+              );
+            })}
+          </HStack>
+        )}
+        <HStack gap={1}>
+          <Button
+            size="2xs"
+            variant={scope.can_download ? "solid" : "outline"}
+            colorPalette={scope.can_download ? "blue" : "gray"}
+            onClick={() => change({ can_download: !scope.can_download })}
+          >
+            {scope.can_download ? "Can download" : "Browse only"}
+          </Button>
+          <Button
+            size="2xs"
+            variant={scope.can_write ? "solid" : "outline"}
+            colorPalette={scope.can_write ? "blue" : "gray"}
+            onClick={() => change({ can_write: !scope.can_write })}
+          >
+            {scope.can_write ? "Saves progress" : "Read-only"}
+          </Button>
+        </HStack>
+        <Input
+          key={scope.hidden_tags.join(",")}
+          size="xs"
+          placeholder="Hide tags, e.g. Adult, Horror"
+          defaultValue={scope.hidden_tags.join(", ")}
+          onBlur={(e) => setHiddenTags(e.target.value)}
+          onKeyDown={(e) => e.key === "Enter" && setHiddenTags(e.currentTarget.value)}
+          bg="bg.muted"
+        />
+        <Input
+          key={scope.virtual_library ?? ""}
+          size="xs"
+          placeholder="Limit to a Calibre virtual library"
+          defaultValue={scope.virtual_library ?? ""}
+          onBlur={(e) => setVirtualLibrary(e.target.value)}
+          onKeyDown={(e) => e.key === "Enter" && setVirtualLibrary(e.currentTarget.value)}
+          bg="bg.muted"
+        />
+      </>
+    );
+  };
+
+  return (
+    <Box bg="bg.subtle" p={6} borderRadius="xl" borderWidth="1px" borderColor="border" mt={6}>
+      <HStack justify="space-between" mb={4}>
+        <Heading size="md" display="flex" alignItems="center" gap={2}>
+          <Icon color="accent" asChild><Library /></Icon>
+          Libraries
+        </Heading>
+        <Button onClick={handleAdd} size="xs" variant="surface">
+          <Icon asChild><Plus /></Icon>
+          Add
+        </Button>
+      </HStack>
+
+      <VStack align="stretch" gap={2}>
+        {libraries.map((library) => (
+          <HStack key={library.id} justify="space-between" p={2} bg="bg.muted" borderRadius="md">
+            <Box>
+              <Text fontSize="sm" fontWeight="medium">{library.name}</Text>
+              <Text fontSize="xs" color="fg.muted">{library.book_count} books</Text>
+            </Box>
+            {library.default ? (
+              <Badge size="xs" variant="surface">Default</Badge>
+            ) : (
+              <IconButton aria-label="Stop serving library" size="xs" variant="ghost" onClick={() => handleRemove(library.id)}>
+                <X />
+              </IconButton>
+            )}
+          </HStack>
+        ))}
+        {libraries.length === 0 && (
+          <Text fontSize="sm" color="fg.muted">No libraries are served yet.</Text>
+        )}
+      </VStack>
+
+      {defaultScope && (
+        <VStack align="stretch" gap={4} mt={6}>
+          <Heading size="xs" color="fg.muted">Device Access</Heading>
+          <VStack align="stretch" gap={2}>
+            <HStack gap={2}>
+              <Icon color="fg.subtle" asChild><Plus /></Icon>
+              <Text fontSize="sm">New devices</Text>
+            </HStack>
+            {scopeControls(defaultScope, api.library.setDefaultScope, (library) => library.id)}
+          </VStack>
+          {devices.map((device) => (
+            <VStack key={device.id} align="stretch" gap={2}>
+              <HStack gap={2}>
+                <Icon color="fg.subtle" asChild><Smartphone /></Icon>
+                <Text fontSize="sm">{device.name ?? "Unnamed device"}</Text>
+              </HStack>
+              {scopeControls(
+                device,
+                (scope) => api.library.setDeviceScope(device.id, scope),
+                (library) => library.scope_id,
+              )}
+            </VStack>
+          ))}
+        </VStack>
+      )}
+
+      {error && <Text fontSize="sm" color="red.500" mt={2}>{error}</Text>}
+    </Box>
+  );
+};
@@ -1,7 +1,7 @@
 import { useQuery, useMutation } from "@tanstack/react-query";
 import { api } from "@/services/api";
-import { Book } from "@/types";
-import { Host } from "@/context/DiscoveryContext";
+import { Book, LibraryInfo, SavedSearch } from "@/types";
+import { Host, hostOrigin, libraryApi } from "@/context/DiscoveryContext";
 
 // --- Keys ---
 /**
@@ -9,10 +9,18 @@ import { Host } from "@/context/DiscoveryContext";
  */
 export const libraryKeys = {
   all: ["library"] as const,
-  manifest: (host: string) => [...libraryKeys.all, "manifest", host] as const,
+  manifest: (host: string, libraryId: string, search: string) => [...libraryKeys.all, "manifest", host, libraryId, search] as const,
+  hostLibraries: (host: string) => [...libraryKeys.all, "host-libraries", host] as const,
+  hostSearches: (host: string, libraryId: string) => [...libraryKeys.all, "host-searches", host, libraryId] as const,
   local: (path: string) => [...libraryKeys.all, "local", path] as const,
 };
 
+// Manifest query string selecting the books of a virtual library or saved search
+const searchParams = (search: SavedSearch | null) => {
+  if (!search) return "";
+  return `?${search.kind}=${encodeURIComponent(search.name)}`;
+};
+
 // --- Hooks ---
 
 /**
@@ -21,21 +29,25 @@ export const libraryKeys = {
  * @param host - The connected host object.
  * @param token - The authentication token.
  * @param enabled - Whether the query should run (e.g., only in client mode).
+ * @param libraryId - The host's library to list; its default library if null.
+ * @param search - A virtual library or saved search to list the books of; every book if null.
  * @returns A query result containing the list of books.
  */
 export const useHostManifest = (
   host: Host | null,
   token: string | undefined,
-  enabled: boolean
+  enabled: boolean,
+  libraryId: string | null = null,
+  search: SavedSearch | null = null
 ) => {
   return useQuery({
-    queryKey: libraryKeys.manifest(host ? `${host.ip}:${host.port}` : ""),
+    queryKey: libraryKeys.manifest(host ? hostOrigin(host) : "", libraryId ?? "", searchParams(search)),
     queryFn: async () => {
       if (!host) throw new Error("No host selected");
       const headers: Record<string, string> = {};
       if (token) headers["Authorization"] = `Bearer ${token}`;
 
-      const response = await fetch(`http://${host.ip}:${host.port}/api/manifest`, {
+      const response = await fetch(`${libraryApi(host, libraryId)}/manifest${searchParams(search)}`, {
         headers,
       });
 
@@ -58,6 +70,88 @@ export const useHostManifest = (
   });
 };
 
+/**
+ * Lists the libraries a remote host lets this device use.
+ *
+ * @param host - The connected host object.
+ * @param token - The authentication token.
+ * @param enabled - Whether the query should run (e.g., only in client mode).
+ * @returns A query result containing the libraries; empty for hosts serving a single library.
+ */
+export const useHostLibraries = (
+  host: Host | null,
+  token: string | undefined,
+  enabled: boolean
+) => {
+  return useQuery({
+    queryKey: libraryKeys.hostLibraries(host ? hostOrigin(host) : ""),
+    queryFn: async () => {
+      if (!host) throw new Error("No host selected");
+      const headers: Record<string, string> = {};
+      if (token) headers["Authorization"] = `Bearer ${token}`;
+
+      const response = await fetch(`${hostOrigin(host)}/api/libraries`, {
+        headers,
+      });
+
+      // Hosts from before several libraries could be served
+      if (response.status === 404) {
+        return [] as LibraryInfo[];
+      }
+
+      if (!response.ok) {
+        throw new Error("Failed to fetch libraries");
+      }
+
+      return response.json() as Promise<LibraryInfo[]>;
+    },
+    enabled: enabled && !!host && !!token,
+    // Revisions tell sync rules when to re-run
+    refetchInterval: 60_000,
+  });
+};
+
+/**
+ * Lists the Calibre virtual libraries and saved searches of a remote host's library.
+ *
+ * @param host - The connected host object.
+ * @param token - The authentication token.
+ * @param enabled - Whether the query should run (e.g., only in client mode).
+ * @param libraryId - The host's library; its default library if null.
+ * @returns A query result containing the searches; empty for hosts that do not list them.
+ */
+export const useHostSearches = (
+  host: Host | null,
+  token: string | undefined,
+  enabled: boolean,
+  libraryId: string | null = null
+) => {
+  return useQuery({
+    queryKey: libraryKeys.hostSearches(host ? hostOrigin(host) : "", libraryId ?? ""),
+    queryFn: async () => {
+      if (!host) throw new Error("No host selected");
+      const headers: Record<string, string> = {};
+      if (token) headers["Authorization"] = `Bearer ${token}`;
+
+      const response = await fetch(`${libraryApi(host, libraryId)}/searches`, {
+        headers,
+      });
+
+      // Hosts from before searches were exposed
+      if (response.status === 404) {
+        return [] as SavedSearch[];
+      }
+
+      if (!response.ok) {
+        throw new Error("Failed to fetch searches");
+      }
+
+      return response.json() as Promise<SavedSearch[]>;
+    },
+    enabled: enabled && !!host && !!token,
+  });
+};
+
 /**
  * Fetches books from the local Calibre database (Host Mode).
  *
@@ -83,7 +177,7 @@ export const useLocalLibrary = (libraryPath: string | null) => {
 export const useCheckPin = () => {
   return useMutation({
     mutationFn: async ({ host, pin }: { host: Host; pin: string }) => {
-      const response = await fetch(`http://${host.ip}:${host.port}/api/check-pin`, {
+      const response = await fetch(`${hostOrigin(host)}/api/check-pin`, {
         method: "POST",
         headers: { "Content-Type": "application/json" },
         body: JSON.stringify({ pin }),
@@ -1,5 +1,34 @@
 import { invoke } from "@tauri-apps/api/core";
-import { Book, ConnectionInfo } from "@/types";
+import {
+    Book,
+    CalibreColumn,
+    CalibreWritebackConfig,
+    CalibreWritebackReport,
+    ConnectionInfo,
+    Device,
+    DeviceScope,
+    DiscoveryStatus,
+    ImportPlan,
+    ImportReport,
+    ImportSource,
+    InboxItem,
+    LibraryInfo,
+    Paired,
+    PairingCode,
+    ReadingStats,
+    SyncPlan,
+    SyncRule,
+} from "@/types";
+
+// Where sync rules fetch their books from and download them to
+export interface SyncTarget {
+    hostIp: string;
+    hostPort: number;
+    token: string;
+    hostKey: string;
+    libraryId: string | null;
+    destinationRoot: string;
+}
 
 export const api = {
     library: {
@@ -9,14 +38,99 @@ export const api = {
         setLibraryPath: (path: string) => 
             invoke<void>("set_library_path", { path }),
 
-        startBulkSync: (bookIds: number[]) =>
-            invoke<void>("start_bulk_sync", { bookIds }),
+        startBulkSync: (books: Book[], target: SyncTarget) =>
+            invoke<void>("start_bulk_sync", { books, target }),
+
+        listLibraries: () =>
+            invoke<LibraryInfo[]>("list_libraries"),
+
+        addLibrary: (path: string, name?: string) =>
+            invoke<LibraryInfo>("add_library", { path, name }),
+
+        removeLibrary: (id: string) =>
+            invoke<boolean>("remove_library", { id }),
+
+        listDevices: () =>
+            invoke<Device[]>("list_devices"),
+
+        setDeviceScope: (deviceId: string, scope: DeviceScope) =>
+            invoke<void>("set_device_scope", { deviceId, scope }),
+
+        getDefaultScope: () =>
+            invoke<DeviceScope>("get_default_scope"),
+
+        setDefaultScope: (scope: DeviceScope) =>
+            invoke<void>("set_default_scope", { scope }),
+
+        previewSyncRules: (rules: SyncRule[], target: SyncTarget) =>
+            invoke<SyncPlan>("preview_sync_rules", { rules, target }),
+
+        runSyncRules: (rules: SyncRule[], target: SyncTarget) =>
+            invoke<SyncPlan>("run_sync_rules", { rules, target }),
+    },
+    inbox: {
+        list: () =>
+            invoke<InboxItem[]>("list_inbox"),
+
+        importItem: (id: string, destination: string) =>
+            invoke<string>("import_inbox_item", { id, destination }),
+
+        discard: (id: string) =>
+            invoke<void>("discard_inbox_item", { id }),
+    },
+    calibre: {
+        listColumns: () =>
+            invoke<CalibreColumn[]>("list_calibre_columns"),
+
+        getWriteback: () =>
+            invoke<CalibreWritebackConfig>("get_calibre_writeback"),
+
+        setWriteback: (config: CalibreWritebackConfig) =>
+            invoke<void>("set_calibre_writeback", { config }),
+
+        runWriteback: () =>
+            invoke<CalibreWritebackReport>("run_calibre_writeback"),
+    },
+    reading: {
+        getStats: (from?: number, to?: number) =>
+            invoke<ReadingStats>("get_reading_stats", {
+                from,
+                to,
+                utcOffsetMinutes: -new Date().getTimezoneOffset(),
+            }),
+
+        previewImport: (source: ImportSource, path?: string) =>
+            invoke<ImportPlan>("preview_history_import", { source, path }),
+
+        applyImport: (plan: ImportPlan) =>
+            invoke<ImportReport>("apply_history_import", { plan }),
+
+        exportAnnotations: (bookId: number, format: "markdown" | "json" = "markdown") =>
+            invoke<string>("export_annotations", { bookId, format }),
     },
     network: {
         getConnectionInfo: () => 
             invoke<ConnectionInfo>("get_connection_info"),
             
-        discoverHosts: () => 
-            invoke<ConnectionInfo[]>("discover_hosts"),
+        discoverHosts: (includeIncompatible = false) => 
+            invoke<ConnectionInfo[]>("discover_hosts", { includeIncompatible }),
+
+        addManualHost: (address: string, port?: number) =>
+            invoke<ConnectionInfo>("add_manual_host", { address, port }),
+
+        removeManualHost: (key: string) =>
+            invoke<void>("remove_manual_host", { key }),
+
+        getDiscoveryStatus: () =>
+            invoke<DiscoveryStatus>("get_discovery_status"),
+
+        setPairingOpen: (open: boolean) =>
+            invoke<void>("set_pairing_open", { open }),
+
+        createPairingCode: () =>
+            invoke<PairingCode>("create_pairing_code"),
+
+        pairWithUri: (uri: string) =>
+            invoke<Paired>("pair_with_uri", { uri }),
     }
 };
@@ -1,3 +1,5 @@
+export type ReadingStatus = 'unread' | 'reading' | 'finished' | 'abandoned' | 'want-to-read';
+
 export interface Book {
     id: number;
     title: string;
@@ -9,17 +11,225 @@ export interface Book {
     series_index?: number;
     tags?: string[];
     publisher?: string;
+    added_at?: number;  // Unix timestamp of when it was added to the library
+    size?: number;      // Bytes of its largest format
     
     // Client-side only extensions
     local_path?: string; 
     remote_id?: number; 
     format?: string;
-    read_status?: 'unread' | 'reading' | 'finished';
+    read_status?: ReadingStatus;
 }
 
 export interface ConnectionInfo {
     ip: string;
+    // Every address of the host, in the order to try them; `ip` is the first
+    addresses?: string[];
     port: number;
     hostname: string;
     pin?: string;
-}
\ No newline at end of file
+    // Published in the host's mDNS TXT record; missing on older hosts
+    host_id?: string;
+    version?: string;
+    api_version?: number;
+    library_name?: string;
+    book_count?: number;
+    library_revision?: string;
+    tls_fingerprint?: string;
+    pairing_open?: boolean;
+    // How many libraries the host serves; GET /api/libraries lists them
+    library_count?: number;
+    // IDs of the served libraries, cut to what fits the TXT record
+    library_ids?: string[];
+    compatible: boolean;
+    // Added by address rather than found over mDNS
+    manual?: boolean;
+    // From the last /api/health probe; undefined until probed
+    online?: boolean;
+    latency_ms?: number;
+}
+
+// A one-time pairing link, shown by the host as a QR code
+export interface PairingCode {
+    uri: string;
+    // Unix timestamp after which the link stops working
+    expires_at: number;
+    svg: string;
+}
+
+export interface Paired {
+    host: ConnectionInfo;
+    token: string;
+}
+
+// A Calibre library served by a host, as listed by /api/libraries
+export interface LibraryInfo {
+    id: string;
+    name: string;
+    book_count: number;
+    revision: string;
+    // Also served on the un-namespaced /api routes
+    default: boolean;
+    // How device scopes refer to the library; for the default one this
+    // follows its path, so a grant survives selecting another library
+    scope_id: string;
+}
+
+// A Calibre virtual library or saved search, as listed by /api/searches
+export interface SavedSearch {
+    name: string;
+    kind: "virtual_library" | "saved_search";
+    // In Calibre's search syntax
+    query: string;
+    // Matching books; null when the search uses unsupported syntax
+    book_count: number | null;
+}
+
+// One requirement of a sync rule
+export type SyncCondition =
+    | { type: "tag"; tag: string }
+    // Books being read in a series and the next `count` unread volumes
+    | { type: "series_in_progress"; count: number }
+    | { type: "added_within"; days: number }
+    | { type: "max_size"; megabytes: number }
+    | { type: "status"; status: ReadingStatus };
+
+// A saved rule choosing which of a host's books to keep on this device
+export interface SyncRule {
+    id: string;
+    name: string;
+    // Host library it applies to; its default library when null
+    library_id: string | null;
+    // A book must meet every condition
+    conditions: SyncCondition[];
+    // Re-run whenever the host's library changes
+    auto_sync: boolean;
+}
+
+// What running sync rules would download
+export interface SyncPlan {
+    books: Book[];
+    already_synced: number;
+    total_size: number;
+}
+
+// What a paired device may see and do
+export interface DeviceScope {
+    // IDs of the libraries it may use; every library when missing
+    libraries?: string[];
+    // Books with these tags (or tags nested under them) are hidden
+    hidden_tags: string[];
+    // Name of a Calibre virtual library it is limited to
+    virtual_library?: string;
+    // Browse-only when false
+    can_download: boolean;
+    // Whether it may record progress and annotations, and upload books
+    can_write: boolean;
+}
+
+// A device paired with this host
+export interface Device extends DeviceScope {
+    id: string;
+    name?: string;
+    paired_at: number; // Unix timestamp
+}
+
+export interface DiscoveryStatus {
+    mdns: "starting" | "running" | "failed";
+    error?: string;
+    retry_in_secs?: number;
+    // Announcing this host with UDP broadcasts, for clients without mDNS
+    beacon_sending: boolean;
+    beacon_listening: boolean;
+}
+
+export interface InboxItem {
+    id: string;
+    file_name: string;
+    format: string;
+    size: number;
+    title?: string;
+    authors?: string;
+    series?: string;
+    series_index?: number;
+    tags: string[];
+    uploaded_at: number; // Unix timestamp
+}
+
+export interface CalibreColumn {
+    id: number;
+    label: string;
+    name: string;
+    datatype: 'bool' | 'int' | 'float' | 'datetime';
+}
+
+export interface CalibreWritebackConfig {
+    enabled: boolean;
+    read_column?: string | null;
+    progress_column?: string | null;
+    last_read_column?: string | null;
+}
+
+export interface CalibreWritebackReport {
+    updated: number;
+    skipped: number;
+    backup?: string | null;
+}
+
+export interface ReadingStats {
+    total_seconds: number;
+    sessions: number;
+    per_day: { date: string; seconds: number }[];
+    per_book: {
+        book_id: number;
+        title?: string | null;
+        seconds: number;
+        sessions: number;
+        pages_read: number;
+        percentage_read: number;
+    }[];
+    per_author: { author: string; seconds: number }[];
+    finished_per_year: { year: number; finished: number }[];
+    current_streak: number;
+    longest_streak: number;
+}
+
+export type ImportSource = 'koreader-statistics' | 'koreader-sidecar' | 'calibre';
+
+export interface ImportItem {
+    source: ImportSource;
+    title: string;
+    authors?: string | null;
+    md5?: string | null;
+    // ISBNs and other identifiers, e.g. "isbn:9780441013593"
+    identifiers?: string[];
+    book_id?: number | null;
+    matched_title?: string | null;
+    method?: 'md5' | 'identifier' | 'calibre-id' | 'title-author' | 'title' | null;
+    include: boolean;
+    status?: ReadingStatus | null;
+    percentage?: number | null;
+    locator?: string | null;
+    last_read?: number | null;
+    sessions: {
+        book_id: number;
+        device?: string | null;
+        started_at: number;
+        ended_at: number;
+        start_percentage?: number | null;
+        end_percentage?: number | null;
+        pages_read?: number | null;
+    }[];
+    annotations: number;
+}
+
+export interface ImportPlan {
+    items: ImportItem[];
+}
+
+export interface ImportReport {
+    progress_updated: number;
+    sessions_imported: number;
+    sessions_skipped: number;
+    items_skipped: number;
+}